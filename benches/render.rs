@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use chimera_md::bench_support::{add_anchors_to_headings, parse_markdown, ResultCache};
+
+// A document of some number of sections, each with the markup a typical
+// page has: headings, lists, emphasis, links, a code block and a table
+fn document(sections: usize) -> String {
+    let mut md = String::from("# Benchmark document\n\n");
+    for section in 0..sections {
+        md.push_str(format!("## Section {section}\n\n").as_str());
+        md.push_str("Some *emphasised* and **strong** text, with a [link](other.md#section) and `inline code`. ");
+        md.push_str("A second sentence to make the paragraph a more typical length for prose.\n\n");
+        md.push_str("### Details\n\n- First point\n- Second point with [a link](https://example.com)\n- Third point\n\n");
+        md.push_str("```rust\nfn main() {\n    println!(\"hello\");\n}\n```\n\n");
+        md.push_str("| Name | Value |\n|------|-------|\n| one | 1 |\n| two | 2 |\n\n");
+    }
+    md
+}
+
+fn bench_parse_markdown(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_markdown");
+    for (name, sections) in [("small", 2), ("large", 200)] {
+        let md = document(sections);
+        group.throughput(Throughput::Bytes(md.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), md.as_str(), |b, md| {
+            b.iter(|| parse_markdown(black_box(md)));
+        });
+    }
+    group.finish();
+}
+
+fn bench_add_anchors(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_anchors_to_headings");
+    for (name, sections) in [("small", 2), ("large", 200)] {
+        let (body, scraper) = parse_markdown(document(sections).as_str());
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| add_anchors_to_headings(black_box(body.clone()), &scraper));
+        });
+    }
+    group.finish();
+}
+
+// Tasks adding and getting pages at the same time, over a cache big enough
+// that nothing is evicted
+fn bench_result_cache(c: &mut Criterion) {
+    const PAGES: usize = 100;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let (html, _scraper) = parse_markdown(document(20).as_str());
+    let paths: Vec<PathBuf> = (0..PAGES).map(|page| PathBuf::from(format!("page-{page}.md"))).collect();
+    let mut group = c.benchmark_group("result_cache");
+    for tasks in [1, 4, 16] {
+        let cache = runtime.block_on(async { ResultCache::new(usize::MAX) });
+        group.throughput(Throughput::Elements((tasks * PAGES * 2) as u64));
+        group.bench_with_input(BenchmarkId::new("add_get", tasks), &tasks, |b, &tasks| {
+            b.iter(|| runtime.block_on(async {
+                let workers: Vec<_> = (0..tasks).map(|task| {
+                    let cache = cache.clone();
+                    let paths = paths.clone();
+                    let html = html.clone();
+                    tokio::spawn(async move {
+                        for (page, path) in paths.iter().enumerate() {
+                            // half the tasks write first, so reads and writes overlap
+                            if (page + task) % 2 == 0 {
+                                cache.add_variant(path, "", html.as_str()).await;
+                                black_box(cache.get(path).await);
+                            }
+                            else {
+                                black_box(cache.get(path).await);
+                                cache.add_variant(path, "", html.as_str()).await;
+                            }
+                        }
+                    })
+                }).collect();
+                for worker in workers {
+                    worker.await.unwrap();
+                }
+            }));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_markdown, bench_add_anchors, bench_result_cache);
+criterion_main!(benches);
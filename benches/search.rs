@@ -0,0 +1,43 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use chimera_md::bench_support::SearchFixture;
+
+const DOCUMENTS: usize = 500;
+
+const WORDS: [&str; 16] = [
+    "server", "markdown", "index", "template", "search", "cache", "render", "heading",
+    "document", "folder", "config", "theme", "image", "table", "link", "query",
+];
+
+// Documents of a few hundred words, drawn from a small vocabulary so every
+// query matches a good share of them
+fn documents() -> Vec<(String, String)> {
+    (0..DOCUMENTS).map(|doc| {
+        let mut md = format!("# Document {doc}\n\n");
+        for word in 0..300 {
+            md.push_str(WORDS[(doc * 7 + word * word) % WORDS.len()]);
+            md.push(if word % 12 == 11 { '\n' } else { ' ' });
+        }
+        (format!("doc-{doc}.md"), md)
+    }).collect()
+}
+
+fn bench_search(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let root = std::env::temp_dir().join(format!("chimera-bench-search-{}", std::process::id()));
+    let fixture = runtime.block_on(SearchFixture::new(root.as_path(), documents().as_slice())).unwrap();
+    let mut group = c.benchmark_group("full_text_search");
+    for (name, query) in [
+        ("term", "markdown"),
+        ("two_terms", "template cache"),
+        ("phrase", "\"search index\""),
+        ("missing", "nonexistent"),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| fixture.search(black_box(query)).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_search);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+// Registers the service worker next to this script, so it follows the site
+// when it's served under a base path
+if ("serviceWorker" in navigator) {
+  const worker = new URL("sw.js", document.currentScript.src);
+  navigator.serviceWorker.register(worker).catch((error) => {
+    console.log(`Service worker registration failed: ${error}`);
+  });
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The whole markdown pipeline, including the passes that scan the parser's
+// html by hand: heading anchors, image sizes, code blocks and tables
+fuzz_target!(|md: &str| {
+    let _ = chimera_md::process_markdown(md);
+});
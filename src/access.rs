@@ -0,0 +1,185 @@
+use std::{net::{IpAddr, SocketAddr}, path::{Component, Path}};
+use axum::{extract::{ConnectInfo, State}, http::HeaderMap, middleware::Next, response::Response};
+
+use crate::proxies::TrustedProxies;
+use crate::toml_config::AccessConfig;
+use crate::AppStateType;
+
+// Index term for documents anyone can read
+pub const PUBLIC_ACCESS: &str = "*";
+
+struct FolderRule {
+    prefix: String,
+    users: Vec<String>,
+}
+
+// Folder access rules. The requesting user's name is expected to come from an
+// authenticating reverse proxy, via a header such as Remote-User
+#[derive(Clone)]
+pub struct AccessControl {
+    user_header: Option<String>,
+    rules: std::sync::Arc<Vec<FolderRule>>,
+}
+
+impl AccessControl {
+    pub fn new(config: AccessConfig) -> Self {
+        let mut rules: Vec<FolderRule> = config.folders.into_iter().map(|(folder, users)| {
+            let folder = folder.trim_matches('/');
+            FolderRule {
+                prefix: format!("{folder}/"),
+                users,
+            }
+        }).collect();
+        // most specific folder wins
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.prefix.len()));
+        AccessControl {
+            user_header: config.user_header,
+            rules: std::sync::Arc::new(rules),
+        }
+    }
+
+    pub fn user(&self, headers: &HeaderMap) -> Option<String> {
+        let user = headers.get(self.user_header.as_deref()?)?.to_str().ok()?.trim();
+        match user.is_empty() {
+            true => None,
+            false => Some(user.to_string()),
+        }
+    }
+
+    // Removes the user header from a request that didn't come through one of
+    // the trusted proxies. True when there was one to remove
+    pub fn drop_untrusted_user(&self, headers: &mut HeaderMap, peer: IpAddr, proxies: &TrustedProxies) -> bool {
+        let Some(user_header) = self.user_header.as_deref() else {
+            return false;
+        };
+        !proxies.trusts(peer) && headers.remove(user_header).is_some()
+    }
+
+    // Users allowed to read a document, relative to the document root.
+    // None means the document is public
+    pub fn allowed_users(&self, relative_path: &Path) -> Option<&[String]> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let path = normalize(relative_path);
+        self.rules.iter()
+            .find(|rule| path.starts_with(rule.prefix.as_str()) || format!("{path}/") == rule.prefix)
+            .map(|rule| rule.users.as_slice())
+    }
+
+    pub fn can_read(&self, relative_path: &Path, user: Option<&str>) -> bool {
+        match self.allowed_users(relative_path) {
+            None => true,
+            Some(users) => user.is_some_and(|user| users.iter().any(|allowed| allowed == user)),
+        }
+    }
+
+    // Index terms recording who can see a document in search results
+    pub fn index_terms(&self, relative_path: &Path) -> Vec<String> {
+        match self.allowed_users(relative_path) {
+            None => vec![PUBLIC_ACCESS.to_string()],
+            Some(users) => users.to_vec(),
+        }
+    }
+
+    // Changes to the rules invalidate the permissions stored in the search index
+    pub fn fingerprint(&self) -> String {
+        self.rules.iter()
+            .map(|rule| format!("{}={}", rule.prefix, rule.users.join(",")))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+// Drops the user header from requests that didn't come through a trusted
+// proxy, where anyone could have set it to any name
+pub async fn mw_trusted_user(
+    State(app_state): State<AppStateType>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    if app_state.access.drop_untrusted_user(request.headers_mut(), addr.ip(), &app_state.trusted_proxies) {
+        tracing::debug!("Ignored the user header from {}, which isn't a trusted proxy", addr.ip());
+    }
+    next.run(request).await
+}
+
+// Resolve . and .. so "public/../private" can't sidestep a folder rule
+fn normalize(path: &Path) -> String {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy()),
+            Component::ParentDir => {
+                parts.pop();
+            },
+            _ => {},
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+
+    fn access_control(folders: &[(&str, &[&str])]) -> AccessControl {
+        let folders: IndexMap<String, Vec<String>> = folders.iter()
+            .map(|(folder, users)| (folder.to_string(), users.iter().map(|user| user.to_string()).collect()))
+            .collect();
+        AccessControl::new(AccessConfig {
+            user_header: Some("Remote-User".to_string()),
+            folders,
+        })
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("public/../private/plans.md")), "private/plans.md");
+        assert_eq!(normalize(Path::new("./private/./plans.md")), "private/plans.md");
+        assert_eq!(normalize(Path::new("../../private/plans.md")), "private/plans.md");
+        assert_eq!(normalize(Path::new("/private/plans.md")), "private/plans.md");
+        // paths arrive decoded, so an encoded .. is just an odd file name
+        assert_eq!(normalize(Path::new("public/%2e%2e/private/plans.md")), "public/%2e%2e/private/plans.md");
+        assert_eq!(normalize(Path::new("public/%2E%2E%2Fprivate/plans.md")), "public/%2E%2E%2Fprivate/plans.md");
+    }
+
+    #[test]
+    fn test_can_read() {
+        let access = access_control(&[("private", &["alice", "bob"]), ("/private/alice/", &["alice"])]);
+        assert!(access.can_read(Path::new("notes/todo.md"), None));
+        assert!(access.can_read(Path::new("privateer/log.md"), None));
+        assert!(!access.can_read(Path::new("private/plans.md"), None));
+        assert!(!access.can_read(Path::new("private"), None));
+        assert!(access.can_read(Path::new("private/plans.md"), Some("bob")));
+        assert!(!access.can_read(Path::new("private/plans.md"), Some("carol")));
+        // the most specific folder wins, whichever order they're listed in
+        assert!(access.can_read(Path::new("private/alice/diary.md"), Some("alice")));
+        assert!(!access.can_read(Path::new("private/alice/diary.md"), Some("bob")));
+        assert!(!access.can_read(Path::new("public/../private/alice/diary.md"), Some("bob")));
+        assert!(!access.can_read(Path::new("public/../private/plans.md"), None));
+        assert_eq!(access.index_terms(Path::new("notes/todo.md")), vec![PUBLIC_ACCESS.to_string()]);
+        assert_eq!(access.index_terms(Path::new("private/alice/diary.md")), vec!["alice".to_string()]);
+    }
+
+    #[test]
+    fn test_drop_untrusted_user() {
+        let access = access_control(&[]);
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("Remote-User", "alice".parse().unwrap());
+        assert!(!access.drop_untrusted_user(&mut headers, "10.1.2.3".parse().unwrap(), &proxies));
+        assert_eq!(access.user(&headers), Some("alice".to_string()));
+        assert!(access.drop_untrusted_user(&mut headers, "192.0.2.7".parse().unwrap(), &proxies));
+        assert_eq!(access.user(&headers), None);
+        assert!(!access.drop_untrusted_user(&mut headers, "192.0.2.7".parse().unwrap(), &proxies));
+
+        // without a user header nobody is signed in, whatever's sent
+        let access = AccessControl::new(AccessConfig::default());
+        headers.insert("Remote-User", "alice".parse().unwrap());
+        assert!(!access.drop_untrusted_user(&mut headers, "10.1.2.3".parse().unwrap(), &proxies));
+        assert_eq!(access.user(&headers), None);
+    }
+}
@@ -0,0 +1,35 @@
+use serde::Serialize;
+
+// A region of the page, as the templates mark it up. Labels are message
+// keys, see i18n.rs, for regions whose role alone doesn't say enough
+#[derive(Serialize)]
+pub struct Landmark {
+    pub id: &'static str,
+    pub label: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct Landmarks {
+    pub main: Landmark,
+    pub navigation: Landmark,
+    pub complementary: Landmark,
+    pub contentinfo: Landmark,
+}
+
+pub const LANDMARKS: Landmarks = Landmarks {
+    main: Landmark { id: "content", label: None },
+    navigation: Landmark { id: "site-nav", label: Some("a11y.navigation") },
+    complementary: Landmark { id: "sidebar", label: Some("a11y.sidebar") },
+    contentinfo: Landmark { id: "site-footer", label: None },
+};
+
+// The first links on the page, for keyboard users to jump past the
+// navigation to one of the landmarks
+#[derive(Clone, Serialize)]
+pub struct SkipLink {
+    pub target: &'static str,
+    pub label: &'static str,
+}
+
+pub const SKIP_TO_CONTENT: SkipLink = SkipLink { target: LANDMARKS.main.id, label: "a11y.skip_to_content" };
+pub const SKIP_TO_SIDEBAR: SkipLink = SkipLink { target: LANDMARKS.complementary.id, label: "a11y.skip_to_sidebar" };
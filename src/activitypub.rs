@@ -0,0 +1,244 @@
+use axum::{extract::State, http::{header, StatusCode}, response::{IntoResponse, Response}};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::feed::{self, Feed};
+use crate::toml_config::ActivityPubConfig;
+use crate::AppStateType;
+
+pub const ACTIVITYPUB_DIR: &str = "/activitypub";
+
+const ACTIVITY_STREAMS: &str = "https://www.w3.org/ns/activitystreams";
+const SECURITY: &str = "https://w3id.org/security/v1";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+#[derive(Serialize)]
+struct Image<'a> {
+    #[serde(rename = "type")]
+    object_type: &'a str,
+    url: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PublicKey<'a> {
+    id: String,
+    owner: &'a str,
+    public_key_pem: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Actor<'a> {
+    #[serde(rename = "@context")]
+    context: [&'a str; 2],
+    id: &'a str,
+    #[serde(rename = "type")]
+    actor_type: &'a str,
+    preferred_username: &'a str,
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a str>,
+    url: String,
+    inbox: String,
+    outbox: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<Image<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<PublicKey<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Article<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    object_type: &'a str,
+    name: &'a str,
+    content: String,
+    url: &'a str,
+    published: String,
+    updated: String,
+    attributed_to: &'a str,
+    to: [&'a str; 1],
+}
+
+#[derive(Serialize)]
+struct Create<'a> {
+    id: String,
+    #[serde(rename = "type")]
+    activity_type: &'a str,
+    actor: &'a str,
+    published: String,
+    to: [&'a str; 1],
+    object: Article<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Outbox<'a> {
+    #[serde(rename = "@context")]
+    context: &'a str,
+    id: String,
+    #[serde(rename = "type")]
+    collection_type: &'a str,
+    total_items: usize,
+    ordered_items: Vec<Create<'a>>,
+}
+
+// A read-only actor whose outbox is the feed's documents, so the site can
+// be looked up and read from the fediverse. Activities aren't delivered to
+// anyone, and nothing sent to the inbox is acted on
+#[derive(Clone)]
+pub struct ActivityPub {
+    username: String,
+    name: String,
+    summary: Option<String>,
+    icon: Option<String>,
+    public_key: Option<String>,
+    // eg: https://notes.example.com
+    site_url: String,
+    feed: Feed,
+}
+
+impl ActivityPub {
+    pub fn new(config: ActivityPubConfig, site_url: Option<&str>, feed: Feed) -> Option<Self> {
+        let Some(site_url) = site_url else {
+            tracing::warn!("Ignoring [activitypub], it needs site_url in [social]");
+            return None;
+        };
+        Some(ActivityPub {
+            name: config.name.unwrap_or_else(|| feed.title().to_string()),
+            username: config.username,
+            summary: config.summary,
+            icon: config.icon,
+            public_key: config.public_key,
+            site_url: site_url.trim_end_matches('/').to_string(),
+            feed,
+        })
+    }
+
+    pub fn actor_url(&self) -> String {
+        format!("{}{ACTIVITYPUB_DIR}/actor", self.site_url)
+    }
+
+    pub fn profile_url(&self) -> String {
+        format!("{}/", self.site_url)
+    }
+
+    // What webfinger is asked for, eg: acct:blog@notes.example.com
+    pub fn account(&self) -> String {
+        let host = self.site_url.split_once("://").map_or(self.site_url.as_str(), |(_scheme, rest)| rest);
+        let host = host.split('/').next().unwrap_or(host);
+        format!("acct:{}@{host}", self.username)
+    }
+
+    fn actor_json(&self) -> Result<String, serde_json::Error> {
+        let actor_url = self.actor_url();
+        let actor = Actor {
+            context: [ACTIVITY_STREAMS, SECURITY],
+            id: actor_url.as_str(),
+            actor_type: "Person",
+            preferred_username: self.username.as_str(),
+            name: self.name.as_str(),
+            summary: self.summary.as_deref(),
+            url: self.profile_url(),
+            inbox: format!("{}{ACTIVITYPUB_DIR}/inbox", self.site_url),
+            outbox: format!("{}{ACTIVITYPUB_DIR}/outbox", self.site_url),
+            icon: self.icon.as_deref().map(|url| Image {
+                object_type: "Image",
+                url,
+            }),
+            public_key: self.public_key.as_deref().map(|pem| PublicKey {
+                id: format!("{actor_url}#main-key"),
+                owner: actor_url.as_str(),
+                public_key_pem: pem,
+            }),
+        };
+        serde_json::to_string(&actor)
+    }
+
+    fn outbox_json(&self) -> Result<String, serde_json::Error> {
+        let actor_url = self.actor_url();
+        let pages: Vec<(OffsetDateTime, OffsetDateTime, String, String, String)> = self.feed.newest().into_iter()
+            .map(|(published, page)| {
+                let url = format!("{}{}", self.site_url, page.url);
+                let mut content = String::new();
+                if !page.excerpt.is_empty() {
+                    content.push_str(format!("<p>{}</p>", feed::escape(page.excerpt.as_str())).as_str());
+                }
+                content.push_str(format!("<p><a href=\"{0}\">{0}</a></p>", feed::escape(url.as_str())).as_str());
+                (published, OffsetDateTime::from(page.modtime), url, page.title, content)
+            })
+            .collect();
+        let items = pages.iter().map(|(published, updated, url, title, content)| {
+            let published = feed::rfc3339(*published);
+            Create {
+                id: format!("{url}#create"),
+                activity_type: "Create",
+                actor: actor_url.as_str(),
+                published: published.clone(),
+                to: [PUBLIC],
+                object: Article {
+                    id: url.as_str(),
+                    object_type: "Article",
+                    name: title.as_str(),
+                    content: content.clone(),
+                    url: url.as_str(),
+                    published,
+                    updated: feed::rfc3339(*updated),
+                    attributed_to: actor_url.as_str(),
+                    to: [PUBLIC],
+                },
+            }
+        }).collect::<Vec<_>>();
+        let outbox = Outbox {
+            context: ACTIVITY_STREAMS,
+            id: format!("{}{ACTIVITYPUB_DIR}/outbox", self.site_url),
+            collection_type: "OrderedCollection",
+            total_items: items.len(),
+            ordered_items: items,
+        };
+        serde_json::to_string(&outbox)
+    }
+}
+
+fn activity_json(json: Result<String, serde_json::Error>) -> Response {
+    match json {
+        Ok(json) => (
+            [
+                (header::CONTENT_TYPE, "application/activity+json"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            json,
+        ).into_response(),
+        Err(e) => {
+            tracing::warn!("Error writing an ActivityPub response: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        },
+    }
+}
+
+pub async fn handle_actor(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    match app_state.activitypub.as_ref() {
+        Some(activitypub) => activity_json(activitypub.actor_json()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn handle_outbox(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    match app_state.activitypub.as_ref() {
+        Some(activitypub) => activity_json(activitypub.outbox_json()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Follows need signed deliveries in return, which the server doesn't make
+pub async fn handle_inbox() -> Response {
+    tracing::debug!("Ignored an ActivityPub inbox delivery");
+    StatusCode::NOT_IMPLEMENTED.into_response()
+}
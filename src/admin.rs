@@ -0,0 +1,282 @@
+use axum::{extract::{Path, Query, State}, http::{header, HeaderMap, StatusCode}, middleware::Next, response::{IntoResponse, Redirect, Response}, Form, Json};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::analytics::PageViewCount;
+use crate::backup::BackupInfo;
+use crate::chimera_error::{handle_404, handle_err};
+use crate::comments::Comment;
+use crate::duplicates::{self, NearDuplicates};
+use crate::link_checker::{self, BrokenAnchors, OutlineProblems};
+use crate::newsletter::SubscriberCounts;
+use crate::perf_timer::StartupReport;
+use crate::result_cache::CacheSummary;
+use crate::trash::TrashEntry;
+use crate::webmentions::Webmention;
+use crate::session::CsrfHtml;
+use crate::{AppStateType, HOME_DIR};
+
+const DASHBOARD_CACHE_ENTRIES: usize = 20;
+
+#[derive(Default, Serialize)]
+pub struct AdminDashboard {
+    pub top_pages: Option<Vec<PageViewCount>>,
+    pub pending_comments: Option<Vec<Comment>>,
+    pub pending_webmentions: Option<Vec<Webmention>>,
+    pub subscribers: Option<SubscriberCounts>,
+    pub broken_anchors: Vec<BrokenAnchors>,
+    pub outline_problems: Vec<OutlineProblems>,
+    pub near_duplicates: Vec<NearDuplicates>,
+    pub cache: Option<CacheSummary>,
+    pub backups: Option<Vec<BackupInfo>>,
+    pub trash: Option<Vec<TrashEntry>>,
+    pub startup: StartupReport,
+}
+
+// Checks HTTP basic auth credentials against the [admin] config section.
+// Admin routes are only registered when that section is present
+pub async fn mw_admin_auth(
+    State(app_state): State<AppStateType>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let Some(admin) = app_state.admin.as_ref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if check_basic_auth(request.headers(), admin.username.as_str(), admin.password.as_str()) {
+        return next.run(request).await;
+    }
+    tracing::warn!("Rejected admin request for {}", request.uri());
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"chimera-md admin\"")],
+        "Authorization required",
+    ).into_response()
+}
+
+fn check_basic_auth(headers: &HeaderMap, username: &str, password: &str) -> bool {
+    let Some(auth) = headers.get(header::AUTHORIZATION) else {
+        return false;
+    };
+    let Ok(auth) = auth.to_str() else {
+        return false;
+    };
+    let Some(encoded) = auth.strip_prefix("Basic ") else {
+        return false;
+    };
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    let Ok(decoded) = String::from_utf8(decoded) else {
+        return false;
+    };
+    match decoded.split_once(':') {
+        // both checked, so a wrong username takes as long as a wrong password
+        Some((user, pass)) => same_secret(user, username) & same_secret(pass, password),
+        None => false,
+    }
+}
+
+// Compares MACs of the two, which takes the same time however much of the
+// guess is right
+fn same_secret(given: &str, expected: &str) -> bool {
+    let mut key = [0_u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let mac = |value: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC can take a key of any size");
+        mac.update(value.as_bytes());
+        mac
+    };
+    let expected = mac(expected).finalize().into_bytes();
+    mac(given).verify_slice(expected.as_slice()).is_ok()
+}
+
+pub async fn handle_admin(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    let dashboard = AdminDashboard {
+        top_pages: app_state.page_views.as_ref().map(|page_views| {
+            page_views.top_pages(app_state.top_pages)
+        }),
+        pending_comments: app_state.comment_store.as_ref().map(|store| store.pending()),
+        pending_webmentions: app_state.webmention_store.as_ref().map(|store| store.pending()),
+        subscribers: app_state.newsletter.as_ref().map(|newsletter| newsletter.counts()),
+        broken_anchors: link_checker::check_anchors(&app_state.page_catalog),
+        outline_problems: link_checker::check_outlines(&app_state.page_catalog),
+        near_duplicates: duplicates::find(&app_state.page_catalog),
+        cache: app_state.result_cache.summary(DASHBOARD_CACHE_ENTRIES),
+        backups: app_state.backups.as_ref().map(|backups| backups.list()),
+        trash: app_state.trash.as_ref().map(|trash| trash.entries()),
+        startup: app_state.startup.clone(),
+    };
+    match app_state.html_generator.gen_admin(&dashboard) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+pub async fn handle_approve_comment(
+    State(app_state): State<AppStateType>,
+    Path(id): Path<u64>,
+) -> Response {
+    let Some(store) = app_state.comment_store.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match store.approve(id).await {
+        Ok(true) => {
+            tracing::info!("Approved comment {id}");
+            // approved comments are baked into the rendered page
+            app_state.result_cache.clear();
+            Redirect::to("/admin").into_response()
+        },
+        Ok(false) => handle_404(app_state).await.into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+pub async fn handle_delete_comment(
+    State(app_state): State<AppStateType>,
+    Path(id): Path<u64>,
+) -> Response {
+    let Some(store) = app_state.comment_store.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match store.delete(id).await {
+        Ok(true) => {
+            tracing::info!("Deleted comment {id}");
+            app_state.result_cache.clear();
+            Redirect::to("/admin").into_response()
+        },
+        Ok(false) => handle_404(app_state).await.into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+pub async fn handle_approve_webmention(
+    State(app_state): State<AppStateType>,
+    Path(id): Path<u64>,
+) -> Response {
+    let Some(store) = app_state.webmention_store.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match store.approve(id).await {
+        Ok(true) => {
+            tracing::info!("Approved webmention {id}");
+            app_state.result_cache.clear();
+            Redirect::to("/admin").into_response()
+        },
+        Ok(false) => handle_404(app_state).await.into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+pub async fn handle_delete_webmention(
+    State(app_state): State<AppStateType>,
+    Path(id): Path<u64>,
+) -> Response {
+    let Some(store) = app_state.webmention_store.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match store.delete(id).await {
+        Ok(true) => {
+            tracing::info!("Deleted webmention {id}");
+            app_state.result_cache.clear();
+            Redirect::to("/admin").into_response()
+        },
+        Ok(false) => handle_404(app_state).await.into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CacheQuery {
+    limit: Option<usize>,
+}
+
+// The largest cached pages, as JSON, eg: /admin/cache?limit=50
+pub async fn handle_cache(
+    State(app_state): State<AppStateType>,
+    Query(query): Query<CacheQuery>,
+) -> Response {
+    let limit = query.limit.unwrap_or(DASHBOARD_CACHE_ENTRIES);
+    match app_state.result_cache.summary(limit) {
+        Some(summary) => Json(summary).into_response(),
+        None => handle_err(app_state).await.into_response(),
+    }
+}
+
+// How long each step of starting up took, as JSON
+pub async fn handle_startup(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    Json(app_state.startup.clone()).into_response()
+}
+
+pub async fn handle_flush_cache(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    tracing::info!("Flushing the result cache");
+    app_state.result_cache.clear();
+    Redirect::to("/admin").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct EvictForm {
+    path: String,
+}
+
+// Takes a cache key or a document URL, eg: /home/notes/todo.md
+pub async fn handle_evict_cache(
+    State(app_state): State<AppStateType>,
+    Form(form): Form<EvictForm>,
+) -> Response {
+    let path = form.path.trim();
+    let key = match path.strip_prefix(format!("{HOME_DIR}/").as_str()) {
+        Some(document) => urlencoding::decode(document).map_or(document.to_string(), |document| document.into_owned()),
+        None => path.to_string(),
+    };
+    let evicted = app_state.result_cache.evict(std::path::Path::new(key.as_str()));
+    tracing::info!("Evicted {evicted} cached pages for {key}");
+    Redirect::to("/admin").into_response()
+}
+
+pub async fn handle_backup(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    let Some(backups) = app_state.backups.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match backups.run().await {
+        Ok(_archive) => Redirect::to("/admin").into_response(),
+        Err(e) => {
+            tracing::error!("Backup failed: {e:?}");
+            handle_err(app_state).await.into_response()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        headers.insert(header::AUTHORIZATION, format!("Basic {encoded}").parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_check_basic_auth() {
+        assert!(check_basic_auth(&basic("admin:hunter2"), "admin", "hunter2"));
+        assert!(check_basic_auth(&basic("admin:pass:word"), "admin", "pass:word"));
+        assert!(!check_basic_auth(&basic("admin:hunter"), "admin", "hunter2"));
+        assert!(!check_basic_auth(&basic("admin:hunter22"), "admin", "hunter2"));
+        assert!(!check_basic_auth(&basic("root:hunter2"), "admin", "hunter2"));
+        assert!(!check_basic_auth(&basic("adminhunter2"), "admin", "hunter2"));
+        assert!(!check_basic_auth(&HeaderMap::new(), "admin", "hunter2"));
+    }
+}
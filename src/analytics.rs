@@ -0,0 +1,163 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::{Arc, RwLock}, time::Duration};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::ChimeraError;
+
+// How often dirty counters are flushed to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Views per URL, bucketed by day. Only the path and the date are stored,
+// nothing about who made the request
+type DailyViews = BTreeMap<String, u64>;
+
+#[derive(Default, Serialize, Deserialize)]
+struct ViewLog {
+    days: BTreeMap<String, DailyViews>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PageViewCount {
+    pub url: String,
+    pub views: u64,
+}
+
+struct PageViewsInternal {
+    path: PathBuf,
+    log: ViewLog,
+    retention_days: u32,
+    dirty: bool,
+}
+
+#[derive(Clone)]
+pub struct PageViews {
+    lock: Arc<RwLock<PageViewsInternal>>,
+}
+
+fn today() -> time::Date {
+    time::OffsetDateTime::now_utc().date()
+}
+
+impl PageViewsInternal {
+    fn load(&mut self) {
+        self.log = match std::fs::read_to_string(self.path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(log) => log,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", self.path.display());
+                        ViewLog::default()
+                    }
+                }
+            },
+            Err(e) => {
+                tracing::debug!("No page view log at {}: {e}", self.path.display());
+                ViewLog::default()
+            }
+        };
+        self.prune();
+        tracing::info!("Page view log loaded with {} days", self.log.days.len());
+    }
+
+    fn prune(&mut self) {
+        let cutoff = today() - time::Duration::days(self.retention_days as i64);
+        let cutoff = cutoff.to_string();
+        let before = self.log.days.len();
+        self.log.days.retain(|day, _| day.as_str() >= cutoff.as_str());
+        if self.log.days.len() != before {
+            self.dirty = true;
+        }
+    }
+}
+
+impl PageViews {
+    pub fn new(analytics_dir: PathBuf, retention_days: u32) -> Self {
+        if let Err(e) = std::fs::create_dir_all(analytics_dir.as_path()) {
+            tracing::warn!("Failed to create analytics directory {}: {e}", analytics_dir.display());
+        }
+        let mut internal = PageViewsInternal {
+            path: analytics_dir.join("page-views.toml"),
+            log: ViewLog::default(),
+            retention_days,
+            dirty: false,
+        };
+        internal.load();
+        let page_views = PageViews {
+            lock: Arc::new(RwLock::new(internal)),
+        };
+        tokio::spawn(periodic_save(page_views.clone()));
+        page_views
+    }
+
+    pub fn record(&self, url: &str) {
+        let Ok(mut lock) = self.lock.write() else {
+            return;
+        };
+        let day = today().to_string();
+        let is_new_day = !lock.log.days.contains_key(day.as_str());
+        let counts = lock.log.days.entry(day).or_default();
+        *counts.entry(url.to_string()).or_default() += 1;
+        lock.dirty = true;
+        if is_new_day {
+            lock.prune();
+        }
+    }
+
+    pub fn view_count(&self, url: &str) -> u64 {
+        let Ok(lock) = self.lock.read() else {
+            return 0;
+        };
+        lock.log.days.values().filter_map(|counts| counts.get(url)).sum()
+    }
+
+    pub fn top_pages(&self, limit: usize) -> Vec<PageViewCount> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        let mut totals: BTreeMap<&str, u64> = BTreeMap::new();
+        for counts in lock.log.days.values() {
+            for (url, views) in counts {
+                *totals.entry(url.as_str()).or_default() += views;
+            }
+        }
+        let mut pages: Vec<PageViewCount> = totals.into_iter().map(|(url, views)| {
+            PageViewCount {
+                url: url.to_string(),
+                views,
+            }
+        }).collect();
+        pages.sort_by_key(|page| std::cmp::Reverse(page.views));
+        pages.truncate(limit);
+        pages
+    }
+
+    pub async fn save(&self) -> Result<(), ChimeraError> {
+        let (path, toml) = {
+            let mut lock = self.lock.write()?;
+            if !lock.dirty {
+                return Ok(());
+            }
+            lock.dirty = false;
+            match toml::to_string(&lock.log) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting page views to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        tracing::debug!("Saved {}", path.display());
+        Ok(())
+    }
+}
+
+async fn periodic_save(page_views: PageViews) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = page_views.save().await {
+            tracing::warn!("Failed to save page views: {e:?}");
+        }
+    }
+}
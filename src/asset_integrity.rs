@@ -0,0 +1,68 @@
+use std::{collections::HashMap, ffi::OsStr, path::Path, sync::Arc};
+use base64::Engine;
+use sha2::{Digest, Sha384};
+
+// Subresource integrity hashes, by URL, for the scripts and styles templates
+// link to. Local files are hashed at startup; external ones (CDN copies of
+// highlight.js, eg) come from the [integrity] config table
+#[derive(Clone, Default)]
+pub struct AssetIntegrity {
+    hashes: Arc<HashMap<String, String>>,
+}
+
+impl AssetIntegrity {
+    // Files in the user web root replace internal ones with the same URL,
+    // just as they do when served
+    pub fn new(web_roots: &[&Path], external: HashMap<String, String>) -> Self {
+        let mut hashes = HashMap::new();
+        for root in web_roots.iter().rev() {
+            hash_folder(root, &mut hashes);
+        }
+        tracing::debug!("Computed integrity hashes for {} assets", hashes.len());
+        hashes.extend(external);
+        AssetIntegrity {
+            hashes: Arc::new(hashes),
+        }
+    }
+
+    // Tera helper, eg: <script src="/x.js"{{ sri(url="/x.js") }}></script>
+    // Adds nothing for assets it doesn't know about
+    pub fn sri_function(&self) -> impl tera::Function {
+        let hashes = self.hashes.clone();
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            let Some(url) = args.get("url").and_then(|v| v.as_str()) else {
+                return Err(tera::Error::msg("sri() needs a url"));
+            };
+            let Some(hash) = hashes.get(url) else {
+                return Ok(tera::Value::String(String::new()));
+            };
+            // cross-origin responses can only be checked when fetched with CORS
+            let crossorigin = match url.starts_with("http://") || url.starts_with("https://") {
+                true => " crossorigin=\"anonymous\"",
+                false => "",
+            };
+            Ok(tera::Value::String(format!(" integrity=\"{hash}\"{crossorigin}")))
+        }
+    }
+}
+
+fn hash_folder(root: &Path, hashes: &mut HashMap<String, String>) {
+    let assets = walkdir::WalkDir::new(root).into_iter().flatten().filter(|entry| {
+        entry.file_type().is_file()
+            && matches!(entry.path().extension().and_then(OsStr::to_str), Some("css" | "js"))
+    });
+    for entry in assets {
+        let Ok(relative) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        let url = format!("/{}", relative.to_string_lossy().replace('\\', "/"));
+        match std::fs::read(entry.path()) {
+            Ok(contents) => {
+                let digest = Sha384::digest(contents.as_slice());
+                let hash = base64::engine::general_purpose::STANDARD.encode(digest);
+                hashes.insert(url, format!("sha384-{hash}"));
+            },
+            Err(e) => tracing::warn!("Failed to read {} for its integrity hash: {e}", entry.path().display()),
+        }
+    }
+}
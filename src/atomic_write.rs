@@ -0,0 +1,51 @@
+use std::{path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}};
+use tokio::io::AsyncWriteExt;
+
+// Tells apart temp files from saves of the same file that overlap
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+// Replaces the file's contents all at once: the data goes to a temp file
+// beside it, which is synced and renamed over the original. A crash part
+// way through leaves the old contents, never half of the new
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp = temp_path(path);
+    if let Err(e) = write_synced(temp.as_path(), contents).await {
+        let _ = tokio::fs::remove_file(temp.as_path()).await;
+        return Err(e);
+    }
+    if let Err(e) = tokio::fs::rename(temp.as_path(), path).await {
+        let _ = tokio::fs::remove_file(temp.as_path()).await;
+        return Err(e);
+    }
+    sync_folder(path).await;
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or_else(|| "file".into(), |name| name.to_string_lossy());
+    let count = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{name}.{}-{count}.tmp", std::process::id()))
+}
+
+async fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await
+}
+
+// Makes the rename itself durable. Only possible on unix, and not worth
+// failing the save over
+#[cfg(unix)]
+async fn sync_folder(path: &Path) {
+    let Some(folder) = path.parent().filter(|folder| !folder.as_os_str().is_empty()) else {
+        return;
+    };
+    if let Ok(folder) = tokio::fs::File::open(folder).await {
+        if let Err(e) = folder.sync_all().await {
+            tracing::debug!("Failed to sync {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn sync_folder(_path: &Path) {}
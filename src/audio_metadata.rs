@@ -0,0 +1,51 @@
+use std::path::Path;
+use lofty::{file::{AudioFile, TaggedFileExt}, tag::Accessor};
+use serde::Serialize;
+
+// Files served with a player page, rather than as they are
+const AUDIO_EXTENSIONS: [&str; 2] = ["mp3", "flac"];
+
+// What a track's tags say about it
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AudioMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    // eg: "3:07", or "1:02:45" for long recordings
+    pub duration: Option<String>,
+}
+
+pub fn is_audio(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        AUDIO_EXTENSIONS.iter().any(|audio_ext| ext.eq_ignore_ascii_case(audio_ext))
+    })
+}
+
+// None when the file can't be read or isn't audio lofty understands
+pub fn read(path: &Path) -> Option<AudioMetadata> {
+    let tagged_file = lofty::read_from_path(path)
+        .inspect_err(|e| tracing::debug!("No audio metadata for {}: {e}", path.display()))
+        .ok()?;
+    let seconds = tagged_file.properties().duration().as_secs();
+    let duration = (seconds > 0).then(|| match seconds >= 3600 {
+        true => format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60),
+        false => format!("{}:{:02}", seconds / 60, seconds % 60),
+    });
+    let mut metadata = AudioMetadata {
+        duration,
+        ..Default::default()
+    };
+    if let Some(tag) = tagged_file.primary_tag().or_else(|| tagged_file.first_tag()) {
+        let text = |value: Option<std::borrow::Cow<str>>| {
+            value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+        };
+        metadata.title = text(tag.title());
+        metadata.artist = text(tag.artist());
+        metadata.album = text(tag.album());
+        metadata.year = tag.year();
+        metadata.track = tag.track();
+    }
+    Some(metadata)
+}
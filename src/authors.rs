@@ -0,0 +1,115 @@
+use axum::{extract::State, response::{IntoResponse, Response}};
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::chimera_error::{handle_404, handle_err};
+use crate::page_catalog::PageSummary;
+use crate::toml_config::AuthorConfig;
+use crate::session::CsrfHtml;
+use crate::AppStateType;
+
+pub const AUTHORS_DIR: &str = "/authors";
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthorProfile {
+    pub key: String,
+    pub name: String,
+    pub avatar: Option<String>,
+    pub bio: Option<String>,
+    pub link: Option<String>,
+    // Listing page for the author's documents
+    pub url: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct Authors {
+    profiles: IndexMap<String, AuthorProfile>,
+}
+
+impl Authors {
+    pub fn new(config: IndexMap<String, AuthorConfig>) -> Self {
+        let profiles = config.into_iter().map(|(key, author)| {
+            let profile = AuthorProfile {
+                url: Some(format!("{AUTHORS_DIR}/{}", urlencoding::encode(key.as_str()))),
+                key: key.clone(),
+                name: author.name,
+                avatar: author.avatar,
+                bio: author.bio,
+                link: author.link,
+            };
+            (key, profile)
+        }).collect();
+        Authors {
+            profiles,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    // Documents can name their author by key or by display name
+    pub fn find(&self, author: &str) -> Option<&AuthorProfile> {
+        self.profiles.get(author).or_else(|| {
+            self.profiles.values().find(|profile| profile.name.eq_ignore_ascii_case(author))
+        })
+    }
+
+    // Profile for a page's author: front matter, with no profile page, if the
+    // author isn't in the table
+    pub fn profile_for(&self, author: &str) -> AuthorProfile {
+        match self.find(author) {
+            Some(profile) => profile.clone(),
+            None => AuthorProfile {
+                key: author.to_string(),
+                name: author.to_string(),
+                avatar: None,
+                bio: None,
+                link: None,
+                url: None,
+            },
+        }
+    }
+
+    pub fn all(&self) -> Vec<AuthorProfile> {
+        self.profiles.values().cloned().collect()
+    }
+}
+
+fn is_by(page: &PageSummary, profile: &AuthorProfile) -> bool {
+    page.metadata.get("author").is_some_and(|author| {
+        *author == profile.key || author.eq_ignore_ascii_case(profile.name.as_str())
+    })
+}
+
+pub async fn handle_authors(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    match app_state.html_generator.gen_authors(app_state.authors.all()) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering author list: {e:?}");
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
+
+pub async fn handle_author(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Response {
+    let Some(profile) = app_state.authors.find(name.as_str()).cloned() else {
+        return handle_404(app_state).await.into_response();
+    };
+    let mut pages: Vec<PageSummary> = app_state.page_catalog.pages().into_iter()
+        .filter(|page| is_by(page, &profile))
+        .collect();
+    pages.sort_by_key(|page| std::cmp::Reverse(page.modtime));
+    match app_state.html_generator.gen_author(&profile, pages) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering author page for {name}: {e:?}");
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
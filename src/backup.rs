@@ -0,0 +1,335 @@
+use std::{fs::File, io::{BufWriter, Read, Write}, path::{Path, PathBuf}, process::Stdio, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+
+use crate::chimera_error::ChimeraError;
+use crate::load_shedding::LoadShedder;
+use crate::toml_config::BackupConfig;
+
+const ARCHIVE_PREFIX: &str = "chimera-";
+const ARCHIVE_EXT: &str = ".tar.gz";
+const BLOCK_SIZE: usize = 512;
+// How often a put off backup checks whether memory pressure has passed
+const PRESSURE_RETRY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Serialize)]
+pub struct BackupInfo {
+    pub name: String,
+    pub size: u64,
+    pub created: String,
+}
+
+// Snapshots of the documents (and optionally the search index) as dated
+// .tar.gz files, made on a schedule or from the admin page. Only the
+// newest `keep` are kept. An upload command can copy each one elsewhere,
+// eg: ["aws", "s3", "cp", "{archive}", "s3://bucket/chimera/"]
+#[derive(Clone)]
+pub struct Backups {
+    dir: PathBuf,
+    // Folder name in the archive => folder
+    sources: Arc<Vec<(String, PathBuf)>>,
+    keep: usize,
+    upload_command: Arc<Vec<String>>,
+    upload_timeout: Duration,
+    // Held while a backup is being made, so two can't overlap
+    running: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Backups {
+    // Scheduled backups are put off while the load shedder reports memory
+    // pressure; ones asked for from the admin page always run
+    pub fn new(config: BackupConfig, chimera_root: &Path, load_shedder: Option<LoadShedder>) -> Self {
+        let dir = chimera_root.join(config.dir.as_str());
+        if let Err(e) = std::fs::create_dir_all(dir.as_path()) {
+            tracing::warn!("Failed to create backup directory {}: {e}", dir.display());
+        }
+        let mut sources = vec![("home".to_string(), chimera_root.join("home"))];
+        if config.include_search {
+            sources.push(("search".to_string(), chimera_root.join("search")));
+        }
+        let backups = Backups {
+            dir,
+            sources: Arc::new(sources),
+            keep: config.keep.max(1),
+            upload_command: Arc::new(config.upload_command),
+            upload_timeout: Duration::from_secs(config.upload_timeout_secs),
+            running: Arc::new(tokio::sync::Mutex::new(())),
+        };
+        // 0 means only when asked for from the admin page
+        if config.interval_hours > 0 {
+            tokio::spawn(scheduled_backups(backups.clone(), load_shedder, Duration::from_secs(config.interval_hours * 60 * 60)));
+        }
+        backups
+    }
+
+    pub async fn run(&self) -> Result<PathBuf, ChimeraError> {
+        let _running = self.running.lock().await;
+        let now = time::OffsetDateTime::now_utc();
+        let stamp = format!("{:04}{:02}{:02}-{:02}{:02}{:02}", now.year(), now.month() as u8, now.day(), now.hour(), now.minute(), now.second());
+        let archive = self.dir.join(format!("{ARCHIVE_PREFIX}{stamp}{ARCHIVE_EXT}"));
+        let partial = self.dir.join(format!("{ARCHIVE_PREFIX}{stamp}{ARCHIVE_EXT}.partial"));
+        let sources = self.sources.clone();
+        let skip = self.dir.clone();
+        let write_to = partial.clone();
+        let count = tokio::task::spawn_blocking(move || write_archive(write_to.as_path(), sources.as_slice(), skip.as_path()))
+            .await
+            .map_err(|e| ChimeraError::BackupError(format!("Backup task failed: {e}")))?;
+        let count = match count {
+            Ok(count) => count,
+            Err(e) => {
+                let _ = std::fs::remove_file(partial.as_path());
+                return Err(ChimeraError::BackupError(format!("Failed writing {}: {e}", partial.display())));
+            }
+        };
+        tokio::fs::rename(partial.as_path(), archive.as_path()).await?;
+        tracing::info!("Backed up {count} files to {}", archive.display());
+        self.prune();
+        if !self.upload_command.is_empty() {
+            if let Err(e) = self.upload(archive.as_path()).await {
+                tracing::error!("Failed to upload backup {}: {e:?}", archive.display());
+            }
+        }
+        Ok(archive)
+    }
+
+    async fn upload(&self, archive: &Path) -> Result<(), ChimeraError> {
+        let archive = archive.to_string_lossy();
+        let command: Vec<String> = self.upload_command.iter().map(|arg| arg.replace("{archive}", archive.as_ref())).collect();
+        let program = command[0].as_str();
+        let child = tokio::process::Command::new(program)
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| ChimeraError::BackupError(format!("Failed to start {program}: {e}")))?;
+        let output = match tokio::time::timeout(self.upload_timeout, child.wait_with_output()).await {
+            Ok(output) => output?,
+            Err(_) => {
+                return Err(ChimeraError::BackupError(format!("{program} took over {}s", self.upload_timeout.as_secs())));
+            }
+        };
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(output.stderr.as_slice());
+            return Err(ChimeraError::BackupError(format!("{program} failed ({}): {}", output.status, stderr.trim())));
+        }
+        tracing::info!("Uploaded backup {archive} with {program}");
+        Ok(())
+    }
+
+    // Newest first
+    pub fn list(&self) -> Vec<BackupInfo> {
+        let Ok(entries) = std::fs::read_dir(self.dir.as_path()) else {
+            return Vec::new();
+        };
+        let mut backups: Vec<(SystemTime, BackupInfo)> = entries.flatten().filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(ARCHIVE_PREFIX) || !name.ends_with(ARCHIVE_EXT) {
+                return None;
+            }
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata.modified().ok()?;
+            let created = time::OffsetDateTime::from(modified)
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default();
+            Some((modified, BackupInfo {
+                name,
+                size: metadata.len(),
+                created,
+            }))
+        }).collect();
+        backups.sort_by_key(|(modified, _backup)| std::cmp::Reverse(*modified));
+        backups.into_iter().map(|(_modified, backup)| backup).collect()
+    }
+
+    fn prune(&self) {
+        for old in self.list().into_iter().skip(self.keep) {
+            let path = self.dir.join(old.name.as_str());
+            match std::fs::remove_file(path.as_path()) {
+                Ok(()) => tracing::info!("Removed old backup {}", path.display()),
+                Err(e) => tracing::warn!("Failed to remove old backup {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+async fn scheduled_backups(backups: Backups, load_shedder: Option<LoadShedder>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // the first tick is immediate; the first backup waits a full interval
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        while load_shedder.as_ref().is_some_and(LoadShedder::under_pressure) {
+            tracing::info!("Putting off the scheduled backup, memory is under pressure");
+            tokio::time::sleep(PRESSURE_RETRY).await;
+        }
+        if let Err(e) = backups.run().await {
+            tracing::error!("Scheduled backup failed: {e:?}");
+        }
+    }
+}
+
+// Writes a gzipped tar of the sources, leaving out anything under skip (the
+// backup folder itself). Returns how many files went in
+fn write_archive(archive: &Path, sources: &[(String, PathBuf)], skip: &Path) -> std::io::Result<usize> {
+    let file = File::create(archive)?;
+    let mut tar = GzEncoder::new(BufWriter::new(file), Compression::default());
+    let mut count = 0;
+    for (name, root) in sources {
+        let files = walkdir::WalkDir::new(root).into_iter().flatten().filter(|entry| {
+            entry.file_type().is_file() && !entry.path().starts_with(skip)
+        });
+        for entry in files {
+            let Ok(relative) = entry.path().strip_prefix(root) else {
+                continue;
+            };
+            let path = format!("{name}/{}", relative.to_string_lossy().replace('\\', "/"));
+            let mut contents = match File::open(entry.path()) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    tracing::warn!("Leaving {} out of the backup: {e}", entry.path().display());
+                    continue;
+                }
+            };
+            let metadata = contents.metadata()?;
+            let mtime = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs());
+            write_entry(&mut tar, path.as_str(), metadata.len(), mtime, &mut contents)?;
+            count += 1;
+        }
+    }
+    // the end of a tar is marked by two empty blocks
+    tar.write_all(&[0; BLOCK_SIZE * 2])?;
+    tar.finish()?.flush()?;
+    Ok(count)
+}
+
+fn write_entry(tar: &mut impl Write, path: &str, size: u64, mtime: u64, contents: &mut impl Read) -> std::io::Result<()> {
+    // ustar names stop at 100 bytes; longer ones go in a pax header first
+    if path.len() > 100 {
+        let record = pax_record("path", path);
+        tar.write_all(&tar_header("PaxHeader", record.len() as u64, mtime, b'x'))?;
+        write_padded(tar, &mut record.as_bytes(), record.len() as u64)?;
+    }
+    let mut name = path;
+    while name.len() > 100 {
+        let mut end = 100;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = &name[..end];
+    }
+    tar.write_all(&tar_header(name, size, mtime, b'0'))?;
+    write_padded(tar, contents, size)
+}
+
+// "<length> <key>=<value>\n", where the length counts its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {key}={value}\n");
+    let mut length = rest.len() + 1;
+    while (length.to_string().len() + rest.len()) != length {
+        length = length.to_string().len() + rest.len();
+    }
+    format!("{length}{rest}")
+}
+
+fn tar_header(name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is taken with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|byte| *byte as u64).sum();
+    let checksum = format!("{checksum:06o}\0 ");
+    header[148..156].copy_from_slice(checksum.as_bytes());
+    header
+}
+
+// Values too big for the field's octal digits, like sizes of 8 GiB and up,
+// go in GNU's base-256 form: a high bit flag, then the value big endian
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}\0", width = field.len() - 1);
+    if digits.len() == field.len() {
+        field.copy_from_slice(digits.as_bytes());
+        return;
+    }
+    field.fill(0);
+    let bytes = value.to_be_bytes();
+    let start = field.len() - bytes.len();
+    field[start..].copy_from_slice(&bytes);
+    field[0] |= 0x80;
+}
+
+// Copies exactly size bytes, zero filled to a whole block, so a file that
+// changes while it's read can't corrupt the archive
+fn write_padded(tar: &mut impl Write, contents: &mut impl Read, size: u64) -> std::io::Result<()> {
+    let copied = std::io::copy(&mut contents.take(size), tar)?;
+    let mut remaining = size - copied;
+    while remaining > 0 {
+        let zeros = remaining.min(BLOCK_SIZE as u64);
+        tar.write_all(&[0; BLOCK_SIZE][..zeros as usize])?;
+        remaining -= zeros;
+    }
+    let padding = (BLOCK_SIZE - (size as usize % BLOCK_SIZE)) % BLOCK_SIZE;
+    tar.write_all(&[0; BLOCK_SIZE][..padding])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+
+    #[test]
+    fn test_large_size_header() {
+        let size = 8 << 30;
+        let header = tar_header("big.bin", size, 1_700_000_000, b'0');
+        let header = tar::Header::from_byte_slice(&header);
+        assert_eq!(header.size().unwrap(), size);
+        assert_eq!(header.mtime().unwrap(), 1_700_000_000);
+        assert_eq!(header.path().unwrap(), Path::new("big.bin"));
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let folder = std::env::temp_dir().join(format!("chimera-backup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(folder.as_path());
+        let root = folder.join("docs");
+        let long_dir = "a-folder-name-long-enough-that-the-whole-path-goes-past-the-hundred-bytes-that-ustar-names-allow";
+        std::fs::create_dir_all(root.join(long_dir)).unwrap();
+        std::fs::create_dir_all(root.join("backups")).unwrap();
+        std::fs::write(root.join("index.md"), "# Home\n").unwrap();
+        std::fs::write(root.join(long_dir).join("notes.md"), "x".repeat(1000)).unwrap();
+        std::fs::write(root.join("backups").join("old.tar.gz"), "skipped").unwrap();
+
+        let archive = folder.join("backup.tar.gz");
+        let sources = vec![("docs".to_string(), root.clone())];
+        let count = write_archive(archive.as_path(), &sources, root.join("backups").as_path()).unwrap();
+        assert_eq!(count, 2);
+
+        let file = File::open(archive.as_path()).unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut entries = BTreeMap::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            entries.insert(path, contents);
+        }
+        let long_path = format!("docs/{long_dir}/notes.md");
+        assert!(long_path.len() > 100);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.get("docs/index.md").map(String::as_str), Some("# Home\n"));
+        assert_eq!(entries.get(long_path.as_str()).map(String::len), Some(1000));
+        let _ = std::fs::remove_dir_all(folder.as_path());
+    }
+}
@@ -0,0 +1,138 @@
+use axum::{body::Body, extract::{Request, State}, http::{header, HeaderValue}, middleware::Next, response::Response};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+lazy_static! {
+    // Site-absolute URLs in attributes, eg: href="/home/index.md"
+    static ref ABSOLUTE_URL_RE: Regex = Regex::new(r#"((?:href|src|action)=")(/[^"]*)""#).unwrap();
+}
+
+// Where the site lives when it isn't at the root of the host, eg: "/docs".
+// Pages link to the site as if it were, so their links are rewritten on the
+// way out
+#[derive(Clone)]
+pub struct BasePath {
+    prefix: String,
+}
+
+impl BasePath {
+    // None for the root, however it's written
+    pub fn new(base_path: &str) -> Option<Self> {
+        let prefix = base_path.trim().trim_end_matches('/');
+        match prefix.is_empty() {
+            true => None,
+            false => Some(BasePath {
+                prefix: format!("/{}", prefix.trim_start_matches('/')),
+            }),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_str()
+    }
+
+    // Leaves alone URLs already under the prefix, and protocol-relative
+    // ones, eg: //cdn.example.com/x.js
+    fn add_to(&self, url: &str) -> Option<String> {
+        let inside = url.strip_prefix(self.prefix.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']));
+        match url.starts_with('/') && !url.starts_with("//") && !inside {
+            true => Some(format!("{}{url}", self.prefix)),
+            false => None,
+        }
+    }
+
+    fn rewrite_html(&self, html: &str) -> String {
+        ABSOLUTE_URL_RE.replace_all(html, |captures: &Captures| {
+            match self.add_to(&captures[2]) {
+                Some(url) => format!("{}{url}\"", &captures[1]),
+                None => captures[0].to_string(),
+            }
+        }).into_owned()
+    }
+}
+
+// Runs before compression, so responses are still plain text
+pub async fn mw_base_path(
+    State(base_path): State<BasePath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    let (mut parts, body) = response.into_parts();
+    if let Some(location) = parts.headers.get(header::LOCATION).and_then(|location| location.to_str().ok()) {
+        if let Some(value) = base_path.add_to(location).and_then(|location| HeaderValue::from_str(location.as_str()).ok()) {
+            parts.headers.insert(header::LOCATION, value);
+        }
+    }
+    let is_html = parts.headers.get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    if !is_html || parts.headers.contains_key(header::CONTENT_ENCODING) {
+        return Response::from_parts(parts, body);
+    }
+    let html = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) => {
+            tracing::warn!("Failed to read response body for {}: {e}", base_path.prefix);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(base_path.rewrite_html(html.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert!(BasePath::new("").is_none());
+        assert!(BasePath::new(" / ").is_none());
+        assert_eq!(BasePath::new("docs/").unwrap().prefix(), "/docs");
+        assert_eq!(BasePath::new("/docs/site").unwrap().prefix(), "/docs/site");
+    }
+
+    #[test]
+    fn test_add_to() {
+        let base_path = BasePath::new("/docs").unwrap();
+        assert_eq!(base_path.add_to("/home/index.md").as_deref(), Some("/docs/home/index.md"));
+        assert_eq!(base_path.add_to("/").as_deref(), Some("/docs/"));
+        // only a whole segment counts as already prefixed
+        assert_eq!(base_path.add_to("/docsite/x.css").as_deref(), Some("/docs/docsite/x.css"));
+        assert_eq!(base_path.add_to("/docs"), None);
+        assert_eq!(base_path.add_to("/docs/home/index.md"), None);
+        assert_eq!(base_path.add_to("/docs?page=2"), None);
+        assert_eq!(base_path.add_to("/docs#top"), None);
+        assert_eq!(base_path.add_to("//cdn.example.com/x.js"), None);
+        assert_eq!(base_path.add_to("https://example.com/x"), None);
+        assert_eq!(base_path.add_to("page.md"), None);
+        assert_eq!(base_path.add_to("#section"), None);
+        // queries and fragments come along
+        assert_eq!(base_path.add_to("/search?q=a#results").as_deref(), Some("/docs/search?q=a#results"));
+    }
+
+    #[test]
+    fn test_rewrite_html() {
+        let base_path = BasePath::new("/docs").unwrap();
+        let html = concat!(
+            "<a href=\"/home/a.md#intro\">A</a>",
+            "<img src=\"/style/logo.png\">",
+            "<form action=\"/search?q=x\">",
+            "<a href=\"/docs/home/b.md\">B</a>",
+            "<script src=\"//cdn.example.com/x.js\"></script>",
+            "<a href=\"c.md\">C</a>",
+            "<a data-url=\"/home/d.md\">D</a>",
+        );
+        assert_eq!(base_path.rewrite_html(html), concat!(
+            "<a href=\"/docs/home/a.md#intro\">A</a>",
+            "<img src=\"/docs/style/logo.png\">",
+            "<form action=\"/docs/search?q=x\">",
+            "<a href=\"/docs/home/b.md\">B</a>",
+            "<script src=\"//cdn.example.com/x.js\"></script>",
+            "<a href=\"c.md\">C</a>",
+            "<a data-url=\"/home/d.md\">D</a>",
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use crate::access::AccessControl;
+use crate::chimera_error::ChimeraError;
+use crate::document_scraper::DocumentScraper;
+use crate::expiry::Expiry;
+use crate::file_manager::FileManager;
+use crate::full_text_index::{FullTextIndex, ScanOptions, WriterOptions};
+use crate::memory_budget::DEFAULT_INDEX_WRITER_BYTES;
+use crate::toml_config::{AccessConfig, ExpiryConfig, MarkupConfig};
+
+// The pieces of the library that benches/ measures, which aren't otherwise
+// reachable from outside the crate. Not a stable API
+pub use crate::document_scraper::parse_markdown;
+pub use crate::result_cache::ResultCache;
+
+// How long SearchFixture waits for the index to take in every document
+const INDEX_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The heading pass over a parsed document, with permalinks and copy buttons on
+pub fn add_anchors_to_headings(body: String, scraper: &DocumentScraper) -> String {
+    let markup = MarkupConfig {
+        heading_permalinks: true,
+        code_copy_buttons: true,
+        ..MarkupConfig::default()
+    };
+    crate::html_generator::add_anchors_to_headings(
+        body,
+        &scraper.internal_links,
+        !scraper.starts_with_heading,
+        &markup,
+    )
+}
+
+// A full text index of generated documents, built in a scratch folder
+pub struct SearchFixture {
+    root: PathBuf,
+    index: FullTextIndex,
+    // keeps the change subscriptions of the scanner open
+    _file_manager: FileManager,
+}
+
+impl SearchFixture {
+    // Indexes the documents, (name, markdown), and waits until all are
+    // searchable. Needs a tokio runtime
+    pub async fn new(root: &Path, documents: &[(String, String)]) -> Result<Self, ChimeraError> {
+        let document_root = root.join("home");
+        let search_index_dir = root.join("search");
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(document_root.as_path())?;
+        std::fs::create_dir_all(search_index_dir.as_path())?;
+        for (name, md) in documents {
+            std::fs::write(document_root.join(name), md)?;
+        }
+        let file_manager = FileManager::new(document_root.as_path(), "index.md").await?;
+        let index = FullTextIndex::new(search_index_dir.as_path(), Expiry::new(&ExpiryConfig::default()), WriterOptions {
+            heap: DEFAULT_INDEX_WRITER_BYTES,
+            threads: 0,
+        })?;
+        let access = AccessControl::new(AccessConfig::default());
+        index.scan_directory(document_root, search_index_dir, &file_manager, access, ScanOptions::default()).await?;
+        let start = std::time::Instant::now();
+        while index.document_count() < documents.len() as u64 {
+            if start.elapsed() > INDEX_TIMEOUT {
+                return Err(ChimeraError::IOError(format!(
+                    "Only {} of {} documents were indexed", index.document_count(), documents.len())));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(SearchFixture {
+            root: root.to_path_buf(),
+            index,
+            _file_manager: file_manager,
+        })
+    }
+
+    // How many results the query found, as an anonymous user
+    pub fn search(&self, query: &str) -> Result<usize, ChimeraError> {
+        let (results, _warnings) = self.index.search(query, None)?;
+        Ok(results.len())
+    }
+}
+
+impl Drop for SearchFixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.root.as_path());
+    }
+}
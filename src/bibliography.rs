@@ -0,0 +1,293 @@
+use std::{collections::HashMap, path::Path};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+// How citations start in a rendered body, before they're filled in
+pub const CITATION_MARKER: &str = "<cite data-keys=";
+
+lazy_static! {
+    // Left in the body by the markdown parser for each [@key; @other] citation
+    static ref CITATION_RE: Regex = Regex::new(r#"<cite data-keys="([^"]*)">([^<]*)</cite>"#).unwrap();
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Reference {
+    pub key: String,
+    // "Family, Given", in the order listed
+    pub authors: Vec<String>,
+    pub year: Option<String>,
+    pub title: Option<String>,
+    // Journal, proceedings or publisher
+    pub container: Option<String>,
+    pub url: Option<String>,
+    #[serde(skip)]
+    families: Vec<String>,
+}
+
+impl Reference {
+    // Author-year, eg: "Smith and Jones 2020", "Smith et al. 2019"
+    fn label(&self) -> String {
+        let names = match self.families.as_slice() {
+            [] => self.title.clone().unwrap_or_else(|| self.key.clone()),
+            [only] => only.clone(),
+            [first, second] => format!("{first} and {second}"),
+            [first, ..] => format!("{first} et al."),
+        };
+        match &self.year {
+            Some(year) => format!("{names} {year}"),
+            None => names,
+        }
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = format!("<li id=\"ref-{}\">", escape(self.key.as_str()));
+        if !self.authors.is_empty() {
+            html.push_str(escape(self.authors.join("; ").as_str()).as_str());
+            html.push(' ');
+        }
+        if let Some(year) = &self.year {
+            html.push_str(format!("({}). ", escape(year)).as_str());
+        }
+        if let Some(title) = &self.title {
+            match &self.url {
+                Some(url) => html.push_str(format!("<a href=\"{}\">{}</a>. ", escape(url), escape(title)).as_str()),
+                None => html.push_str(format!("{}. ", escape(title)).as_str()),
+            }
+        }
+        if let Some(container) = &self.container {
+            html.push_str(format!("<i>{}</i>.", escape(container)).as_str());
+        }
+        html.truncate(html.trim_end().len());
+        html.push_str("</li>");
+        html
+    }
+}
+
+// References from a BibTeX (.bib) or CSL-JSON (.json) file
+#[derive(Default)]
+pub struct Bibliography {
+    references: HashMap<String, Reference>,
+}
+
+impl Bibliography {
+    pub fn load(path: &Path) -> Option<Bibliography> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::warn!("Failed to read bibliography {}: {e}", path.display());
+                return None;
+            }
+        };
+        let references = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => match serde_json::from_str::<Vec<CslItem>>(text.as_str()) {
+                Ok(items) => items.into_iter().map(CslItem::into_reference).collect(),
+                Err(e) => {
+                    tracing::warn!("Failed to parse bibliography {}: {e}", path.display());
+                    return None;
+                }
+            },
+            _ => parse_bibtex(text.as_str()),
+        };
+        let references = references.into_iter().map(|reference| (reference.key.clone(), reference)).collect();
+        Some(Bibliography {
+            references,
+        })
+    }
+
+    // Swaps the citation markers in a rendered body for links to the
+    // references, returning the references cited, in order of first use
+    pub fn cite(&self, html: &str) -> (String, Vec<&Reference>) {
+        let mut cited: Vec<&Reference> = Vec::new();
+        let html = CITATION_RE.replace_all(html, |captures: &regex::Captures| {
+            let mut labels = Vec::new();
+            for key in captures[1].split(';') {
+                let Some(reference) = self.references.get(key) else {
+                    tracing::debug!("No reference for citation {key}");
+                    return captures[0].to_string();
+                };
+                if !cited.iter().any(|cited| cited.key == reference.key) {
+                    cited.push(reference);
+                }
+                labels.push(format!("<a href=\"#ref-{}\">{}</a>", escape(key), escape(reference.label().as_str())));
+            }
+            format!("<cite class=\"citation\">({})</cite>", labels.join("; "))
+        });
+        (html.into_owned(), cited)
+    }
+}
+
+pub fn references_section(cited: &[&Reference]) -> String {
+    let mut html = String::from("<section class=\"references\">\n<h2 id=\"references\">References</h2>\n<ol>\n");
+    for reference in cited {
+        html.push_str(reference.to_html().as_str());
+        html.push('\n');
+    }
+    html.push_str("</ol>\n</section>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[derive(Deserialize)]
+struct CslName {
+    family: Option<String>,
+    given: Option<String>,
+    literal: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CslDate {
+    #[serde(rename = "date-parts", default)]
+    date_parts: Vec<Vec<serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+struct CslItem {
+    id: serde_json::Value,
+    #[serde(default)]
+    author: Vec<CslName>,
+    issued: Option<CslDate>,
+    title: Option<String>,
+    #[serde(rename = "container-title")]
+    container_title: Option<String>,
+    publisher: Option<String>,
+    #[serde(rename = "URL")]
+    url: Option<String>,
+    #[serde(rename = "DOI")]
+    doi: Option<String>,
+}
+
+impl CslItem {
+    fn into_reference(self) -> Reference {
+        let key = match self.id {
+            serde_json::Value::String(id) => id,
+            id => id.to_string(),
+        };
+        let families = self.author.iter()
+            .filter_map(|name| name.family.clone().or(name.literal.clone()))
+            .collect();
+        let authors = self.author.into_iter()
+            .filter_map(|name| match (name.family, name.given) {
+                (Some(family), Some(given)) => Some(format!("{family}, {given}")),
+                (family, _) => family.or(name.literal),
+            })
+            .collect();
+        let year = self.issued
+            .and_then(|issued| issued.date_parts.into_iter().next())
+            .and_then(|parts| parts.into_iter().next())
+            .map(|year| match year {
+                serde_json::Value::String(year) => year,
+                year => year.to_string(),
+            });
+        Reference {
+            key,
+            authors,
+            year,
+            title: self.title,
+            container: self.container_title.or(self.publisher),
+            url: self.url.or(self.doi.map(|doi| format!("https://doi.org/{doi}"))),
+            families,
+        }
+    }
+}
+
+// Enough BibTeX for reference lists: @type{key, field = {value}, field = "value", year = 2020}
+fn parse_bibtex(text: &str) -> Vec<Reference> {
+    let mut references = Vec::new();
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        rest = &rest[at + 1..];
+        let Some(open) = rest.find(['{', '(']) else {
+            break;
+        };
+        let entry_type = rest[..open].trim().to_ascii_lowercase();
+        let Some(len) = entry_length(&rest[open..]) else {
+            break;
+        };
+        let body = &rest[open + 1..open + len - 1];
+        rest = &rest[open + len..];
+        if matches!(entry_type.as_str(), "comment" | "string" | "preamble") {
+            continue;
+        }
+        let Some((key, fields)) = body.split_once(',') else {
+            continue;
+        };
+        let fields = parse_bibtex_fields(fields);
+        let names: Vec<&str> = fields.get("author").or(fields.get("editor"))
+            .map_or(Vec::new(), |authors| authors.split(" and ").map(str::trim).collect());
+        let families = names.iter().map(|name| match name.split_once(',') {
+            Some((family, _given)) => family.trim().to_string(),
+            None => name.rsplit(' ').next().unwrap_or(name).to_string(),
+        }).collect();
+        let authors = names.iter().map(|name| {
+            match name.split_once(',') {
+                Some(_) => name.to_string(),
+                None => match name.rsplit_once(' ') {
+                    Some((given, family)) => format!("{family}, {given}"),
+                    None => name.to_string(),
+                },
+            }
+        }).collect();
+        references.push(Reference {
+            key: key.trim().to_string(),
+            authors,
+            year: fields.get("year").cloned(),
+            title: fields.get("title").cloned(),
+            container: fields.get("journal").or(fields.get("booktitle")).or(fields.get("publisher")).cloned(),
+            url: fields.get("url").cloned().or(fields.get("doi").map(|doi| format!("https://doi.org/{doi}"))),
+            families,
+        });
+    }
+    references
+}
+
+// Length of a braced (or parenthesized) entry, including both delimiters
+fn entry_length(text: &str) -> Option<usize> {
+    let (open, close) = match text.starts_with('(') {
+        true => ('(', ')'),
+        false => ('{', '}'),
+    };
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        if c == open {
+            depth += 1;
+        }
+        else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i + 1);
+            }
+        }
+    }
+    None
+}
+
+fn parse_bibtex_fields(text: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let value_text = rest[equals + 1..].trim_start();
+        let (value, len) = match value_text.chars().next() {
+            Some('{') => match entry_length(value_text) {
+                Some(len) => (&value_text[1..len - 1], len),
+                None => break,
+            },
+            Some('"') => match value_text[1..].find('"') {
+                Some(end) => (&value_text[1..end + 1], end + 2),
+                None => break,
+            },
+            _ => {
+                let end = value_text.find(',').unwrap_or(value_text.len());
+                (value_text[..end].trim(), end)
+            },
+        };
+        let value = value.replace(['{', '}'], "").split_whitespace().collect::<Vec<&str>>().join(" ");
+        fields.insert(name, value);
+        rest = &value_text[len..];
+    }
+    fields
+}
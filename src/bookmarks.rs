@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::{Arc, RwLock}};
+use axum::{extract::State, http::{HeaderMap, StatusCode}, response::{IntoResponse, Redirect, Response}, Form};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::{handle_err, ChimeraError};
+use crate::comments::page_exists;
+use crate::page_catalog::page_url;
+use crate::request_info::is_user_variant;
+use crate::session::CsrfHtml;
+use crate::{AppStateType, HOME_DIR};
+
+pub const BOOKMARKS_URL: &str = "/bookmarks";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub url: String,
+    // As it was when starred, for documents that have since gone
+    pub title: String,
+    pub added: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct BookmarkFile {
+    // User => bookmarks, newest last
+    users: BTreeMap<String, Vec<Bookmark>>,
+}
+
+struct BookmarkStoreInternal {
+    path: PathBuf,
+    file: BookmarkFile,
+}
+
+// Pages each user has starred, kept in chimera_root/bookmarks. Users are
+// named by the authenticating proxy, as for access control, so bookmarks
+// are only offered when there is one
+#[derive(Clone)]
+pub struct BookmarkStore {
+    lock: Arc<RwLock<BookmarkStoreInternal>>,
+    max_per_user: usize,
+}
+
+impl BookmarkStore {
+    pub fn new(bookmarks_dir: PathBuf, max_per_user: usize) -> Self {
+        if let Err(e) = std::fs::create_dir_all(bookmarks_dir.as_path()) {
+            tracing::warn!("Failed to create bookmarks directory {}: {e}", bookmarks_dir.display());
+        }
+        let path = bookmarks_dir.join("bookmarks.toml");
+        let file = match std::fs::read_to_string(path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", path.display());
+                        BookmarkFile::default()
+                    }
+                }
+            },
+            Err(_) => BookmarkFile::default(),
+        };
+        tracing::info!("Bookmark store loaded for {} users", file.users.len());
+        BookmarkStore {
+            lock: Arc::new(RwLock::new(BookmarkStoreInternal {
+                path,
+                file,
+            })),
+            max_per_user,
+        }
+    }
+
+    pub fn for_user(&self, user: &str) -> Vec<Bookmark> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.file.users.get(user).cloned().unwrap_or_default()
+    }
+
+    // Returns false if the user already has as many as they're allowed
+    pub async fn add(&self, user: &str, url: &str, title: &str) -> Result<bool, ChimeraError> {
+        {
+            let mut lock = self.lock.write()?;
+            let bookmarks = lock.file.users.entry(user.to_string()).or_default();
+            if bookmarks.iter().any(|bookmark| bookmark.url == url) {
+                return Ok(true);
+            }
+            if bookmarks.len() >= self.max_per_user {
+                return Ok(false);
+            }
+            let added = time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default();
+            bookmarks.push(Bookmark {
+                url: url.to_string(),
+                title: title.to_string(),
+                added,
+            });
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    pub async fn remove(&self, user: &str, url: &str) -> Result<(), ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            let Some(bookmarks) = lock.file.users.get_mut(user) else {
+                return Ok(());
+            };
+            let before = bookmarks.len();
+            bookmarks.retain(|bookmark| bookmark.url != url);
+            let found = bookmarks.len() != before;
+            if bookmarks.is_empty() {
+                lock.file.users.remove(user);
+            }
+            found
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    async fn save(&self) -> Result<(), ChimeraError> {
+        let (path, toml) = {
+            let lock = self.lock.read()?;
+            match toml::to_string(&lock.file) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting bookmarks to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BookmarkForm {
+    page: String,
+    // "add" or "remove"
+    action: String,
+    // Set to /bookmarks to go back to the list instead of the page
+    #[serde(default)]
+    back: String,
+}
+
+pub async fn handle_post_bookmark(
+    State(app_state): State<AppStateType>,
+    headers: HeaderMap,
+    Form(form): Form<BookmarkForm>,
+) -> Response {
+    let (Some(store), Some(user)) = (app_state.bookmark_store.as_ref(), app_state.access.user(&headers)) else {
+        return (StatusCode::FORBIDDEN, "Bookmarks need you to be signed in").into_response();
+    };
+    let page = form.page.as_str();
+    // it's also where we redirect to, so it has to be one of ours
+    let Some(relative) = page.strip_prefix(format!("{HOME_DIR}/").as_str()).and_then(|relative| urlencoding::decode(relative).ok()) else {
+        return (StatusCode::BAD_REQUEST, "Bookmark rejected").into_response();
+    };
+    let readable = app_state.access.can_read(std::path::Path::new(relative.as_ref()), Some(user.as_str()));
+    // however the form encoded it, match the catalog's URLs
+    let page = page_url(relative.as_ref());
+    let page = page.as_str();
+    let result = match form.action.as_str() {
+        "add" if readable && page_exists(page) => {
+            let title = app_state.page_catalog.page(page).map_or_else(|| page.to_string(), |summary| summary.title);
+            match store.add(user.as_str(), page, title.as_str()).await {
+                Ok(true) => Ok(()),
+                Ok(false) => return (StatusCode::BAD_REQUEST, "Too many bookmarks").into_response(),
+                Err(e) => Err(e),
+            }
+        },
+        "remove" => store.remove(user.as_str(), page).await,
+        _ => return (StatusCode::BAD_REQUEST, "Bookmark rejected").into_response(),
+    };
+    if let Err(e) = result {
+        tracing::warn!("Failed to save bookmarks: {e:?}");
+        return handle_err(app_state).await.into_response();
+    }
+    // the user's pages show their bookmarks
+    let evicted = app_state.result_cache.evict_variants(|variant| is_user_variant(variant, user.as_str()));
+    tracing::debug!("Bookmark {} {page} for {user}, evicted {evicted} cached pages", form.action);
+    match form.back == BOOKMARKS_URL {
+        true => Redirect::to(BOOKMARKS_URL).into_response(),
+        false => Redirect::to(page).into_response(),
+    }
+}
+
+pub async fn handle_bookmarks(
+    State(app_state): State<AppStateType>,
+    headers: HeaderMap,
+) -> Response {
+    let (Some(store), Some(user)) = (app_state.bookmark_store.as_ref(), app_state.access.user(&headers)) else {
+        return (StatusCode::FORBIDDEN, "Bookmarks need you to be signed in").into_response();
+    };
+    match app_state.html_generator.gen_bookmarks(user.as_str(), store.for_user(user.as_str())) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering bookmarks for {user}: {e:?}");
+            handle_err(app_state).await.into_response()
+        },
+    }
+}
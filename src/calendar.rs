@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+use axum::{extract::State, http::HeaderMap, response::{IntoResponse, Response}};
+use serde::Serialize;
+use time::{Date, Month};
+
+use crate::chimera_error::{handle_404, handle_err};
+use crate::expiry::parse_date;
+use crate::file_manager::PeerInfo;
+use crate::session::CsrfHtml;
+use crate::{AppStateType, HOME_DIR};
+
+pub const CALENDAR_DIR: &str = "/calendar";
+
+#[derive(Serialize)]
+pub struct CalendarDay {
+    pub day: u8,
+    // The journal entry for the day, if there is one
+    pub url: Option<String>,
+}
+
+// One month of a folder of journal entries, named like 2024-03-17.md
+#[derive(Serialize)]
+pub struct CalendarMonth {
+    pub folder: String,
+    pub folder_url: String,
+    pub year: i32,
+    pub month: u8,
+    // Monday first. Days outside the month are None
+    pub weeks: Vec<Vec<Option<CalendarDay>>>,
+    pub entries: usize,
+    pub previous: String,
+    pub next: String,
+}
+
+// The entries in a folder listing, by date
+fn journal_entries(peers: &PeerInfo) -> Vec<(Date, &str)> {
+    peers.files.iter()
+        .filter(|file| file.name.len() == 10)
+        .filter_map(|file| parse_date(file.name.as_str()).map(|date| (date, file.url.as_str())))
+        .collect()
+}
+
+fn month_url(folder: &str, year: i32, month: Month) -> String {
+    let folder: Vec<String> = folder.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .map(|part| urlencoding::encode(part).into_owned())
+        .collect();
+    match folder.is_empty() {
+        true => format!("{CALENDAR_DIR}/{year:04}-{:02}", month as u8),
+        false => format!("{CALENDAR_DIR}/{}/{year:04}-{:02}", folder.join("/"), month as u8),
+    }
+}
+
+// Where a folder listing can link to its calendar, when it has journal
+// entries. The newest entry's month
+pub fn calendar_url(folder: &Path, peers: &PeerInfo) -> Option<String> {
+    let (newest, _url) = journal_entries(peers).into_iter().max()?;
+    Some(month_url(folder.to_string_lossy().as_ref(), newest.year(), newest.month()))
+}
+
+fn build_month(folder: &str, peers: &PeerInfo, year: i32, month: Month) -> Option<CalendarMonth> {
+    let first = Date::from_calendar_date(year, month, 1).ok()?;
+    let entries: Vec<(Date, &str)> = journal_entries(peers).into_iter()
+        .filter(|(date, _url)| date.year() == year && date.month() == month)
+        .collect();
+    let folder_url = match folder.is_empty() {
+        true => format!("{HOME_DIR}/"),
+        false => format!("{HOME_DIR}/{}/", folder.split('/').map(|part| urlencoding::encode(part).into_owned()).collect::<Vec<_>>().join("/")),
+    };
+    let mut weeks = Vec::new();
+    let mut week: Vec<Option<CalendarDay>> = (0..first.weekday().number_days_from_monday()).map(|_| None).collect();
+    for day in 1..=month.length(year) {
+        let url = entries.iter()
+            .find(|(date, _url)| date.day() == day)
+            .map(|(_date, url)| format!("{folder_url}{url}"));
+        week.push(Some(CalendarDay { day, url }));
+        if week.len() == 7 {
+            weeks.push(std::mem::take(&mut week));
+        }
+    }
+    if !week.is_empty() {
+        week.resize_with(7, || None);
+        weeks.push(week);
+    }
+    let previous = match month {
+        Month::January => month_url(folder, year - 1, Month::December),
+        month => month_url(folder, year, month.previous()),
+    };
+    let next = match month {
+        Month::December => month_url(folder, year + 1, Month::January),
+        month => month_url(folder, year, month.next()),
+    };
+    Some(CalendarMonth {
+        folder: folder.to_string(),
+        folder_url,
+        year,
+        month: month as u8,
+        weeks,
+        entries: entries.len(),
+        previous,
+        next,
+    })
+}
+
+// The folder, and the month asked for if the path ends with one, eg:
+// journal/2024-03
+fn parse_path(path: &str) -> (PathBuf, Option<(i32, Month)>) {
+    let path = path.trim_matches('/');
+    let (folder, last) = match path.rsplit_once('/') {
+        Some((folder, last)) => (folder, last),
+        None => ("", path),
+    };
+    let month = last.split_once('-')
+        .filter(|(year, month)| year.len() == 4 && month.len() == 2)
+        .and_then(|(year, month)| {
+            let year = year.parse::<i32>().ok()?;
+            let month = Month::try_from(month.parse::<u8>().ok()?).ok()?;
+            Some((year, month))
+        });
+    match month {
+        Some(month) => (PathBuf::from(folder), Some(month)),
+        None => (PathBuf::from(path), None),
+    }
+}
+
+pub async fn handle_calendar_root(
+    app_state: State<AppStateType>,
+    headers: HeaderMap,
+) -> Response {
+    handle_calendar(app_state, axum::extract::Path(String::new()), headers).await
+}
+
+pub async fn handle_calendar(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if path.split('/').any(|part| part == "..") {
+        return handle_404(app_state).await.into_response();
+    }
+    let (folder, month) = parse_path(path.as_str());
+    let user = app_state.access.user(&headers);
+    if !app_state.access.can_read(folder.as_path(), user.as_deref()) {
+        return handle_404(app_state).await.into_response();
+    }
+    // listings are keyed by canonical path, and cwd is the document root
+    let peers = Path::new(".").join(folder.as_path()).canonicalize().ok()
+        .filter(|abs_path| abs_path.is_dir())
+        .and_then(|abs_path| app_state.file_manager.find_peers_in_folder(abs_path.as_path(), None));
+    let Some(peers) = peers else {
+        return handle_404(app_state).await.into_response();
+    };
+    let month = month.or_else(|| {
+        let (newest, _url) = journal_entries(&peers).into_iter().max()?;
+        Some((newest.year(), newest.month()))
+    });
+    let folder = folder.to_string_lossy().replace('\\', "/");
+    let Some(calendar) = month.and_then(|(year, month)| build_month(folder.as_str(), &peers, year, month)) else {
+        return handle_404(app_state).await.into_response();
+    };
+    let lang = app_state.messages.negotiate(&headers);
+    match app_state.html_generator.gen_calendar(&calendar, lang.as_deref()) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering the calendar for {folder}: {e:?}");
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
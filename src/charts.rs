@@ -0,0 +1,233 @@
+use std::ops::Range;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag, TagEnd};
+use yaml_rust2::{Yaml, YamlLoader};
+
+const WIDTH: f64 = 600.0;
+const HEIGHT: f64 = 300.0;
+const MARGIN_LEFT: f64 = 48.0;
+const MARGIN_RIGHT: f64 = 12.0;
+const MARGIN_TOP: f64 = 12.0;
+const MARGIN_BOTTOM: f64 = 32.0;
+
+// A ```chart block, as yaml or json, eg:
+//   type: line
+//   title: Visitors
+//   labels: [Mon, Tue, Wed]
+//   series:
+//     - name: This week
+//       values: [12, 30, 18]
+// `values:` on its own is a chart of one series
+struct ChartSpec {
+    kind: ChartKind,
+    title: Option<String>,
+    labels: Vec<String>,
+    series: Vec<(Option<String>, Vec<f64>)>,
+}
+
+#[derive(PartialEq)]
+enum ChartKind {
+    Bar,
+    Line,
+}
+
+// Swaps ```chart code blocks for inline SVG drawn from their spec. A spec
+// that can't be read is left as the code block it was
+#[derive(Default)]
+pub struct Charts<'a> {
+    held: Vec<(Event<'a>, Range<usize>)>,
+    spec: Option<String>,
+}
+
+impl<'a> Charts<'a> {
+    pub fn rewrite(&mut self, ev: Event<'a>, range: Range<usize>) -> Vec<(Event<'a>, Range<usize>)> {
+        match ev {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref lang))) if lang.trim().eq_ignore_ascii_case("chart") => {
+                self.spec = Some(String::new());
+                self.held.push((ev, range));
+                Vec::new()
+            },
+            Event::Text(ref text) if self.spec.is_some() => {
+                if let Some(spec) = self.spec.as_mut() {
+                    spec.push_str(text);
+                }
+                self.held.push((ev, range));
+                Vec::new()
+            },
+            Event::End(TagEnd::CodeBlock) if self.spec.is_some() => {
+                let spec = self.spec.take().unwrap_or_default();
+                self.held.push((ev, range.clone()));
+                match parse_spec(spec.as_str()) {
+                    Some(chart) => {
+                        self.held.clear();
+                        vec![(Event::Html(CowStr::from(render_svg(&chart))), range)]
+                    },
+                    None => {
+                        tracing::debug!("Leaving an unreadable chart as a code block");
+                        std::mem::take(&mut self.held)
+                    },
+                }
+            },
+            ev => vec![(ev, range)],
+        }
+    }
+}
+
+fn parse_spec(spec: &str) -> Option<ChartSpec> {
+    let docs = YamlLoader::load_from_str(spec).ok()?;
+    let doc = docs.first()?;
+    let kind = match doc["type"].as_str().map(str::to_ascii_lowercase).as_deref() {
+        None | Some("bar") => ChartKind::Bar,
+        Some("line") => ChartKind::Line,
+        Some(_) => return None,
+    };
+    let series = match &doc["series"] {
+        Yaml::Array(series) => series.iter()
+            .map(|series| Some((scalar(&series["name"]), numbers(&series["values"])?)))
+            .collect::<Option<Vec<_>>>()?,
+        _ => vec![(None, numbers(&doc["values"])?)],
+    };
+    let points = series.iter().map(|(_name, values)| values.len()).max().filter(|points| *points > 0)?;
+    let mut labels: Vec<String> = match &doc["labels"] {
+        Yaml::Array(labels) => labels.iter().map(|label| scalar(label).unwrap_or_default()).collect(),
+        _ => Vec::new(),
+    };
+    labels.resize(points, String::new());
+    Some(ChartSpec {
+        kind,
+        title: scalar(&doc["title"]),
+        labels,
+        series,
+    })
+}
+
+fn scalar(yaml: &Yaml) -> Option<String> {
+    match yaml {
+        Yaml::String(value) | Yaml::Real(value) => Some(value.clone()),
+        Yaml::Integer(value) => Some(value.to_string()),
+        Yaml::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn numbers(yaml: &Yaml) -> Option<Vec<f64>> {
+    let Yaml::Array(values) = yaml else {
+        return None;
+    };
+    values.iter().map(|value| match value {
+        Yaml::Integer(value) => Some(*value as f64),
+        Yaml::Real(value) => value.parse::<f64>().ok().filter(|value| value.is_finite()),
+        _ => None,
+    }).collect()
+}
+
+// A round step for about 4 gridlines across the range
+fn tick_step(range: f64) -> f64 {
+    let rough = range / 4.0;
+    let magnitude = 10_f64.powf(rough.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].into_iter()
+        .map(|factor| factor * magnitude)
+        .find(|step| *step >= rough)
+        .unwrap_or(10.0 * magnitude)
+}
+
+fn format_number(value: f64) -> String {
+    match value.fract() == 0.0 {
+        true => format!("{value:.0}"),
+        false => format!("{}", (value * 1000.0).round() / 1000.0),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let _ = pulldown_cmark_escape::escape_html(&mut escaped, text);
+    escaped
+}
+
+fn render_svg(chart: &ChartSpec) -> String {
+    let values = chart.series.iter().flat_map(|(_name, values)| values.iter().copied());
+    let (low, high) = values.fold((0.0_f64, 0.0_f64), |(low, high), value| (low.min(value), high.max(value)));
+    let step = tick_step(match high - low {
+        range if range > 0.0 => range,
+        _ => 1.0,
+    });
+    let low = (low / step).floor() * step;
+    let high = ((high / step).ceil() * step).max(low + step);
+    let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let plot_height = HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+    let y = |value: f64| MARGIN_TOP + plot_height * (high - value) / (high - low);
+    let slot = plot_width / chart.labels.len() as f64;
+    let x = |point: usize| MARGIN_LEFT + slot * (point as f64 + 0.5);
+
+    let label = chart.title.as_deref().map(escape).unwrap_or_else(|| "Chart".to_string());
+    let mut svg = format!(
+        "<figure class=\"chart\">\n<svg viewBox=\"0 0 {WIDTH} {HEIGHT}\" role=\"img\" aria-label=\"{label}\">\n"
+    );
+    let mut tick = low;
+    while tick <= high + step / 2.0 {
+        svg.push_str(format!(
+            "<line class=\"chart-grid\" x1=\"{MARGIN_LEFT}\" x2=\"{}\" y1=\"{:.1}\" y2=\"{:.1}\"/><text class=\"chart-tick\" x=\"{}\" y=\"{:.1}\" text-anchor=\"end\" dominant-baseline=\"middle\">{}</text>\n",
+            WIDTH - MARGIN_RIGHT, y(tick), y(tick), MARGIN_LEFT - 6.0, y(tick), format_number(tick)
+        ).as_str());
+        tick += step;
+    }
+    for (point, text) in chart.labels.iter().enumerate() {
+        svg.push_str(format!(
+            "<text class=\"chart-label\" x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>\n",
+            x(point), HEIGHT - MARGIN_BOTTOM + 20.0, escape(text)
+        ).as_str());
+    }
+    let bar_width = slot * 0.8 / chart.series.len() as f64;
+    for (index, (name, values)) in chart.series.iter().enumerate() {
+        let name = name.as_deref().map(escape);
+        let tooltip = |point: usize, value: f64| {
+            let label = escape(chart.labels[point].as_str());
+            match &name {
+                Some(name) => format!("<title>{name}, {label}: {}</title>", format_number(value)),
+                None => format!("<title>{label}: {}</title>", format_number(value)),
+            }
+        };
+        let class = format!("chart-series chart-series-{}", index % 6 + 1);
+        match chart.kind {
+            ChartKind::Bar => {
+                for (point, value) in values.iter().enumerate() {
+                    let left = x(point) - slot * 0.4 + bar_width * index as f64;
+                    let (top, bottom) = (y(value.max(0.0)), y(value.min(0.0)));
+                    svg.push_str(format!(
+                        "<rect class=\"{class}\" x=\"{left:.1}\" y=\"{top:.1}\" width=\"{bar_width:.1}\" height=\"{:.1}\">{}</rect>\n",
+                        bottom - top, tooltip(point, *value)
+                    ).as_str());
+                }
+            },
+            ChartKind::Line => {
+                let points: Vec<String> = values.iter().enumerate()
+                    .map(|(point, value)| format!("{:.1},{:.1}", x(point), y(*value)))
+                    .collect();
+                svg.push_str(format!("<polyline class=\"{class}\" fill=\"none\" points=\"{}\"/>\n", points.join(" ")).as_str());
+                for (point, value) in values.iter().enumerate() {
+                    svg.push_str(format!(
+                        "<circle class=\"{class}\" cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\">{}</circle>\n",
+                        x(point), y(*value), tooltip(point, *value)
+                    ).as_str());
+                }
+            },
+        }
+    }
+    svg.push_str("</svg>\n");
+    let legend: Vec<String> = chart.series.iter().enumerate()
+        .filter_map(|(index, (name, _values))| name.as_ref().map(|name| {
+            format!("<li class=\"chart-series-{}\">{}</li>", index % 6 + 1, escape(name))
+        }))
+        .collect();
+    if chart.title.is_some() || !legend.is_empty() {
+        svg.push_str("<figcaption>");
+        if let Some(title) = &chart.title {
+            svg.push_str(escape(title).as_str());
+        }
+        if !legend.is_empty() {
+            svg.push_str(format!("<ul class=\"chart-legend\">{}</ul>", legend.concat()).as_str());
+        }
+        svg.push_str("</figcaption>\n");
+    }
+    svg.push_str("</figure>\n");
+    svg
+}
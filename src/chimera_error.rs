@@ -2,6 +2,7 @@ use std::error::Error;
 
 use axum::{http::StatusCode, response::IntoResponse};
 
+use crate::session::CsrfHtml;
 use crate::AppStateType;
 
 #[derive(Debug, PartialEq)]
@@ -14,6 +15,10 @@ pub enum ChimeraError {
     RwLock,
     NotifyError,
     TomlError(String),
+    ConverterError(String),
+    BackupError(String),
+    WebmentionError(String),
+    MailError(String),
 }
 
 impl From<tera::Error> for ChimeraError {
@@ -98,21 +103,25 @@ impl IntoResponse for ChimeraError {
 pub async fn handle_404(
     app_state: AppStateType,
 ) -> Result<axum::response::Response, ChimeraError> {
-    let html = app_state.html_generator.gen_error(
-        "404: Not found",
-        "Page not found",
-        "The page you are looking for does not exist or has been moved",
-    )?;
-    Ok((StatusCode::NOT_FOUND, axum::response::Html(html)).into_response())
+    let html = app_state.html_generator.gen_error("404: Not found", "not_found")?;
+    Ok((StatusCode::NOT_FOUND, CsrfHtml(html)).into_response())
+}
+
+// For requests shed to stay inside the memory budget, see LoadShedder
+pub async fn handle_overloaded(
+    app_state: AppStateType,
+) -> Result<axum::response::Response, ChimeraError> {
+    let html = app_state.html_generator.gen_error("503: Service unavailable", "busy")?;
+    Ok((
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(axum::http::header::RETRY_AFTER, "60")],
+        CsrfHtml(html),
+    ).into_response())
 }
 
 pub async fn handle_err(
     app_state: AppStateType,
 ) -> Result<axum::response::Response, ChimeraError> {
-    let html = app_state.html_generator.gen_error(
-        "500: Internal server error",
-        "Internal server error",
-        "Chimera failed attempting to complete this request",
-    )?;
-    Ok((StatusCode::INTERNAL_SERVER_ERROR, axum::response::Html(html)).into_response())
+    let html = app_state.html_generator.gen_error("500: Internal server error", "internal")?;
+    Ok((StatusCode::INTERNAL_SERVER_ERROR, CsrfHtml(html)).into_response())
 }
@@ -0,0 +1,294 @@
+use std::{collections::HashMap, net::SocketAddr, path::{Component, PathBuf}, sync::{Arc, RwLock}, time::{Duration, Instant}};
+use axum::{extract::{ConnectInfo, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Redirect, Response}, Form};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::{handle_404, ChimeraError};
+use crate::{AppStateType, HOME_DIR};
+
+const MAX_AUTHOR_LEN: usize = 80;
+const MAX_BODY_LEN: usize = 5000;
+const MAX_LINKS: usize = 2;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: u64,
+    pub page: String,
+    pub author: String,
+    pub body: String,
+    pub posted: String,
+    pub approved: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CommentFile {
+    next_id: u64,
+    comments: Vec<Comment>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommentRejection {
+    Spam,
+    InvalidPage,
+    TooLong,
+    Empty,
+    TooManyLinks,
+    TooSoon,
+    QueueFull,
+}
+
+struct CommentStoreInternal {
+    path: PathBuf,
+    file: CommentFile,
+    last_post: HashMap<String, Instant>,
+}
+
+#[derive(Clone)]
+pub struct CommentStore {
+    lock: Arc<RwLock<CommentStoreInternal>>,
+    min_interval: Duration,
+    max_pending: usize,
+}
+
+impl CommentStore {
+    pub fn new(comments_dir: PathBuf, min_interval_secs: u64, max_pending: usize) -> Self {
+        if let Err(e) = std::fs::create_dir_all(comments_dir.as_path()) {
+            tracing::warn!("Failed to create comments directory {}: {e}", comments_dir.display());
+        }
+        let path = comments_dir.join("comments.toml");
+        let file = match std::fs::read_to_string(path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", path.display());
+                        CommentFile::default()
+                    }
+                }
+            },
+            Err(_) => CommentFile::default(),
+        };
+        tracing::info!("Comment store loaded with {} comments", file.comments.len());
+        CommentStore {
+            lock: Arc::new(RwLock::new(CommentStoreInternal {
+                path,
+                file,
+                last_post: HashMap::new(),
+            })),
+            min_interval: Duration::from_secs(min_interval_secs),
+            max_pending,
+        }
+    }
+
+    pub fn approved_for(&self, page: &str) -> Vec<Comment> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.file.comments.iter().filter(|c| c.approved && c.page == page).cloned().collect()
+    }
+
+    pub fn pending(&self) -> Vec<Comment> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.file.comments.iter().filter(|c| !c.approved).cloned().collect()
+    }
+
+    // Comments go into the moderation queue. The poster's address is only
+    // kept in memory for rate limiting and is never written to disk
+    pub async fn submit(
+        &self,
+        addr: &str,
+        page: &str,
+        author: &str,
+        body: &str,
+        honeypot: &str,
+    ) -> Result<(), CommentRejection> {
+        if !honeypot.is_empty() {
+            return Err(CommentRejection::Spam);
+        }
+        let author = author.trim();
+        let body = body.trim();
+        if author.is_empty() || body.is_empty() {
+            return Err(CommentRejection::Empty);
+        }
+        if author.chars().count() > MAX_AUTHOR_LEN || body.chars().count() > MAX_BODY_LEN {
+            return Err(CommentRejection::TooLong);
+        }
+        if body.matches("http").count() > MAX_LINKS {
+            return Err(CommentRejection::TooManyLinks);
+        }
+        if !page_exists(page) {
+            return Err(CommentRejection::InvalidPage);
+        }
+        {
+            let Ok(mut lock) = self.lock.write() else {
+                return Err(CommentRejection::QueueFull);
+            };
+            let now = Instant::now();
+            let min_interval = self.min_interval;
+            lock.last_post.retain(|_, when| now.duration_since(*when) < min_interval);
+            if lock.last_post.contains_key(addr) {
+                return Err(CommentRejection::TooSoon);
+            }
+            if lock.file.comments.iter().filter(|c| !c.approved).count() >= self.max_pending {
+                return Err(CommentRejection::QueueFull);
+            }
+            lock.last_post.insert(addr.to_string(), now);
+            let id = lock.file.next_id;
+            lock.file.next_id += 1;
+            let posted = time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default();
+            tracing::info!("New comment {id} queued for moderation on {page}");
+            lock.file.comments.push(Comment {
+                id,
+                page: page.to_string(),
+                author: author.to_string(),
+                body: body.to_string(),
+                posted,
+                approved: false,
+            });
+        }
+        if let Err(e) = self.save().await {
+            tracing::warn!("Failed to save comments: {e:?}");
+        }
+        Ok(())
+    }
+
+    pub async fn approve(&self, id: u64) -> Result<bool, ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            match lock.file.comments.iter_mut().find(|c| c.id == id) {
+                Some(comment) => {
+                    comment.approved = true;
+                    true
+                },
+                None => false,
+            }
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(found)
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<bool, ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            let before = lock.file.comments.len();
+            lock.file.comments.retain(|c| c.id != id);
+            lock.file.comments.len() != before
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(found)
+    }
+
+    async fn save(&self) -> Result<(), ChimeraError> {
+        let (path, toml) = {
+            let lock = self.lock.read()?;
+            match toml::to_string(&lock.file) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting comments to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+// Comments can only be attached to markdown documents that exist
+pub fn page_exists(page: &str) -> bool {
+    page_path(page).is_some()
+}
+
+// The document a page URL names, relative to the document root. None unless
+// it's a markdown file under the root. /home//etc/x.md decodes to an
+// absolute path, which PathBuf would happily look for outside of it
+pub fn page_path(page: &str) -> Option<PathBuf> {
+    let relative = page.strip_prefix(format!("{HOME_DIR}/").as_str())?;
+    let relative = urlencoding::decode(relative).ok()?;
+    let path = PathBuf::from(relative.as_ref());
+    let escapes = path.is_absolute() || path.components().any(|component| {
+        matches!(component, Component::RootDir | Component::Prefix(_) | Component::ParentDir)
+    });
+    let is_markdown = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md"));
+    (!escapes && is_markdown && path.is_file()).then_some(path)
+}
+
+#[derive(Deserialize)]
+pub struct CommentForm {
+    page: String,
+    author: String,
+    body: String,
+    // Left empty by people, filled in by naive bots
+    #[serde(default)]
+    website: String,
+}
+
+pub async fn handle_post_comment(
+    State(app_state): State<AppStateType>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<CommentForm>,
+) -> Response {
+    let Some(store) = app_state.comment_store.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    let addr = app_state.trusted_proxies.client_addr(&headers, addr);
+    let back = match form.page.starts_with(format!("{HOME_DIR}/").as_str()) {
+        true => format!("{}#comments", form.page),
+        false => "/".to_string(),
+    };
+    // a page the poster can't read is refused as though it weren't there
+    let user = app_state.access.user(&headers);
+    let readable = page_path(form.page.as_str())
+        .is_some_and(|path| app_state.access.can_read(path.as_path(), user.as_deref()));
+    let result = match readable {
+        true => store.submit(addr.as_str(), form.page.as_str(), form.author.as_str(), form.body.as_str(), form.website.as_str()).await,
+        false => Err(CommentRejection::InvalidPage),
+    };
+    match result {
+        Ok(()) => {
+            Redirect::to(back.as_str()).into_response()
+        },
+        Err(CommentRejection::Spam) => {
+            // Don't tell the bot it was caught
+            tracing::info!("Discarded spam comment from {addr}");
+            Redirect::to(back.as_str()).into_response()
+        },
+        Err(CommentRejection::TooSoon) => {
+            (StatusCode::TOO_MANY_REQUESTS, "Please wait before commenting again").into_response()
+        },
+        Err(e) => {
+            tracing::info!("Rejected comment from {addr}: {e:?}");
+            (StatusCode::BAD_REQUEST, "Comment rejected").into_response()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_path_stays_under_the_root() {
+        let folder = std::env::temp_dir().join(format!("chimera-comments-{}", std::process::id()));
+        std::fs::create_dir_all(folder.as_path()).unwrap();
+        let document = folder.join("outside.md");
+        std::fs::write(document.as_path(), "# Outside").unwrap();
+        let absolute = document.to_string_lossy().replace('\\', "/");
+        assert!(document.is_file());
+        assert_eq!(page_path(format!("{HOME_DIR}/{absolute}").as_str()), None);
+        assert_eq!(page_path(format!("{HOME_DIR}/{}", urlencoding::encode(absolute.as_str())).as_str()), None);
+        assert_eq!(page_path(format!("{HOME_DIR}/../{absolute}").as_str()), None);
+        assert_eq!(page_path(format!("{HOME_DIR}/%2e%2e/notes.md").as_str()), None);
+        assert_eq!(page_path(absolute.as_str()), None);
+        let _ = std::fs::remove_dir_all(folder.as_path());
+    }
+}
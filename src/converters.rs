@@ -0,0 +1,140 @@
+use std::{collections::HashMap, ffi::OsStr, path::{Path, PathBuf}, process::Stdio, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+use tokio::io::AsyncWriteExt;
+
+use crate::chimera_error::ChimeraError;
+use crate::file_manager::FileManager;
+use crate::result_cache::ResultCache;
+use crate::toml_config::{ConverterConfig, ConverterOutput};
+
+// Converted documents kept, beyond which the oldest conversions are dropped
+const MAX_CACHED_CONVERSIONS: usize = 256;
+
+#[derive(Clone)]
+pub enum Converted {
+    Markdown(String),
+    Html(String),
+}
+
+// Serves other document formats (.org, .docx, ...) by piping them through
+// an external command, eg: pandoc. Its output is kept until the file changes
+#[derive(Clone, Default)]
+pub struct Converters {
+    // Extension => how to convert files with it
+    commands: Arc<HashMap<String, ConverterConfig>>,
+    // Document => (modified time it was converted at, output)
+    cache: Arc<RwLock<HashMap<PathBuf, (SystemTime, Converted)>>>,
+}
+
+impl Converters {
+    pub fn new(config: HashMap<String, ConverterConfig>) -> Self {
+        let commands = config.into_iter()
+            .filter(|(ext, converter)| {
+                if converter.command.is_empty() {
+                    tracing::warn!("Ignoring the converter for .{ext}, it has no command");
+                }
+                !converter.command.is_empty()
+            })
+            .map(|(ext, converter)| (ext.trim_start_matches('.').to_ascii_lowercase(), converter))
+            .collect();
+        Converters {
+            commands: Arc::new(commands),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn command_for(&self, path: &Path) -> Option<&ConverterConfig> {
+        let ext = path.extension().and_then(OsStr::to_str)?.to_ascii_lowercase();
+        self.commands.get(ext.as_str())
+    }
+
+    pub fn handles(&self, path: &Path) -> bool {
+        self.command_for(path).is_some()
+    }
+
+    pub fn listen_for_changes(&self, file_manager: &FileManager, result_cache: ResultCache) {
+        if self.commands.is_empty() {
+            return;
+        }
+        let rx = file_manager.subscribe();
+        tokio::spawn(listen_for_changes(rx, self.clone(), result_cache));
+    }
+
+    pub async fn convert(&self, path: &Path) -> Result<Converted, ChimeraError> {
+        let Some(converter) = self.command_for(path) else {
+            return Err(ChimeraError::ConverterError(format!("No converter for {}", path.display())));
+        };
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+        if let Ok(lock) = self.cache.read() {
+            if let Some((converted_at, converted)) = lock.get(path) {
+                if *converted_at == modified {
+                    return Ok(converted.clone());
+                }
+            }
+        }
+        let input = tokio::fs::read(path).await?;
+        let output = run_command(converter, path, input).await?;
+        let converted = match converter.output {
+            ConverterOutput::Markdown => Converted::Markdown(output),
+            ConverterOutput::Html => Converted::Html(output),
+        };
+        if let Ok(mut lock) = self.cache.write() {
+            if lock.len() >= MAX_CACHED_CONVERSIONS {
+                if let Some(oldest) = lock.iter().min_by_key(|(_path, (converted_at, _))| *converted_at).map(|(path, _)| path.clone()) {
+                    lock.remove(&oldest);
+                }
+            }
+            lock.insert(path.to_path_buf(), (modified, converted.clone()));
+        }
+        Ok(converted)
+    }
+}
+
+// The document goes in on stdin, and the command runs in its folder so
+// relative references (images, includes) resolve
+pub async fn run_command(converter: &ConverterConfig, path: &Path, input: Vec<u8>) -> Result<String, ChimeraError> {
+    let program = converter.command[0].as_str();
+    let folder = path.parent().filter(|folder| !folder.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    tracing::debug!("Converting {} with {program}", path.display());
+    let mut child = tokio::process::Command::new(program)
+        .args(&converter.command[1..])
+        .current_dir(folder)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| ChimeraError::ConverterError(format!("Failed to start {program}: {e}")))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // written alongside reading the output, so a full pipe can't stall both
+        tokio::spawn(async move {
+            if let Err(e) = stdin.write_all(input.as_slice()).await {
+                tracing::debug!("Converter stopped reading its input: {e}");
+            }
+        });
+    }
+    let timeout = Duration::from_secs(converter.timeout_secs);
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            return Err(ChimeraError::ConverterError(format!("{program} took over {}s on {}", converter.timeout_secs, path.display())));
+        }
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(output.stderr.as_slice());
+        return Err(ChimeraError::ConverterError(format!("{program} failed on {} ({}): {}", path.display(), output.status, stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(output.stdout.as_slice()).into_owned())
+}
+
+async fn listen_for_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    converters: Converters,
+    result_cache: ResultCache,
+) {
+    while let Ok(path) = rx.recv().await {
+        if converters.handles(path.as_path()) {
+            tracing::debug!("Converted document change event {}", path.display());
+            result_cache.clear();
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::path::Path;
+
+// The styles every page needs before it can be drawn. site.css and the
+// highlight.js theme are left to load afterwards
+const CORE_STYLES: [&str; 2] = ["style/skeleton.css", "style/chimera.css"];
+
+// The core styles, to inline into the page <head>, as long as together they
+// come in under the limit (bytes). Files in earlier web roots win
+pub fn load(web_roots: &[&Path], limit: usize) -> Option<String> {
+    let mut css = String::new();
+    for style in CORE_STYLES {
+        let Some(path) = web_roots.iter().map(|root| root.join(style)).find(|path| path.exists()) else {
+            tracing::warn!("Not inlining styles, {style} wasn't found");
+            return None;
+        };
+        match std::fs::read_to_string(path.as_path()) {
+            Ok(contents) => {
+                css.push_str(contents.trim());
+                css.push('\n');
+            },
+            Err(e) => {
+                tracing::warn!("Not inlining styles, failed to read {}: {e}", path.display());
+                return None;
+            }
+        }
+    }
+    if css.len() > limit {
+        tracing::info!("Not inlining styles, they're {} bytes, over the {limit} byte limit", css.len());
+        return None;
+    }
+    tracing::debug!("Inlining {} bytes of styles", css.len());
+    Some(css)
+}
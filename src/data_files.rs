@@ -0,0 +1,101 @@
+use std::{collections::HashMap, fs, path::{Component, Path, PathBuf}, sync::{Arc, RwLock}};
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::file_manager::FileManager;
+
+// Structured data for templates, from .yaml, .json and .toml files in the
+// data folder. Files are parsed on first use and kept until they change
+#[derive(Clone)]
+pub struct DataFiles {
+    dir: PathBuf,
+    lock: Arc<RwLock<HashMap<PathBuf, tera::Value>>>,
+}
+
+impl DataFiles {
+    pub fn new(dir: PathBuf) -> Self {
+        DataFiles {
+            dir,
+            lock: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn listen_for_changes(&self, file_manager: &FileManager) {
+        let rx = file_manager.subscribe();
+        tokio::spawn(listen_for_changes(rx, self.clone()));
+    }
+
+    // Takes a path within the data folder, with or without the "data/"
+    fn load(&self, name: &str) -> tera::Result<tera::Value> {
+        let name = Path::new(name.trim_start_matches('/'));
+        let name = name.strip_prefix("data").unwrap_or(name);
+        if name.as_os_str().is_empty() || !name.components().all(|part| matches!(part, Component::Normal(_))) {
+            return Err(tera::Error::msg(format!("load_data() can't read {}", name.display())));
+        }
+        let path = self.dir.join(name);
+        if let Some(value) = self.lock.read().ok().and_then(|lock| lock.get(&path).cloned()) {
+            return Ok(value);
+        }
+        let text = fs::read_to_string(path.as_path())
+            .map_err(|e| tera::Error::msg(format!("Failed to read {}: {e}", path.display())))?;
+        let ext = path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let value = match ext.as_str() {
+            "json" => serde_json::from_str(text.as_str()).map_err(|e| e.to_string()),
+            "toml" => toml::from_str::<toml::Value>(text.as_str())
+                .map_err(|e| e.to_string())
+                .and_then(|value| serde_json::to_value(value).map_err(|e| e.to_string())),
+            "yaml" | "yml" => YamlLoader::load_from_str(text.as_str())
+                .map_err(|e| e.to_string())
+                .map(|docs| docs.first().map_or(tera::Value::Null, yaml_value)),
+            _ => Err("only .yaml, .json and .toml files are supported".to_string()),
+        }.map_err(|e| tera::Error::msg(format!("Error parsing {}: {e}", path.display())))?;
+        if let Ok(mut lock) = self.lock.write() {
+            lock.insert(path, value.clone());
+        }
+        Ok(value)
+    }
+
+    // Tera helper, eg: {% for speaker in load_data(path="data/speakers.yaml") %}
+    pub fn load_data_function(&self) -> impl tera::Function {
+        let data_files = self.clone();
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            let path = args.get("path")
+                .and_then(tera::Value::as_str)
+                .ok_or_else(|| tera::Error::msg("load_data() needs a path"))?;
+            data_files.load(path)
+        }
+    }
+}
+
+fn yaml_value(yaml: &Yaml) -> tera::Value {
+    match yaml {
+        Yaml::String(value) => tera::Value::String(value.clone()),
+        Yaml::Integer(value) => tera::Value::from(*value),
+        Yaml::Real(value) => value.parse::<f64>().map_or(tera::Value::String(value.clone()), tera::Value::from),
+        Yaml::Boolean(value) => tera::Value::Bool(*value),
+        Yaml::Array(values) => tera::Value::Array(values.iter().map(yaml_value).collect()),
+        Yaml::Hash(hash) => tera::Value::Object(hash.iter().map(|(key, value)| {
+            let key = match key {
+                Yaml::String(key) | Yaml::Real(key) => key.clone(),
+                Yaml::Integer(key) => key.to_string(),
+                Yaml::Boolean(key) => key.to_string(),
+                _ => String::new(),
+            };
+            (key, yaml_value(value))
+        }).collect()),
+        _ => tera::Value::Null,
+    }
+}
+
+async fn listen_for_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    data_files: DataFiles,
+) {
+    while let Ok(changed) = rx.recv().await {
+        if changed.starts_with(data_files.dir.as_path()) {
+            tracing::info!("Data file change event {}", changed.display());
+            if let Ok(mut lock) = data_files.lock.write() {
+                lock.clear();
+            }
+        }
+    }
+}
@@ -1,11 +1,16 @@
-use std::{cmp::Ordering, collections::{HashMap, HashSet}, ops::Range};
+use std::{borrow::Cow, cmp::Ordering, collections::{HashMap, HashSet}, ops::Range};
 use lazy_static::lazy_static;
 use regex::Regex;
-use pulldown_cmark::{Event, Tag, TagEnd};
+use pulldown_cmark::{BlockQuoteKind, CowStr, Event, LinkType, Tag, TagEnd};
 use serde::Serialize;
 use slugify::slugify;
 use yaml_rust2::YamlLoader;
 
+use crate::charts::Charts;
+use crate::publishing;
+use crate::toml_config::MarkupConfig;
+use crate::wikilinks::WIKILINK_MARKER;
+
 #[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct InternalLink {
     pub anchor: String,
@@ -23,10 +28,15 @@ impl InternalLink {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Clone, Serialize, Debug)]
 pub struct ExternalLink {
     pub url: String,
     pub name: String,
+    // Folder links pick these up from the folder's index front matter
+    pub description: Option<String>,
+    pub icon: Option<String>,
+    // Set on the entry for the page being viewed
+    pub active: bool,
 }
 
 impl ExternalLink {
@@ -34,11 +44,23 @@ impl ExternalLink {
         ExternalLink {
             url,
             name,
+            description: None,
+            icon: None,
+            active: false,
         }
     }
 }
 
 lazy_static! {
+    static ref CITATION_RE: Regex = Regex::new(r"\[@[^\]\s;]+(?:;\s*@[^\]\s;]+)*\]").unwrap();
+    // [[Page Name]], [[Page Name#Heading]] or [[Page Name|alias]]
+    static ref WIKILINK_RE: Regex = Regex::new(r"\[\[([^\[\]|#]*)(?:#([^\[\]|]*))?(?:\|([^\[\]]*))?\]\]").unwrap();
+    static ref BARE_URL_RE: Regex = Regex::new(r"(?:https?://|www\.)[^\s<>]+").unwrap();
+    static ref ABBREVIATION_RE: Regex = Regex::new(r"^\*\[([^\]]+)\]:\s*(.*)$").unwrap();
+    static ref IMG_SRC_RE: Regex = Regex::new(r#"<img src="([^"]*)""#).unwrap();
+    static ref HTML_TAG_RE: Regex = Regex::new(r"<[^>]*>").unwrap();
+    // Obsidian's callout line, eg: [!tip] Try this, or [!faq]- for a folded one
+    static ref CALLOUT_RE: Regex = Regex::new(r"^\[!([A-Za-z][\w-]*)\][+-]?(?:\s+(.*))?$").unwrap();
     static ref CODE_LANGUAGES: HashSet<&'static str> = HashSet::from([
         "applescript", "bash", "c", "cpp", "csharp", "erlang", "fortran", "go", "haskell",
         "html", "ini", "java", "js", "make", "markdown", "objectivec", "perl", "php",
@@ -46,20 +68,84 @@ lazy_static! {
     ]);
 }
 
+// Characters of a document's description, about what search engines show
+pub const DESCRIPTION_LENGTH: usize = 160;
+
+// Documents with this template are shown as a board, see BoardColumn
+pub const BOARD_TEMPLATE: &str = "board.html";
+
+// A task list item, as a card on a board
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BoardCard {
+    pub text: String,
+    pub done: bool,
+}
+
+// A level 2 heading of a board, with the task list items under it
+#[derive(Clone, Debug, Serialize)]
+pub struct BoardColumn {
+    pub name: String,
+    pub anchor: String,
+    pub cards: Vec<BoardCard>,
+}
+
 #[derive(Clone)]
 pub struct DocumentScraper {
     pub internal_links: Vec<InternalLink>,
     pub code_languages: Vec<&'static str>,
     pub metadata: HashMap<String, String>,
+    pub metadata_lists: HashMap<String, Vec<String>>,
+    // Fragments of links within this document, eg: "setup" for [Setup](#setup)
+    pub anchor_links: Vec<String>,
+    // Image sources as they'll appear in the html, for sizing them in one go
+    pub images: Vec<String>,
+    // Footnote definitions, numbered in order of first mention. Anchors are
+    // fn-<label>, and fnref-<label> for where they're referenced
+    pub footnotes: Vec<InternalLink>,
+    // How often each footnote has been referenced so far, by label
+    footnote_refs: HashMap<String, usize>,
+    // Label of the definition being read
+    footnote_definition: Option<String>,
     pub title: Option<String>,
     heading_re: Regex,
     id_re: Regex,
     text_collector: Option<String>,
+    // Author-provided id of the current heading, eg: # Title {#custom-id}
+    heading_id: Option<String>,
     pub has_code_blocks: bool,
+    // Has - [ ] or - [x] items, which need the task list styles
+    pub has_tasklists: bool,
+    // Has $inline$ or $$display$$ math, which needs the math renderer
+    pub has_math: bool,
+    // Has ```mermaid diagrams, drawn in the browser rather than highlighted
+    pub has_mermaid: bool,
+    // Has ```dot graphs, for Graphviz to draw when it's set up
+    pub has_graphs: bool,
+    // Kinds of > [!NOTE] callouts used, eg: ["note", "warning"]
+    pub callout_types: Vec<String>,
     pub starts_with_heading: bool,
+    // Problems with the headings as written, such as skipped levels, for
+    // the content health report and dev mode
+    pub outline_warnings: Vec<String>,
+    // Only filled in for the board template
+    pub board: Vec<BoardColumn>,
+    // Plain text of the first paragraph, see summary
+    pub first_paragraph: Option<String>,
+    paragraph_collector: Option<String>,
+    // Reading an image's alt text, which isn't part of the paragraph
+    in_image: bool,
+    // Depth of the list item being read, and of the task being made a card
+    item_depth: usize,
+    card_depth: Option<usize>,
     has_readable_text: bool,
 }
 
+impl Default for DocumentScraper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DocumentScraper {
     pub fn new() -> Self {
         let heading_re = Regex::new(r"<[hH](\d)\s*([^<]*)>([^<]*)</[hH]\d>").unwrap();
@@ -68,20 +154,196 @@ impl DocumentScraper {
             internal_links: Vec::new(),
             code_languages: Vec::new(),
             metadata: HashMap::new(),
+            metadata_lists: HashMap::new(),
+            anchor_links: Vec::new(),
+            images: Vec::new(),
+            footnotes: Vec::new(),
+            footnote_refs: HashMap::new(),
+            footnote_definition: None,
             title: None,
             heading_re,
             id_re,
             text_collector: None,
+            heading_id: None,
             has_code_blocks: false,
+            has_tasklists: false,
+            has_math: false,
+            has_mermaid: false,
+            has_graphs: false,
+            callout_types: Vec::new(),
             starts_with_heading: false,
+            outline_warnings: Vec::new(),
+            board: Vec::new(),
+            first_paragraph: None,
+            paragraph_collector: None,
+            in_image: false,
+            item_depth: 0,
+            card_depth: None,
             has_readable_text: false,
         }
     }
 
+    // Same-document links that don't lead to any heading
+    pub fn broken_anchors(&self) -> Vec<String> {
+        let mut broken: Vec<String> = Vec::new();
+        for fragment in &self.anchor_links {
+            let anchor = urlencoding::decode(fragment).map_or(fragment.clone(), |anchor| anchor.into_owned());
+            // browsers scroll to the top for #top even without such an element
+            let found = anchor.is_empty()
+                || anchor == "top"
+                || self.internal_links.iter().any(|link| link.anchor == anchor)
+                || self.is_footnote_anchor(anchor.as_str());
+            if !found && !broken.contains(fragment) {
+                broken.push(fragment.clone());
+            }
+        }
+        broken
+    }
+
+    fn is_footnote_anchor(&self, anchor: &str) -> bool {
+        if self.footnotes.iter().any(|footnote| footnote.anchor == anchor) {
+            return true;
+        }
+        // fnref-<label>, or fnref-<label>-<n> for later references
+        let Some(reference) = anchor.strip_prefix("fnref-") else {
+            return false;
+        };
+        self.footnote_refs.iter().any(|(label, count)| {
+            let slug = footnote_slug(label);
+            reference == slug || (2..=*count).any(|n| reference == format!("{slug}-{n}"))
+        })
+    }
+
+    // The footnote's number, giving it the next one if it's new
+    fn footnote_number(&mut self, label: &str) -> usize {
+        let anchor = format!("fn-{}", footnote_slug(label));
+        match self.footnotes.iter().position(|footnote| footnote.anchor == anchor) {
+            Some(index) => index + 1,
+            None => {
+                self.footnotes.push(InternalLink::new(anchor, label.to_string(), 1));
+                self.footnotes.len()
+            },
+        }
+    }
+
+    // Footnotes with anchors at both ends, in place of pulldown's, which
+    // only link one way
+    fn footnote_markup<'a>(&mut self, ev: Event<'a>) -> Event<'a> {
+        match ev {
+            Event::FootnoteReference(label) => {
+                let number = self.footnote_number(&label);
+                let slug = footnote_slug(&label);
+                let count = self.footnote_refs.entry(label.to_string()).or_default();
+                *count += 1;
+                let id = match *count {
+                    1 => format!("fnref-{slug}"),
+                    n => format!("fnref-{slug}-{n}"),
+                };
+                Event::InlineHtml(CowStr::from(format!(
+                    "<sup class=\"footnote-reference\" id=\"{id}\"><a href=\"#fn-{slug}\">{number}</a></sup>"
+                )))
+            },
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                let number = self.footnote_number(&label);
+                self.footnote_definition = Some(label.to_string());
+                Event::Html(CowStr::from(format!(
+                    "<div class=\"footnote-definition\" id=\"fn-{}\"><sup class=\"footnote-definition-label\">{number}</sup>",
+                    footnote_slug(&label),
+                )))
+            },
+            Event::End(TagEnd::FootnoteDefinition) => {
+                let backref = self.footnote_definition.take()
+                    .filter(|label| self.footnote_refs.contains_key(label))
+                    .map(|label| format!(
+                        "<a class=\"footnote-backref\" href=\"#fnref-{}\" aria-label=\"Back to the text\">↩</a>",
+                        footnote_slug(&label),
+                    ))
+                    .unwrap_or_default();
+                Event::Html(CowStr::from(format!("{backref}</div>\n")))
+            },
+            ev => ev,
+        }
+    }
+
+    // Front matter `title:` if there is one, otherwise the first heading
+    pub fn page_title(&self) -> Option<&String> {
+        self.metadata.get("title").or(self.title.as_ref())
+    }
+
+    // Unfinished documents, `draft: true`, are only shown with the preview token
+    pub fn is_draft(&self) -> bool {
+        self.metadata.get("draft").is_some_and(|draft| draft.eq_ignore_ascii_case("true"))
+    }
+
+    // Has a `publish_date:` still to come
+    pub fn is_scheduled(&self) -> bool {
+        publishing::is_scheduled(&self.metadata)
+    }
+
+    // The document opens with a heading that just repeats its front matter title
+    pub fn repeats_title(&self) -> bool {
+        match (self.metadata.get("title"), &self.title) {
+            (Some(title), Some(heading)) => {
+                self.starts_with_heading && title.trim().eq_ignore_ascii_case(heading.trim())
+            },
+            _ => false,
+        }
+    }
+
+    // Front matter `description:`, or the first paragraph cut down to about
+    // max_chars, for excerpts and link previews
+    pub fn summary(&self, max_chars: usize) -> Option<String> {
+        if let Some(description) = self.metadata.get("description") {
+            return Some(description.clone());
+        }
+        let text = self.first_paragraph.as_ref()?;
+        if text.chars().count() <= max_chars {
+            return Some(text.clone());
+        }
+        let cut: String = text.chars().take(max_chars).collect();
+        let cut = match cut.rfind(' ') {
+            Some(space) => &cut[..space],
+            None => cut.as_str(),
+        };
+        Some(format!("{}…", cut.trim_end_matches([',', '.', ';', ':'])))
+    }
+
+    // For <meta name="description"> and listings
+    pub fn description(&self) -> Option<String> {
+        self.summary(DESCRIPTION_LENGTH)
+    }
+
     pub fn get_template(&self) -> &str {
         self.metadata.get("template").map_or("markdown.html", |v| {v.as_str()})
     }
 
+    // Scalars become metadata strings, and lists of scalars (eg: tags) go in
+    // metadata_lists. Anything more deeply nested is ignored
+    fn add_metadata(&mut self, key: &str, value: &yaml_rust2::Yaml) {
+        match value {
+            yaml_rust2::Yaml::Array(values) => {
+                let values: Vec<String> = values.iter().filter_map(yaml_scalar).collect();
+                tracing::debug!("Adding metadata list: {key} = {values:?}");
+                self.metadata_lists.insert(key.to_string(), values);
+            },
+            _ => {
+                if let Some(value) = yaml_scalar(value) {
+                    tracing::debug!("Adding metadata var: {key} = {value}");
+                    self.metadata.insert(key.to_string(), value);
+                }
+                else {
+                    tracing::debug!("Ignoring metadata var: {key}");
+                }
+            },
+        }
+    }
+
+    fn collect_images(&mut self, html: &str) {
+        for captures in IMG_SRC_RE.captures_iter(html) {
+            self.images.push(captures[1].to_string());
+        }
+    }
+
     pub fn check_event(&mut self, ev: &Event, range: Range<usize>) {
         tracing::trace!("md-event: {ev:?} - {range:?}");
         match ev {
@@ -90,31 +352,85 @@ impl DocumentScraper {
                     Tag::MetadataBlock(_) => {
                         self.text_collector = Some(String::with_capacity(1024));
                     },
-                    Tag::Heading { level: _, id: _, classes: _, attrs: _ } => {
+                    Tag::Heading { level: _, id, classes: _, attrs: _ } => {
                         if !self.has_readable_text {
                             self.starts_with_heading = true;
                             self.has_readable_text = true;
                         }
                         self.text_collector = Some(String::with_capacity(64));
+                        self.heading_id = id.as_ref().map(|id| id.to_string());
                     },
                     Tag::CodeBlock(kind) => {
-                        self.has_code_blocks = true;
-                        if let pulldown_cmark::CodeBlockKind::Fenced(lang) = kind {
-                            let lang = lang.to_ascii_lowercase();
+                        let lang = match kind {
+                            pulldown_cmark::CodeBlockKind::Fenced(lang) => lang.to_ascii_lowercase(),
+                            pulldown_cmark::CodeBlockKind::Indented => String::new(),
+                        };
+                        if lang == "mermaid" {
+                            self.has_mermaid = true;
+                        }
+                        else if lang == "dot" {
+                            self.has_graphs = true;
+                        }
+                        else {
+                            self.has_code_blocks = true;
                             if let Some(js) = CODE_LANGUAGES.get(lang.as_str()) {
                                 self.code_languages.push(js);
                             }
                         }
                     },
-                    // Tag::Image { link_type, dest_url, title, id } => {
-                    //     tracing::info!("Image: {link_type:?}, dest_url: {dest_url}, title: {title}, id: {id}");
-                    // }
+                    Tag::Link { link_type: _, dest_url, title: _, id: _ } => {
+                        self.has_readable_text = true;
+                        if let Some(fragment) = dest_url.strip_prefix('#') {
+                            self.anchor_links.push(fragment.to_string());
+                        }
+                    },
+                    Tag::Paragraph => {
+                        self.has_readable_text = true;
+                        if self.first_paragraph.is_none() {
+                            self.paragraph_collector = Some(String::new());
+                        }
+                    },
+                    Tag::Item => {
+                        self.has_readable_text = true;
+                        self.item_depth += 1;
+                    },
+                    Tag::Image { link_type: _, dest_url, title: _, id: _ } => {
+                        self.has_readable_text = true;
+                        self.in_image = true;
+                        // escaped the way the html writer will
+                        let mut src = String::with_capacity(dest_url.len());
+                        if pulldown_cmark_escape::escape_href(&mut src, dest_url).is_ok() {
+                            self.images.push(src);
+                        }
+                    },
                     _ => {
                         self.has_readable_text = true;
                     }
                 }
             },
+            Event::InlineHtml(text) => {
+                self.collect_images(text);
+                if let Some(paragraph) = self.paragraph_collector.as_mut() {
+                    // eg: a wikilink's label
+                    let text = HTML_TAG_RE.replace_all(text, "");
+                    paragraph.push_str(text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&").as_str());
+                }
+            },
+            Event::TaskListMarker(checked) => {
+                self.has_tasklists = true;
+                if self.card_depth.is_none() {
+                    if let Some(column) = self.board.last_mut() {
+                        column.cards.push(BoardCard { text: String::new(), done: *checked });
+                        self.card_depth = Some(self.item_depth);
+                    }
+                }
+            },
+            Event::InlineMath(_) | Event::DisplayMath(_) => {
+                self.has_math = true;
+                self.has_readable_text = true;
+            },
             Event::Html(text) => {
+                self.collect_images(text);
                 // <h3 id="the-middle">The middle</h3>
                 if let Some(captures) = self.heading_re.captures(text) {
                     let level = captures.get(1);
@@ -165,6 +481,16 @@ impl DocumentScraper {
                 if let Some(name) = self.text_collector.as_mut() {
                     name.push_str(t);
                 }
+                self.add_card_text(t);
+                self.add_paragraph_text(t);
+            },
+            Event::Code(code) => {
+                self.add_card_text(code);
+                self.add_paragraph_text(code);
+            },
+            Event::SoftBreak | Event::HardBreak => {
+                self.add_card_text(" ");
+                self.add_paragraph_text(" ");
             },
             Event::End(tag) => {
                 match tag {
@@ -174,9 +500,11 @@ impl DocumentScraper {
                             if self.title.is_none() {
                                 self.title = Some(name.clone());
                             }
-                            let link = InternalLink::new(
-                                slugify!(name.as_str()),
-                                name, *level as u8);
+                            let anchor = self.heading_id.take().unwrap_or_else(|| slugify!(name.as_str()));
+                            if self.get_template() == BOARD_TEMPLATE {
+                                self.add_board_heading(*level, name.as_str(), anchor.as_str());
+                            }
+                            let link = InternalLink::new(anchor, name, *level as u8);
                             tracing::debug!("Doclink found: {link:?}");
                             self.internal_links.push(link);
                         }
@@ -186,30 +514,42 @@ impl DocumentScraper {
                             if let Ok(docs) = YamlLoader::load_from_str(metadata.as_str()) {
                                 for doc in docs {
                                     match doc {
-                                        yaml_rust2::Yaml::Real(_) => todo!(),
-                                        yaml_rust2::Yaml::Integer(_) => todo!(),
-                                        yaml_rust2::Yaml::String(_) => todo!(),
-                                        yaml_rust2::Yaml::Boolean(_) => todo!(),
-                                        yaml_rust2::Yaml::Array(vec) => {
-                                            tracing::debug!("Vec: {vec:?}");
-                                        },
                                         yaml_rust2::Yaml::Hash(linked_hash_map) => {
-                                            //tracing::debug!("Hash: {linked_hash_map:?}");
-                                            for (key,value) in linked_hash_map {
-                                                let key = key.as_str().unwrap();
-                                                let value = value.as_str().unwrap();
-                                                tracing::debug!("Adding metadata var: {key} = {value}");
-                                                self.metadata.insert(key.to_string(), value.to_string());
+                                            for (key, value) in linked_hash_map {
+                                                let Some(key) = key.as_str() else {
+                                                    continue;
+                                                };
+                                                self.add_metadata(key, &value);
                                             }
                                         },
-                                        yaml_rust2::Yaml::Alias(_) => todo!(),
-                                        yaml_rust2::Yaml::Null => todo!(),
-                                        yaml_rust2::Yaml::BadValue => todo!(),
+                                        _ => {
+                                            tracing::debug!("Ignoring metadata block that isn't a map: {doc:?}");
+                                        },
                                     }
                                 }
                             }
                         }
                     },
+                    TagEnd::Image => self.in_image = false,
+                    TagEnd::Paragraph => {
+                        if let Some(paragraph) = self.paragraph_collector.take() {
+                            let text = paragraph.split_whitespace().collect::<Vec<&str>>().join(" ");
+                            // a [TOC] marker isn't what the document is about
+                            let toc = text.eq_ignore_ascii_case("[toc]") || text.eq_ignore_ascii_case("{{toc}}");
+                            if !text.is_empty() && !toc {
+                                self.first_paragraph = Some(text);
+                            }
+                        }
+                    },
+                    TagEnd::Item => {
+                        if self.card_depth == Some(self.item_depth) {
+                            self.card_depth = None;
+                            if let Some(card) = self.board.last_mut().and_then(|column| column.cards.last_mut()) {
+                                card.text = card.text.trim().to_string();
+                            }
+                        }
+                        self.item_depth = self.item_depth.saturating_sub(1);
+                    },
                     _ => {
                     }
                 }
@@ -218,12 +558,47 @@ impl DocumentScraper {
         }
     }
 
+    // Level 2 headings start a column, and deeper ones stay in it. Tasks
+    // before the first aren't on the board
+    fn add_board_heading(&mut self, level: pulldown_cmark::HeadingLevel, name: &str, anchor: &str) {
+        if level == pulldown_cmark::HeadingLevel::H2 {
+            self.board.push(BoardColumn {
+                name: name.to_string(),
+                anchor: anchor.to_string(),
+                cards: Vec::new(),
+            });
+        }
+    }
+
+    fn add_paragraph_text(&mut self, text: &str) {
+        if self.in_image {
+            return;
+        }
+        if let Some(paragraph) = self.paragraph_collector.as_mut() {
+            paragraph.push_str(text);
+        }
+    }
+
+    // The card's own line. Items nested under it aren't part of it
+    fn add_card_text(&mut self, text: &str) {
+        if self.card_depth != Some(self.item_depth) {
+            return;
+        }
+        if let Some(card) = self.board.last_mut().and_then(|column| column.cards.last_mut()) {
+            card.text.push_str(text);
+        }
+    }
+
     // The indenting scheme requires that we not grow more than 1 step at a time
     // Unfortunately, because this depends on user data, we can easily be asked
     // to process an invalid setup. Eg: <h1> directly to <h3>
     // Outdents don't have the same problem
     // Renumber the link list so we don't violate that assumption
     fn normalize_headings(&mut self) {
+        // the links to the top of the page and an index's listing aren't headings
+        let first = usize::from(!self.starts_with_heading);
+        let last = self.internal_links.len() - usize::from(self.get_template() == "index.html");
+        self.outline_warnings = self.check_outline(&self.internal_links[first..last.max(first)]);
         let mut last_used_level = 0;
         let mut last_seen_level = 0;
         for link in self.internal_links.iter_mut() {
@@ -245,18 +620,474 @@ impl DocumentScraper {
     }
 }
 
+impl DocumentScraper {
+    // What would trip up a reader following the document by its headings,
+    // as a screen reader does
+    fn check_outline(&self, headings: &[InternalLink]) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.page_title().is_none() {
+            warnings.push("The document has no title, in its front matter or a heading".to_string());
+        }
+        match headings.iter().filter(|heading| heading.level == 1).count() {
+            0 => warnings.push("The document has no level 1 heading".to_string()),
+            1 => {},
+            count => warnings.push(format!("The document has {count} level 1 headings, where one is expected")),
+        }
+        let mut last_level: Option<u8> = None;
+        for heading in headings {
+            let name = heading.name.trim();
+            if name.is_empty() {
+                warnings.push(format!("A level {} heading has no text", heading.level));
+            }
+            if let Some(last_level) = last_level.filter(|last_level| heading.level > last_level + 1) {
+                warnings.push(format!(
+                    "\"{name}\" skips from a level {last_level} heading to level {}",
+                    heading.level,
+                ));
+            }
+            last_level = Some(heading.level);
+        }
+        warnings
+    }
+}
+
+fn yaml_scalar(value: &yaml_rust2::Yaml) -> Option<String> {
+    match value {
+        yaml_rust2::Yaml::String(value) => Some(value.clone()),
+        yaml_rust2::Yaml::Real(value) => Some(value.clone()),
+        yaml_rust2::Yaml::Integer(value) => Some(value.to_string()),
+        yaml_rust2::Yaml::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// Collapsible sections, which can nest:
+// :::details Click to expand
+// Hidden until opened
+// :::
+fn expand_details_blocks(md: &str) -> Cow<'_, str> {
+    const OPEN: &str = ":::details";
+    const CLOSE: &str = ":::";
+    if !md.contains(OPEN) {
+        return Cow::Borrowed(md);
+    }
+    let mut expanded = String::with_capacity(md.len() + 256);
+    let mut depth = 0;
+    let mut fence: Option<&str> = None;
+    for line in md.lines() {
+        let trimmed = line.trim();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+        }
+        else if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+        }
+        else if let Some(summary) = trimmed.strip_prefix(OPEN) {
+            let summary = summary.trim();
+            let summary = match summary.is_empty() {
+                true => "Details".to_string(),
+                false => summary.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+            };
+            // the blank lines let the markdown inside be parsed as usual
+            expanded.push_str(format!("<details>\n<summary>{summary}</summary>\n\n").as_str());
+            depth += 1;
+            continue;
+        }
+        else if trimmed == CLOSE && depth > 0 {
+            expanded.push_str("\n</details>\n\n");
+            depth -= 1;
+            continue;
+        }
+        expanded.push_str(line);
+        expanded.push('\n');
+    }
+    for _ in 0..depth {
+        expanded.push_str("\n</details>\n");
+    }
+    Cow::Owned(expanded)
+}
+
+// PHP Markdown Extra style abbreviations, defined on lines of their own:
+// *[HTML]: HyperText Markup Language
+struct Abbreviations {
+    titles: HashMap<String, String>,
+    terms_re: Regex,
+}
+
+impl Abbreviations {
+    // Takes the definitions out of the document, leaving code blocks alone
+    fn extract(md: &str) -> (Cow<'_, str>, Option<Abbreviations>) {
+        if !md.contains("*[") {
+            return (Cow::Borrowed(md), None);
+        }
+        let mut titles = HashMap::new();
+        let mut remaining = String::with_capacity(md.len());
+        let mut fence: Option<&str> = None;
+        for line in md.lines() {
+            let trimmed = line.trim();
+            if let Some(marker) = fence {
+                if trimmed.starts_with(marker) {
+                    fence = None;
+                }
+            }
+            else if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                fence = Some(&trimmed[..3]);
+            }
+            else if let Some(captures) = ABBREVIATION_RE.captures(trimmed) {
+                titles.insert(captures[1].trim().to_string(), captures[2].trim().to_string());
+                continue;
+            }
+            remaining.push_str(line);
+            remaining.push('\n');
+        }
+        if titles.is_empty() {
+            return (Cow::Borrowed(md), None);
+        }
+        let mut terms: Vec<&String> = titles.keys().collect();
+        // longer terms win over ones they contain
+        terms.sort_by_key(|term| std::cmp::Reverse(term.len()));
+        let pattern = terms.iter().map(|term| {
+            // \b only makes sense next to word characters, eg: not after "C++"
+            let start = if term.starts_with(|c: char| c.is_alphanumeric()) { r"\b" } else { "" };
+            let end = if term.ends_with(|c: char| c.is_alphanumeric()) { r"\b" } else { "" };
+            format!("{start}{}{end}", regex::escape(term))
+        }).collect::<Vec<String>>().join("|");
+        let abbreviations = Regex::new(pattern.as_str()).ok().map(|terms_re| Abbreviations {
+            titles,
+            terms_re,
+        });
+        (Cow::Owned(remaining), abbreviations)
+    }
+
+    // The term stays text, so headings still read (and slugify) the same
+    fn expand<'a>(&self, text: CowStr<'a>) -> Vec<Event<'a>> {
+        let mut events = Vec::new();
+        let mut last = 0;
+        for found in self.terms_re.find_iter(&text) {
+            let Some(title) = self.titles.get(found.as_str()) else {
+                continue;
+            };
+            if found.start() > last {
+                events.push(Event::Text(CowStr::from(text[last..found.start()].to_string())));
+            }
+            let title = title.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;");
+            events.push(Event::InlineHtml(CowStr::from(format!("<abbr title=\"{title}\">"))));
+            events.push(Event::Text(CowStr::from(found.as_str().to_string())));
+            events.push(Event::InlineHtml(CowStr::Borrowed("</abbr>")));
+            last = found.end();
+        }
+        if last == 0 {
+            return vec![Event::Text(text)];
+        }
+        if last < text.len() {
+            events.push(Event::Text(CowStr::from(text[last..].to_string())));
+        }
+        events
+    }
+}
+
+// Marks [@key] and [@key; @other] citations for the bibliography to fill
+// in, see bibliography::cite, and [[wikilinks]]. The rest of the text is autolinked if asked,
+// and has its abbreviations marked up
+// Labels are matched case-insensitively, and may be anything
+// GitHub's > [!NOTE] alerts, and Obsidian's callouts of any type with an
+// optional title, eg: > [!tip] Try this. Both become an
+// <div class="admonition tip"> with the title on top
+#[derive(Default)]
+struct Callouts<'a> {
+    // A blockquote and its first paragraph, held until the text shows
+    // whether it's a callout
+    held: Vec<(Event<'a>, Range<usize>)>,
+    // Whether each open blockquote became a callout
+    open: Vec<bool>,
+    // The marker line was taken out, so the break after it goes too
+    after_marker: bool,
+    types: Vec<String>,
+}
+
+impl<'a> Callouts<'a> {
+    fn rewrite(&mut self, ev: Event<'a>, range: Range<usize>) -> Vec<(Event<'a>, Range<usize>)> {
+        let mut events = Vec::new();
+        if self.after_marker {
+            self.after_marker = false;
+            match ev {
+                // the marker was the whole paragraph
+                Event::End(TagEnd::Paragraph) => {},
+                Event::SoftBreak | Event::HardBreak => events.push((Event::Start(Tag::Paragraph), range)),
+                ev => {
+                    events.push((Event::Start(Tag::Paragraph), range.clone()));
+                    events.push((ev, range));
+                },
+            }
+            return events;
+        }
+        match ev {
+            Event::Start(Tag::BlockQuote(kind)) => {
+                self.flush(&mut events);
+                match kind {
+                    Some(kind) => {
+                        self.open.push(true);
+                        events.push((self.open_callout(alert_type(kind), None), range));
+                    },
+                    None => self.held.push((ev, range)),
+                }
+                return events;
+            },
+            Event::Start(Tag::Paragraph) if self.held.len() == 1 => {
+                self.held.push((ev, range));
+                return events;
+            },
+            Event::Text(ref text) if self.held.len() == 2 => {
+                if let Some(captures) = CALLOUT_RE.captures(text) {
+                    self.held.clear();
+                    self.open.push(true);
+                    self.after_marker = true;
+                    let kind = captures[1].to_ascii_lowercase();
+                    let title = captures.get(2).map(|title| title.as_str().trim()).filter(|title| !title.is_empty());
+                    events.push((self.open_callout(kind.as_str(), title), range));
+                    return events;
+                }
+                self.flush(&mut events);
+            },
+            Event::End(TagEnd::BlockQuote(_)) => {
+                self.flush(&mut events);
+                if self.open.pop() == Some(true) {
+                    events.push((Event::Html(CowStr::Borrowed("</div>\n")), range));
+                    return events;
+                }
+            },
+            _ => self.flush(&mut events),
+        }
+        events.push((ev, range));
+        events
+    }
+
+    // A held blockquote that turned out to be just a blockquote
+    fn flush(&mut self, events: &mut Vec<(Event<'a>, Range<usize>)>) {
+        if !self.held.is_empty() {
+            self.open.push(false);
+            events.append(&mut self.held);
+        }
+    }
+
+    fn open_callout(&mut self, kind: &str, title: Option<&str>) -> Event<'a> {
+        if !self.types.iter().any(|known| known == kind) {
+            self.types.push(kind.to_string());
+        }
+        let mut heading = String::with_capacity(32);
+        let _ = match title {
+            Some(title) => pulldown_cmark_escape::escape_html(&mut heading, title),
+            None => {
+                let mut chars = kind.chars();
+                let capitalized: String = chars.next().map(|first| first.to_ascii_uppercase()).into_iter().chain(chars).collect();
+                pulldown_cmark_escape::escape_html(&mut heading, capitalized.as_str())
+            },
+        };
+        Event::Html(CowStr::from(format!(
+            "<div class=\"admonition {kind}\">\n<p class=\"admonition-title\">{heading}</p>\n"
+        )))
+    }
+}
+
+fn alert_type(kind: BlockQuoteKind) -> &'static str {
+    match kind {
+        BlockQuoteKind::Note => "note",
+        BlockQuoteKind::Tip => "tip",
+        BlockQuoteKind::Important => "important",
+        BlockQuoteKind::Warning => "warning",
+        BlockQuoteKind::Caution => "caution",
+    }
+}
+
+fn footnote_slug(label: &str) -> String {
+    let slug = slugify!(label);
+    match slug.is_empty() {
+        true => urlencoding::encode(label.to_lowercase().as_str()).into_owned(),
+        false => slug,
+    }
+}
+
+fn expand_text<'a>(text: CowStr<'a>, autolinks: bool, abbreviations: Option<&Abbreviations>) -> Vec<Event<'a>> {
+    fn plain<'a>(text: CowStr<'a>, autolinks: bool, abbreviations: Option<&Abbreviations>) -> Vec<Event<'a>> {
+        let events = match autolinks {
+            true => autolink(text),
+            false => vec![Event::Text(text)],
+        };
+        let Some(abbreviations) = abbreviations else {
+            return events;
+        };
+        // not within the URLs just linked
+        let mut in_link = false;
+        events.into_iter().flat_map(|ev| match ev {
+            Event::Start(Tag::Link { .. }) => {
+                in_link = true;
+                vec![ev]
+            },
+            Event::End(TagEnd::Link) => {
+                in_link = false;
+                vec![ev]
+            },
+            Event::Text(text) if !in_link => abbreviations.expand(text),
+            ev => vec![ev],
+        }).collect()
+    }
+    if !text.contains("[@") && !text.contains("[[") {
+        return plain(text, autolinks, abbreviations);
+    }
+    let mut found: Vec<regex::Match> = CITATION_RE.find_iter(&text).chain(WIKILINK_RE.find_iter(&text)).collect();
+    found.sort_by_key(|found| found.start());
+    let mut events = Vec::new();
+    let mut last = 0;
+    for found in found {
+        // ![[embeds]] aren't links
+        if found.start() < last || text[..found.start()].ends_with('!') {
+            continue;
+        }
+        let Some(marker) = citation_marker(found.as_str()).or_else(|| wikilink_marker(found.as_str())) else {
+            continue;
+        };
+        if found.start() > last {
+            events.extend(plain(CowStr::from(text[last..found.start()].to_string()), autolinks, abbreviations));
+        }
+        events.push(Event::InlineHtml(CowStr::from(marker)));
+        last = found.end();
+    }
+    if last == 0 {
+        return plain(text, autolinks, abbreviations);
+    }
+    if last < text.len() {
+        events.extend(plain(CowStr::from(text[last..].to_string()), autolinks, abbreviations));
+    }
+    events
+}
+
+fn citation_marker(citation: &str) -> Option<String> {
+    if !citation.starts_with("[@") {
+        return None;
+    }
+    let keys: Vec<&str> = citation[1..citation.len() - 1].split(';')
+        .map(|key| key.trim().trim_start_matches('@'))
+        .collect();
+    Some(format!(
+        "<cite data-keys=\"{}\">{}</cite>",
+        keys.join(";").replace('&', "&amp;").replace('"', "&quot;"),
+        citation.replace('&', "&amp;").replace('<', "&lt;"),
+    ))
+}
+
+// Marks wikilinks for resolving against the documents on disk, see
+// wikilinks::resolve
+fn wikilink_marker(wikilink: &str) -> Option<String> {
+    let captures = WIKILINK_RE.captures(wikilink)?;
+    let target = captures[1].trim();
+    let heading = captures.get(2).map_or("", |heading| heading.as_str().trim());
+    if target.is_empty() && heading.is_empty() {
+        return None;
+    }
+    let label = captures.get(3)
+        .map(|alias| alias.as_str().trim())
+        .filter(|alias| !alias.is_empty())
+        .unwrap_or(&wikilink[2..wikilink.len() - 2]);
+    let mut escaped_label = String::with_capacity(label.len());
+    let _ = pulldown_cmark_escape::escape_html(&mut escaped_label, label);
+    Some(format!(
+        "{WIKILINK_MARKER}\"{}\" data-anchor=\"{}\">{escaped_label}</a>",
+        target.replace('&', "&amp;").replace('"', "&quot;"),
+        slugify!(heading),
+    ))
+}
+
+// Splits bare URLs out of text into links, as GitHub does. Trailing
+// punctuation is left out, as are closing parentheses with no opening one
+fn autolink(text: CowStr<'_>) -> Vec<Event<'_>> {
+    let mut events = Vec::new();
+    let mut last = 0;
+    for found in BARE_URL_RE.find_iter(&text) {
+        let mut url = found.as_str().trim_end_matches(['.', ',', ';', ':', '!', '?', '\'', '"']);
+        while url.ends_with(')') && url.matches(')').count() > url.matches('(').count() {
+            url = &url[..url.len() - 1];
+        }
+        if url.len() <= "www.".len() {
+            continue;
+        }
+        if found.start() > last {
+            events.push(Event::Text(CowStr::from(text[last..found.start()].to_string())));
+        }
+        let dest_url = match url.starts_with("www.") {
+            true => format!("http://{url}"),
+            false => url.to_string(),
+        };
+        events.push(Event::Start(Tag::Link {
+            link_type: LinkType::Autolink,
+            dest_url: CowStr::from(dest_url),
+            title: CowStr::Borrowed(""),
+            id: CowStr::Borrowed(""),
+        }));
+        events.push(Event::Text(CowStr::from(url.to_string())));
+        events.push(Event::End(TagEnd::Link));
+        last = found.start() + url.len();
+    }
+    if last == 0 {
+        return vec![Event::Text(text)];
+    }
+    if last < text.len() {
+        events.push(Event::Text(CowStr::from(text[last..].to_string())));
+    }
+    events
+}
+
 pub fn parse_markdown(md: &str) -> (String, DocumentScraper) {
+    parse_markdown_with(md, &MarkupConfig::default())
+}
+
+// The markup options that only change how a document looks
+pub fn parse_markdown_with(md: &str, markup: &MarkupConfig) -> (String, DocumentScraper) {
     let mut scraper = DocumentScraper::new();
+    let (md, abbreviations) = Abbreviations::extract(md);
+    let md = expand_details_blocks(&md);
+    // bare URLs are left alone in links and code
+    let mut in_link_or_code = false;
+    let mut callouts = Callouts::default();
+    let mut charts = Charts::default();
     let parser = pulldown_cmark::Parser::new_ext(
-        md, pulldown_cmark::Options::ENABLE_TABLES |
+        &md, pulldown_cmark::Options::ENABLE_TABLES |
         pulldown_cmark::Options::ENABLE_SMART_PUNCTUATION |
-        pulldown_cmark::Options::ENABLE_YAML_STYLE_METADATA_BLOCKS
-    ).into_offset_iter().map(|(ev, range)| {
+        pulldown_cmark::Options::ENABLE_YAML_STYLE_METADATA_BLOCKS |
+        pulldown_cmark::Options::ENABLE_HEADING_ATTRIBUTES |
+        pulldown_cmark::Options::ENABLE_STRIKETHROUGH |
+        pulldown_cmark::Options::ENABLE_FOOTNOTES |
+        pulldown_cmark::Options::ENABLE_TASKLISTS |
+        pulldown_cmark::Options::ENABLE_MATH |
+        pulldown_cmark::Options::ENABLE_GFM
+    ).into_offset_iter();
+    // text comes in pieces, which would split up URLs
+    let parser = pulldown_cmark::utils::TextMergeWithOffset::new(parser)
+        .flat_map(|(ev, range)| callouts.rewrite(ev, range))
+        .flat_map(|(ev, range)| charts.rewrite(ev, range));
+    let parser = parser.flat_map(|(ev, range)| {
+        let events = match ev {
+            Event::Start(Tag::Link { .. } | Tag::CodeBlock(_) | Tag::MetadataBlock(_)) => {
+                in_link_or_code = true;
+                vec![ev]
+            },
+            Event::End(TagEnd::Link | TagEnd::CodeBlock | TagEnd::MetadataBlock(_)) => {
+                in_link_or_code = false;
+                vec![ev]
+            },
+            Event::Text(text) if !in_link_or_code => expand_text(text, markup.autolinks, abbreviations.as_ref()),
+            Event::SoftBreak if markup.hard_breaks => vec![Event::HardBreak],
+            ev => vec![ev],
+        };
+        events.into_iter().map(move |ev| (ev, range.clone()))
+    }).map(|(ev, range)| {
         scraper.check_event(&ev, range);
-        ev
+        scraper.footnote_markup(ev)
     });
     let mut html_content = String::with_capacity(md.len() * 3 / 2);
     pulldown_cmark::html::push_html(&mut html_content, parser);
+    scraper.callout_types = callouts.types;
     if !scraper.starts_with_heading {
         scraper.internal_links.insert(0, InternalLink::new("top".to_string(), "Top".to_string(), 1));
     }
@@ -312,6 +1143,77 @@ mod tests {
         assert_eq!(scraper.title, Some("The title".to_string()));
     }
 
+    #[test]
+    fn test_front_matter_title_wins() {
+        let md = "---\ntitle: The Title\n---\n# The title\n\nBody";
+        let (_html_content, scraper) = parse_markdown(md);
+        assert_eq!(scraper.page_title(), Some(&"The Title".to_string()));
+        assert!(scraper.repeats_title());
+
+        let md = "---\ntitle: Another\n---\nIntro\n\n# The title";
+        let (_html_content, scraper) = parse_markdown(md);
+        assert_eq!(scraper.page_title(), Some(&"Another".to_string()));
+        assert!(!scraper.repeats_title());
+    }
+
+    #[test]
+    fn test_heading_attributes() {
+        let md = "# Installing {#setup .wide}\n\n## Usage {.note}\n\nSee [setup](#setup)";
+        let (html_content, scraper) = parse_markdown(md);
+        assert_eq!(scraper.internal_links[0], InternalLink::new(
+            "setup".to_string(),
+            "Installing".to_string(),
+            1
+        ));
+        assert_eq!(scraper.internal_links[1], InternalLink::new(
+            "usage".to_string(),
+            "Usage".to_string(),
+            2
+        ));
+        assert!(scraper.broken_anchors().is_empty());
+        assert!(html_content.contains("<h1 id=\"setup\" class=\"wide\">Installing</h1>"));
+    }
+
+    #[test]
+    fn test_details_blocks() {
+        let md = ":::details Click <here>\nHidden *text*\n\n```\n:::details not this\n```\n:::\n\nAfter";
+        let (html_content, _scraper) = parse_markdown(md);
+        assert!(html_content.starts_with("<details>\n<summary>Click &lt;here&gt;</summary>\n<p>Hidden <em>text</em></p>\n"));
+        assert!(html_content.contains("<code>:::details not this\n</code>"));
+        assert!(html_content.contains("</details>\n<p>After</p>"));
+    }
+
+    #[test]
+    fn test_abbreviations() {
+        let md = "# About HTML\n\nHTML and HTMLX, in [HTML links](/x) and `HTML` code.\n\n*[HTML]: HyperText \"Markup\" Language\n\n```\n*[CSS]: kept\n```";
+        let (html_content, scraper) = parse_markdown(md);
+        assert!(html_content.contains("<h1>About <abbr title=\"HyperText &quot;Markup&quot; Language\">HTML</abbr></h1>"));
+        assert!(html_content.contains("<p><abbr title=\"HyperText &quot;Markup&quot; Language\">HTML</abbr> and HTMLX, in <a href=\"/x\">HTML links</a> and <code>HTML</code> code.</p>"));
+        assert!(!html_content.contains("*[HTML]"));
+        assert!(html_content.contains("*[CSS]: kept"));
+        assert_eq!(scraper.title, Some("About HTML".to_string()));
+    }
+
+    #[test]
+    fn test_github_flavored_markup() {
+        let markup = MarkupConfig {
+            autolinks: true,
+            hard_breaks: true,
+            ..Default::default()
+        };
+        let md = "~~old~~ see https://example.com/a_(b)), or www.example.com.\nnext `https://no.link`\n\n> [!NOTE]\n> Heads up";
+        let (html_content, _scraper) = parse_markdown_with(md, &markup);
+        assert!(html_content.contains("<del>old</del>"));
+        assert!(html_content.contains("<a href=\"https://example.com/a_(b)\">https://example.com/a_(b)</a>), or "));
+        assert!(html_content.contains("<a href=\"http://www.example.com\">www.example.com</a>.<br />"));
+        assert!(html_content.contains("<code>https://no.link</code>"));
+        assert!(html_content.contains("<div class=\"admonition note\">\n<p class=\"admonition-title\">Note</p>\n<p>Heads up</p>\n</div>"));
+
+        let (html_content, _scraper) = parse_markdown(md);
+        assert!(!html_content.contains("<a href"));
+        assert!(!html_content.contains("<br />"));
+    }
+
     #[test]
     fn test_metadata_with_multiple_lines() {
         let md = 
@@ -343,4 +1245,221 @@ og:
   - type: website
 ---";
     }
+
+    #[test]
+    fn test_metadata_lists() {
+        let md =
+"---
+title: Tagged
+tags:
+  - rust
+  - web
+draft: false
+weight: 3
+---";
+        let (_html_content, scraper) = parse_markdown(md);
+        assert_eq!(scraper.metadata.get("title"), Some(&String::from("Tagged")));
+        assert_eq!(scraper.metadata.get("draft"), Some(&String::from("false")));
+        assert!(!scraper.is_draft());
+        assert_eq!(scraper.metadata.get("weight"), Some(&String::from("3")));
+        assert_eq!(scraper.metadata_lists.get("tags"), Some(&vec![String::from("rust"), String::from("web")]));
+    }
+
+    #[test]
+    fn test_summary() {
+        let md = "# Title\n\n[TOC]\n\nThe *first* paragraph, with `code`\nand [[Some Page|a wikilink]]. ![A picture](a.png)\n\nThe second paragraph.";
+        let (_html_content, scraper) = parse_markdown(md);
+        assert_eq!(scraper.first_paragraph.as_deref(), Some("The first paragraph, with code and a wikilink."));
+        assert_eq!(scraper.summary(21).as_deref(), Some("The first paragraph…"));
+
+        let (_html_content, scraper) = parse_markdown("---\ndescription: Described\n---\nText");
+        assert_eq!(scraper.summary(20).as_deref(), Some("Described"));
+    }
+
+    #[test]
+    fn test_board() {
+        let md = "---\ntemplate: board.html\n---\n# Project\n\n- [ ] Not a card\n\n## To do\n\n- [ ] Write the `parser`\n  - [ ] subtask\n- plain item\n\n## Done\n\n### Last week\n\n- [x] Ship it\n";
+        let (_html_content, scraper) = parse_markdown(md);
+        let columns: Vec<&str> = scraper.board.iter().map(|column| column.name.as_str()).collect();
+        assert_eq!(columns, vec!["To do", "Done"]);
+        assert_eq!(scraper.board[0].cards, vec![BoardCard { text: String::from("Write the parser"), done: false }]);
+        assert_eq!(scraper.board[1].cards, vec![BoardCard { text: String::from("Ship it"), done: true }]);
+
+        let (_html_content, scraper) = parse_markdown("## To do\n\n- [ ] Not a board\n");
+        assert!(scraper.board.is_empty());
+    }
+
+    #[test]
+    fn test_publish_date() {
+        let (_html_content, scraper) = parse_markdown("---\npublish_date: 2999-01-01\n---\n# Later");
+        assert!(scraper.is_scheduled());
+        let (_html_content, scraper) = parse_markdown("---\npublish_date: 2020-01-01T09:00:00+02:00\n---\n# Earlier");
+        assert!(!scraper.is_scheduled());
+        let (_html_content, scraper) = parse_markdown("---\ndraft: true\n---\n# Unfinished");
+        assert!(scraper.is_draft() && !scraper.is_scheduled());
+    }
+
+    #[test]
+    fn test_broken_anchors() {
+        let md = "# Setup\n\nSee [setup](#setup), [usage](#usage) and [the top](#top). [Again](#usage)";
+        let (_html_content, scraper) = parse_markdown(md);
+        assert_eq!(scraper.anchor_links.len(), 4);
+        assert_eq!(scraper.broken_anchors(), vec![String::from("usage")]);
+    }
+
+    #[test]
+    fn test_process_markdown_image_sizes() {
+        // the rewrite has to step over the tag by characters, not bytes
+        let (html, scraper) = crate::render::process_markdown("<img src=\"café.png\" alt=\"x\">\n\n## Title\n");
+        assert!(html.starts_with("<img src=\"café.png\" width=\"1\" height = \"1\" style=\"aspect-ratio: 1 / 1\" alt=\"x\">"));
+        assert!(html.contains("<h2 id=\"title\">"));
+        assert_eq!(scraper.internal_links.len(), 2);
+    }
+
+    #[test]
+    fn test_footnotes() {
+        let md = "# Notes\n\nOne[^a], two[^Big Note] and one again[^a].\n\n[^a]: First.\n[^Big Note]: Second.\n\nSee [the first](#fn-a) and [its return](#fnref-a-2).";
+        let (html, scraper) = parse_markdown(md);
+        assert!(html.contains("<sup class=\"footnote-reference\" id=\"fnref-a\"><a href=\"#fn-a\">1</a></sup>"));
+        assert!(html.contains("<sup class=\"footnote-reference\" id=\"fnref-a-2\"><a href=\"#fn-a\">1</a></sup>"));
+        assert!(html.contains("id=\"fn-big-note\"><sup class=\"footnote-definition-label\">2</sup>"));
+        assert!(html.contains("<a class=\"footnote-backref\" href=\"#fnref-big-note\""));
+        let anchors: Vec<&str> = scraper.footnotes.iter().map(|footnote| footnote.anchor.as_str()).collect();
+        assert_eq!(anchors, vec!["fn-a", "fn-big-note"]);
+        assert!(scraper.broken_anchors().is_empty());
+        assert_eq!(scraper.internal_links.len(), 1);
+    }
+
+    #[test]
+    fn test_tasklists() {
+        let (html, scraper) = parse_markdown("- [ ] Write it\n- [x] Plan it\n");
+        assert!(html.contains("<li><input disabled=\"\" type=\"checkbox\"/>\nWrite it</li>"));
+        assert!(html.contains("<li><input disabled=\"\" type=\"checkbox\" checked=\"\"/>\nPlan it</li>"));
+        assert!(scraper.has_tasklists);
+        let (_html, scraper) = parse_markdown("- [link](a.md)\n");
+        assert!(!scraper.has_tasklists);
+    }
+
+    #[test]
+    fn test_math() {
+        let (html, scraper) = parse_markdown("Euler: $e^{i\\pi} + 1 = 0$\n\n$$\n\\int_0^1 x\\,dx\n$$\n");
+        assert!(html.contains("<span class=\"math math-inline\">e^{i\\pi} + 1 = 0</span>"));
+        assert!(html.contains("<span class=\"math math-display\">"));
+        assert!(scraper.has_math);
+        let (_html, scraper) = parse_markdown("It costs $5 or $ 10.\n\n`$x$` is code.\n");
+        assert!(!scraper.has_math);
+    }
+
+    #[test]
+    fn test_wikilinks() {
+        let (html, _scraper) = parse_markdown("See [[Page Name]], [[notes/Other#Some Heading|the other one]] and [[#Top]]. ![[photo.png]] [[]]\n");
+        assert!(html.contains("<a data-wikilink=\"Page Name\" data-anchor=\"\">Page Name</a>"));
+        assert!(html.contains("<a data-wikilink=\"notes/Other\" data-anchor=\"some-heading\">the other one</a>"));
+        assert!(html.contains("![[photo.png]] [[]]"));
+        let resolved = crate::wikilinks::resolve(html.as_str(), std::path::Path::new("index.md"), None);
+        assert!(resolved.contains("<span class=\"wikilink missing\">Page Name</span>"));
+        assert!(resolved.contains("<a class=\"wikilink\" href=\"#top\">#Top</a>"));
+    }
+
+    #[test]
+    fn test_outline() {
+        let (_html, scraper) = parse_markdown("## Intro\n\ntext\n\n#### Details\n\n## More\n");
+        assert_eq!(scraper.outline_warnings, vec![
+            "The document has no level 1 heading",
+            "\"Details\" skips from a level 2 heading to level 4",
+        ]);
+        let (_html, scraper) = parse_markdown("# Title\n\n## Part\n\n### Section\n");
+        assert!(scraper.outline_warnings.is_empty());
+        let (_html, scraper) = parse_markdown("Just text\n\n# One\n\n# Two\n\n##\n");
+        assert_eq!(scraper.outline_warnings, vec![
+            "The document has 2 level 1 headings, where one is expected",
+            "A level 2 heading has no text",
+        ]);
+        let (_html, scraper) = parse_markdown("Just text\n");
+        assert_eq!(scraper.outline_warnings, vec![
+            "The document has no title, in its front matter or a heading",
+            "The document has no level 1 heading",
+        ]);
+    }
+
+    #[test]
+    fn test_inline_toc() {
+        let md = "# Guide\n\n[TOC]\n\n## Setup\n\n### Linux & Mac\n\n## Usage\n\n`[TOC]` stays.\n";
+        let (html, _scraper) = crate::render::process_markdown(md);
+        assert!(html.contains("</h1>\n<div class=\"toc\">\n<ul>\n<li><a href=\"#setup\">Setup</a>\n<ul>\n<li><a href=\"#linux-mac\">Linux &amp; Mac</a></li>\n</ul>\n</li>\n<li><a href=\"#usage\">Usage</a></li>\n</ul>\n</div>\n"));
+        assert!(html.contains("<code>[TOC]</code> stays."));
+    }
+
+    #[test]
+    fn test_document_links() {
+        let md = "[a](../up.md) [b](<notes/My Note.md#part>) [c](/guide/z.md?x=1) [d](https://e.com/x.md) [e](/home/y.md) [f](notes/../f%20g.md) [h](pic.png)\n";
+        let (html, _scraper) = crate::render::process_markdown(md);
+        assert_eq!(html, "<p><a href=\"../up.md\">a</a> <a href=\"/home/notes/My%20Note.md#part\">b</a> <a href=\"/home/guide/z.md?x=1\">c</a> <a href=\"https://e.com/x.md\">d</a> <a href=\"/home/y.md\">e</a> <a href=\"/home/f%20g.md\">f</a> <a href=\"pic.png\">h</a></p>\n");
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let md = "```mermaid\ngraph TD\n  A --> B\n```\n";
+        let (html, scraper) = crate::render::process_markdown(md);
+        assert_eq!(html, "<pre class=\"mermaid\">graph TD\n  A --&gt; B\n</pre>\n");
+        assert!(scraper.has_mermaid);
+        assert!(!scraper.has_code_blocks);
+        let (_html, scraper) = parse_markdown("```rust\nfn main() {}\n```\n");
+        assert!(!scraper.has_mermaid);
+        assert!(scraper.has_code_blocks);
+    }
+
+    #[test]
+    fn test_graphs() {
+        let (html, scraper) = parse_markdown("```dot\ndigraph { a -> b }\n```\n");
+        assert_eq!(html, "<pre><code class=\"language-dot\">digraph { a -&gt; b }\n</code></pre>\n");
+        assert!(scraper.has_graphs);
+        assert!(!scraper.has_code_blocks);
+    }
+
+    #[test]
+    fn test_charts() {
+        let md = "```chart\ntype: line\ntitle: Visits & views\nlabels: [Mon, Tue]\nseries:\n  - name: Visits\n    values: [3, 7]\n```\n";
+        let (html, scraper) = parse_markdown(md);
+        assert!(html.starts_with("<figure class=\"chart\">\n<svg viewBox=\"0 0 600 300\" role=\"img\" aria-label=\"Visits &amp; views\">"));
+        assert!(html.contains("<polyline class=\"chart-series chart-series-1\""));
+        assert!(html.contains("<title>Visits, Tue: 7</title>"));
+        assert!(html.contains("<ul class=\"chart-legend\"><li class=\"chart-series-1\">Visits</li></ul>"));
+        assert!(!scraper.has_code_blocks);
+        let (html, _scraper) = parse_markdown("```chart\n{\"values\": [1, 2.5, -1]}\n```\n");
+        assert_eq!(html.matches("<rect ").count(), 3);
+        let (html, scraper) = parse_markdown("```chart\nvalues: [one, two]\n```\n");
+        assert!(html.starts_with("<pre><code class=\"language-chart\">"));
+        assert!(scraper.has_code_blocks);
+    }
+
+    #[test]
+    fn test_callouts() {
+        let md = "> [!WARNING]\n> Hot\n\n> [!tip] Try & see\n> Body text\n\n> [!faq]-\n\n> Just a quote\n\n> > [!info]\n> > Nested\n";
+        let (html, scraper) = parse_markdown(md);
+        assert!(html.contains("<div class=\"admonition warning\">\n<p class=\"admonition-title\">Warning</p>\n<p>Hot</p>\n</div>"));
+        assert!(html.contains("<div class=\"admonition tip\">\n<p class=\"admonition-title\">Try &amp; see</p>\n<p>Body text</p>\n</div>"));
+        assert!(html.contains("<div class=\"admonition faq\">\n<p class=\"admonition-title\">Faq</p>\n</div>"));
+        assert!(html.contains("<blockquote>\n<p>Just a quote</p>\n</blockquote>"));
+        assert!(html.contains("<blockquote>\n<div class=\"admonition info\">\n<p class=\"admonition-title\">Info</p>\n<p>Nested</p>\n</div>\n</blockquote>"));
+        assert_eq!(scraper.callout_types, vec!["warning", "tip", "faq", "info"]);
+    }
+
+    #[test]
+    fn test_figures() {
+        let md = "![Cat](cat.png \"A \\\"fat\\\" cat\")\n\nText ![Dog](dog.png \"Inline\")\n\n![Bird](bird.png)\n";
+        let (html, _scraper) = crate::render::process_markdown(md);
+        assert!(html.contains("<figure><img src=\"cat.png\" width=\"1\" height = \"1\" style=\"aspect-ratio: 1 / 1\" alt=\"Cat\" /><figcaption>A &quot;fat&quot; cat</figcaption></figure>"));
+        assert!(html.contains("<p>Text <img src=\"dog.png\""));
+        assert!(html.contains("<p><img src=\"bird.png\""));
+    }
+
+    #[test]
+    fn test_image_sources() {
+        let md = "![Cat](<pics/my cat.png>)\n\nInline <img src=\"dog.png\"> too";
+        let (html, scraper) = crate::render::process_markdown(md);
+        assert_eq!(scraper.images, vec![String::from("pics/my%20cat.png"), String::from("dog.png")]);
+        assert!(html.contains("<img src=\"pics/my%20cat.png\" width=\"1\" height = \"1\" style=\"aspect-ratio: 1 / 1\" alt=\"Cat\""));
+        assert!(html.contains("<img src=\"dog.png\" width=\"1\" height = \"1\" style=\"aspect-ratio: 1 / 1\">"));
+    }
 }
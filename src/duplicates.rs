@@ -0,0 +1,111 @@
+use std::{collections::{HashMap, HashSet}, hash::{DefaultHasher, Hash, Hasher}};
+use serde::Serialize;
+
+use crate::page_catalog::PageCatalog;
+
+// Runs of this many words are what documents are compared by
+const SHINGLE_WORDS: usize = 5;
+// Documents shorter than this many shingles are too short to tell apart
+const MIN_SHINGLES: usize = 20;
+// Hashes kept per document. More are more accurate, and cost 8 bytes each
+const SIGNATURE_LEN: usize = 64;
+// Documents are only compared when a band of their signatures matches.
+// With 16 bands of 4 rows, pairs 80% alike are nearly always compared
+const BANDS: usize = 16;
+// Share of the signature two documents must have in common to be reported
+const MIN_SIMILARITY: f64 = 0.8;
+
+// A document's text, boiled down to the smallest hashes of its shingles
+// under SIGNATURE_LEN different hash functions (MinHash). The share of
+// entries two signatures agree on estimates how much text they share
+pub fn signature(text: &str) -> Option<Vec<u64>> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let shingles: HashSet<u64> = words.windows(SHINGLE_WORDS)
+        .map(|shingle| {
+            let mut hasher = DefaultHasher::new();
+            shingle.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect();
+    if shingles.len() < MIN_SHINGLES {
+        return None;
+    }
+    let mut signature = vec![u64::MAX; SIGNATURE_LEN];
+    for shingle in shingles {
+        for (seed, min) in signature.iter_mut().enumerate() {
+            *min = (*min).min(mix(shingle ^ (seed as u64).wrapping_mul(0x9e3779b97f4a7c15)));
+        }
+    }
+    Some(signature)
+}
+
+// splitmix64's finalizer, so each seed gives an unrelated ordering
+fn mix(value: u64) -> u64 {
+    let mut value = value;
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+#[derive(Serialize)]
+pub struct DuplicatePage {
+    pub url: String,
+    pub title: String,
+}
+
+// Two documents that are mostly the same text, eg: a copy left behind
+#[derive(Serialize)]
+pub struct NearDuplicates {
+    pub first: DuplicatePage,
+    pub second: DuplicatePage,
+    // Estimated share of text in common, as a percentage
+    pub similarity: u32,
+}
+
+// Most alike first
+pub fn find(catalog: &PageCatalog) -> Vec<NearDuplicates> {
+    let pages: Vec<_> = catalog.pages().into_iter()
+        .filter(|page| page.signature.is_some())
+        .collect();
+    let rows = SIGNATURE_LEN / BANDS;
+    let mut candidates = HashSet::new();
+    for band in 0..BANDS {
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (index, page) in pages.iter().enumerate() {
+            if let Some(signature) = &page.signature {
+                buckets.entry(&signature[band * rows..(band + 1) * rows]).or_default().push(index);
+            }
+        }
+        for bucket in buckets.values().filter(|bucket| bucket.len() > 1) {
+            for (i, first) in bucket.iter().enumerate() {
+                for second in &bucket[i + 1..] {
+                    candidates.insert((*first, *second));
+                }
+            }
+        }
+    }
+    let mut duplicates: Vec<NearDuplicates> = candidates.into_iter().filter_map(|(first, second)| {
+        let (first, second) = (&pages[first], &pages[second]);
+        let (Some(a), Some(b)) = (&first.signature, &second.signature) else {
+            return None;
+        };
+        let similarity = a.iter().zip(b.iter()).filter(|(a, b)| a == b).count() as f64 / SIGNATURE_LEN as f64;
+        (similarity >= MIN_SIMILARITY).then(|| NearDuplicates {
+            first: DuplicatePage {
+                url: first.url.clone(),
+                title: first.title.clone(),
+            },
+            second: DuplicatePage {
+                url: second.url.clone(),
+                title: second.title.clone(),
+            },
+            similarity: (similarity * 100.0).round() as u32,
+        })
+    }).collect();
+    duplicates.sort_by(|a, b| b.similarity.cmp(&a.similarity).then_with(|| a.first.url.cmp(&b.first.url)));
+    duplicates
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use time::{Date, Duration};
+
+use crate::toml_config::ExpiryConfig;
+
+// Template variable for pages past their expiry or review date
+#[derive(Serialize)]
+pub struct Outdated {
+    pub since: String,
+    // "expired" or "review"
+    pub reason: &'static str,
+}
+
+#[derive(Clone, Copy)]
+pub struct Expiry {
+    review_days: u32,
+    search_penalty: Option<f32>,
+}
+
+impl Expiry {
+    pub fn new(config: &ExpiryConfig) -> Self {
+        Expiry {
+            review_days: config.review_days,
+            search_penalty: config.search_penalty,
+        }
+    }
+
+    // The date a document goes out of date: its `expires:` date, or
+    // review_days after it was last `reviewed:`, whichever comes first
+    pub fn outdated_on(&self, expires: Option<&str>, reviewed: Option<&str>) -> Option<(Date, &'static str)> {
+        let expired = expires.and_then(parse_date).map(|date| (date, "expired"));
+        let review = reviewed.and_then(parse_date)
+            .and_then(|date| date.checked_add(Duration::days(self.review_days as i64)))
+            .map(|date| (date, "review"));
+        match (expired, review) {
+            (Some(expired), Some(review)) => Some(if expired.0 <= review.0 { expired } else { review }),
+            (expired, review) => expired.or(review),
+        }
+    }
+
+    pub fn outdated(&self, metadata: &HashMap<String, String>) -> Option<Outdated> {
+        let (date, reason) = self.outdated_on(
+            metadata.get("expires").map(String::as_str),
+            metadata.get("reviewed").map(String::as_str),
+        )?;
+        if date > today() {
+            return None;
+        }
+        Some(Outdated {
+            since: date.to_string(),
+            reason,
+        })
+    }
+
+    pub fn is_outdated(&self, expires: Option<&str>, reviewed: Option<&str>) -> bool {
+        self.outdated_on(expires, reviewed).is_some_and(|(date, _reason)| date <= today())
+    }
+
+    // Multiplier applied to the search score of outdated documents
+    pub fn search_penalty(&self) -> Option<f32> {
+        self.search_penalty
+    }
+}
+
+// Whether the page's banner depends on today's date
+pub fn is_dated(metadata: &HashMap<String, String>) -> bool {
+    metadata.contains_key("expires") || metadata.contains_key("reviewed")
+}
+
+fn today() -> Date {
+    time::OffsetDateTime::now_utc().date()
+}
+
+// Accepts YYYY-MM-DD, ignoring any time that follows
+pub fn parse_date(date: &str) -> Option<Date> {
+    let format = time::format_description::parse("[year]-[month]-[day]").ok()?;
+    Date::parse(date.trim().get(..10)?, &format).ok()
+}
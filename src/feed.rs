@@ -0,0 +1,140 @@
+use std::fmt::Write;
+use axum::{extract::State, http::{header, HeaderMap}, response::{IntoResponse, Response}};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::expiry::parse_date;
+use crate::page_catalog::{PageCatalog, PageSummary};
+use crate::toml_config::FeedConfig;
+use crate::AppStateType;
+
+pub const FEED_URL: &str = "/feed.xml";
+
+// An Atom feed of the newest documents, by front matter `date:` or else
+// when they last changed
+#[derive(Clone)]
+pub struct Feed {
+    title: String,
+    limit: usize,
+    // Relative to the document root, eg: "blog"
+    folder: Option<String>,
+    // Feed entries need absolute URLs. Without a [social] site_url they're
+    // made from the Host header
+    site_url: Option<String>,
+    page_catalog: PageCatalog,
+}
+
+impl Feed {
+    pub fn new(config: &FeedConfig, site_title: &str, site_url: Option<&str>, page_catalog: PageCatalog) -> Self {
+        Feed {
+            title: config.title.clone().unwrap_or_else(|| site_title.to_string()),
+            limit: config.limit,
+            folder: config.folder.as_ref()
+                .map(|folder| folder.trim_matches('/').to_string())
+                .filter(|folder| !folder.is_empty()),
+            site_url: site_url.map(|url| url.trim_end_matches('/').to_string()),
+            page_catalog,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        self.title.as_str()
+    }
+
+    // Newest first, with when each was published
+    pub fn newest(&self) -> Vec<(OffsetDateTime, PageSummary)> {
+        let mut pages: Vec<(OffsetDateTime, PageSummary)> = self.page_catalog.pages().into_iter()
+            .filter(|page| self.folder.as_ref().is_none_or(|folder| page.path.starts_with(format!("{folder}/").as_str())))
+            .map(|page| (page_time(&page), page))
+            .collect();
+        pages.sort_by_key(|(time, _page)| std::cmp::Reverse(*time));
+        pages.truncate(self.limit);
+        pages
+    }
+
+    pub fn xml(&self, headers: &HeaderMap) -> String {
+        let site_url = escape(site_url(self.site_url.as_deref(), headers).as_str());
+        let pages = self.newest();
+        let updated = pages.iter()
+            .map(|(_time, page)| OffsetDateTime::from(page.modtime))
+            .max()
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+        let mut xml = String::with_capacity(1024 + pages.len() * 512);
+        xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        let _ = writeln!(xml, "  <title>{}</title>", escape(self.title.as_str()));
+        let _ = writeln!(xml, "  <link rel=\"self\" href=\"{site_url}{FEED_URL}\"/>");
+        let _ = writeln!(xml, "  <link href=\"{site_url}/\"/>");
+        let _ = writeln!(xml, "  <id>{site_url}/</id>");
+        let _ = writeln!(xml, "  <updated>{}</updated>", rfc3339(updated));
+        let _ = writeln!(xml, "  <author><name>{}</name></author>", escape(self.title.as_str()));
+        xml.push_str("  <generator>Chimera-md</generator>\n");
+        for (published, page) in &pages {
+            let url = format!("{site_url}{}", escape(page.url.as_str()));
+            xml.push_str("  <entry>\n");
+            let _ = writeln!(xml, "    <title>{}</title>", escape(page.title.as_str()));
+            let _ = writeln!(xml, "    <link href=\"{url}\"/>");
+            let _ = writeln!(xml, "    <id>{url}</id>");
+            let _ = writeln!(xml, "    <published>{}</published>", rfc3339(*published));
+            let _ = writeln!(xml, "    <updated>{}</updated>", rfc3339(OffsetDateTime::from(page.modtime)));
+            if let Some(author) = page.metadata.get("author") {
+                let _ = writeln!(xml, "    <author><name>{}</name></author>", escape(author.as_str()));
+            }
+            for tag in &page.tags {
+                let _ = writeln!(xml, "    <category term=\"{}\"/>", escape(tag.as_str()));
+            }
+            if !page.excerpt.is_empty() {
+                let _ = writeln!(xml, "    <summary>{}</summary>", escape(page.excerpt.as_str()));
+            }
+            xml.push_str("  </entry>\n");
+        }
+        xml.push_str("</feed>\n");
+        xml
+    }
+}
+
+// The configured site_url, or else the one the request was made to
+pub fn site_url(configured: Option<&str>, headers: &HeaderMap) -> String {
+    if let Some(site_url) = configured {
+        return site_url.trim_end_matches('/').to_string();
+    }
+    let host = headers.get("x-forwarded-host")
+        .or_else(|| headers.get(header::HOST))
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    let scheme = headers.get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .unwrap_or("http");
+    format!("{scheme}://{host}")
+}
+
+// Front matter `date:`, as a date or an RFC 3339 time, or the file's time
+fn page_time(page: &PageSummary) -> OffsetDateTime {
+    page.metadata.get("date")
+        .map(|date| date.trim())
+        .and_then(|date| {
+            OffsetDateTime::parse(date, &Rfc3339).ok()
+                .or_else(|| parse_date(date).map(|date| date.midnight().assume_utc()))
+        })
+        .unwrap_or_else(|| OffsetDateTime::from(page.modtime))
+}
+
+pub fn rfc3339(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub async fn handle_feed(
+    State(app_state): State<AppStateType>,
+    headers: HeaderMap,
+) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/atom+xml; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        app_state.feed.xml(&headers),
+    ).into_response()
+}
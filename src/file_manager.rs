@@ -1,15 +1,70 @@
-use std::{borrow::Borrow, collections::HashSet, ffi::{OsStr, OsString}, path::{Path, PathBuf}, time::Duration};
+use std::{borrow::Borrow, collections::{HashMap, HashSet}, ffi::{OsStr, OsString}, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
 use async_watcher::{notify::{EventKind, RecommendedWatcher, RecursiveMode}, AsyncDebouncer, DebouncedEvent};
 use serde::Serialize;
 
-use crate::{chimera_error::ChimeraError, document_scraper::ExternalLink};
+use crate::{chimera_error::ChimeraError, document_scraper::{parse_markdown, ExternalLink}};
+use crate::image_metadata::{self, ImageMetadata};
+use crate::publishing::Publisher;
+use crate::wikilinks::DocumentNames;
 
 type NotifyError = async_watcher::notify::Error;
 
-#[derive(Default, Debug, Serialize)]
+const ORDER_FILE: &str = ".order";
+
+#[derive(Clone, Default, Debug, Serialize)]
 pub struct PeerInfo {
     pub folders: Vec<ExternalLink>,
     pub files: Vec<ExternalLink>,
+    // Photos in the folder, oldest first, when [gallery] is on
+    pub images: Vec<ImageLink>,
+    // Metadata of the folder being listed
+    pub folder: Option<FolderMetadata>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageLink {
+    pub url: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub metadata: ImageMetadata,
+}
+
+// Settings a folder's index document can declare in its front matter
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FolderMetadata {
+    pub description: Option<String>,
+    pub icon: Option<String>,
+    // "name" (the default) or "modified", newest first
+    pub sort: Option<String>,
+    pub listing_template: Option<String>,
+    // Position of the folder in its parent's listing
+    pub weight: Option<i64>,
+    // References for documents in the folder to cite, relative to it
+    pub bibliography: Option<String>,
+}
+
+#[derive(Clone, Default)]
+struct FolderInfo {
+    metadata: Option<FolderMetadata>,
+    // Names listed in the folder's .order file
+    order: Vec<String>,
+    // Front matter weights of the documents in the folder, by file name
+    weights: HashMap<OsString, i64>,
+    // Documents left out of the listing: drafts, and ones scheduled for later
+    hidden: HashSet<OsString>,
+    // Of the documents, by file name, see DocumentScraper::description
+    descriptions: HashMap<OsString, String>,
+}
+
+// Folder metadata and listings, read on demand. A change only drops the
+// entries of the folders it can show up in, see invalidate
+#[derive(Clone)]
+pub struct FolderCache {
+    lock: Arc<RwLock<HashMap<PathBuf, FolderInfo>>>,
+    // Full folder listings, so peer lookups don't walk the file system
+    listings: Arc<RwLock<HashMap<PathBuf, PeerInfo>>>,
+    document_root: PathBuf,
+    index_file: String,
 }
 
 pub struct FileManager {
@@ -17,6 +72,10 @@ pub struct FileManager {
     debouncer: AsyncDebouncer<RecommendedWatcher>,
     document_root: PathBuf,
     index_file: String,
+    folder_cache: FolderCache,
+    document_names: DocumentNames,
+    publisher: Publisher,
+    list_images: bool,
 }
 
 impl FileManager {
@@ -26,29 +85,46 @@ impl FileManager {
             AsyncDebouncer::new_with_channel(Duration::from_secs(1), Some(Duration::from_secs(1))).await?;
         tokio::spawn(directory_watcher(broadcast_tx.clone(), file_events));
 
+        let folder_cache = FolderCache {
+            lock: Arc::new(RwLock::new(HashMap::new())),
+            listings: Arc::new(RwLock::new(HashMap::new())),
+            document_root: document_root.to_path_buf(),
+            index_file: index_file.to_string(),
+        };
+        tokio::spawn(listen_for_folder_changes(broadcast_tx.subscribe(), folder_cache.clone()));
+        let document_names = DocumentNames::new(document_root);
+        tokio::spawn(listen_for_document_changes(broadcast_tx.subscribe(), document_names.clone()));
+
+        let publisher = Publisher::new(broadcast_tx.clone());
+
         let file_manager = FileManager{
             broadcast_tx,
             debouncer,
             document_root: document_root.to_path_buf(),
             index_file: index_file.to_string(),
+            folder_cache,
+            document_names,
+            publisher,
+            list_images: false,
         };
         Ok(file_manager)
     }
 
+    // Folder listings also get the folder's images, with their EXIF dates
+    // and captions
+    pub fn list_images(&mut self) {
+        self.list_images = true;
+    }
+
     pub fn get_markdown_files(&self) -> Vec<PathBuf> {
-        let mut files = Vec::new();
-        for entry in walkdir::WalkDir::new(self.document_root.as_path()).into_iter().flatten() {
-            let p = entry.path();
-            if entry.file_type().is_file() {
-                let fname = entry.file_name().to_string_lossy();
-                if let Some((_stem, ext)) = fname.rsplit_once('.') {
-                    if ext.eq_ignore_ascii_case("md") {
-                        files.push(p.to_owned());
-                    }
-                }
-            }
-        }
-        files
+        find_markdown_files(self.document_root.as_path())
+    }
+
+    pub fn get_image_files(&self) -> Vec<PathBuf> {
+        walkdir::WalkDir::new(self.document_root.as_path()).into_iter().flatten()
+            .filter(|entry| entry.file_type().is_file() && image_metadata::is_image(entry.path()))
+            .map(|entry| entry.into_path())
+            .collect()
     }
 
     pub fn find_files(&self, abs_path: &Path, ext: &OsStr) -> Vec<walkdir::DirEntry> {
@@ -63,6 +139,19 @@ impl FileManager {
     }
 
     pub fn find_peers_in_folder(&self, abs_path: &Path, skip: Option<&OsStr>) -> Option<PeerInfo> {
+        let mut peers = self.folder_cache.listing(abs_path, || self.list_folder(abs_path))?;
+        if let Some(skip) = skip {
+            let skip_url = urlencoding::encode(skip.to_string_lossy().borrow()).into_owned();
+            peers.files.retain(|link| link.url != skip_url);
+            if peers.files.is_empty() && peers.folders.is_empty() && peers.images.is_empty() {
+                return None;
+            }
+        }
+        Some(peers)
+    }
+
+    fn list_folder(&self, abs_path: &Path) -> Option<PeerInfo> {
+        let info = self.folder_cache.info(abs_path);
         let mut folder_set = HashSet::new();
         let mut files = Vec::new();
         let md_ext = OsString::from("md");
@@ -72,37 +161,68 @@ impl FileManager {
             let fname_str = fname.to_string_lossy();
             let direct_child = parent.as_os_str().len() == abs_path.as_os_str().len();
             if direct_child {
-                if let Some(skip) = skip {
-                    if fname.eq(skip) {
-                        continue;
-                    }
+                if info.hidden.contains(fname) {
+                    continue;
                 }
                 if let Some(stem) = entry.path().file_stem() {
-                    files.push(ExternalLink::new(
+                    let modtime = entry.metadata().ok().and_then(|m| m.modified().ok());
+                    let mut link = ExternalLink::new(
                         urlencoding::encode(fname_str.borrow()).into_owned(), 
-                        stem.to_string_lossy().to_string())
-                    );
+                        stem.to_string_lossy().to_string());
+                    link.description = info.descriptions.get(fname).cloned();
+                    files.push(SortableLink {
+                        link,
+                        file_name: fname.to_os_string(),
+                        modtime,
+                    });
                 }
             }
             else if let Ok(parent) = parent.strip_prefix(abs_path) {
                 folder_set.insert(parent.to_owned());
             }
         }
-        if files.is_empty() && folder_set.is_empty() {
+        let images = match self.list_images {
+            true => list_images(abs_path),
+            false => Vec::new(),
+        };
+        if files.is_empty() && folder_set.is_empty() && images.is_empty() {
             return None;
         }
-        let folders:Vec<ExternalLink> = folder_set.into_iter().map(|folder| {
-            ExternalLink::new(
+        let mut folders: Vec<(ExternalLink, Option<i64>)> = folder_set.into_iter().map(|folder| {
+            let mut link = ExternalLink::new(
                 format!("{}/", urlencoding::encode(folder.to_string_lossy().borrow())), 
                 folder.to_string_lossy().into_owned()
-            )
+            );
+            let mut weight = None;
+            if let Some(metadata) = self.folder_cache.get(abs_path.join(folder).as_path()) {
+                link.description = metadata.description;
+                link.icon = metadata.icon;
+                weight = metadata.weight;
+            }
+            (link, weight)
         }).collect();
-        let mut peers = PeerInfo {
-            files,
-            folders
-        };
-        peers.sort();
-        Some(peers)
+
+        // .order entries first, then weighted items, then the folder's sort order
+        let by_modtime = info.metadata.as_ref().is_some_and(|folder| folder.sort.as_deref() == Some("modified"));
+        files.sort_by(|a, b| {
+            info.rank(a.file_name.as_os_str(), a.link.name.as_str())
+                .cmp(&info.rank(b.file_name.as_os_str(), b.link.name.as_str()))
+                .then_with(|| match by_modtime {
+                    true => b.modtime.cmp(&a.modtime),
+                    false => a.link.name.cmp(&b.link.name),
+                })
+        });
+        folders.sort_by(|(a, a_weight), (b, b_weight)| {
+            info.order_rank(a.name.as_str(), a.name.as_str()).cmp(&info.order_rank(b.name.as_str(), b.name.as_str()))
+                .then_with(|| a_weight.unwrap_or(i64::MAX).cmp(&b_weight.unwrap_or(i64::MAX)))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Some(PeerInfo {
+            files: files.into_iter().map(|file| file.link).collect(),
+            folders: folders.into_iter().map(|(link, _weight)| link).collect(),
+            images,
+            folder: info.metadata,
+        })
     }
 
     pub fn find_peers(&self, relative_path: &Path) -> Option<PeerInfo> {
@@ -135,16 +255,268 @@ impl FileManager {
     pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<PathBuf> {
         self.broadcast_tx.subscribe()
     }
+
+    pub fn folder_cache(&self) -> FolderCache {
+        self.folder_cache.clone()
+    }
+
+    pub fn document_names(&self) -> DocumentNames {
+        self.document_names.clone()
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
 }
 
-impl PeerInfo {
-    fn sort(&mut self) {
-        self.files.sort_unstable_by(|a, b| {
-            a.name.cmp(&b.name)
+impl FolderCache {
+    // Takes an absolute folder path, or one relative to the document root
+    pub fn get(&self, folder: &Path) -> Option<FolderMetadata> {
+        self.info(folder).metadata
+    }
+
+    fn info(&self, folder: &Path) -> FolderInfo {
+        let folder = self.document_root.join(folder);
+        if let Ok(lock) = self.lock.read() {
+            if let Some(info) = lock.get(&folder) {
+                return info.clone();
+            }
+        }
+        let documents = read_documents(folder.as_path());
+        let info = FolderInfo {
+            metadata: self.read_metadata(folder.as_path()),
+            order: read_order_file(folder.as_path()),
+            weights: documents.weights,
+            hidden: documents.hidden,
+            descriptions: documents.descriptions,
+        };
+        if let Ok(mut lock) = self.lock.write() {
+            lock.insert(folder, info.clone());
+        }
+        info
+    }
+
+    fn read_metadata(&self, folder: &Path) -> Option<FolderMetadata> {
+        let md = std::fs::read_to_string(folder.join(self.index_file.as_str())).ok()?;
+        let (_html, mut scraper) = parse_markdown(md.as_str());
+        Some(FolderMetadata {
+            description: scraper.metadata.remove("description"),
+            icon: scraper.metadata.remove("icon"),
+            sort: scraper.metadata.remove("sort"),
+            listing_template: scraper.metadata.remove("listing_template"),
+            weight: scraper.metadata.get("weight").and_then(|weight| weight.parse().ok()),
+            bibliography: scraper.metadata.remove("bibliography"),
+        })
+    }
+
+    fn listing(&self, folder: &Path, list: impl FnOnce() -> Option<PeerInfo>) -> Option<PeerInfo> {
+        if let Ok(lock) = self.listings.read() {
+            if let Some(peers) = lock.get(folder) {
+                return Some(peers.clone());
+            }
+        }
+        let peers = list()?;
+        if let Ok(mut lock) = self.listings.write() {
+            lock.insert(folder.to_path_buf(), peers.clone());
+        }
+        Some(peers)
+    }
+
+    // A listing shows the documents of its folder and the subfolders that
+    // have any, with their index front matter, so a change reaches up to
+    // the folder's parent. A changed folder also drops everything below it
+    fn invalidate(&self, path: &Path) {
+        let mut paths = vec![path.to_path_buf()];
+        // listings are keyed by canonical path, which a deleted file no longer has
+        let canonical = path.canonicalize().ok().or_else(|| {
+            let parent = path.parent()?.canonicalize().ok()?;
+            Some(parent.join(path.file_name()?))
         });
-        self.folders.sort_unstable_by(|a, b| {
-            a.name.cmp(&b.name)
+        paths.extend(canonical.filter(|canonical| canonical != path));
+        let affects = |folder: &PathBuf| paths.iter().any(|path| {
+            folder.starts_with(path)
+                || path.strip_prefix(folder).is_ok_and(|rest| rest.components().count() <= 2)
         });
+        if let Ok(mut lock) = self.lock.write() {
+            lock.retain(|folder, _info| !affects(folder));
+        }
+        if let Ok(mut lock) = self.listings.write() {
+            lock.retain(|folder, _peers| !affects(folder));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut lock) = self.lock.write() {
+            lock.clear();
+        }
+        if let Ok(mut lock) = self.listings.write() {
+            lock.clear();
+        }
+    }
+}
+
+impl FolderInfo {
+    fn order_rank(&self, file_name: &str, stem: &str) -> usize {
+        self.order.iter()
+            .position(|name| name == file_name || name == stem)
+            .unwrap_or(usize::MAX)
+    }
+
+    fn rank(&self, file_name: &OsStr, stem: &str) -> (usize, i64) {
+        let order = self.order_rank(file_name.to_string_lossy().borrow(), stem);
+        let weight = self.weights.get(file_name).copied().unwrap_or(i64::MAX);
+        (order, weight)
+    }
+}
+
+struct SortableLink {
+    link: ExternalLink,
+    file_name: OsString,
+    modtime: Option<SystemTime>,
+}
+
+// One file or folder name per line. Blank lines and # comments are skipped
+fn read_order_file(folder: &Path) -> Vec<String> {
+    let Ok(order) = std::fs::read_to_string(folder.join(ORDER_FILE)) else {
+        return Vec::new();
+    };
+    order.lines()
+        .map(|line| line.trim().trim_end_matches('/'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// What a folder listing needs from the documents in it, by file name
+#[derive(Default)]
+struct FolderDocuments {
+    weights: HashMap<OsString, i64>,
+    hidden: HashSet<OsString>,
+    descriptions: HashMap<OsString, String>,
+}
+
+fn read_documents(folder: &Path) -> FolderDocuments {
+    let mut documents = FolderDocuments::default();
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return documents;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md")) {
+            continue;
+        }
+        let Ok(md) = std::fs::read_to_string(path.as_path()) else {
+            continue;
+        };
+        let (_html, scraper) = parse_markdown(md.as_str());
+        if let Some(weight) = scraper.metadata.get("weight").and_then(|weight| weight.parse().ok()) {
+            documents.weights.insert(entry.file_name(), weight);
+        }
+        if scraper.is_draft() || scraper.is_scheduled() {
+            documents.hidden.insert(entry.file_name());
+        }
+        if let Some(description) = scraper.description() {
+            documents.descriptions.insert(entry.file_name(), description);
+        }
+    }
+    documents
+}
+
+// The images directly inside a folder, by when they were taken, then name.
+// Ones without a date go last
+fn list_images(folder: &Path) -> Vec<ImageLink> {
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return Vec::new();
+    };
+    let mut images: Vec<ImageLink> = entries.flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()) && image_metadata::is_image(&entry.path()))
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            ImageLink {
+                url: urlencoding::encode(name.as_str()).into_owned(),
+                metadata: image_metadata::read(&entry.path()).unwrap_or_default(),
+                name,
+            }
+        })
+        .collect();
+    images.sort_by(|a, b| {
+        a.metadata.taken.is_none().cmp(&b.metadata.taken.is_none())
+            .then_with(|| a.metadata.taken.cmp(&b.metadata.taken))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    images
+}
+
+pub fn find_markdown_files(document_root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(document_root).into_iter().flatten() {
+        let p = entry.path();
+        if entry.file_type().is_file() {
+            let fname = entry.file_name().to_string_lossy();
+            if let Some((_stem, ext)) = fname.rsplit_once('.') {
+                if ext.eq_ignore_ascii_case("md") {
+                    files.push(p.to_owned());
+                }
+            }
+        }
+    }
+    files
+}
+
+// The markdown documents directly inside a folder
+pub fn markdown_files_in(folder: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return Vec::new();
+    };
+    entries.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md")))
+        .collect()
+}
+
+async fn listen_for_folder_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    folder_cache: FolderCache,
+) {
+    loop {
+        let path = match rx.recv().await {
+            Ok(path) => path,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::debug!("Folder cache missed {missed} change events, clearing it");
+                folder_cache.clear();
+                continue;
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        // any document can change its weight
+        let is_markdown = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md"));
+        let is_order = path.file_name().is_some_and(|name| name == ORDER_FILE);
+        let is_image = image_metadata::is_image(path.as_path());
+        if is_markdown || is_order || is_image || path.is_dir() || path.extension().is_none() {
+            tracing::debug!("Folder metadata invalidated by {}", path.display());
+            folder_cache.invalidate(path.as_path());
+        }
+    }
+}
+
+async fn listen_for_document_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    document_names: DocumentNames,
+) {
+    loop {
+        let path = match rx.recv().await {
+            Ok(path) => path,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_missed)) => {
+                document_names.clear();
+                continue;
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        // a renamed folder moves the documents in it
+        let is_markdown = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md"));
+        if is_markdown || path.is_dir() || path.extension().is_none() {
+            document_names.clear();
+        }
     }
 }
 
@@ -0,0 +1,419 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::io::AsyncWriteExt;
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::ChimeraError;
+
+const SNAPSHOT_FILE: &str = "ft.bin";
+const JOURNAL_FILE: &str = "ft.log";
+// What this replaced, migrated on first load
+const LEGACY_FILE: &str = "ft.toml";
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"CHFT";
+const JOURNAL_MAGIC: &[u8; 4] = b"CHFL";
+const HEADER_SIZE: usize = 12;
+
+const SET: u8 = 1;
+const REMOVE: u8 = 2;
+
+// Journals shorter than this are never worth compacting
+const MIN_COMPACT_RECORDS: usize = 1024;
+
+enum Change {
+    Set(PathBuf, SystemTime),
+    Remove(PathBuf),
+}
+
+// The modtime of each document when it was last indexed, so only changed
+// ones are indexed again on startup. Kept as a binary snapshot (ft.bin) plus
+// a journal of the changes since (ft.log), so saving a batch only appends
+// what changed. The journal is folded into a new snapshot once it outgrows
+// it. Both start with the same generation number; a journal from another
+// generation is left over from an interrupted compaction, and ignored
+pub struct FileTimes {
+    folder: PathBuf,
+    files: BTreeMap<PathBuf, SystemTime>,
+    pending: Vec<Change>,
+    generation: u64,
+    journal_records: usize,
+    needs_snapshot: bool,
+}
+
+impl FileTimes {
+    pub async fn load(search_index_dir: &Path) -> FileTimes {
+        let mut file_times = FileTimes {
+            folder: search_index_dir.to_path_buf(),
+            files: BTreeMap::new(),
+            pending: Vec::new(),
+            generation: 0,
+            journal_records: 0,
+            needs_snapshot: false,
+        };
+        match tokio::fs::read(search_index_dir.join(SNAPSHOT_FILE)).await {
+            Ok(snapshot) => file_times.read_snapshot(snapshot.as_slice()),
+            Err(_) => file_times.migrate_legacy().await,
+        }
+        if let Ok(journal) = tokio::fs::read(search_index_dir.join(JOURNAL_FILE)).await {
+            file_times.replay_journal(journal.as_slice());
+        }
+        tracing::debug!("Loaded modtimes of {} indexed documents", file_times.files.len());
+        file_times
+    }
+
+    fn read_snapshot(&mut self, snapshot: &[u8]) {
+        let Some(generation) = read_header(snapshot, SNAPSHOT_MAGIC) else {
+            tracing::warn!("{SNAPSHOT_FILE} is damaged, reindexing all documents");
+            self.needs_snapshot = true;
+            return;
+        };
+        self.generation = generation;
+        let (complete, _records) = apply_records(&mut self.files, &snapshot[HEADER_SIZE..]);
+        if !complete {
+            tracing::warn!("{SNAPSHOT_FILE} is truncated, reindexing the documents it lost");
+            self.needs_snapshot = true;
+        }
+    }
+
+    fn replay_journal(&mut self, journal: &[u8]) {
+        if read_header(journal, JOURNAL_MAGIC) != Some(self.generation) {
+            // the snapshot already has these changes, or the journal is damaged
+            self.needs_snapshot = true;
+            return;
+        }
+        let (complete, records) = apply_records(&mut self.files, &journal[HEADER_SIZE..]);
+        self.journal_records = records;
+        if !complete {
+            // cut off mid-record by a crash. Anything appended after it
+            // couldn't be read back, so start over from a snapshot
+            tracing::warn!("{JOURNAL_FILE} ends in a partial record, dropping it");
+            self.needs_snapshot = true;
+        }
+    }
+
+    async fn migrate_legacy(&mut self) {
+        let legacy = self.folder.join(LEGACY_FILE);
+        let Ok(toml) = tokio::fs::read_to_string(legacy.as_path()).await else {
+            return;
+        };
+        match toml::from_str::<BTreeMap<PathBuf, SystemTime>>(toml.as_str()) {
+            Ok(files) => {
+                tracing::info!("Migrating {} document modtimes from {LEGACY_FILE} to {SNAPSHOT_FILE}", files.len());
+                self.files = files;
+            },
+            Err(e) => tracing::warn!("Couldn't migrate {}, reindexing all documents: {e}", legacy.display()),
+        }
+        // the old file goes once the snapshot is written, see save
+        self.needs_snapshot = true;
+    }
+
+    // Whether the document is unchanged since it was indexed. If not, it's
+    // recorded at its new modtime, or forgotten if it's gone
+    pub fn check_up_to_date(&mut self, path: &Path, current_modtime: Option<SystemTime>) -> bool {
+        let Some(current_modtime) = current_modtime else {
+            tracing::debug!("No such file, forgetting it: {}", path.display());
+            self.remove(path);
+            return false;
+        };
+        if self.files.get(path) == Some(&current_modtime) {
+            tracing::debug!("Up-to-date: {}", path.display());
+            return true;
+        }
+        tracing::debug!("Recording modtime of {}", path.display());
+        self.files.insert(path.to_path_buf(), current_modtime);
+        self.pending.push(Change::Set(path.to_path_buf(), current_modtime));
+        false
+    }
+
+    // So the document is indexed on its next change event, whether or not
+    // the file changed
+    pub fn forget(&mut self, path: &Path) {
+        self.remove(path);
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if self.files.remove(path).is_some() {
+            self.pending.push(Change::Remove(path.to_path_buf()));
+        }
+    }
+
+    // Forgets documents that no longer exist, and returns them
+    pub fn remove_missing(&mut self) -> Vec<PathBuf> {
+        let deleted: Vec<PathBuf> = self.files.keys().filter(|path| !path.exists()).cloned().collect();
+        for path in deleted.iter() {
+            self.remove(path.as_path());
+        }
+        deleted
+    }
+
+    // Forgets everything, so every document is indexed again
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.pending.clear();
+        self.needs_snapshot = true;
+    }
+
+    pub async fn save(&mut self) -> Result<(), ChimeraError> {
+        if self.needs_snapshot || self.journal_records + self.pending.len() > self.files.len().max(MIN_COMPACT_RECORDS) {
+            return self.write_snapshot().await;
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let journal = self.folder.join(JOURNAL_FILE);
+        let mut records = Vec::new();
+        if self.journal_records == 0 {
+            // a fresh journal, maybe replacing one from an old generation
+            records.extend_from_slice(header(JOURNAL_MAGIC, self.generation).as_slice());
+        }
+        let mut written = 0;
+        for change in self.pending.iter() {
+            let was_written = match change {
+                Change::Set(path, modtime) => write_record(&mut records, SET, path, *modtime),
+                Change::Remove(path) => write_record(&mut records, REMOVE, path, UNIX_EPOCH),
+            };
+            written += usize::from(was_written);
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(self.journal_records > 0)
+            .truncate(self.journal_records == 0)
+            .open(journal.as_path())
+            .await?;
+        file.write_all(records.as_slice()).await?;
+        file.sync_data().await?;
+        self.journal_records += written;
+        tracing::debug!("Appended {written} modtime changes to {JOURNAL_FILE}");
+        self.pending.clear();
+        Ok(())
+    }
+
+    async fn write_snapshot(&mut self) -> Result<(), ChimeraError> {
+        let generation = self.generation + 1;
+        let mut snapshot = Vec::with_capacity(HEADER_SIZE + self.files.len() * 64);
+        snapshot.extend_from_slice(header(SNAPSHOT_MAGIC, generation).as_slice());
+        for (path, modtime) in self.files.iter() {
+            let _ = write_record(&mut snapshot, SET, path, *modtime);
+        }
+        write_atomic(self.folder.join(SNAPSHOT_FILE).as_path(), snapshot.as_slice()).await?;
+        // from here the old journal is ignored, its generation is out of date
+        self.generation = generation;
+        self.journal_records = 0;
+        self.pending.clear();
+        self.needs_snapshot = false;
+        let _ = tokio::fs::remove_file(self.folder.join(JOURNAL_FILE)).await;
+        if tokio::fs::remove_file(self.folder.join(LEGACY_FILE)).await.is_ok() {
+            tracing::info!("Removed {LEGACY_FILE}, migrated to {SNAPSHOT_FILE}");
+        }
+        tracing::debug!("Wrote {SNAPSHOT_FILE} with {} documents", self.files.len());
+        Ok(())
+    }
+}
+
+fn header(magic: &[u8; 4], generation: u64) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..4].copy_from_slice(magic);
+    header[4..].copy_from_slice(&generation.to_le_bytes());
+    header
+}
+
+fn read_header(data: &[u8], magic: &[u8; 4]) -> Option<u64> {
+    if data.len() < HEADER_SIZE || &data[..4] != magic {
+        return None;
+    }
+    Some(u64::from_le_bytes(data[4..HEADER_SIZE].try_into().ok()?))
+}
+
+// kind: u8, path length: u32, path: utf-8, seconds: u64, nanoseconds: u32,
+// all little endian. Paths that aren't utf-8 are skipped, so those documents
+// are indexed again on each startup rather than recorded under a mangled name
+fn write_record(out: &mut Vec<u8>, kind: u8, path: &Path, modtime: SystemTime) -> bool {
+    let Some(path) = path.to_str() else {
+        tracing::debug!("Not recording modtime of non utf-8 path: {}", path.display());
+        return false;
+    };
+    let since = modtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    out.push(kind);
+    out.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    out.extend_from_slice(path.as_bytes());
+    out.extend_from_slice(&since.as_secs().to_le_bytes());
+    out.extend_from_slice(&since.subsec_nanos().to_le_bytes());
+    true
+}
+
+// Returns whether every record was whole, and how many were applied
+fn apply_records(files: &mut BTreeMap<PathBuf, SystemTime>, mut data: &[u8]) -> (bool, usize) {
+    let mut records = 0;
+    while !data.is_empty() {
+        let Some((kind, path, modtime, rest)) = read_record(data) else {
+            return (false, records);
+        };
+        match kind {
+            SET => { files.insert(path, modtime); },
+            REMOVE => { files.remove(path.as_path()); },
+            _ => return (false, records),
+        }
+        records += 1;
+        data = rest;
+    }
+    (true, records)
+}
+
+fn read_record(data: &[u8]) -> Option<(u8, PathBuf, SystemTime, &[u8])> {
+    let (&kind, data) = data.split_first()?;
+    let (length, data) = data.split_first_chunk::<4>()?;
+    let length = u32::from_le_bytes(*length) as usize;
+    if data.len() < length {
+        return None;
+    }
+    let (path, data) = data.split_at(length);
+    let path = PathBuf::from(std::str::from_utf8(path).ok()?);
+    let (secs, data) = data.split_first_chunk::<8>()?;
+    let (nanos, data) = data.split_first_chunk::<4>()?;
+    let nanos = u32::from_le_bytes(*nanos);
+    if nanos >= 1_000_000_000 {
+        return None;
+    }
+    let modtime = UNIX_EPOCH.checked_add(Duration::new(u64::from_le_bytes(*secs), nanos))?;
+    Some((kind, path, modtime, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempFolder(PathBuf);
+
+    impl TempFolder {
+        fn new(name: &str) -> TempFolder {
+            let folder = std::env::temp_dir().join(format!("chimera-ft-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(folder.as_path());
+            std::fs::create_dir_all(folder.as_path()).unwrap();
+            TempFolder(folder)
+        }
+    }
+
+    impl Drop for TempFolder {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.as_path());
+        }
+    }
+
+    fn at(secs: u64, nanos: u32) -> SystemTime {
+        UNIX_EPOCH + Duration::new(secs, nanos)
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let folder = TempFolder::new("round-trip");
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        assert!(!file_times.check_up_to_date(Path::new("a.md"), Some(at(100, 5))));
+        assert!(!file_times.check_up_to_date(Path::new("b.md"), Some(at(200, 0))));
+        file_times.save().await.unwrap();
+        // the journal on top of the first snapshot
+        assert!(!file_times.check_up_to_date(Path::new("c.md"), Some(at(300, 999_999_999))));
+        file_times.forget(Path::new("b.md"));
+        file_times.save().await.unwrap();
+        assert!(folder.0.join(JOURNAL_FILE).exists());
+
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        assert!(file_times.check_up_to_date(Path::new("a.md"), Some(at(100, 5))));
+        assert!(file_times.check_up_to_date(Path::new("c.md"), Some(at(300, 999_999_999))));
+        assert!(!file_times.check_up_to_date(Path::new("b.md"), Some(at(200, 0))));
+        assert!(!file_times.needs_snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_migrates_legacy() {
+        let folder = TempFolder::new("legacy");
+        let mut legacy = BTreeMap::new();
+        legacy.insert(PathBuf::from("old.md"), at(1_700_000_000, 42));
+        std::fs::write(folder.0.join(LEGACY_FILE), toml::to_string(&legacy).unwrap()).unwrap();
+
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        assert!(file_times.check_up_to_date(Path::new("old.md"), Some(at(1_700_000_000, 42))));
+        file_times.save().await.unwrap();
+        assert!(!folder.0.join(LEGACY_FILE).exists());
+        assert!(folder.0.join(SNAPSHOT_FILE).exists());
+
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        assert!(file_times.check_up_to_date(Path::new("old.md"), Some(at(1_700_000_000, 42))));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_journal() {
+        let folder = TempFolder::new("truncated");
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        file_times.check_up_to_date(Path::new("a.md"), Some(at(100, 0)));
+        file_times.save().await.unwrap();
+        file_times.check_up_to_date(Path::new("b.md"), Some(at(200, 0)));
+        file_times.check_up_to_date(Path::new("c.md"), Some(at(300, 0)));
+        file_times.save().await.unwrap();
+        // a crash partway through the last record
+        let journal = folder.0.join(JOURNAL_FILE);
+        let data = std::fs::read(journal.as_path()).unwrap();
+        std::fs::write(journal.as_path(), &data[..data.len() - 3]).unwrap();
+
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        assert!(file_times.needs_snapshot);
+        assert!(file_times.check_up_to_date(Path::new("a.md"), Some(at(100, 0))));
+        assert!(file_times.check_up_to_date(Path::new("b.md"), Some(at(200, 0))));
+        assert!(!file_times.check_up_to_date(Path::new("c.md"), Some(at(300, 0))));
+    }
+
+    #[tokio::test]
+    async fn test_stale_generation_journal() {
+        let folder = TempFolder::new("stale");
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        file_times.check_up_to_date(Path::new("a.md"), Some(at(100, 0)));
+        file_times.save().await.unwrap();
+        file_times.check_up_to_date(Path::new("a.md"), Some(at(500, 0)));
+        file_times.save().await.unwrap();
+        let old_journal = std::fs::read(folder.0.join(JOURNAL_FILE)).unwrap();
+        // compaction wrote a new snapshot, then was interrupted before the
+        // old journal was removed
+        file_times.check_up_to_date(Path::new("a.md"), Some(at(900, 0)));
+        file_times.needs_snapshot = true;
+        file_times.save().await.unwrap();
+        std::fs::write(folder.0.join(JOURNAL_FILE), old_journal.as_slice()).unwrap();
+
+        let mut file_times = FileTimes::load(folder.0.as_path()).await;
+        assert!(file_times.needs_snapshot);
+        assert!(file_times.check_up_to_date(Path::new("a.md"), Some(at(900, 0))));
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let mut data = Vec::new();
+        assert!(write_record(&mut data, SET, Path::new("a.md"), at(100, 0)));
+        let whole = data.len();
+        // nanoseconds out of range
+        data[whole - 4..].copy_from_slice(&1_000_000_000u32.to_le_bytes());
+        assert!(read_record(data.as_slice()).is_none());
+        // seconds past what a SystemTime holds
+        data[whole - 12..whole - 4].copy_from_slice(&u64::MAX.to_le_bytes());
+        data[whole - 4..].copy_from_slice(&0u32.to_le_bytes());
+        assert!(read_record(data.as_slice()).is_none());
+        // a path that isn't utf-8
+        let mut data = vec![SET, 1, 0, 0, 0, 0xff];
+        data.extend_from_slice(&[0; 12]);
+        assert!(read_record(data.as_slice()).is_none());
+
+        let mut files = BTreeMap::new();
+        let mut data = Vec::new();
+        write_record(&mut data, SET, Path::new("a.md"), at(100, 0));
+        write_record(&mut data, 9, Path::new("b.md"), at(100, 0));
+        assert_eq!(apply_records(&mut files, data.as_slice()), (false, 1));
+        assert_eq!(files.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_skips_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        let mut data = Vec::new();
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"bad\xff.md"));
+        assert!(!write_record(&mut data, SET, path, at(100, 0)));
+        assert!(data.is_empty());
+    }
+}
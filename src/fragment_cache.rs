@@ -0,0 +1,24 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+
+// Rendered partial templates that only depend on site-wide data, kept
+// until the page catalog changes
+#[derive(Clone, Default)]
+pub struct FragmentCache {
+    // Template name => (catalog generation it was rendered at, html)
+    lock: Arc<RwLock<HashMap<String, (u64, String)>>>,
+}
+
+impl FragmentCache {
+    pub fn get(&self, name: &str, generation: u64) -> Option<String> {
+        let lock = self.lock.read().ok()?;
+        lock.get(name)
+            .filter(|(rendered_at, _html)| *rendered_at == generation)
+            .map(|(_rendered_at, html)| html.clone())
+    }
+
+    pub fn insert(&self, name: &str, generation: u64, html: &str) {
+        if let Ok(mut lock) = self.lock.write() {
+            lock.insert(name.to_string(), (generation, html.to_string()));
+        }
+    }
+}
@@ -1,14 +1,21 @@
 use core::ops::Range;
-use std::{collections::BTreeMap, ffi::OsStr, path::PathBuf, sync::{Arc, RwLock}, time::SystemTime};
-use serde::{Deserialize, Serialize};
+use std::{ffi::OsStr, path::{Path, PathBuf}, sync::{Arc, OnceLock, RwLock}, time::SystemTime};
+use serde::Serialize;
 use tantivy::{collector::TopDocs, directory::MmapDirectory, IndexReader};
-use tantivy::query::QueryParser;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery};
 use tantivy::{schema::*, SnippetGenerator};
 use tantivy::{Index, IndexWriter, ReloadPolicy};
-use tokio::{io::AsyncWriteExt, sync::mpsc::{self, Receiver}};
+use tokio::sync::mpsc::{self, Receiver};
 
+use crate::memory_budget::MIN_INDEX_WRITER_BYTES;
+use crate::access::{AccessControl, PUBLIC_ACCESS};
+use crate::atomic_write::write_atomic;
 use crate::chimera_error::ChimeraError;
-use crate::file_manager::FileManager;
+use crate::document_scraper::parse_markdown;
+use crate::expiry::Expiry;
+use crate::file_manager::{markdown_files_in, FileManager};
+use crate::image_metadata;
+use crate::file_times::FileTimes;
 use crate::HOME_DIR;
 
 #[derive(Serialize)]
@@ -18,12 +25,21 @@ pub struct SearchResult {
     snippet: String,
 }
 
-type FileMapType = BTreeMap<PathBuf, SystemTime>;
+// What scan_directory queues
+#[derive(Clone, Copy, Default)]
+pub struct ScanOptions {
+    // Folders are queued by index_folder as they're visited, see index_on_demand
+    pub on_demand: bool,
+    // Photos are indexed by their EXIF caption, see [gallery]
+    pub image_captions: bool,
+}
 
-#[derive(Default, Serialize, Deserialize)]
-struct FileTimes {
-    index_location: PathBuf,
-    files: FileMapType,
+// How the index writer is set up, see MemoryBudget and [search]
+#[derive(Clone, Copy)]
+pub struct WriterOptions {
+    pub heap: usize,
+    // 0 lets tantivy pick
+    pub threads: usize,
 }
 
 pub struct FullTextIndex {
@@ -31,8 +47,14 @@ pub struct FullTextIndex {
     title_field: Field,
     link_field: Field,
     body_field: Field,
-    index_writer: Arc<RwLock<IndexWriter>>,
+    access_field: Field,
+    expires_field: Field,
+    reviewed_field: Field,
     index_reader: IndexReader,
+    expiry: Expiry,
+    writer_options: WriterOptions,
+    // Feeds the scanner once scan_directory has started it
+    work_queue: OnceLock<(mpsc::Sender<PathBuf>, ScanOptions)>,
 }
 
 struct DocumentScanner {
@@ -43,10 +65,14 @@ struct DocumentScanner {
     title: Field,
     link: Field,
     body: Field,
+    access_field: Field,
+    expires_field: Field,
+    reviewed_field: Field,
+    access: AccessControl,
 }
 
 impl FullTextIndex {
-    pub fn new(index_path: &std::path::Path) -> Result<Self, ChimeraError> {
+    pub fn new(index_path: &std::path::Path, expiry: Expiry, writer_options: WriterOptions) -> Result<Self, ChimeraError> {
         let text_field_indexing = TextFieldIndexing::default()
             .set_tokenizer("en_stem")
             .set_index_option(IndexRecordOption::WithFreqsAndPositions);
@@ -59,12 +85,27 @@ impl FullTextIndex {
         let title_field = schema_builder.add_text_field("title", STRING | STORED);
         let link_field = schema_builder.add_text_field("link", STRING | STORED);
         let body_field = schema_builder.add_text_field("body", text_options);
+        let access_field = schema_builder.add_text_field("access", STRING);
+        // Front matter dates, kept so outdated documents can be down-ranked
+        let expires_field = schema_builder.add_text_field("expires", STORED);
+        let reviewed_field = schema_builder.add_text_field("reviewed", STORED);
         let schema = schema_builder.build();
 
         let dir = MmapDirectory::open(index_path)?;
-        let index = Index::open_or_create(dir, schema.clone())?;
-        let index_writer = Arc::new(RwLock::new(index.writer(50_000_000)?));
-
+        let index = match Index::open_or_create(dir, schema.clone()) {
+            Ok(index) => index,
+            Err(e) if needs_rebuild(&e) => {
+                // Most likely an index written by an older version with a
+                // different schema. It is only a cache, so start over
+                tracing::warn!("Rebuilding full text index at {}: {e}", index_path.display());
+                std::fs::remove_dir_all(index_path)?;
+                std::fs::create_dir_all(index_path)?;
+                let dir = MmapDirectory::open(index_path)?;
+                Index::create(dir, schema.clone(), tantivy::IndexSettings::default())?
+            },
+            // eg: another instance has it locked, which deleting would break
+            Err(e) => return Err(e.into()),
+        };
         let index_reader = index
             .reader_builder()
             .reload_policy(ReloadPolicy::OnCommitWithDelay)
@@ -75,8 +116,13 @@ impl FullTextIndex {
             title_field,
             link_field,
             body_field,
-            index_writer,
+            access_field,
+            expires_field,
+            reviewed_field,
             index_reader,
+            expiry,
+            writer_options,
+            work_queue: OnceLock::new(),
         };
         Ok(fti)
     }
@@ -85,42 +131,117 @@ impl FullTextIndex {
         &self,
         root_directory: PathBuf,
         search_index_dir: PathBuf,
-        file_manager: &FileManager
+        file_manager: &FileManager,
+        access: AccessControl,
+        options: ScanOptions,
     ) -> Result<(), ChimeraError> {
-        let file_times = FileTimes::try_load(search_index_dir).await;
+        let mut file_times = FileTimes::load(search_index_dir.as_path()).await;
+
+        // Permissions are baked into the index, so changed rules mean a full rescan
+        let access_file = search_index_dir.join("access.txt");
+        let fingerprint = access.fingerprint();
+        let last_fingerprint = tokio::fs::read_to_string(access_file.as_path()).await.unwrap_or_default();
+        if fingerprint != last_fingerprint {
+            tracing::info!("Access rules changed, reindexing all documents");
+            file_times.clear();
+            write_atomic(access_file.as_path(), fingerprint.as_bytes()).await?;
+        }
 
         let (tx, rx) = mpsc::channel::<PathBuf>(32);
-        let scanner = DocumentScanner {
-            index_writer: self.index_writer.clone(),
-            file_times,
-            work_queue: rx,
-            document_root: root_directory,
-            title: self.title_field,
-            link: self.link_field,
-            body: self.body_field,
-        };
-        tokio::spawn(scanner.scan());
+        let index = self.index.clone();
+        let writer_options = self.writer_options;
+        let (title, link, body) = (self.title_field, self.link_field, self.body_field);
+        let (access_field, expires_field, reviewed_field) = (self.access_field, self.expires_field, self.reviewed_field);
+        tokio::spawn(async move {
+            let index_writer = acquire_writer(&index, writer_options).await?;
+            let scanner = DocumentScanner {
+                index_writer: Arc::new(RwLock::new(index_writer)),
+                file_times,
+                work_queue: rx,
+                document_root: root_directory,
+                title,
+                link,
+                body,
+                access_field,
+                expires_field,
+                reviewed_field,
+                access,
+            };
+            scanner.scan().await
+        });
 
-        let md_files = file_manager.get_markdown_files();
-        for md in md_files {
-            tx.send(md).await?;
+        // Queued from a task, as the scanner may still be waiting for the writer.
+        // On demand, folders are queued by index_folder as they're visited
+        if !options.on_demand {
+            let mut md_files = file_manager.get_markdown_files();
+            if options.image_captions {
+                md_files.extend(file_manager.get_image_files());
+            }
+            let scan_tx = tx.clone();
+            tokio::spawn(async move {
+                for md in md_files {
+                    if scan_tx.send(md).await.is_err() {
+                        break;
+                    }
+                }
+            });
         }
+        let _ = self.work_queue.set((tx.clone(), options));
 
         let change_rx = file_manager.subscribe();
-        tokio::spawn(listen_for_changes(change_rx, tx));
+        tokio::spawn(listen_for_changes(change_rx, tx, options.image_captions));
 
         Ok(())
     }
 
-    pub fn search(&self, query_str: &str) -> Result<Vec<SearchResult>, ChimeraError> {
+    // Queues the documents directly inside a folder. Unchanged ones are
+    // skipped by the scanner, so this is cheap for folders indexed before
+    pub fn index_folder(&self, folder: &Path) {
+        let Some((tx, options)) = self.work_queue.get() else {
+            return;
+        };
+        let mut md_files = markdown_files_in(folder);
+        if options.image_captions {
+            let images = std::fs::read_dir(folder).into_iter().flatten().flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file() && image_metadata::is_image(path));
+            md_files.extend(images);
+        }
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            for md in md_files {
+                if tx.send(md).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Documents searchable now, which lags the scanner until it commits
+    pub fn document_count(&self) -> u64 {
+        self.index_reader.searcher().num_docs()
+    }
+
+    // Malformed queries (unbalanced quotes, stray operators) are parsed as
+    // well as possible, and the parser complaints are returned as warnings.
+    // Only documents the user is allowed to read are returned
+    pub fn search(&self, query_str: &str, user: Option<&str>) -> Result<(Vec<SearchResult>, Vec<String>), ChimeraError> {
         let searcher = self.index_reader.searcher();
         let query_parser = QueryParser::for_index(&self.index, vec![self.body_field]);
-        let query = query_parser.parse_query(query_str)?;
+        let (query, errors) = query_parser.parse_query_lenient(query_str);
+        let mut warnings: Vec<String> = Vec::with_capacity(errors.len());
+        for e in errors {
+            tracing::debug!("Lenient query parse of \"{query_str}\": {e}");
+            let warning = e.to_string();
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+        let query = self.restrict_to_user(query, user);
         let mut results = Vec::new();
         let snippet_generator = SnippetGenerator::create(&searcher, &query, self.body_field)?;
-        let top_docs = searcher.search(&query, &TopDocs::with_limit(10))?;
-        for (_score, doc_address) in top_docs {
-            let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
+        let top_docs = self.rank(&searcher, &query)?;
+        for retrieved_doc in top_docs {
             let title = retrieved_doc.get_first(self.title_field);
             let anchor = retrieved_doc.get_first(self.link_field);
             tracing::debug!("Search result: {title:?} {anchor:?}");
@@ -138,7 +259,51 @@ impl FullTextIndex {
             }
         }
         tracing::debug!("Result count: {}", results.len());
-        Ok(results)
+        Ok((results, warnings))
+    }
+
+    // With a search penalty configured, extra candidates are fetched so
+    // outdated documents can drop below current ones
+    fn rank(&self, searcher: &tantivy::Searcher, query: &dyn Query) -> Result<Vec<TantivyDocument>, ChimeraError> {
+        const RESULT_COUNT: usize = 10;
+        let Some(penalty) = self.expiry.search_penalty() else {
+            let top_docs = searcher.search(query, &TopDocs::with_limit(RESULT_COUNT))?;
+            return top_docs.into_iter()
+                .map(|(_score, doc_address)| Ok(searcher.doc(doc_address)?))
+                .collect();
+        };
+        let top_docs = searcher.search(query, &TopDocs::with_limit(RESULT_COUNT * 3))?;
+        let mut ranked = Vec::with_capacity(top_docs.len());
+        for (score, doc_address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(doc_address)?;
+            let expires = doc.get_first(self.expires_field).and_then(|v| v.as_str());
+            let reviewed = doc.get_first(self.reviewed_field).and_then(|v| v.as_str());
+            let score = match self.expiry.is_outdated(expires, reviewed) {
+                true => score * penalty,
+                false => score,
+            };
+            ranked.push((score, doc));
+        }
+        ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        Ok(ranked.into_iter().take(RESULT_COUNT).map(|(_score, doc)| doc).collect())
+    }
+
+    fn restrict_to_user(&self, query: Box<dyn Query>, user: Option<&str>) -> Box<dyn Query> {
+        let mut readable: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Should, self.access_term(PUBLIC_ACCESS)),
+        ];
+        if let Some(user) = user {
+            readable.push((Occur::Should, self.access_term(user)));
+        }
+        Box::new(BooleanQuery::new(vec![
+            (Occur::Must, query),
+            (Occur::Must, Box::new(BooleanQuery::new(readable))),
+        ]))
+    }
+
+    fn access_term(&self, value: &str) -> Box<dyn Query> {
+        let term = Term::from_field_text(self.access_field, value);
+        Box::new(TermQuery::new(term, IndexRecordOption::Basic))
     }
 
     fn highlight(&self, snippet: &str, highlights: &[Range<usize>]) -> String {
@@ -185,6 +350,45 @@ fn normalize_ranges(ranges: &[Range<usize>]) -> Vec<Range<usize>> {
     results
 }
 
+// Only one process can write the index. During a handover the previous
+// instance holds the lock until it exits, so wait for it
+async fn acquire_writer(index: &Index, options: WriterOptions) -> Result<IndexWriter, ChimeraError> {
+    let threads = match options.threads {
+        0 => None,
+        threads => {
+            // each thread needs tantivy's minimum out of the heap
+            let affordable = (options.heap / MIN_INDEX_WRITER_BYTES).max(1);
+            if threads > affordable {
+                tracing::warn!("A {} MB writer heap only allows {affordable} indexing threads, not {threads}", options.heap / (1024 * 1024));
+            }
+            Some(threads.min(affordable))
+        },
+    };
+    let mut waiting = false;
+    loop {
+        let writer = match threads {
+            Some(threads) => index.writer_with_num_threads(threads, options.heap),
+            None => index.writer(options.heap),
+        };
+        match writer {
+            Ok(writer) => {
+                if waiting {
+                    tracing::info!("Acquired the full text index writer");
+                }
+                return Ok(writer);
+            },
+            Err(tantivy::TantivyError::LockFailure(tantivy::directory::error::LockError::LockBusy, _)) => {
+                if !waiting {
+                    tracing::info!("Full text index is locked by another instance, waiting for it to exit");
+                    waiting = true;
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 async fn get_modtime(path: &std::path::Path) -> Option<SystemTime> {
     if let Ok(metadata) = tokio::fs::metadata(path).await {
         if let Ok(modtime) = metadata.modified() {
@@ -197,16 +401,7 @@ async fn get_modtime(path: &std::path::Path) -> Option<SystemTime> {
 impl DocumentScanner {
     async fn prune_deleted_documents(&mut self) -> Result<(), ChimeraError> {
         // look for deleted documents since we last ran
-        let mut deleted = Vec::new();
-        self.file_times.files.retain(|path, _time| {
-            if !path.exists() {
-                deleted.push(path.clone());
-                false
-            }
-            else {
-                true
-            }
-        });
+        let deleted = self.file_times.remove_missing();
         if !deleted.is_empty()
         {
             let mut index = self.index_writer.write()?;
@@ -223,8 +418,30 @@ impl DocumentScanner {
         Ok(())
     }
 
+    // A photo is found by its caption, which also stands in for its title
+    async fn add_image(&self, path: &Path, relative_path: &Path, anchor_string: String) -> Result<(), ChimeraError> {
+        let image = path.to_path_buf();
+        let metadata = tokio::task::spawn_blocking(move || image_metadata::read(image.as_path())).await.ok().flatten();
+        let Some(caption) = metadata.and_then(|metadata| metadata.caption) else {
+            return Ok(());
+        };
+        tracing::debug!("Adding image {} to full-text index", path.display());
+        let mut doc = TantivyDocument::default();
+        doc.add_text(self.title, &caption);
+        doc.add_text(self.link, anchor_string);
+        doc.add_text(self.body, &caption);
+        for access in self.access.index_terms(relative_path) {
+            doc.add_text(self.access_field, access);
+        }
+        let index = self.index_writer.write()?;
+        index.add_document(doc)?;
+        Ok(())
+    }
+
     async fn scan(mut self) -> Result<(), ChimeraError> {
         self.prune_deleted_documents().await?;
+        // also finishes a migration from the old format
+        self.file_times.save().await?;
 
         let mut docs_since_last_commit = 0;
         while let Some(path) = self.work_queue.recv().await {
@@ -244,16 +461,45 @@ impl DocumentScanner {
                     index.delete_term(doc_term);
                 }
 
-                if let Some(title_string) = path.file_name() {
-                    let title_string = title_string.to_string_lossy();
+                if image_metadata::is_image(path.as_path()) {
+                    self.add_image(path.as_path(), relative_path, anchor_string).await?;
+                    docs_since_last_commit += 1;
+                }
+                else if let Some(file_name) = path.file_name() {
                     if let Ok(body_text) = tokio::fs::read_to_string(path.as_path()).await {
-                        tracing::debug!("Adding {} to full-text index", title_string);
-                        doc.add_text(self.title, title_string);
-                        doc.add_text(self.link, anchor_string);
-                        doc.add_text(self.body, body_text);
-                        {
-                            let index = self.index_writer.write()?;
-                            index.add_document(doc)?;
+                        let (_html, scraper) = parse_markdown(body_text.as_str());
+                        let title_string = scraper.page_title().cloned()
+                            .unwrap_or_else(|| file_name.to_string_lossy().into_owned());
+                        if scraper.metadata.contains_key("redirect_to") {
+                            // stubs left behind by moved documents aren't worth finding
+                            tracing::debug!("Skipping redirect stub {}", path.display());
+                        }
+                        else if scraper.is_draft() {
+                            tracing::debug!("Skipping draft {}", path.display());
+                        }
+                        else if scraper.is_scheduled() {
+                            // looked at again when it's published, see Publisher
+                            tracing::debug!("Skipping scheduled document {}", path.display());
+                            self.file_times.forget(path.as_path());
+                        }
+                        else {
+                            tracing::debug!("Adding {} to full-text index", title_string);
+                            doc.add_text(self.title, &title_string);
+                            doc.add_text(self.link, anchor_string);
+                            doc.add_text(self.body, &body_text);
+                            for access in self.access.index_terms(relative_path) {
+                                doc.add_text(self.access_field, access);
+                            }
+                            if let Some(expires) = scraper.metadata.get("expires") {
+                                doc.add_text(self.expires_field, expires);
+                            }
+                            if let Some(reviewed) = scraper.metadata.get("reviewed") {
+                                doc.add_text(self.reviewed_field, reviewed);
+                            }
+                            {
+                                let index = self.index_writer.write()?;
+                                index.add_document(doc)?;
+                            }
                         }
                     }
                     docs_since_last_commit += 1;
@@ -275,9 +521,14 @@ impl DocumentScanner {
 async fn listen_for_changes(
     mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
     tx: tokio::sync::mpsc::Sender<PathBuf>,
+    image_captions: bool,
 ) {
     while let Ok(path) = rx.recv().await {
         tracing::debug!("FTI change event {}", path.display());
+        if image_captions && image_metadata::is_image(path.as_path()) {
+            let _ = tx.send(path).await;
+            continue;
+        }
         if let Some(ext) = path.extension() {
             if ext == OsStr::new("md") {
                 // forward to the DocumentScanner
@@ -287,63 +538,52 @@ async fn listen_for_changes(
     }
 }
 
-impl FileTimes {
-    async fn try_load(search_index_dir: PathBuf) -> FileTimes {
-        let index_file = search_index_dir.join("ft.toml");
-        let times = match tokio::fs::read_to_string(index_file.as_path()).await {
-            Ok(f) => {
-                toml::from_str(f.as_str()).unwrap_or_default()
-            },
-            Err(_) => {
-                FileMapType::default()
-            }
+// Errors from opening an index that starting over fixes
+fn needs_rebuild(e: &tantivy::TantivyError) -> bool {
+    matches!(e,
+        tantivy::TantivyError::SchemaError(_)
+        | tantivy::TantivyError::DataCorruption(_)
+        | tantivy::TantivyError::IncompatibleIndex(_)
+        | tantivy::TantivyError::OpenReadError(tantivy::directory::error::OpenReadError::FileDoesNotExist(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toml_config::ExpiryConfig;
+
+    fn open(index_path: &Path) -> Result<FullTextIndex, ChimeraError> {
+        let writer_options = WriterOptions {
+            heap: MIN_INDEX_WRITER_BYTES,
+            threads: 1,
         };
-        FileTimes {
-            index_location: index_file,
-            files: times,
-        }
+        FullTextIndex::new(index_path, Expiry::new(&ExpiryConfig::default()), writer_options)
     }
 
-    fn check_up_to_date(&mut self, path: &std::path::Path, current_modtime: Option<SystemTime>) -> bool {
-        if current_modtime.is_none() {
-            // No such file, remove from index, if it's there
-            tracing::debug!("File not in ft.toml: {}", path.display());
-            let _ = self.files.remove(path);
-            return false;
-        }
-        let current_modtime = current_modtime.unwrap();
-        let last_modtime = self.files.get(path);
-        if last_modtime.is_some() && *last_modtime.unwrap() == current_modtime {
-            tracing::debug!("Up-to-date in ft.toml: {}", path.display());
-            return true;
-        }
-        tracing::debug!("Adding to ft.toml: {}", path.display());
-        self.files.insert(path.to_path_buf(), current_modtime);
-        false
-    }
+    #[test]
+    fn test_rebuilds_only_broken_indexes() {
+        let folder = std::env::temp_dir().join(format!("chimera-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(folder.as_path());
 
-    async fn save(&self) -> Result<(), ChimeraError> {
-        let mut file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(self.index_location.as_path())
-            .await?;
-        match toml::to_string(&self.files) {
-            Ok(toml) => {
-                match tokio::fs::File::write_all(&mut file, toml.as_bytes()).await {
-                    Ok(_) => {
-                        tracing::debug!("Saved ft.toml");
-                    },
-                    Err(e) => {
-                        tracing::warn!("Failure writing full text index file times: {e}");
-                    }
-                }
-            },
-            Err(e) => {
-                tracing::warn!("Failure converting file times to toml: {e}");
-            }
-        }
-        Ok(())
+        let old_schema = folder.join("old-schema");
+        std::fs::create_dir_all(old_schema.as_path()).unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", STRING | STORED);
+        Index::create_in_dir(old_schema.as_path(), schema_builder.build()).unwrap();
+        assert!(open(old_schema.as_path()).is_ok());
+
+        let corrupt = folder.join("corrupt");
+        std::fs::create_dir_all(corrupt.as_path()).unwrap();
+        std::fs::write(corrupt.join("meta.json"), "not json").unwrap();
+        assert!(open(corrupt.as_path()).is_ok());
+        // and it's usable afterwards
+        assert!(open(corrupt.as_path()).is_ok());
+
+        let io_error = tantivy::TantivyError::IoError(Arc::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied)));
+        assert!(!needs_rebuild(&io_error));
+        assert!(!needs_rebuild(&tantivy::TantivyError::Poisoned));
+        assert!(needs_rebuild(&tantivy::TantivyError::SchemaError("mismatch".to_string())));
+        let _ = std::fs::remove_dir_all(folder.as_path());
     }
 }
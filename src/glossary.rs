@@ -0,0 +1,183 @@
+use std::{collections::{HashMap, HashSet}, fs, path::PathBuf, sync::{Arc, RwLock}};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::file_manager::FileManager;
+
+// Text inside these is left alone
+const SKIP_TAGS: [&str; 11] = ["a", "abbr", "cite", "code", "pre", "h1", "h2", "h3", "h4", "h5", "h6"];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum GlossaryEntry {
+    Definition(String),
+    Full {
+        definition: String,
+        url: Option<String>,
+    },
+}
+
+struct GlossaryInternal {
+    path: PathBuf,
+    // Lowercased term => entry
+    entries: HashMap<String, GlossaryEntry>,
+    terms_re: Option<Regex>,
+}
+
+// Terms to explain wherever documents first use them, from a toml file of
+// `term = "definition"` or `[term]` tables with a definition and url
+#[derive(Clone)]
+pub struct Glossary {
+    lock: Arc<RwLock<GlossaryInternal>>,
+}
+
+impl GlossaryInternal {
+    fn load(&mut self) {
+        let entries: HashMap<String, GlossaryEntry> = match fs::read_to_string(self.path.as_path()) {
+            Ok(text) => match toml::from_str(text.as_str()) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    tracing::error!("Error parsing {}: {e}", self.path.display());
+                    HashMap::new()
+                },
+            },
+            Err(e) => {
+                tracing::warn!("Failed to read {}: {e}", self.path.display());
+                HashMap::new()
+            },
+        };
+        let mut terms: Vec<&String> = entries.keys().collect();
+        // longer terms win over ones they contain
+        terms.sort_by_key(|term| std::cmp::Reverse(term.len()));
+        let pattern = terms.iter().map(|term| regex::escape(term)).collect::<Vec<String>>().join("|");
+        self.terms_re = match terms.is_empty() {
+            true => None,
+            false => Regex::new(format!(r"(?i)\b(?:{pattern})\b").as_str()).ok(),
+        };
+        self.entries = entries.into_iter().map(|(term, entry)| (term.to_lowercase(), entry)).collect();
+        tracing::info!("Glossary loaded with {} terms", self.entries.len());
+    }
+
+    fn explain(&self, text: &str, used: &mut HashSet<String>) -> String {
+        let Some(terms_re) = &self.terms_re else {
+            return text.to_string();
+        };
+        let mut html = String::with_capacity(text.len());
+        let mut last = 0;
+        for found in terms_re.find_iter(text) {
+            let term = found.as_str().to_lowercase();
+            let Some(entry) = self.entries.get(term.as_str()) else {
+                continue;
+            };
+            if !used.insert(term) {
+                continue;
+            }
+            html.push_str(&text[last..found.start()]);
+            let (definition, url) = match entry {
+                GlossaryEntry::Definition(definition) => (definition, None),
+                GlossaryEntry::Full { definition, url } => (definition, url.as_ref()),
+            };
+            let definition = escape(definition);
+            match url {
+                Some(url) => html.push_str(format!(
+                    "<a class=\"glossary\" href=\"{}\" title=\"{definition}\">{}</a>",
+                    escape(url), found.as_str()
+                ).as_str()),
+                None => html.push_str(format!(
+                    "<dfn class=\"glossary\" title=\"{definition}\">{}</dfn>", found.as_str()
+                ).as_str()),
+            }
+            last = found.end();
+        }
+        html.push_str(&text[last..]);
+        html
+    }
+}
+
+impl Glossary {
+    pub fn new(path: PathBuf) -> Self {
+        let mut glossary = GlossaryInternal {
+            path,
+            entries: HashMap::new(),
+            terms_re: None,
+        };
+        glossary.load();
+        Glossary {
+            lock: Arc::new(RwLock::new(glossary)),
+        }
+    }
+
+    fn load(&self) {
+        if let Ok(mut lock) = self.lock.write() {
+            lock.load();
+        }
+    }
+
+    pub fn listen_for_changes(&self, file_manager: &FileManager) {
+        let rx = file_manager.subscribe();
+        tokio::spawn(listen_for_changes(rx, self.clone()));
+    }
+
+    // Marks the first use of each term in a rendered body, outside of links,
+    // code and headings
+    pub fn apply(&self, html: &str) -> String {
+        let Ok(lock) = self.lock.read() else {
+            return html.to_string();
+        };
+        if lock.terms_re.is_none() {
+            return html.to_string();
+        }
+        let mut used = HashSet::new();
+        let mut new_html = String::with_capacity(html.len() * 11 / 10);
+        let mut skip_depth = 0_usize;
+        let mut rest = html;
+        while !rest.is_empty() {
+            let text_end = rest.find('<').unwrap_or(rest.len());
+            match skip_depth {
+                0 => new_html.push_str(lock.explain(&rest[..text_end], &mut used).as_str()),
+                _ => new_html.push_str(&rest[..text_end]),
+            }
+            rest = &rest[text_end..];
+            let Some(tag_end) = rest.find('>').map(|end| end + 1) else {
+                new_html.push_str(rest);
+                break;
+            };
+            let tag = &rest[..tag_end];
+            let closing = tag.starts_with("</");
+            let name: String = tag.trim_start_matches(['<', '/'])
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .collect::<String>()
+                .to_ascii_lowercase();
+            if SKIP_TAGS.contains(&name.as_str()) {
+                match closing {
+                    true => skip_depth = skip_depth.saturating_sub(1),
+                    false => skip_depth += 1,
+                }
+            }
+            new_html.push_str(tag);
+            rest = &rest[tag_end..];
+        }
+        new_html
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+async fn listen_for_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    glossary: Glossary,
+) {
+    let path = match glossary.lock.read() {
+        Ok(lock) => lock.path.clone(),
+        Err(_) => return,
+    };
+    while let Ok(changed) = rx.recv().await {
+        if changed == path {
+            tracing::info!("Glossary change event {}", changed.display());
+            glossary.load();
+        }
+    }
+}
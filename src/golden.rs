@@ -0,0 +1,155 @@
+use std::{collections::BTreeMap, net::SocketAddr, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use axum::{body::Body, extract::ConnectInfo, http::{Request, StatusCode}, Router};
+use tower::ServiceExt;
+
+use crate::chimera_error::ChimeraError;
+use crate::toml_config::TomlConfig;
+use crate::HOME_DIR;
+
+// Set to rewrite golden files with what's rendered now, eg:
+// CHIMERA_UPDATE_GOLDEN=1 cargo test
+pub const UPDATE_GOLDEN_VAR: &str = "CHIMERA_UPDATE_GOLDEN";
+
+// Folders of a site that pages are rendered with, copied when present
+const SITE_FOLDERS: [&str; 4] = ["template", "template-internal", "www", "www-internal"];
+
+// Every fixture gets this modtime, as pages show when they last changed
+const FIXTURE_MODTIME: Duration = Duration::from_secs(1_704_067_200);
+
+static NEXT_ROOT: AtomicU64 = AtomicU64::new(0);
+
+// Renders a tree of fixture documents as the server would, without
+// listening on a port, for comparing against golden HTML files. The site
+// folder supplies the templates and stylesheets (eg: example/, or a theme),
+// and is copied along with the fixtures into a scratch chimera root, so
+// neither is written to. The server changes the working directory to the
+// document root, so only one harness should run at a time
+pub struct GoldenHarness {
+    root: PathBuf,
+    router: Router,
+}
+
+impl GoldenHarness {
+    pub async fn new(site: &Path, fixtures: &Path) -> Result<Self, ChimeraError> {
+        Self::with_config(site, fixtures, "").await
+    }
+
+    // Extra chimera.toml settings, eg: "[markup]\nheading_permalinks = true"
+    pub async fn with_config(site: &Path, fixtures: &Path, config: &str) -> Result<Self, ChimeraError> {
+        let root = std::env::temp_dir().join(format!(
+            "chimera-golden-{}-{}",
+            std::process::id(),
+            NEXT_ROOT.fetch_add(1, Ordering::Relaxed),
+        ));
+        for folder in SITE_FOLDERS {
+            let from = site.join(folder);
+            if from.is_dir() {
+                copy_tree(from.as_path(), root.join(folder).as_path(), None)?;
+            }
+        }
+        copy_tree(fixtures, root.join("home").as_path(), Some(UNIX_EPOCH + FIXTURE_MODTIME))?;
+        std::fs::create_dir_all(root.join("search"))?;
+        // ahead of the extra settings, which may open tables
+        let config = format!("chimera_root = {:?}\n{config}", root.to_string_lossy());
+        let toml_config: TomlConfig = toml::from_str(config.as_str())
+            .map_err(|e| ChimeraError::TomlError(e.to_string()))?;
+        let router = crate::router(toml_config).await?;
+        Ok(GoldenHarness {
+            root,
+            router,
+        })
+    }
+
+    // The page at a URL, eg: /home/notes/todo.md. Fails unless it's a 200
+    pub async fn render(&self, url: &str) -> Result<String, ChimeraError> {
+        let mut request = Request::get(url).body(Body::empty())
+            .map_err(|e| ChimeraError::IOError(e.to_string()))?;
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+        let response = self.router.clone().oneshot(request).await
+            .map_err(|e| ChimeraError::IOError(e.to_string()))?;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await
+            .map_err(|e| ChimeraError::IOError(e.to_string()))?;
+        if status != StatusCode::OK {
+            return Err(ChimeraError::IOError(format!("{url} returned {status}")));
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    // Every markdown fixture, keyed by its path in the fixture tree
+    pub async fn render_all(&self) -> Result<BTreeMap<String, String>, ChimeraError> {
+        let home = self.root.join("home");
+        let mut documents: Vec<String> = walkdir::WalkDir::new(home.as_path()).into_iter().flatten()
+            .filter(|entry| entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "md"))
+            .filter_map(|entry| entry.path().strip_prefix(home.as_path()).ok().map(|path| path.to_string_lossy().replace('\\', "/")))
+            .collect();
+        documents.sort();
+        let mut pages = BTreeMap::new();
+        for document in documents {
+            let url = format!("{HOME_DIR}/{}", urlencoding::encode(document.as_str()).replace("%2F", "/"));
+            let html = self.render(url.as_str()).await?;
+            pages.insert(document, html);
+        }
+        Ok(pages)
+    }
+}
+
+impl Drop for GoldenHarness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.root.as_path());
+    }
+}
+
+// Compares a render with golden_dir/name, or writes the file instead when
+// CHIMERA_UPDATE_GOLDEN is set. Panics with the first line that differs, or
+// when there's no golden file to compare with, for use in tests
+pub fn assert_golden(golden_dir: &Path, name: &str, actual: &str) {
+    let golden = golden_dir.join(name);
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        if let Some(folder) = golden.parent() {
+            std::fs::create_dir_all(folder).expect("creating the golden folder");
+        }
+        std::fs::write(golden.as_path(), actual).expect("writing the golden file");
+        return;
+    }
+    let expected = match std::fs::read_to_string(golden.as_path()) {
+        Ok(expected) => expected,
+        Err(e) => panic!(
+            "No golden file {}: {e}\nRerun with {UPDATE_GOLDEN_VAR}=1 to write it from the render",
+            golden.display(),
+        ),
+    };
+    if expected == actual {
+        return;
+    }
+    let (line, expected_line, actual_line) = expected.lines().zip(actual.lines()).enumerate()
+        .find(|(_line, (expected, actual))| expected != actual)
+        .map_or_else(
+            || (expected.lines().count().min(actual.lines().count()), "<end>", "<end>"),
+            |(line, (expected, actual))| (line, expected, actual),
+        );
+    panic!(
+        "{} doesn't match the render at line {}\n  golden: {expected_line}\n  render: {actual_line}\nRerun with {UPDATE_GOLDEN_VAR}=1 to accept the render",
+        golden.display(),
+        line + 1,
+    );
+}
+
+fn copy_tree(from: &Path, to: &Path, modtime: Option<SystemTime>) -> Result<(), ChimeraError> {
+    for entry in walkdir::WalkDir::new(from).into_iter().flatten() {
+        let Ok(relative) = entry.path().strip_prefix(from) else {
+            continue;
+        };
+        let target = to.join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(target.as_path())?;
+        }
+        else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), target.as_path())?;
+            if let Some(modtime) = modtime {
+                std::fs::File::options().write(true).open(target.as_path())?.set_modified(modtime)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashMap, path::Path, sync::{Arc, RwLock}, time::Instant};
+
+use crate::converters::run_command;
+use crate::toml_config::{ConverterConfig, ConverterOutput, GraphvizConfig};
+
+// Drawn graphs kept, beyond which the least recently drawn are dropped
+const MAX_CACHED_GRAPHS: usize = 256;
+
+const DOT_BLOCK: &str = "<pre><code class=\"language-dot\">";
+
+// Replaces ```dot blocks with the SVG Graphviz draws of them. The drawings
+// are kept by their source, so unchanged graphs aren't drawn again when a
+// document is edited. Graphs that fail to draw stay as code
+#[derive(Clone)]
+pub struct Graphviz {
+    command: Arc<ConverterConfig>,
+    // Graph source => (when it was drawn, svg)
+    cache: Arc<RwLock<HashMap<String, (Instant, String)>>>,
+}
+
+impl Graphviz {
+    pub fn new(config: GraphvizConfig) -> Option<Self> {
+        if config.command.is_empty() {
+            tracing::warn!("Ignoring [graphviz], it has no command");
+            return None;
+        }
+        Some(Graphviz {
+            command: Arc::new(ConverterConfig {
+                command: config.command,
+                output: ConverterOutput::Html,
+                timeout_secs: config.timeout_secs,
+            }),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    // Takes the document's body as the parser made it
+    pub async fn render(&self, html: String, document: &Path) -> String {
+        if !html.contains(DOT_BLOCK) {
+            return html;
+        }
+        let mut new_html = String::with_capacity(html.len());
+        let mut rest = html.as_str();
+        while let Some(start) = rest.find(DOT_BLOCK) {
+            let source_start = start + DOT_BLOCK.len();
+            let Some(source_len) = rest[source_start..].find("</code></pre>") else {
+                break;
+            };
+            let block_end = source_start + source_len + "</code></pre>".len();
+            new_html.push_str(&rest[..start]);
+            match self.draw(unescape(&rest[source_start..source_start + source_len]), document).await {
+                Some(svg) => {
+                    new_html.push_str("<figure class=\"graph\">\n");
+                    new_html.push_str(svg.as_str());
+                    new_html.push_str("</figure>\n");
+                },
+                None => new_html.push_str(&rest[start..block_end]),
+            }
+            rest = &rest[block_end..];
+        }
+        new_html.push_str(rest);
+        new_html
+    }
+
+    async fn draw(&self, source: String, document: &Path) -> Option<String> {
+        if let Ok(lock) = self.cache.read() {
+            if let Some((_drawn_at, svg)) = lock.get(source.as_str()) {
+                return Some(svg.clone());
+            }
+        }
+        let output = match run_command(&self.command, document, source.clone().into_bytes()).await {
+            Ok(output) => output,
+            Err(e) => {
+                tracing::warn!("Failed to draw a graph in {}: {e:?}", document.display());
+                return None;
+            },
+        };
+        // the xml declaration and doctype can't go inside a page
+        let svg = output.find("<svg").map(|start| output[start..].to_string())?;
+        if let Ok(mut lock) = self.cache.write() {
+            if lock.len() >= MAX_CACHED_GRAPHS {
+                if let Some(oldest) = lock.iter().min_by_key(|(_source, (drawn_at, _svg))| *drawn_at).map(|(source, _)| source.clone()) {
+                    lock.remove(&oldest);
+                }
+            }
+            lock.insert(source, (Instant::now(), svg.clone()));
+        }
+        Some(svg)
+    }
+}
+
+// Code blocks come out of the parser html escaped
+fn unescape(code: &str) -> String {
+    code.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
@@ -0,0 +1,144 @@
+use std::path::Path;
+use axum::http::{header, HeaderMap};
+
+use crate::toml_config::HotlinkingConfig;
+
+// Keeps other sites from embedding media served under /home. The site's
+// own pages, and links to a file followed from anywhere, still get it
+#[derive(Clone)]
+pub struct HotlinkGuard {
+    extensions: Vec<String>,
+    // Exact hosts, or `*.` followed by the domain
+    allowed_sites: Vec<String>,
+    allow_no_referer: bool,
+}
+
+impl HotlinkGuard {
+    pub fn new(config: HotlinkingConfig) -> Self {
+        HotlinkGuard {
+            extensions: config.extensions.iter()
+                .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            allowed_sites: config.allowed_sites.iter().map(|site| site.to_ascii_lowercase()).collect(),
+            allow_no_referer: config.allow_no_referer,
+        }
+    }
+
+    pub fn protects(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|ext| {
+            self.extensions.iter().any(|protected| ext.eq_ignore_ascii_case(protected))
+        })
+    }
+
+    pub fn allows(&self, headers: &HeaderMap) -> bool {
+        // following a link isn't embedding
+        if headers.get("sec-fetch-mode").is_some_and(|mode| mode == "navigate") {
+            return true;
+        }
+        let Some(referer) = headers.get(header::REFERER).and_then(|referer| referer.to_str().ok()) else {
+            return self.allow_no_referer;
+        };
+        let Some(referer_host) = host_of(referer) else {
+            return false;
+        };
+        let own_host = headers.get("x-forwarded-host")
+            .or_else(|| headers.get(header::HOST))
+            .and_then(|host| host.to_str().ok());
+        if own_host.is_some_and(|host| host.eq_ignore_ascii_case(referer_host)) {
+            return true;
+        }
+        let referer_host = referer_host.rsplit_once(':').map_or(referer_host, |(host, _port)| host).to_ascii_lowercase();
+        self.allowed_sites.iter().any(|site| match site.strip_prefix("*.") {
+            Some(domain) => referer_host == domain || referer_host.ends_with(format!(".{domain}").as_str()),
+            None => referer_host == *site,
+        })
+    }
+}
+
+// eg: "https://example.com:8080/page.md" => "example.com:8080"
+fn host_of(url: &str) -> Option<&str> {
+    let (_scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit_once('@').map_or(authority, |(_user, host)| host);
+    (!host.is_empty()).then_some(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(allowed_sites: &[&str], allow_no_referer: bool) -> HotlinkGuard {
+        HotlinkGuard::new(HotlinkingConfig {
+            extensions: vec![".JPG".to_string(), "mp4".to_string()],
+            allowed_sites: allowed_sites.iter().map(|site| site.to_string()).collect(),
+            allow_no_referer,
+        })
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_protects() {
+        let guard = guard(&[], true);
+        assert!(guard.protects(Path::new("photos/cat.jpg")));
+        assert!(guard.protects(Path::new("clips/Intro.MP4")));
+        assert!(!guard.protects(Path::new("notes/cat.md")));
+        assert!(!guard.protects(Path::new("photos/jpg")));
+    }
+
+    #[test]
+    fn test_allowed_referers() {
+        let guard = guard(&["Friend.example", "*.cdn.example"], false);
+        let own = [(header::HOST.as_str(), "notes.example.com:8080")];
+        for referer in [
+            "https://notes.example.com:8080/home/post.md",
+            "https://friend.example/embed",
+            "http://friend.example:3000/",
+            "https://cdn.example/x",
+            "https://img.cdn.example/x",
+            "https://user@friend.example/",
+        ] {
+            let mut pairs = own.to_vec();
+            pairs.push(("referer", referer));
+            assert!(guard.allows(&headers(&pairs)), "{referer} is allowed");
+        }
+        // behind a proxy, the forwarded host is the site's own
+        let proxied = headers(&[("x-forwarded-host", "notes.example.com"), ("host", "127.0.0.1:8080"), ("referer", "https://notes.example.com/home/a.md")]);
+        assert!(guard.allows(&proxied));
+    }
+
+    #[test]
+    fn test_denied_referers() {
+        let guard = guard(&["friend.example", "*.cdn.example"], false);
+        let own = [(header::HOST.as_str(), "notes.example.com")];
+        for referer in [
+            "https://elsewhere.example/page",
+            "https://friend.example.evil.example/",
+            "https://notfriend.example/",
+            "https://evilcdn.example/",
+            "https://notes.example.com.evil.example/",
+            "not a url",
+            "https:///no-host",
+        ] {
+            let mut pairs = own.to_vec();
+            pairs.push(("referer", referer));
+            assert!(!guard.allows(&headers(&pairs)), "{referer} is denied");
+        }
+    }
+
+    #[test]
+    fn test_missing_referer() {
+        let host = [(header::HOST.as_str(), "notes.example.com")];
+        assert!(guard(&[], true).allows(&headers(&host)));
+        assert!(!guard(&[], false).allows(&headers(&host)));
+        // following a link to the file is fine either way
+        let navigate = [(header::HOST.as_str(), "notes.example.com"), ("sec-fetch-mode", "navigate")];
+        assert!(guard(&[], false).allows(&headers(&navigate)));
+    }
+}
@@ -1,15 +1,43 @@
-use std::{collections::HashSet, ffi::{OsStr, OsString}, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, ffi::{OsStr, OsString}, path::{Path, PathBuf}, sync::{Arc, RwLock}};
 use indexmap::IndexMap;
 use serde::Serialize;
 use tera::Tera;
 
-use crate::{chimera_error::ChimeraError, image_size_cache::ImageSizeCache};
+use crate::{admin::AdminDashboard, analytics::PageViews, chimera_error::ChimeraError, image_size_cache::{ImageSizeCache, WidthAndHeight}};
+use crate::comments::{Comment, CommentStore};
+use crate::webmentions::{WebmentionStore, WEBMENTION_URL};
+use crate::newsletter::SUBSCRIBE_URL;
+use crate::bookmarks::{Bookmark, BookmarkStore, BOOKMARKS_URL};
+use crate::reading_history::{self, Visit};
+use crate::session;
+use crate::page_catalog::{self, PageCatalog, PageSummary};
+use crate::toml_config::{CommentsConfig, MarkupConfig};
 use crate::document_scraper::{DocumentScraper, ExternalLink, InternalLink};
-use crate::file_manager::{FileManager, PeerInfo};
+use crate::file_manager::{FileManager, FolderCache, PeerInfo};
 use crate::full_text_index::SearchResult;
+use crate::request_info::{self, RequestInfo, RequestUsage};
+use crate::site_data::SiteData;
+use crate::authors::{AuthorProfile, Authors, AUTHORS_DIR};
+use crate::series::SeriesInfo;
+use crate::calendar::{self, CalendarMonth, CALENDAR_DIR};
+use crate::tags::{self, TagInfo, TagSummary, TAGS_DIR};
+use crate::expiry::Expiry;
+use crate::asset_integrity::AssetIntegrity;
+use crate::fragment_cache::FragmentCache;
+use crate::accessibility::{LANDMARKS, SKIP_TO_CONTENT, SKIP_TO_SIDEBAR};
+use crate::social::Social;
+use crate::bibliography::{self, Bibliography, CITATION_MARKER};
+use crate::wikilinks::{self, DocumentNames, WIKILINK_MARKER};
+use crate::glossary::Glossary;
+use crate::data_files::DataFiles;
+use crate::audio_metadata::AudioMetadata;
+use crate::i18n::Messages;
+#[cfg(feature = "scripting")]
+use crate::scripting::Scripts;
 use crate::HOME_DIR;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const MAIN_MENU: &str = "main";
 
 pub struct HtmlGeneratorCfg<'a> {
     pub user_template_root: PathBuf,
@@ -18,15 +46,50 @@ pub struct HtmlGeneratorCfg<'a> {
     pub index_file: &'a str,
     pub site_lang: &'a str,
     pub highlight_style: &'a str,
+    pub math_renderer: &'a str,
+    pub messages: Messages,
     pub menu: IndexMap<String, String>,
     pub file_manager: &'a FileManager,
     pub image_size_cache: Option<ImageSizeCache>,
+    pub glossary: Option<Glossary>,
+    pub data_files: DataFiles,
+    #[cfg(feature = "scripting")]
+    pub scripts: Option<Scripts>,
+    pub page_views: Option<PageViews>,
+    pub comments: Option<CommentsConfig>,
+    pub comment_store: Option<CommentStore>,
+    pub webmention_store: Option<WebmentionStore>,
+    pub bookmark_store: Option<BookmarkStore>,
+    // Pages can show the visitor's recently read documents
+    pub reading_history: bool,
+    pub page_catalog: PageCatalog,
+    pub authors: Authors,
+    pub expiry: Expiry,
+    pub asset_integrity: AssetIntegrity,
+    pub cached_fragments: Vec<String>,
+    pub markup: MarkupConfig,
+    pub social: Social,
+    // Pages register the offline service worker
+    pub offline: bool,
+    // Documents list their accessibility warnings
+    pub dev_mode: bool,
+    pub critical_css: Option<String>,
+    // Where front matter `css:` and `js:` files are looked for, user first
+    pub web_roots: Vec<PathBuf>,
 }
 
-#[derive (Debug, Serialize)]
+#[derive (Clone, Debug, Serialize)]
 struct MenuItem {
     title: String,
     target: String,
+    active: bool,
+}
+
+#[derive (Serialize)]
+struct CommentVars<'a> {
+    #[serde(flatten)]
+    config: &'a CommentsConfig,
+    entries: Vec<Comment>,
 }
 
 pub struct HtmlGenerator {
@@ -34,9 +97,41 @@ pub struct HtmlGenerator {
     site_title: String,
     site_lang: String,
     highlight_style: String,
+    math_renderer: String,
+    messages: Messages,
     index_file: String,
     menu: Vec<MenuItem>,
     image_size_cache: Option<ImageSizeCache>,
+    glossary: Option<Glossary>,
+    #[cfg(feature = "scripting")]
+    scripts: Option<Scripts>,
+    page_views: Option<PageViews>,
+    comments: Option<CommentsConfig>,
+    comment_store: Option<CommentStore>,
+    webmention_store: Option<WebmentionStore>,
+    bookmark_store: Option<BookmarkStore>,
+    folder_cache: FolderCache,
+    document_names: DocumentNames,
+    page_catalog: PageCatalog,
+    authors: Authors,
+    expiry: Expiry,
+    critical_css: Option<String>,
+    web_roots: Vec<PathBuf>,
+    cached_fragments: Vec<String>,
+    fragment_cache: FragmentCache,
+    // Template => what it and the templates it includes or extends read of
+    // the request
+    template_usage: HashMap<String, RequestUsage>,
+    // Templates with the page view count on them, which goes stale if cached
+    shows_view_count: HashSet<String>,
+    // Templates that list other documents, eg: with pages()
+    catalog_templates: HashSet<String>,
+    // Document => what its last render read of the request
+    page_usage: Arc<RwLock<HashMap<PathBuf, RequestUsage>>>,
+    markup: MarkupConfig,
+    social: Social,
+    offline: bool,
+    dev_mode: bool,
 }
 
 impl HtmlGenerator {
@@ -45,6 +140,12 @@ impl HtmlGenerator {
     ) -> Result<HtmlGenerator, ChimeraError> {
         let mut tera = Tera::default();
         tera.autoescape_on(vec![]);
+        tera.register_function("csrf_field", session::csrf_field);
+        tera.register_function("pages", page_catalog::pages_function(cfg.page_catalog.clone()));
+        tera.register_function("sri", cfg.asset_integrity.sri_function());
+        tera.register_function("continue_reading", reading_history::continue_reading_function(cfg.reading_history));
+        tera.register_function("t", cfg.messages.t_function());
+        tera.register_function("load_data", cfg.data_files.load_data_function());
 
         let html_ext = OsString::from("html");
         let mut found = HashSet::new();
@@ -64,53 +165,214 @@ impl HtmlGenerator {
         }
         let names: Vec<_> = tera.get_template_names().collect();
         tracing::info!("Templates: {names:?}");
+        // markdown pages show the user's bookmarks
+        let user_vars: &[&str] = match cfg.bookmark_store {
+            Some(_) => &["bookmarks", "bookmarked"],
+            None => &[],
+        };
+        let mut template_usage = HashMap::new();
+        let mut shows_view_count = HashSet::new();
+        let mut catalog_templates = HashSet::new();
+        for name in names.iter() {
+            let tags = all_template_tags(&tera, name);
+            let usage = match &tags {
+                Some(tags) => tags.iter().fold(RequestUsage::default(), |usage, tag| usage.or(RequestUsage::of_tag(tag, user_vars))),
+                None => RequestUsage::ALL,
+            };
+            template_usage.insert(name.to_string(), usage);
+            if tags.as_ref().is_none_or(|tags| tags.iter().any(|tag| mentions(tag, "view_count"))) {
+                shows_view_count.insert(name.to_string());
+            }
+            let reads_catalog = |tag: &String| ["pages", "site", "fragments", "bookmarks"].iter().any(|var| mentions(tag, var));
+            if tags.is_none_or(|tags| tags.iter().any(reads_catalog)) {
+                catalog_templates.insert(name.to_string());
+            }
+        }
 
         Ok(HtmlGenerator {
             tera,
             site_title: cfg.site_title.to_owned(),
             site_lang: cfg.site_lang.to_owned(),
             highlight_style: cfg.highlight_style.to_owned(),
+            math_renderer: cfg.math_renderer.to_owned(),
+            messages: cfg.messages,
             index_file: cfg.index_file.to_string(),
             menu: cfg.menu.into_iter().map(|(title, target)| {
                 MenuItem {
                     title,
-                    target
+                    target,
+                    active: false,
                 }
             }).collect(),
-            image_size_cache: cfg.image_size_cache
+            image_size_cache: cfg.image_size_cache,
+            glossary: cfg.glossary,
+            #[cfg(feature = "scripting")]
+            scripts: cfg.scripts,
+            page_views: cfg.page_views,
+            comments: cfg.comments,
+            comment_store: cfg.comment_store,
+            webmention_store: cfg.webmention_store,
+            bookmark_store: cfg.bookmark_store,
+            folder_cache: cfg.file_manager.folder_cache(),
+            document_names: cfg.file_manager.document_names(),
+            page_catalog: cfg.page_catalog,
+            authors: cfg.authors,
+            expiry: cfg.expiry,
+            critical_css: cfg.critical_css,
+            web_roots: cfg.web_roots,
+            cached_fragments: cfg.cached_fragments,
+            fragment_cache: FragmentCache::default(),
+            template_usage,
+            shows_view_count,
+            catalog_templates,
+            page_usage: Arc::new(RwLock::new(HashMap::new())),
+            markup: cfg.markup,
+            social: cfg.social,
+            offline: cfg.offline,
+            dev_mode: cfg.dev_mode,
         })
     }
 
-    fn get_vars(&self, title: &str, has_code: bool) -> tera::Context {
+    // Configured menu items, followed by documents that asked to be included
+    fn get_menu(&self) -> Vec<MenuItem> {
+        let mut menu = self.menu.clone();
+        for (title, target) in self.page_catalog.menu_items(MAIN_MENU) {
+            if !menu.iter().any(|item| item.target == target) {
+                menu.push(MenuItem {
+                    title,
+                    target,
+                    active: false,
+                });
+            }
+        }
+        menu
+    }
+
+    // current_url is the page being rendered, used to mark the active menu item
+    fn get_vars(&self, title: &str, has_code: bool, current_url: Option<&str>) -> tera::Context {
         let mut vars = tera::Context::new();
         vars.insert("title", title);
         vars.insert("site_title", self.site_title.as_str());
         vars.insert("site_lang", self.site_lang.as_str());
         vars.insert("highlight_style", self.highlight_style.as_str());
+        vars.insert("math_renderer", self.math_renderer.as_str());
+        // for t(), overridden by pages that know the visitor's language
+        vars.insert("lang", self.messages.default_lang());
         vars.insert("has_code", &has_code);
         vars.insert("version", VERSION);
-        vars.insert("menu", &self.menu);
+        vars.insert("landmarks", &LANDMARKS);
+        vars.insert("skip_links", &[SKIP_TO_CONTENT]);
+        if self.offline {
+            vars.insert("offline", &true);
+        }
+        if self.webmention_store.is_some() {
+            vars.insert("webmention_endpoint", WEBMENTION_URL);
+        }
+        if let Some(critical_css) = &self.critical_css {
+            vars.insert("critical_css", critical_css);
+        }
+        let mut menu = self.get_menu();
+        if let Some(current_url) = current_url {
+            mark_active_menu_item(&mut menu, current_url);
+            vars.insert("current_path", current_url);
+        }
+        vars.insert("menu", &menu);
+        if !self.cached_fragments.is_empty() {
+            vars.insert("fragments", &self.get_fragments());
+        }
         vars
     }
 
-    pub fn gen_search(&self, query: &str, results: Vec<SearchResult>) -> Result<String, ChimeraError> {
+    // The visitor's language, when it isn't the site's
+    fn set_lang(&self, vars: &mut tera::Context, lang: Option<&str>) {
+        if let Some(lang) = lang {
+            vars.insert("lang", lang);
+        }
+    }
+
+    // Extra stylesheets or scripts a document asks for in its front matter,
+    // eg: `css: [dashboard.css]`. Only files of that type in a web root are
+    // linked, by their URL there
+    fn page_assets(&self, scraper: &DocumentScraper, kind: &str) -> Vec<String> {
+        let files = match (scraper.metadata_lists.get(kind), scraper.metadata.get(kind)) {
+            (Some(files), _) => files.clone(),
+            (None, Some(file)) => vec![file.clone()],
+            (None, None) => return Vec::new(),
+        };
+        files.iter().filter_map(|file| {
+            let relative = Path::new(file.trim().trim_start_matches('/'));
+            let valid = relative.extension().is_some_and(|ext| ext == kind)
+                && relative.components().all(|part| matches!(part, std::path::Component::Normal(_)));
+            if !valid || !self.web_roots.iter().any(|root| root.join(relative).is_file()) {
+                tracing::warn!("Ignoring {kind}: {file}, it isn't a .{kind} file in the web root");
+                return None;
+            }
+            Some(format!("/{}", relative.to_string_lossy().replace('\\', "/")))
+        }).collect()
+    }
+
+    // A document's own `bibliography:`, or the closest one set by a folder's
+    // index. Either is relative to where it's declared
+    fn find_bibliography(&self, path: &Path, scraper: &DocumentScraper) -> Option<Bibliography> {
+        let folder = path.parent().unwrap_or(Path::new(""));
+        if let Some(file) = scraper.metadata.get("bibliography") {
+            return Bibliography::load(folder.join(file).as_path());
+        }
+        folder.ancestors().find_map(|folder| {
+            let file = self.folder_cache.get(folder)?.bibliography?;
+            Bibliography::load(folder.join(file).as_path())
+        })
+    }
+
+    // Rendered cached_fragments, by template name. They see the site data
+    // but nothing about the page they end up in
+    fn get_fragments(&self) -> HashMap<&str, String> {
+        let generation = self.page_catalog.generation();
+        let mut site = None;
+        self.cached_fragments.iter().map(|name| {
+            let html = self.fragment_cache.get(name, generation).unwrap_or_else(|| {
+                let site = site.get_or_insert_with(|| SiteData::from_catalog(&self.page_catalog));
+                let mut vars = tera::Context::new();
+                vars.insert("site_title", self.site_title.as_str());
+                vars.insert("site_lang", self.site_lang.as_str());
+                vars.insert("version", VERSION);
+                vars.insert("site", site);
+                let html = self.tera.render(name, &vars).unwrap_or_else(|e| {
+                    tracing::warn!("Error rendering fragment {name}: {e:?}");
+                    String::new()
+                });
+                self.fragment_cache.insert(name, generation, html.as_str());
+                html
+            });
+            (name.as_str(), html)
+        }).collect()
+    }
+
+    pub fn gen_search(&self, query: &str, results: Vec<SearchResult>, warnings: Vec<String>, lang: Option<&str>) -> Result<String, ChimeraError> {
         tracing::debug!("Got {} search results", results.len());
-        let title = format!("{}: Search results", self.site_title);
-        let mut vars = self.get_vars(title.as_str(), false);
+        let heading = self.messages.get(lang, "search.title_for").replace("{query}", tera::escape_html(query).as_str());
+        let title = format!("{}: {heading}", self.site_title);
+        let mut vars = self.get_vars(title.as_str(), false, Some("/search"));
+        self.set_lang(&mut vars, lang);
         vars.insert("query", query);
         vars.insert("placeholder", query);
+        vars.insert("search_url", format!("/search?query={}", urlencoding::encode(query)).as_str());
         if !results.is_empty() {
             vars.insert("results", &results);
         }
+        if !warnings.is_empty() {
+            vars.insert("warnings", &warnings);
+        }
         Ok(self.tera.render("search.html", &vars)?)
     }
 
-    pub fn gen_search_blank(&self) -> Result<String, ChimeraError> {
+    pub fn gen_search_blank(&self, lang: Option<&str>) -> Result<String, ChimeraError> {
         tracing::debug!("No query, generating blank search page");
-        let title = format!("{}: Search results", self.site_title);
-        let mut vars = self.get_vars(title.as_str(), false);
+        let title = format!("{}: {}", self.site_title, self.messages.get(lang, "search.title"));
+        let mut vars = self.get_vars(title.as_str(), false, Some("/search"));
+        self.set_lang(&mut vars, lang);
         vars.insert("query", "");
-        vars.insert("placeholder", "Search...");
+        vars.insert("placeholder", self.messages.get(lang, "search.placeholder"));
         Ok(self.tera.render("search.html", &vars)?)
     }
 
@@ -119,131 +381,877 @@ impl HtmlGenerator {
         path: &std::path::Path,
         body: String,
         scraper: DocumentScraper,
-        peers: Option<PeerInfo>,
+        mut peers: Option<PeerInfo>,
+        request: &RequestInfo,
     ) -> Result<String, ChimeraError> {
-        let html_content = self.add_anchors_to_headings(body, &scraper.internal_links, !scraper.starts_with_heading);
+        let bibliography = match body.contains(CITATION_MARKER) {
+            true => self.find_bibliography(path, &scraper),
+            false => None,
+        };
+        let (html_content, doclinks) = finish_body(body, &scraper, &BodyOptions {
+            markup: &self.markup,
+            image_size_cache: self.image_size_cache.as_ref(),
+            document: path,
+            glossary: self.glossary.as_ref(),
+            bibliography,
+            document_names: Some(&self.document_names),
+            rewrite_links: true,
+            table_page: request.table_page.unwrap_or(1),
+            query: request.query.as_str(),
+        });
         let template = scraper.get_template();
-        let title = scraper.title.as_ref().cloned().unwrap_or_else(|| {
+        if let Ok(mut page_usage) = self.page_usage.write() {
+            page_usage.insert(path.to_path_buf(), self.template_request_usage(template));
+        }
+        let page_title = scraper.page_title().cloned().unwrap_or_else(|| {
             match path.file_name() {
                 Some(name) => name,
                 None => path.as_os_str(),
             }.to_string_lossy().into_owned()
         });
-        let breadcrumbs = get_breadcrumbs(path, self.index_file.as_str());
-        let title = format!("{}: {}", self.site_title, title);
+        let mut breadcrumbs = get_breadcrumbs(path, self.index_file.as_str(), &self.folder_cache);
+        if path.file_name().is_some_and(|name| name != self.index_file.as_str()) {
+            if let Some(last) = breadcrumbs.last_mut() {
+                last.name = page_title.clone();
+            }
+        }
+        let title = format!("{}: {}", self.site_title, page_title);
 
-        let mut vars = self.get_vars(title.as_str(), scraper.has_code_blocks);
+        let url = format!("{HOME_DIR}/{}", &path.to_string_lossy());
+        if let (Some(peers), Some(file_name)) = (peers.as_mut(), path.file_name()) {
+            let file_url = urlencoding::encode(&file_name.to_string_lossy()).into_owned();
+            peers.files.iter_mut().for_each(|file| file.active = file.url == file_url);
+        }
+
+        let mut vars = self.get_vars(title.as_str(), scraper.has_code_blocks, Some(url.as_str()));
+        self.set_lang(&mut vars, request.lang.as_deref());
+        vars.insert("page_title", page_title.as_str());
+        vars.insert("description", &scraper.description());
+        vars.insert("social", &self.social.card(&scraper, page_title.as_str(), url.as_str()));
         vars.insert("body", html_content.as_str());
-        vars.insert("doclinks", &scraper.internal_links);
+        vars.insert("doclinks", &doclinks);
+        vars.insert("skip_links", &[SKIP_TO_CONTENT, SKIP_TO_SIDEBAR]);
         vars.insert("peers", &peers);
         vars.insert("code_languages", &scraper.code_languages);
+        vars.insert("has_tasklists", &scraper.has_tasklists);
+        vars.insert("has_math", &scraper.has_math);
+        vars.insert("has_mermaid", &scraper.has_mermaid);
+        vars.insert("callout_types", &scraper.callout_types);
+        if !scraper.board.is_empty() {
+            vars.insert("board", &scraper.board);
+        }
+        if self.dev_mode && !scraper.outline_warnings.is_empty() {
+            vars.insert("a11y_warnings", &scraper.outline_warnings);
+        }
         vars.insert("breadcrumbs", &breadcrumbs);
-        vars.insert("url", format!("{HOME_DIR}/{}", &path.to_string_lossy()).as_str());
+        vars.insert("url", url.as_str());
+        vars.insert("request", request);
+        if let Some(page_views) = &self.page_views {
+            vars.insert("view_count", &page_views.view_count(url.as_str()));
+        }
+        if let Some(comments) = &self.comments {
+            let entries = match &self.comment_store {
+                Some(store) => store.approved_for(url.as_str()),
+                None => Vec::new(),
+            };
+            vars.insert("comments", &CommentVars {
+                config: comments,
+                entries,
+            });
+        }
+        if let Some(store) = &self.webmention_store {
+            vars.insert("webmentions", &store.approved_for(url.as_str()));
+        }
+        if let (Some(store), Some(user)) = (&self.bookmark_store, &request.user) {
+            let bookmarks = self.bookmark_titles(store.for_user(user.as_str()));
+            let page_url = page_catalog::page_url(path.to_string_lossy().as_ref());
+            vars.insert("bookmarked", &bookmarks.iter().any(|bookmark| bookmark.url == page_url));
+            vars.insert("bookmarks", &bookmarks);
+        }
 
         for (key, value) in &scraper.metadata {
-            vars.insert(key, value);
+            // the page title is already in, with the site title added
+            if key != "title" {
+                vars.insert(key, value);
+            }
+        }
+        for (key, values) in &scraper.metadata_lists {
+            vars.insert(key, values);
+        }
+        let tag_links: Vec<_> = tags::document_tags(&scraper).iter().map(|tag| tags::tag_link(tag)).collect();
+        if !tag_links.is_empty() {
+            vars.insert("tag_links", &tag_links);
+        }
+        vars.insert("page_css", &self.page_assets(&scraper, "css"));
+        vars.insert("page_js", &self.page_assets(&scraper, "js"));
+        if let Some(series) = scraper.metadata.get("series") {
+            if let Some(series) = SeriesInfo::build(&self.page_catalog, series.as_str(), Some(url.as_str())) {
+                vars.insert("series_info", &series);
+            }
         }
+        if let Some(outdated) = self.expiry.outdated(&scraper.metadata) {
+            vars.insert("outdated", &outdated);
+        }
+        if let Some(author) = scraper.metadata.get("author") {
+            vars.insert("author_info", &self.authors.profile_for(author));
+        }
+        self.add_listing(&mut vars, &peers);
 
+        #[cfg(feature = "scripting")]
+        if let Some(scripts) = &self.scripts {
+            let vars = scripts.transform_context(path, vars, request);
+            let html = self.tera.render(template, &vars)?;
+            return Ok(scripts.transform_html(path, html, request));
+        }
         let html = self.tera.render(template, &vars)?;
         Ok(html)
     }
 
-    pub fn gen_error(&self, error_code: &str, heading: &str, message: &str) -> Result<String, ChimeraError> {
+    // The heading and message are the error.<name>.* messages
+    pub fn gen_error(&self, error_code: &str, name: &str) -> Result<String, ChimeraError> {
         let title = format!("{}: Error", self.site_title);
-        let mut vars = self.get_vars(title.as_str(), false);
+        let mut vars = self.get_vars(title.as_str(), false, None);
         vars.insert("error_code", error_code);
-        vars.insert("heading", heading);
-        vars.insert("message", message);
+        vars.insert("heading", self.messages.get(None, format!("error.{name}.heading").as_str()));
+        vars.insert("message", self.messages.get(None, format!("error.{name}.message").as_str()));
         let html = self.tera.render("error.html", &vars)?;
         Ok(html)
     }
 
-    pub async fn gen_index(&self, path: &Path, peers: Option<PeerInfo>) -> Result<String, ChimeraError> {
-        let breadcrumbs = get_breadcrumbs(path, self.index_file.as_str());
-        let path_os_str = path.iter().last().unwrap_or(path.as_os_str());
+    pub fn gen_offline(&self) -> Result<String, ChimeraError> {
+        let title = format!("{}: Offline", self.site_title);
+        let vars = self.get_vars(title.as_str(), false, None);
+        let html = self.tera.render("offline.html", &vars)?;
+        Ok(html)
+    }
+
+    // status is set after subscribing, action when following an emailed link
+    pub fn gen_subscribe(&self, status: Option<&str>, action: Option<&str>, token: Option<&str>) -> Result<String, ChimeraError> {
+        let title = format!("{}: Subscribe", self.site_title);
+        let mut vars = self.get_vars(title.as_str(), false, Some(SUBSCRIBE_URL));
+        vars.insert("status", &status);
+        vars.insert("action", &action);
+        vars.insert("token", &token);
+        let html = self.tera.render("subscribe.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_admin(&self, dashboard: &AdminDashboard) -> Result<String, ChimeraError> {
+        let title = format!("{}: Admin", self.site_title);
+        let mut vars = self.get_vars(title.as_str(), false, Some("/admin"));
+        vars.extend(tera::Context::from_serialize(dashboard)?);
+        let html = self.tera.render("admin.html", &vars)?;
+        Ok(html)
+    }
+
+    // Filled in per visitor, after the page comes out of the cache
+    pub fn gen_continue_reading(&self, visits: Vec<Visit>, lang: Option<&str>) -> Result<String, ChimeraError> {
+        let mut vars = tera::Context::new();
+        vars.insert("lang", lang.unwrap_or(self.messages.default_lang()));
+        vars.insert("visits", &visits);
+        let html = self.tera.render("continue-reading.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_bookmarks(&self, user: &str, bookmarks: Vec<Bookmark>) -> Result<String, ChimeraError> {
+        let title = format!("{}: Bookmarks", self.site_title);
+        let mut vars = self.get_vars(title.as_str(), false, Some(BOOKMARKS_URL));
+        vars.insert("user", user);
+        vars.insert("bookmarks", &self.bookmark_titles(bookmarks));
+        let html = self.tera.render("bookmarks.html", &vars)?;
+        Ok(html)
+    }
+
+    // Documents may have been retitled since they were starred
+    fn bookmark_titles(&self, mut bookmarks: Vec<Bookmark>) -> Vec<Bookmark> {
+        for bookmark in bookmarks.iter_mut() {
+            if let Some(page) = self.page_catalog.page(bookmark.url.as_str()) {
+                bookmark.title = page.title;
+            }
+        }
+        bookmarks
+    }
+
+    pub fn gen_authors(&self, authors: Vec<AuthorProfile>) -> Result<String, ChimeraError> {
+        let title = format!("{}: Authors", self.site_title);
+        let mut vars = self.get_vars(title.as_str(), false, Some(AUTHORS_DIR));
+        vars.insert("authors", &authors);
+        let html = self.tera.render("authors.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_author(&self, author: &AuthorProfile, pages: Vec<PageSummary>) -> Result<String, ChimeraError> {
+        let title = format!("{}: {}", self.site_title, author.name);
+        let url = author.url.as_deref().unwrap_or(AUTHORS_DIR);
+        let mut vars = self.get_vars(title.as_str(), false, Some(url));
+        vars.insert("author", author);
+        vars.insert("pages", &pages);
+        let html = self.tera.render("author.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_tags(&self, tags: &[TagSummary], lang: Option<&str>) -> Result<String, ChimeraError> {
+        let title = format!("{}: {}", self.site_title, self.messages.get(lang, "tags.heading"));
+        let mut vars = self.get_vars(title.as_str(), false, Some(TAGS_DIR));
+        self.set_lang(&mut vars, lang);
+        vars.insert("tags", tags);
+        let html = self.tera.render("tags.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_tag(&self, tag: &TagInfo, lang: Option<&str>) -> Result<String, ChimeraError> {
+        let title = format!("{}: {}", self.site_title, tag.name);
+        let mut vars = self.get_vars(title.as_str(), false, Some(tag.url.as_str()));
+        self.set_lang(&mut vars, lang);
+        vars.insert("tag", tag);
+        let html = self.tera.render("tags.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_calendar(&self, month: &CalendarMonth, lang: Option<&str>) -> Result<String, ChimeraError> {
+        // twelve names, and seven, separated by spaces
+        let month_name = self.messages.get(lang, "calendar.months").split_whitespace()
+            .nth(month.month as usize - 1)
+            .map_or_else(|| month.month.to_string(), str::to_string);
+        let weekdays: Vec<&str> = self.messages.get(lang, "calendar.weekdays").split_whitespace().collect();
+        let heading = format!("{month_name} {}", month.year);
+        let title = format!("{}: {heading}", self.site_title);
+        let url = format!("{CALENDAR_DIR}/{}", month.folder);
+        let mut vars = self.get_vars(title.as_str(), false, Some(url.as_str()));
+        self.set_lang(&mut vars, lang);
+        vars.insert("calendar", month);
+        vars.insert("heading", heading.as_str());
+        vars.insert("weekdays", &weekdays);
+        let html = self.tera.render("calendar.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn gen_series(&self, series: &SeriesInfo) -> Result<String, ChimeraError> {
+        let title = format!("{}: {}", self.site_title, series.name);
+        let mut vars = self.get_vars(title.as_str(), false, Some(series.url.as_str()));
+        vars.insert("series", series);
+        let html = self.tera.render("series.html", &vars)?;
+        Ok(html)
+    }
+
+    // A player page for an audio file. The file itself is at its own URL
+    // with ?raw added
+    pub fn gen_audio(&self, path: &Path, metadata: &AudioMetadata, lang: Option<&str>) -> Result<String, ChimeraError> {
+        let file_name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+        let page_title = metadata.title.clone().unwrap_or_else(|| file_name.clone());
+        let mut breadcrumbs = get_breadcrumbs(path, self.index_file.as_str(), &self.folder_cache);
+        if let Some(last) = breadcrumbs.last_mut() {
+            last.name = page_title.clone();
+        }
+        let title = format!("{}: {}", self.site_title, page_title);
+        let url = format!("{HOME_DIR}/{}", &path.to_string_lossy());
+        let mut vars = self.get_vars(title.as_str(), false, Some(url.as_str()));
+        self.set_lang(&mut vars, lang);
+        vars.insert("page_title", page_title.as_str());
+        vars.insert("file_name", file_name.as_str());
+        vars.insert("audio_url", format!("{}?raw", urlencoding::encode(file_name.as_str())).as_str());
+        vars.insert("audio", metadata);
+        vars.insert("breadcrumbs", &breadcrumbs);
+        vars.insert("url", url.as_str());
+        let html = self.tera.render("audio.html", &vars)?;
+        Ok(html)
+    }
+
+    pub fn shows_view_count(&self, template: &str) -> bool {
+        self.page_views.is_some() && self.shows_view_count.contains(template)
+    }
+
+    // Whether the document's render shows anything from the page catalog,
+    // so goes stale when another document changes. The menu is left out,
+    // as a change to it clears every page anyway
+    pub fn renders_from_catalog(&self, scraper: &DocumentScraper) -> bool {
+        self.catalog_templates.contains(scraper.get_template()) || scraper.metadata.contains_key("series")
+    }
+
+    pub fn template_request_usage(&self, template: &str) -> RequestUsage {
+        self.template_usage.get(template).copied().unwrap_or(RequestUsage::ALL)
+    }
+
+    // What a document's render reads of the request, going by the template
+    // it used last time. Scripts are handed the whole request
+    pub fn page_request_usage(&self, path: &Path) -> RequestUsage {
+        #[cfg(feature = "scripting")]
+        if self.scripts.as_ref().is_some_and(|scripts| scripts.applies_to(path)) {
+            return RequestUsage::ALL;
+        }
+        self.page_usage.read().ok()
+            .and_then(|page_usage| page_usage.get(path).copied())
+            .unwrap_or(RequestUsage::ALL)
+    }
+
+    pub fn gen_dynamic_page(&self, template: &str, site: &SiteData, request: &RequestInfo) -> Result<String, ChimeraError> {
+        let name = request.path.rsplit('/').next().unwrap_or_default();
+        let title = format!("{}: {}", self.site_title, urlencoding::decode(name).unwrap_or_default());
+        let mut vars = self.get_vars(title.as_str(), false, Some(request.path.as_str()));
+        vars.insert("url", request.path.as_str());
+        vars.insert("request", request);
+        vars.insert("site", site);
+        let html = self.tera.render(template, &vars)?;
+        Ok(html)
+    }
+
+    pub async fn gen_index(&self, path: &Path, peers: Option<PeerInfo>, lang: Option<&str>) -> Result<String, ChimeraError> {
+        let breadcrumbs = get_breadcrumbs(path, self.index_file.as_str(), &self.folder_cache);
+        let path_os_str = path.iter().next_back().unwrap_or(path.as_os_str());
         let path_str = path_os_str.to_string_lossy().to_string();
         let title = format!("{}: {}", self.site_title, path_str);
-        let mut vars = self.get_vars(title.as_str(), false);
+        let url = format!("{HOME_DIR}/{}", path.to_string_lossy());
+        let mut vars = self.get_vars(title.as_str(), false, Some(url.as_str()));
+        self.set_lang(&mut vars, lang);
         vars.insert("path", path_str.as_str());
         vars.insert("breadcrumbs", &breadcrumbs);
-        let doclinks = vec![InternalLink::new("contents".to_string(), "Contents".to_string(), 2)];
+        let doclinks = vec![InternalLink::new("contents".to_string(), self.messages.get(lang, "index.contents").to_string(), 2)];
         vars.insert("doclinks", &doclinks);
         vars.insert("peers", &peers);
+        // the documents' descriptions, which the sidebar has no room for
+        vars.insert("file_descriptions", &true);
         vars.insert("body", "");
+        if let Some(calendar_url) = peers.as_ref().and_then(|peers| calendar::calendar_url(path, peers)) {
+            vars.insert("calendar_url", calendar_url.as_str());
+        }
+        self.add_listing(&mut vars, &peers);
         let html = self.tera.render("index.html", &vars)?;
         Ok(html)
     }
 
-    fn add_anchors_to_headings(&self, original_html: String, links: &[InternalLink], inserted_top: bool) -> String {
-        let start_index = if inserted_top { 1 } else { 0 };
-        let num_links = links.len();
-        if num_links == start_index {
-            return original_html;
-        }
-        //tracing::info!("Image sizes: {:?}", self.image_size_cache);
-        let mut link_index = start_index;
-        let mut new_html = String::with_capacity(original_html.len() * 11 / 10);
-        let mut char_iter = original_html.char_indices();
-        while let Some((i, c)) = char_iter.next() {
-            if c == '<' {
-                if let Some(open_slice) = original_html.get(i..i+4) {
-                    let mut slice_it = open_slice.chars().skip(1);
-                    let tag_start = slice_it.next();
-                    match tag_start {
-                        Some('h') => {
-                            if let Some(heading_size) = slice_it.next() {
-                                if link_index < links.len() && slice_it.next() == Some('>') {
-                                    let anchor = links[link_index].anchor.as_str();
-                                    tracing::debug!("Rewriting anchor: {anchor}");
-                                    new_html.push_str(format!("<h{heading_size} id=\"{anchor}\">").as_str());
-                                    link_index += 1;
-                                    // advance outer iterator
-                                    let _ = char_iter.nth(open_slice.len()-2);
-                                    continue;
-                                }
-                                else if slice_it.next() == Some(' ') {
-                                    // already has an id?
-                                    link_index += 1;
-                                }
-                            }
-                        },
-                        Some('i') => {
-                            if let Some(image_size_cache) = &self.image_size_cache {
-                                if slice_it.next() == Some('m') && slice_it.next() == Some('g') {
-                                    tracing::debug!("<img");
-                                    let mut consume = 5;
-                                    let forward = &original_html[i+consume..];
-                                    let mut parts = forward.split('\"');
-                                    let src_tag = "src=";
-                                    if parts.next() == Some(src_tag) {
-                                        consume += src_tag.len();
-                                        if let Some(img_src) = parts.next() {
-                                            tracing::debug!("Found img tag \"{img_src}\"");
-                                            if let Some(dim) = image_size_cache.get_dimensions(img_src) {
-                                                tracing::debug!("Rewriting img tag \"{img_src}\"");
-                                                new_html.push_str(format!("<img src=\"{img_src}\" width=\"{}\" height = \"{}\"", dim.width, dim.height).as_str());
-                                                consume += img_src.len();
-                                                // advance outer iterator
-                                                let _ = char_iter.nth(consume);
-                                                continue;
-                                            }
-                                        }
+    // A folder can ask for its contents to be listed with its own template.
+    // peers.html shows the result instead of the default list
+    fn add_listing(&self, vars: &mut tera::Context, peers: &Option<PeerInfo>) {
+        let template = peers.as_ref()
+            .and_then(|peers| peers.folder.as_ref())
+            .and_then(|folder| folder.listing_template.as_deref());
+        if let Some(template) = template {
+            // fall back to the default listing rather than failing the page
+            match self.tera.render(template, vars) {
+                Ok(listing) => vars.insert("listing", listing.as_str()),
+                Err(e) => tracing::warn!("Failed to render listing template {template}: {e:?}"),
+            }
+        }
+    }
+}
+
+// What shapes a document body, other than the markdown itself
+pub struct BodyOptions<'a> {
+    pub markup: &'a MarkupConfig,
+    pub image_size_cache: Option<&'a ImageSizeCache>,
+    // Relative to the document root, for resolving relative image srcs
+    pub document: &'a Path,
+    pub glossary: Option<&'a Glossary>,
+    // Only needed when the body has citations, see CITATION_MARKER
+    pub bibliography: Option<Bibliography>,
+    // For resolving [[wikilinks]], which stay text without it
+    pub document_names: Option<&'a DocumentNames>,
+    // Links to other documents become /home URLs, as the server has them
+    pub rewrite_links: bool,
+    // Of long tables, 1-based
+    pub table_page: usize,
+    // Other parameters for the table page links
+    pub query: &'a str,
+}
+
+// Turns the parser's html into the body that goes in the page, along with
+// the table of contents for it
+pub fn finish_body(body: String, scraper: &DocumentScraper, options: &BodyOptions) -> (String, Vec<InternalLink>) {
+    let mut html_content = body;
+    if let Some(image_size_cache) = options.image_size_cache.filter(|_| !scraper.images.is_empty()) {
+        let sizes = image_size_cache.get_dimensions(options.document, &scraper.images);
+        if !sizes.is_empty() {
+            html_content = add_image_sizes(html_content.as_str(), &sizes);
+        }
+    }
+    if options.markup.figures && html_content.contains("<p><img ") {
+        html_content = wrap_figures(html_content.as_str());
+    }
+    if scraper.has_mermaid {
+        html_content = wrap_mermaid(html_content.as_str());
+    }
+    let mut html_content = add_anchors_to_headings(
+        html_content,
+        &scraper.internal_links,
+        !scraper.starts_with_heading,
+        options.markup,
+    );
+    if html_content.contains("<table>") {
+        let paging = options.markup.table_page_rows.map(|rows| TablePaging {
+            rows,
+            page: options.table_page,
+            query: options.query,
+        });
+        html_content = enhance_tables(html_content.as_str(), paging);
+    }
+    if options.rewrite_links && html_content.contains(".md") {
+        html_content = rewrite_document_links(html_content.as_str(), options.document);
+    }
+    if html_content.contains(WIKILINK_MARKER) {
+        html_content = wikilinks::resolve(html_content.as_str(), options.document, options.document_names);
+    }
+    let mut doclinks = scraper.internal_links.clone();
+    if let Some(bibliography) = &options.bibliography {
+        let (cited_html, cited) = bibliography.cite(html_content.as_str());
+        html_content = cited_html;
+        if !cited.is_empty() {
+            html_content.push_str(bibliography::references_section(&cited).as_str());
+            let level = if doclinks.is_empty() { 1 } else { 2 };
+            doclinks.push(InternalLink::new("references".to_string(), "References".to_string(), level));
+        }
+    }
+    if let Some(glossary) = options.glossary {
+        if scraper.metadata.get("glossary").is_none_or(|glossary| glossary != "false") {
+            html_content = glossary.apply(html_content.as_str());
+        }
+    }
+    if options.markup.hide_repeated_title && scraper.repeats_title() {
+        if let Some(trimmed) = remove_opening_heading(html_content.as_str()) {
+            html_content = trimmed;
+            // the heading's anchor is gone, but the top of the page is where it was
+            if let Some(first) = doclinks.first_mut() {
+                first.anchor = "top".to_string();
+            }
+        }
+    }
+    if TOC_MARKERS.iter().any(|marker| html_content.contains(marker)) {
+        let toc = inline_toc(&doclinks);
+        for marker in TOC_MARKERS {
+            html_content = html_content.replace(marker, toc.as_str());
+        }
+    }
+    (html_content, doclinks)
+}
+
+// Links between documents as local editors write them, relative to the
+// file, eg: ../guide/setup.md or <My Notes.md>, or to the document root, as
+// /guide/setup.md. They're made /home URLs, encoded, so they work wherever
+// the page is served from. Backslashes from Windows editors are separators
+fn rewrite_document_links(html: &str, document: &Path) -> String {
+    const HREF: &str = "<a href=\"";
+    let mut new_html = String::with_capacity(html.len() + html.len() / 20);
+    let mut rest = html;
+    while let Some(start) = rest.find(HREF) {
+        let url_start = start + HREF.len();
+        let Some(url_len) = rest[url_start..].find('"') else {
+            break;
+        };
+        let url = &rest[url_start..url_start + url_len];
+        new_html.push_str(&rest[..url_start]);
+        match document_url(url, document) {
+            Some(new_url) => new_html.push_str(new_url.as_str()),
+            None => new_html.push_str(url),
+        }
+        rest = &rest[url_start + url_len..];
+    }
+    new_html.push_str(rest);
+    new_html
+}
+
+fn document_url(href: &str, document: &Path) -> Option<String> {
+    let (path, suffix) = href.split_at(href.find(['?', '#']).unwrap_or(href.len()));
+    if path.contains(':') || path.starts_with("//") || path.starts_with(format!("{HOME_DIR}/").as_str()) {
+        return None;
+    }
+    let path = urlencoding::decode(path).ok()?.replace('\\', "/");
+    if !path.to_ascii_lowercase().ends_with(".md") {
+        return None;
+    }
+    let (mut parts, relative): (Vec<String>, &str) = match path.strip_prefix('/') {
+        Some(relative) => (Vec::new(), relative),
+        None => (
+            document.parent().unwrap_or(Path::new("")).iter().map(|part| part.to_string_lossy().into_owned()).collect(),
+            path.as_str(),
+        ),
+    };
+    for part in relative.split('/') {
+        match part {
+            "" | "." => {},
+            // not above the document root
+            ".." => {
+                parts.pop()?;
+            },
+            part => parts.push(part.to_string()),
+        }
+    }
+    let url: Vec<String> = parts.iter().map(|part| urlencoding::encode(part).into_owned()).collect();
+    Some(format!("{HOME_DIR}/{}{suffix}", url.join("/")))
+}
+
+// Paragraphs asking for the table of contents in the body itself, where
+// it prints, unlike the sidebar's
+const TOC_MARKERS: [&str; 4] = ["<p>[TOC]</p>", "<p>[toc]</p>", "<p>{{toc}}</p>", "<p>{{TOC}}</p>"];
+
+// The doclinks as nested lists, less the top of the page, empty headings
+// and a title heading everything else would sit under
+fn inline_toc(doclinks: &[InternalLink]) -> String {
+    let mut links: Vec<&InternalLink> = doclinks.iter()
+        .filter(|link| link.anchor != "top" && !link.anchor.is_empty() && !link.name.trim().is_empty())
+        .collect();
+    if links.first().is_some_and(|first| first.level == 1) && links.iter().filter(|link| link.level == 1).count() == 1 {
+        links.remove(0);
+    }
+    let Some(base) = links.iter().map(|link| link.level).min() else {
+        return String::new();
+    };
+    let mut html = String::from("<div class=\"toc\">\n<ul>\n");
+    let mut depth: Option<usize> = None;
+    for link in links {
+        // a level at a time, so every list sits in an item
+        let level = match depth {
+            None => 0,
+            Some(depth) => ((link.level - base) as usize).min(depth + 1),
+        };
+        match depth {
+            None => {},
+            Some(depth) if level > depth => html.push_str("\n<ul>\n"),
+            Some(depth) => {
+                html.push_str("</li>\n");
+                for _ in level..depth {
+                    html.push_str("</ul>\n</li>\n");
+                }
+            },
+        }
+        html.push_str(format!(
+            "<li><a href=\"#{}\">{}</a>",
+            tera::escape_html(link.anchor.as_str()),
+            tera::escape_html(link.name.as_str()),
+        ).as_str());
+        depth = Some(level);
+    }
+    if let Some(depth) = depth {
+        html.push_str("</li>\n");
+        for _ in 0..depth {
+            html.push_str("</ul>\n</li>\n");
+        }
+    }
+    html.push_str("</ul>\n</div>\n");
+    html
+}
+
+pub(crate) fn add_anchors_to_headings(
+    original_html: String,
+    links: &[InternalLink],
+    inserted_top: bool,
+    markup: &MarkupConfig,
+) -> String {
+    let start_index = if inserted_top { 1 } else { 0 };
+    let num_links = links.len();
+    if num_links == start_index && !markup.code_copy_buttons {
+        return original_html;
+    }
+    let mut link_index = start_index;
+    let mut new_html = String::with_capacity(original_html.len() * 11 / 10);
+    let mut char_iter = original_html.char_indices();
+    while let Some((i, c)) = char_iter.next() {
+        if c == '<' {
+            if let Some(open_slice) = original_html.get(i..i+4) {
+                let mut slice_it = open_slice.chars().skip(1);
+                let tag_start = slice_it.next();
+                match tag_start {
+                    Some('h') => {
+                        let heading_size = slice_it.next().filter(|size| ('1'..='6').contains(size));
+                        if let Some(heading_size) = heading_size.filter(|_| link_index < links.len()) {
+                            let anchor = links[link_index].anchor.as_str();
+                            let open_tag = match slice_it.next() {
+                                Some('>') => Some(format!("<h{heading_size} id=\"{anchor}\">")),
+                                // heading attributes or html, eg: <h2 id="setup" class="wide">
+                                // raw html headings without an id weren't recorded, so the
+                                // tag is only ours if its id or its text says so
+                                Some(' ') => original_html[i..].find('>').and_then(|end| {
+                                    let tag = &original_html[i..end + i + 1];
+                                    match tag_id(tag) {
+                                        Some(id) => (id == anchor).then(|| tag.to_string()),
+                                        None => heading_text_matches(&original_html[end + i + 1..], heading_size, links[link_index].name.as_str())
+                                            .then(|| format!("<h{heading_size} id=\"{anchor}\"{}", &tag[3..])),
                                     }
+                                }),
+                                _ => None,
+                            };
+                            if let Some(open_tag) = open_tag {
+                                // an empty heading has nothing to link to
+                                let tag_len = original_html[i..].find('>').unwrap_or_default();
+                                match anchor.is_empty() {
+                                    true => new_html.push_str(&original_html[i..=i + tag_len]),
+                                    false => {
+                                        tracing::debug!("Rewriting anchor: {anchor}");
+                                        new_html.push_str(open_tag.as_str());
+                                    },
+                                }
+                                if markup.heading_permalinks && !anchor.is_empty() {
+                                    new_html.push_str(format!("<a class=\"permalink\" href=\"#{anchor}\" aria-label=\"Link to this section\">#</a>").as_str());
                                 }
+                                link_index += 1;
+                                // advance outer iterator past the original tag
+                                let _ = char_iter.nth(original_html[i..i + tag_len].chars().count() - 1);
+                                continue;
                             }
-                        },
-                        Some(_) => {},
-                        None => {},
-                    }
+                        }
+                    },
+                    // <pre><code class="language-rust">
+                    Some('p') if markup.code_copy_buttons && original_html[i..].starts_with("<pre><code") => {
+                        let lang = original_html[i+10..].strip_prefix(" class=\"language-")
+                            .and_then(|rest| rest.split_once('"'))
+                            .map(|(lang, _rest)| lang);
+                        match lang {
+                            Some(lang) => new_html.push_str(format!("<pre data-lang=\"{lang}\">").as_str()),
+                            None => new_html.push_str("<pre>"),
+                        }
+                        new_html.push_str("<button class=\"copy-code\" type=\"button\">Copy</button>");
+                        // advance outer iterator past <pre>
+                        let _ = char_iter.nth(3);
+                        continue;
+                    },
+                    Some(_) => {},
+                    None => {},
                 }
             }
-            new_html.push(c);
         }
-        new_html
+        new_html.push(c);
     }
+    new_html
 }
 
-fn get_breadcrumbs(path: &Path, skip: &str) -> Vec<ExternalLink> {
+fn tag_id(tag: &str) -> Option<&str> {
+    let (_, rest) = tag.split_once(" id=\"")?;
+    rest.split_once('"').map(|(id, _)| id)
+}
+
+// Whether the heading's text reads like the recorded name. The name skips
+// inline code, so its words only need to show up in order
+fn heading_text_matches(html: &str, heading_size: char, name: &str) -> bool {
+    let close = format!("</h{heading_size}>");
+    let inner = html.find(close.as_str()).map_or(html, |end| &html[..end]);
+    let mut text = String::with_capacity(inner.len());
+    let mut in_tag = false;
+    for c in inner.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            },
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {},
+        }
+    }
+    let text = text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&");
+    let mut words = text.split_whitespace();
+    let mut name_words = name.split_whitespace().peekable();
+    if name_words.peek().is_none() {
+        return words.next().is_none();
+    }
+    name_words.all(|name_word| words.any(|word| word.contains(name_word)))
+}
+
+// Gives each <img> with a known size its width, height and aspect ratio, so
+// the page doesn't shift as images load. Hi-DPI images, named like
+// photo@2x.jpg, are shown at their size in CSS pixels
+fn add_image_sizes(html: &str, sizes: &HashMap<String, WidthAndHeight>) -> String {
+    const IMG_SRC: &str = "<img src=\"";
+    let mut new_html = String::with_capacity(html.len() + sizes.len() * 32);
+    let mut rest = html;
+    while let Some(start) = rest.find(IMG_SRC) {
+        let src_start = start + IMG_SRC.len();
+        let Some(src_len) = rest[src_start..].find('"') else {
+            break;
+        };
+        let src_end = src_start + src_len;
+        new_html.push_str(&rest[..src_end + 1]);
+        let src = &rest[src_start..src_end];
+        if let Some(size) = sizes.get(src) {
+            let density = pixel_density(src);
+            new_html.push_str(format!(" width=\"{}\" height = \"{}\"", size.width / density, size.height / density).as_str());
+            // an author's style is left alone
+            let tag = rest[src_end..].split_once('>').map_or("", |(tag, _rest)| tag);
+            if size.width > 0 && size.height > 0 && !tag.contains(" style=") {
+                new_html.push_str(format!(" style=\"aspect-ratio: {} / {}\"", size.width, size.height).as_str());
+            }
+        }
+        rest = &rest[src_end + 1..];
+    }
+    new_html.push_str(rest);
+    new_html
+}
+
+// <p><img src="a.jpg" alt="A" title="Caption" /></p> becomes
+// <figure><img src="a.jpg" alt="A" /><figcaption>Caption</figcaption></figure>.
+// The title is already escaped, so it goes in as is
+fn wrap_figures(html: &str) -> String {
+    const TITLE: &str = " title=\"";
+    let mut new_html = String::with_capacity(html.len() + 64);
+    let mut rest = html;
+    while let Some(start) = rest.find("<p><img ") {
+        new_html.push_str(&rest[..start]);
+        rest = &rest[start + "<p>".len()..];
+        let Some(end) = rest.find("</p>") else {
+            new_html.push_str("<p>");
+            break;
+        };
+        let img = &rest[..end];
+        // nothing else in the paragraph, and a caption to show
+        let title = img.find(TITLE)
+            .filter(|_| img.ends_with('>') && img.matches('<').count() == 1)
+            .and_then(|title_start| {
+                let title_end = title_start + TITLE.len() + img[title_start + TITLE.len()..].find('"')?;
+                Some((title_start, title_end))
+            })
+            .filter(|(title_start, title_end)| !img[title_start + TITLE.len()..*title_end].trim().is_empty());
+        match title {
+            Some((title_start, title_end)) => {
+                new_html.push_str("<figure>");
+                new_html.push_str(&img[..title_start]);
+                new_html.push_str(&img[title_end + 1..]);
+                new_html.push_str("<figcaption>");
+                new_html.push_str(&img[title_start + TITLE.len()..title_end]);
+                new_html.push_str("</figcaption></figure>");
+                rest = &rest[end + "</p>".len()..];
+            },
+            None => new_html.push_str("<p>"),
+        }
+    }
+    new_html.push_str(rest);
+    new_html
+}
+
+// Mermaid draws diagrams from the text of <pre class="mermaid"> elements,
+// rather than the code blocks the parser makes of them
+fn wrap_mermaid(html: &str) -> String {
+    const MERMAID_BLOCK: &str = "<pre><code class=\"language-mermaid\">";
+    let mut new_html = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(MERMAID_BLOCK) {
+        let diagram_start = start + MERMAID_BLOCK.len();
+        let Some(diagram_len) = rest[diagram_start..].find("</code></pre>") else {
+            break;
+        };
+        new_html.push_str(&rest[..start]);
+        new_html.push_str("<pre class=\"mermaid\">");
+        new_html.push_str(&rest[diagram_start..diagram_start + diagram_len]);
+        new_html.push_str("</pre>");
+        rest = &rest[diagram_start + diagram_len + "</code></pre>".len()..];
+    }
+    new_html.push_str(rest);
+    new_html
+}
+
+// 2 for photo@2x.jpg, 1 without a density suffix
+fn pixel_density(src: &str) -> u32 {
+    let path = src.split(['?', '#']).next().unwrap_or_default();
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _ext)| stem);
+    stem.rsplit_once('@')
+        .and_then(|(_name, density)| density.strip_suffix('x'))
+        .and_then(|density| density.parse::<u32>().ok())
+        .filter(|density| (1..=4).contains(density))
+        .unwrap_or(1)
+}
+
+// An exact match wins. Otherwise the item whose folder holds the current page
+// is active, as long as that folder isn't the site root
+fn mark_active_menu_item(menu: &mut [MenuItem], current_url: &str) {
+    let current_url = urlencoding::decode(current_url).map_or(current_url.to_string(), |url| url.into_owned());
+    let targets: Vec<String> = menu.iter().map(|item| {
+        urlencoding::decode(item.target.as_str()).map_or(item.target.clone(), |target| target.into_owned())
+    }).collect();
+    let active = targets.iter().position(|target| *target == current_url).or_else(|| {
+        let root = format!("{HOME_DIR}/");
+        targets.iter().enumerate()
+            .filter_map(|(i, target)| target.rsplit_once('/').map(|(folder, _)| (i, format!("{folder}/"))))
+            .filter(|(_, folder)| folder.len() > root.len() && current_url.starts_with(folder.as_str()))
+            .max_by_key(|(_, folder)| folder.len())
+            .map(|(i, _)| i)
+    });
+    if let Some(active) = active {
+        menu[active].active = true;
+    }
+}
+
+struct TablePaging<'a> {
+    rows: usize,
+    // 1-based
+    page: usize,
+    // Other parameters for the page links
+    query: &'a str,
+}
+
+// Wraps tables so wide ones scroll on their own, swaps the inline alignment
+// styles for classes, and turns a "Table: caption" paragraph right after a
+// table into its caption. Long tables are cut down to one page of rows
+fn enhance_tables(html: &str, paging: Option<TablePaging>) -> String {
+    const CAPTION_START: &str = "\n<p>Table: ";
+    let mut new_html = String::with_capacity(html.len() * 11 / 10);
+    let mut rest = html;
+    while let Some(start) = rest.find("<table>") {
+        let Some(end) = rest[start..].find("</table>").map(|end| start + end + "</table>".len()) else {
+            break;
+        };
+        new_html.push_str(&rest[..start]);
+        let mut table = rest[start..end]
+            .replace(" style=\"text-align: left\"", " class=\"align-left\"")
+            .replace(" style=\"text-align: center\"", " class=\"align-center\"")
+            .replace(" style=\"text-align: right\"", " class=\"align-right\"");
+        rest = &rest[end..];
+        if let Some(caption) = rest.strip_prefix(CAPTION_START) {
+            if let Some(caption_end) = caption.find("</p>") {
+                table.insert_str("<table>".len(), format!("<caption>{}</caption>", &caption[..caption_end]).as_str());
+                rest = &caption[caption_end + "</p>".len()..];
+            }
+        }
+        let pages = paging.as_ref().and_then(|paging| paginate_table(&mut table, paging));
+        new_html.push_str("<div class=\"table-wrapper\">");
+        new_html.push_str(table.as_str());
+        new_html.push_str("</div>");
+        if let Some(pages) = pages {
+            new_html.push_str(pages.as_str());
+        }
+    }
+    new_html.push_str(rest);
+    new_html
+}
+
+// Drops the table's body rows outside the requested page, returning links to
+// the other pages. Tables that fit on one page are left alone
+fn paginate_table(table: &mut String, paging: &TablePaging) -> Option<String> {
+    let body_start = table.find("<tbody>")? + "<tbody>".len();
+    let body_end = table.rfind("</tbody>")?;
+    let mut starts: Vec<usize> = table[body_start..body_end].match_indices("<tr>")
+        .map(|(offset, _)| body_start + offset)
+        .collect();
+    let total = starts.len();
+    if paging.rows == 0 || total <= paging.rows {
+        return None;
+    }
+    starts.push(body_end);
+    let page_count = total.div_ceil(paging.rows);
+    let page = paging.page.clamp(1, page_count);
+    let first = (page - 1) * paging.rows;
+    let last = (first + paging.rows).min(total);
+    let rows = table[starts[first]..starts[last]].to_string();
+    table.replace_range(body_start..body_end, format!("\n{rows}").as_str());
+
+    let mut nav = format!("<nav class=\"table-pages\">Rows {}&ndash;{last} of {total}", first + 1);
+    if page > 1 {
+        nav.push_str(format!(" <a href=\"{}\" rel=\"prev\">&laquo; Previous</a>", request_info::table_page_url(paging.query, page - 1)).as_str());
+    }
+    if page < page_count {
+        nav.push_str(format!(" <a href=\"{}\" rel=\"next\">Next &raquo;</a>", request_info::table_page_url(paging.query, page + 1)).as_str());
+    }
+    nav.push_str("</nav>");
+    Some(nav)
+}
+
+// The body without the <h1> it opens with
+fn remove_opening_heading(html: &str) -> Option<String> {
+    let trimmed = html.trim_start();
+    if !trimmed.starts_with("<h1") {
+        return None;
+    }
+    let end = trimmed.find("</h1>")? + "</h1>".len();
+    Some(trimmed[end..].trim_start().to_string())
+}
+
+fn get_breadcrumbs(path: &Path, skip: &str, folders: &FolderCache) -> Vec<ExternalLink> {
     let parts: Vec<&OsStr> = path.iter().filter(|el| {
         el != &skip
     }).collect();
@@ -253,10 +1261,50 @@ fn get_breadcrumbs(path: &Path, skip: &str) -> Vec<ExternalLink> {
 
     crumbs.push(ExternalLink::new(format!("{}{}", url, skip), "Home".to_string()));
 
+    let mut folder = PathBuf::new();
     for p in parts {
         url.push_str(&urlencoding::encode(&p.to_string_lossy()));
         url.push('/');
-        crumbs.push(ExternalLink::new(format!("{}{}", url, skip), p.to_string_lossy().into_owned()));
+        folder.push(p);
+        let mut crumb = ExternalLink::new(format!("{}{}", url, skip), p.to_string_lossy().into_owned());
+        if let Some(metadata) = folders.get(folder.as_path()) {
+            crumb.description = metadata.description;
+            crumb.icon = metadata.icon;
+        }
+        crumbs.push(crumb);
+    }
+    if let Some(last) = crumbs.last_mut() {
+        last.active = true;
     }
     crumbs
 }
+
+// The tags of a template and of the templates it includes or extends. None
+// when one wasn't loaded from a file, so could have anything in it
+fn all_template_tags(tera: &Tera, name: &str) -> Option<Vec<String>> {
+    let mut tags = Vec::new();
+    let mut pending = vec![name.to_string()];
+    let mut seen = HashSet::new();
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        // a missing include is either ignored or fails the render
+        let Ok(template) = tera.get_template(name.as_str()) else {
+            continue;
+        };
+        let source = template.path.as_ref().and_then(|path| std::fs::read_to_string(path).ok())?;
+        for tag in request_info::template_tags(source.as_str()) {
+            let tag = tag.trim_start_matches('-').trim_start();
+            if ["include", "extends", "import"].iter().any(|keyword| tag.starts_with(keyword)) {
+                pending.extend(tag.split(['"', '\'', '`']).skip(1).step_by(2).map(str::to_string));
+            }
+            tags.push(tag.to_string());
+        }
+    }
+    Some(tags)
+}
+
+fn mentions(tag: &str, var: &str) -> bool {
+    tag.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).any(|word| word == var)
+}
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+use axum::http::{header, HeaderMap};
+
+use crate::toml_config::I18nConfig;
+
+// The server's own wording, in English. Catalogs replace any of these
+const ENGLISH: &[(&str, &str)] = &[
+    ("menu", "Menu"),
+    ("home", "Home"),
+    ("search.placeholder", "Search..."),
+    ("search.button", "search"),
+    ("search.title", "Search results"),
+    ("search.title_for", "Search results for {query}"),
+    ("search.results_for", "Search results for: \"{query}\""),
+    ("search.link", "Link to these results"),
+    ("search.warnings", "Part of your query could not be understood and was searched as plain text:"),
+    ("search.no_results", "No results found. You can try using operators to refine your query."),
+    ("search.syntax.heading", "Search syntax:"),
+    ("search.syntax.boolean", "Boolean operators"),
+    ("search.syntax.required", "Required terms"),
+    ("search.syntax.excluded", "Excluded terms"),
+    ("search.syntax.phrases", "Phrases"),
+    ("search.syntax.grouping", "Grouping"),
+    ("a11y.skip_to_content", "Skip to content"),
+    ("a11y.skip_to_sidebar", "Skip to page contents"),
+    ("a11y.navigation", "Site"),
+    ("a11y.sidebar", "Page contents and related pages"),
+    ("a11y.warnings", "Accessibility warnings"),
+    ("quickopen.placeholder", "Go to page..."),
+    ("quickopen.label", "Go to page"),
+    ("index.contents", "Contents"),
+    ("index.folders", "Folders:"),
+    ("index.files", "Files:"),
+    ("doclinks.heading", "Within this document:"),
+    ("series.part_of", "part {position} of {total}"),
+    ("series.previous", "Previous"),
+    ("series.next", "Next"),
+    ("tags.heading", "Tags"),
+    ("tags.label", "Tags:"),
+    ("tags.count", "{count} documents"),
+    ("tags.all", "All tags"),
+    ("calendar.view", "Calendar"),
+    ("calendar.previous", "Previous month"),
+    ("calendar.next", "Next month"),
+    ("calendar.entries", "{count} entries"),
+    ("calendar.all", "All entries"),
+    ("calendar.months", "January February March April May June July August September October November December"),
+    ("calendar.weekdays", "Mon Tue Wed Thu Fri Sat Sun"),
+    ("comments.heading", "Comments"),
+    ("comments.name", "Name"),
+    ("comments.comment", "Comment"),
+    ("comments.submit", "Submit for moderation"),
+    ("webmentions.heading", "Mentions"),
+    ("newsletter.heading", "Subscribe"),
+    ("newsletter.intro", "Get new posts by email. You'll be sent a link to confirm your address first."),
+    ("newsletter.email", "Email address"),
+    ("newsletter.submit", "Subscribe"),
+    ("newsletter.sent", "Check your inbox for a link to confirm your subscription."),
+    ("newsletter.invalid", "That doesn't look like an email address."),
+    ("newsletter.confirm", "Confirm subscription"),
+    ("newsletter.confirmed", "You're subscribed. New posts will be sent to your inbox."),
+    ("newsletter.expired", "That link has expired. Please subscribe again."),
+    ("newsletter.unsubscribe", "Unsubscribe"),
+    ("newsletter.unsubscribed", "You've been unsubscribed, and won't be sent any more posts."),
+    ("bookmark.add", "Bookmark"),
+    ("bookmark.add_title", "Bookmark this page"),
+    ("bookmark.bookmarked", "Bookmarked"),
+    ("bookmark.remove_title", "Remove bookmark"),
+    ("bookmark.all", "All bookmarks ({count})"),
+    ("history.heading", "Continue reading"),
+    ("history.clear", "Clear history"),
+    ("audio.artist", "Artist"),
+    ("audio.album", "Album"),
+    ("audio.track", "track {track}"),
+    ("audio.year", "Year"),
+    ("audio.length", "Length"),
+    ("audio.download", "Download {file}"),
+    ("offline.heading", "You're offline"),
+    ("offline.message", "This page hasn't been saved for reading offline. Pages you've visited recently are still available, or try again once you're back online."),
+    ("error.not_found.heading", "Page not found"),
+    ("error.not_found.message", "The page you are looking for does not exist or has been moved"),
+    ("error.busy.heading", "Server busy"),
+    ("error.busy.message", "This page is too large to render right now. Please try again in a minute"),
+    ("error.internal.heading", "Internal server error"),
+    ("error.internal.message", "Chimera failed attempting to complete this request"),
+];
+
+// Translations of the server's own wording, one toml file per language in
+// the messages folder, eg: fr.toml or pt-BR.toml, of `"key" = "text"`
+// entries or tables of them. Keys missing from a catalog stay in English
+#[derive(Clone)]
+pub struct Messages {
+    // By lowercase language tag
+    catalogs: Arc<HashMap<String, HashMap<String, String>>>,
+    // The site_lang, for requests no catalog suits
+    default_lang: String,
+    negotiate: bool,
+}
+
+impl Messages {
+    pub fn new(config: Option<&I18nConfig>, chimera_root: &Path, site_lang: &str) -> Self {
+        let mut catalogs = HashMap::new();
+        if let Some(config) = config {
+            let dir = chimera_root.join(config.messages_dir.as_str());
+            for entry in std::fs::read_dir(dir.as_path()).into_iter().flatten().flatten() {
+                let path = entry.path();
+                if path.extension().is_none_or(|ext| ext != "toml") {
+                    continue;
+                }
+                let Some(lang) = path.file_stem().map(|stem| stem.to_string_lossy().to_ascii_lowercase()) else {
+                    continue;
+                };
+                match load_catalog(path.as_path()) {
+                    Some(catalog) => {
+                        tracing::info!("Loaded {} messages for {lang}", catalog.len());
+                        catalogs.insert(lang, catalog);
+                    },
+                    None => tracing::warn!("Failed to read message catalog {}", path.display()),
+                }
+            }
+        }
+        Messages {
+            catalogs: Arc::new(catalogs),
+            default_lang: site_lang.to_ascii_lowercase(),
+            negotiate: config.is_some_and(|config| config.negotiate),
+        }
+    }
+
+    pub fn default_lang(&self) -> &str {
+        self.default_lang.as_str()
+    }
+
+    // Pages can differ by Accept-Language
+    pub fn negotiates(&self) -> bool {
+        self.negotiate && !self.catalogs.is_empty()
+    }
+
+    // The visitor's preferred language with a catalog, eg: "fr" for
+    // "fr-CA,fr;q=0.9,en;q=0.8". None for the default language
+    pub fn negotiate(&self, headers: &HeaderMap) -> Option<String> {
+        if !self.negotiates() {
+            return None;
+        }
+        let accept = headers.get(header::ACCEPT_LANGUAGE)?.to_str().ok()?;
+        let mut ranges: Vec<(String, f32)> = accept.split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim().to_ascii_lowercase();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(1.0, |quality| quality.trim().parse::<f32>().unwrap_or(0.0));
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // stable, so equal preferences keep their order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let default_primary = self.default_lang.split('-').next().unwrap_or_default();
+        let lang = ranges.iter().find_map(|(tag, _quality)| {
+            let primary = tag.split('-').next().unwrap_or_default();
+            if self.catalogs.contains_key(tag) {
+                Some(tag.clone())
+            }
+            else if self.catalogs.contains_key(primary) {
+                Some(primary.to_string())
+            }
+            else {
+                (tag == "*" || primary == default_primary).then(|| self.default_lang.clone())
+            }
+        })?;
+        (lang != self.default_lang).then_some(lang)
+    }
+
+    // In the language asked for, then the default language, then English
+    pub fn get<'a>(&'a self, lang: Option<&str>, key: &'a str) -> &'a str {
+        [lang, Some(self.default_lang.as_str())].into_iter().flatten()
+            .find_map(|lang| self.catalogs.get(lang).and_then(|catalog| catalog.get(key)))
+            .map(String::as_str)
+            .or_else(|| ENGLISH.iter().find(|(english_key, _)| *english_key == key).map(|(_, text)| *text))
+            .unwrap_or(key)
+    }
+
+    // Tera helper, eg: {{ t(key="series.part_of", lang=lang, position=2, total=5) }}.
+    // Other arguments fill in the {placeholders}
+    pub fn t_function(&self) -> impl tera::Function {
+        let messages = self.clone();
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            let key = args.get("key")
+                .and_then(tera::Value::as_str)
+                .ok_or_else(|| tera::Error::msg("t() needs a key"))?;
+            let lang = args.get("lang").and_then(tera::Value::as_str);
+            let mut text = messages.get(lang, key).to_string();
+            for (name, value) in args.iter().filter(|(name, _)| *name != "key" && *name != "lang") {
+                let value = match value {
+                    tera::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                text = text.replace(format!("{{{name}}}").as_str(), value.as_str());
+            }
+            Ok(tera::Value::String(text))
+        }
+    }
+}
+
+fn load_catalog(path: &Path) -> Option<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let table = text.parse::<toml::Table>().ok()?;
+    let mut catalog = HashMap::new();
+    flatten(&table, "", &mut catalog);
+    Some(catalog)
+}
+
+// [search]\nplaceholder = "..." is the same as "search.placeholder" = "..."
+fn flatten(table: &toml::Table, prefix: &str, catalog: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let key = match prefix.is_empty() {
+            true => key.clone(),
+            false => format!("{prefix}.{key}"),
+        };
+        match value {
+            toml::Value::String(text) => {
+                catalog.insert(key, text.clone());
+            },
+            toml::Value::Table(table) => flatten(table, key.as_str(), catalog),
+            _ => tracing::warn!("Ignoring message {key}, it isn't text"),
+        }
+    }
+}
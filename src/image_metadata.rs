@@ -0,0 +1,43 @@
+use std::{fs::File, io::BufReader, path::Path};
+use exif::{DateTime, In, Reader, Tag, Value};
+use serde::Serialize;
+
+// Files whose EXIF data is read for galleries and search
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "webp", "tif", "tiff", "heic", "heif"];
+
+// What a photo says about itself
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ImageMetadata {
+    // When it was taken, eg: "2024-05-01 14:03"
+    pub taken: Option<String>,
+    pub caption: Option<String>,
+}
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        IMAGE_EXTENSIONS.iter().any(|image_ext| ext.eq_ignore_ascii_case(image_ext))
+    })
+}
+
+// None when the file can't be read or has no EXIF data
+pub fn read(path: &Path) -> Option<ImageMetadata> {
+    let file = File::open(path).ok()?;
+    let exif = Reader::new().read_from_container(&mut BufReader::new(file)).ok()?;
+    let text = |tag: Tag| -> Option<String> {
+        let Value::Ascii(values) = &exif.get_field(tag, In::PRIMARY)?.value else {
+            return None;
+        };
+        let text = String::from_utf8_lossy(values.first()?).trim().to_string();
+        (!text.is_empty()).then_some(text)
+    };
+    let taken = text(Tag::DateTimeOriginal).or_else(|| text(Tag::DateTime))
+        .and_then(|taken| DateTime::from_ascii(taken.as_bytes()).ok())
+        .map(|taken| format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            taken.year, taken.month, taken.day, taken.hour, taken.minute,
+        ));
+    Some(ImageMetadata {
+        taken,
+        caption: text(Tag::ImageDescription),
+    })
+}
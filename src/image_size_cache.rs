@@ -1,8 +1,9 @@
-use std::{ffi::OsStr, fs, path::PathBuf, sync::{Arc, RwLock}};
+use std::{borrow::Cow, collections::HashMap, ffi::OsStr, fs, path::{Path, PathBuf}, sync::{Arc, RwLock}};
 use indexmap::IndexMap;
 use serde::Deserialize;
 
 use crate::file_manager::FileManager;
+use crate::HOME_DIR;
 
 #[derive (Deserialize, Debug, Clone)]
 pub struct WidthAndHeight {
@@ -60,6 +61,16 @@ impl ImageSizeCache {
         }
     }
     
+    // Sizes given up front, with no file behind them
+    pub fn from_sizes(map: IndexMap<String, WidthAndHeight>) -> Self {
+        ImageSizeCache {
+            lock: Arc::new(RwLock::new(ImageSizeCacheInternal {
+                path: PathBuf::new(),
+                map,
+            }))
+        }
+    }
+
     fn load(&mut self) {
         let Ok(mut lock) = self.lock.write() else {
             return;
@@ -72,12 +83,68 @@ impl ImageSizeCache {
         tokio::spawn(listen_for_changes(rx, self.clone()));
     }
 
-    pub fn get_dimensions(&self, img: &str) -> Option<WidthAndHeight> {
+    // Bytes held by the map, roughly
+    pub fn approximate_size(&self) -> usize {
         let Ok(lock) = self.lock.read() else {
-            return None;
+            return 0;
         };
-        lock.map.get(img).cloned()
+        lock.map.keys().map(|img| img.len() + std::mem::size_of::<(String, WidthAndHeight)>() + 16).sum()
+    }
+
+    // Sizes of a document's images, by src, taking the lock once. The cache
+    // is keyed by URL path, eg: /home/pics/a.jpg, so srcs relative to the
+    // document (path, relative to the document root) are resolved and
+    // query strings dropped. Images without a known size are left out
+    pub fn get_dimensions(&self, document: &Path, images: &[String]) -> HashMap<String, WidthAndHeight> {
+        let Ok(lock) = self.lock.read() else {
+            return HashMap::new();
+        };
+        images.iter()
+            .filter_map(|img| {
+                let size = lock.map.get(img).or_else(|| {
+                    image_keys(document, img).iter().find_map(|key| lock.map.get(key))
+                })?;
+                Some((img.clone(), size.clone()))
+            })
+            .collect()
+    }
+}
+
+// Where a src points, as a URL path, both as written and percent-decoded
+fn image_keys(document: &Path, src: &str) -> Vec<String> {
+    let src = src.split(['?', '#']).next().unwrap_or_default();
+    if src.is_empty() || src.starts_with("//") || src.contains(':') {
+        // another site, or a data: URL
+        return vec![src.to_string()];
+    }
+    let folder = match src.starts_with('/') {
+        true => String::new(),
+        false => match document.parent().map(|folder| folder.to_string_lossy()) {
+            Some(folder) if !folder.is_empty() => format!("{HOME_DIR}/{folder}/"),
+            _ => format!("{HOME_DIR}/"),
+        },
+    };
+    let decoded = urlencoding::decode(src).unwrap_or(Cow::Borrowed(src));
+    let mut keys = vec![resolve_dots(format!("{folder}{src}").as_str())];
+    if decoded != src {
+        keys.push(resolve_dots(format!("{folder}{decoded}").as_str()));
+    }
+    keys
+}
+
+// Drops . and empty segments and backs up a folder for each ..
+fn resolve_dots(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
     }
+    format!("/{}", segments.join("/"))
 }
 
 async fn listen_for_changes(
@@ -0,0 +1,1252 @@
+//! A Markdown-aware web server. The binary runs the server; the library
+//! can mount it in another axum application, see [`router`], or render
+//! single documents, see [`render_document`]
+
+mod chimera_error;
+mod toml_config;
+mod document_scraper;
+mod full_text_index;
+mod html_generator;
+mod file_manager;
+mod result_cache;
+mod perf_timer;
+mod image_size_cache;
+mod image_metadata;
+mod audio_metadata;
+mod analytics;
+mod admin;
+mod comments;
+mod proxies;
+mod session;
+mod access;
+mod request_info;
+mod site_data;
+mod page_catalog;
+mod authors;
+mod series;
+mod tags;
+mod calendar;
+mod expiry;
+mod publishing;
+mod link_checker;
+mod duplicates;
+mod listener;
+mod systemd;
+mod tracking_params;
+mod hotlinking;
+mod i18n;
+mod accessibility;
+mod social;
+mod asset_integrity;
+mod critical_css;
+mod fragment_cache;
+mod bibliography;
+mod wikilinks;
+mod glossary;
+mod data_files;
+mod charts;
+mod base_path;
+mod converters;
+mod graphviz;
+mod offline;
+mod search_export;
+mod feed;
+mod quickopen;
+mod random_page;
+mod qr_code;
+mod well_known;
+mod webmentions;
+mod activitypub;
+mod newsletter;
+mod bookmarks;
+mod reading_history;
+mod backup;
+mod trash;
+mod atomic_write;
+mod file_times;
+mod load_shedding;
+mod memory_budget;
+mod warm_up;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod render;
+pub mod golden;
+#[doc(hidden)]
+pub mod bench_support;
+
+pub use chimera_error::ChimeraError;
+pub use document_scraper::{DocumentScraper, InternalLink};
+pub use render::{process_markdown, render_document, DocumentSource, RenderOptions, RenderedDocument};
+pub use toml_config::{MarkupConfig, TomlConfig};
+
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use axum::{extract::{ConnectInfo, Query, RawQuery, State}, http::{HeaderMap, Request, StatusCode}, middleware::{self, Next}, response::{IntoResponse, Redirect, Response}, routing::{get, post, MethodRouter}, Router};
+use image_size_cache::ImageSizeCache;
+use glossary::Glossary;
+use data_files::DataFiles;
+use tokio::signal;
+use tower_http::services::ServeDir;
+use serde::Deserialize;
+
+#[allow(unused_imports)]
+use axum::{debug_handler, debug_middleware};
+
+use crate::file_manager::FileManager;
+use crate::full_text_index::{FullTextIndex, ScanOptions, WriterOptions};
+use crate::html_generator::{HtmlGenerator, HtmlGeneratorCfg};
+use crate::chimera_error::{handle_404, handle_err, handle_overloaded};
+use crate::document_scraper::parse_markdown_with;
+use crate::result_cache::ResultCache;
+use crate::perf_timer::{PerfTimer, StartupReport, StartupTimer};
+use crate::toml_config::AdminConfig;
+use crate::analytics::PageViews;
+use crate::comments::CommentStore;
+use crate::proxies::TrustedProxies;
+use crate::session::{CsrfHtml, SessionManager};
+use crate::access::AccessControl;
+use crate::request_info::RequestInfo;
+use crate::site_data::SiteData;
+use crate::page_catalog::PageCatalog;
+use crate::authors::{Authors, AUTHORS_DIR};
+use crate::series::SERIES_DIR;
+use crate::tags::TAGS_DIR;
+use crate::social::Social;
+use crate::calendar::CALENDAR_DIR;
+use crate::expiry::Expiry;
+use crate::tracking_params::TrackingParams;
+use crate::hotlinking::HotlinkGuard;
+use crate::i18n::Messages;
+use crate::asset_integrity::AssetIntegrity;
+use crate::base_path::BasePath;
+use crate::converters::{Converted, Converters};
+use crate::graphviz::Graphviz;
+use crate::offline::{Offline, OFFLINE_URL, SERVICE_WORKER_URL};
+use crate::search_export::{SearchExport, SEARCH_INDEX_URL};
+use crate::feed::{Feed, FEED_URL};
+use crate::quickopen::QUICKOPEN_URL;
+use crate::random_page::RANDOM_URL;
+use crate::qr_code::{QrCodes, QR_URL};
+use crate::well_known::{WellKnown, WELL_KNOWN_DIR};
+use crate::webmentions::{WebmentionStore, WEBMENTION_URL};
+use crate::activitypub::{ActivityPub, ACTIVITYPUB_DIR};
+use crate::newsletter::{Newsletter, SUBSCRIBE_URL};
+use crate::bookmarks::{BookmarkStore, BOOKMARKS_URL};
+use crate::reading_history::{ReadingHistory, CLEAR_HISTORY_URL};
+use crate::backup::Backups;
+use crate::trash::{Trash, TRASH_URL};
+use crate::load_shedding::LoadShedder;
+use crate::memory_budget::MemoryBudget;
+use crate::warm_up::WarmUp;
+
+const SERVER_TIMING: &str = "server-timing";
+const CACHED_HEADER: &str = "cached";
+const HOME_DIR: &str = "/home";
+const DRAFT_PREVIEW_PARAM: &str = "preview";
+
+struct AppState {
+    user_web_root: PathBuf,
+    internal_web_root: PathBuf,
+    index_file: String,
+    generate_index: bool,
+    full_text_index: FullTextIndex,
+    html_generator: HtmlGenerator,
+    file_manager: FileManager,
+    known_redirects: HashMap<String, String>,
+    result_cache: ResultCache,
+    admin: Option<AdminConfig>,
+    page_views: Option<PageViews>,
+    top_pages: usize,
+    comment_store: Option<CommentStore>,
+    trusted_proxies: TrustedProxies,
+    webmention_store: Option<WebmentionStore>,
+    bookmark_store: Option<BookmarkStore>,
+    reading_history: Option<ReadingHistory>,
+    backups: Option<Backups>,
+    trash: Option<Trash>,
+    load_shedder: Option<LoadShedder>,
+    sessions: SessionManager,
+    access: AccessControl,
+    template_params: Vec<String>,
+    uncached_folders: Vec<PathBuf>,
+    draft_token: Option<String>,
+    serve_scheduled: bool,
+    tracking_params: TrackingParams,
+    hotlink_guard: Option<HotlinkGuard>,
+    messages: Messages,
+    markup: MarkupConfig,
+    dynamic_pages: HashMap<String, String>,
+    page_catalog: PageCatalog,
+    authors: Authors,
+    converters: Converters,
+    graphviz: Option<Graphviz>,
+    offline: Option<Offline>,
+    search_export: SearchExport,
+    feed: Feed,
+    qr_codes: QrCodes,
+    well_known: WellKnown,
+    activitypub: Option<ActivityPub>,
+    newsletter: Option<Newsletter>,
+    // [social] site_url, for checking webmention targets
+    site_url: Option<String>,
+    startup: StartupReport,
+}
+
+impl AppState {
+    pub async fn new(chimera_root: PathBuf, config: TomlConfig) -> Result<Self, ChimeraError> {
+        let mut startup = StartupTimer::new();
+        startup.add("config", config.load_time);
+        let user_template_root = chimera_root.join("template");
+        let internal_template_root = chimera_root.join("template-internal");
+        let user_web_root = chimera_root.join("www");
+        let internal_web_root = chimera_root.join("www-internal");
+        let document_root = chimera_root.join("home");
+        let search_index_dir = chimera_root.join("search");
+
+        tracing::debug!("Document root: {}", document_root.display());
+        // request paths are resolved against the current directory, for the
+        // whole process, see router
+        if let Err(e) = std::env::set_current_dir(document_root.as_path()) {
+            tracing::error!("Failed to set web root to {}: {e}", document_root.display());
+        }
+
+        let mut file_manager = FileManager::new(
+            document_root.as_path(),
+            config.index_file.as_str(),
+        ).await?;
+        tracing::debug!("Template roots: User: {}, Internal: {}", user_template_root.display(), internal_template_root.display());
+        file_manager.add_watch(document_root.as_path());
+        file_manager.add_watch(user_template_root.as_path());
+        file_manager.add_watch(internal_template_root.as_path());
+        if config.gallery.is_some() {
+            file_manager.list_images();
+        }
+        startup.phase("file-watcher");
+
+        let image_size_cache = config.image_size_file.map(|name| {
+            let image_size_file = chimera_root.join(name.as_str());
+            file_manager.add_watch(&image_size_file);
+            let cache = ImageSizeCache::new(image_size_file);
+            cache.listen_for_changes(&file_manager);
+            cache
+        });
+
+        let glossary = config.glossary_file.map(|name| {
+            let glossary_file = chimera_root.join(name.as_str());
+            file_manager.add_watch(&glossary_file);
+            let glossary = Glossary::new(glossary_file);
+            glossary.listen_for_changes(&file_manager);
+            glossary
+        });
+
+        // the data folder is optional, but watched when it's there
+        let data_dir = chimera_root.join("data");
+        let data_dir = data_dir.canonicalize().unwrap_or(data_dir);
+        if data_dir.is_dir() {
+            file_manager.add_watch(data_dir.as_path());
+        }
+        let data_files = DataFiles::new(data_dir);
+        data_files.listen_for_changes(&file_manager);
+
+        #[cfg(feature = "scripting")]
+        let scripts = scripting::Scripts::new(config.scripts, chimera_root.as_path()).inspect(|scripts| {
+            for file in scripts.script_files() {
+                file_manager.add_watch(&file);
+            }
+            scripts.listen_for_changes(&file_manager);
+        });
+        #[cfg(not(feature = "scripting"))]
+        if !config.scripts.folders.is_empty() {
+            tracing::warn!("Ignoring [scripts], this build doesn't have the scripting feature");
+        }
+
+        let trash = config.trash.map(|trash| Trash::new(chimera_root.join("trash"), document_root.as_path(), trash));
+        let memory_budget = MemoryBudget::new(config.max_cache_size, config.memory.as_ref(), &config.search);
+        let result_cache = ResultCache::new(memory_budget.result_cache).with_trash(trash.clone());
+        result_cache.listen_for_changes(&file_manager);
+        startup.phase("caches");
+
+        if config.access.user_header.is_some() && config.trusted_proxies.is_empty() {
+            tracing::warn!("[access] user_header is only believed from trusted_proxies, and there are none");
+        }
+        let access = AccessControl::new(config.access);
+        let page_catalog = PageCatalog::new(document_root.as_path(), &file_manager, access.clone(), config.index_on_demand).await;
+        page_catalog.listen_for_changes(&file_manager, result_cache.clone());
+        let load_shedder = config.memory.map(|memory| {
+            LoadShedder::new(memory, result_cache.clone(), page_catalog.clone(), image_size_cache.clone())
+        });
+        startup.phase("page-catalog");
+        let converters = Converters::new(config.converters);
+        converters.listen_for_changes(&file_manager, result_cache.clone());
+        let graphviz = config.graphviz.and_then(Graphviz::new);
+
+        let top_pages = config.analytics.as_ref().map_or(0, |analytics| analytics.top_pages);
+        let page_views = config.analytics.map(|analytics| {
+            PageViews::new(chimera_root.join("analytics"), analytics.retention_days)
+        });
+
+        let comment_store = config.comments.as_ref().and_then(|comments| {
+            match comments.provider.as_str() {
+                "builtin" => Some(CommentStore::new(
+                    chimera_root.join("comments"),
+                    comments.min_interval_secs,
+                    comments.max_pending,
+                )),
+                _ => None,
+            }
+        });
+
+        let webmention_store = config.webmentions.and_then(|webmentions| {
+            WebmentionStore::new(chimera_root.join("webmentions"), webmentions)
+        });
+
+        let bookmark_store = config.bookmarks.map(|bookmarks| {
+            BookmarkStore::new(chimera_root.join("bookmarks"), bookmarks.max_per_user)
+        });
+
+        let reading_history = config.reading_history.map(|history| {
+            ReadingHistory::new(chimera_root.join("history"), history)
+        });
+
+        let backups = config.backup.map(|backup| Backups::new(backup, chimera_root.as_path(), load_shedder.clone()));
+
+        let search_export = SearchExport::new(page_catalog.clone());
+        let feed = Feed::new(&config.feed, config.site_title.as_str(), config.social.site_url.as_deref(), page_catalog.clone());
+        let activitypub = config.activitypub.and_then(|activitypub| {
+            ActivityPub::new(activitypub, config.social.site_url.as_deref(), feed.clone())
+        });
+        let newsletter = config.newsletter.and_then(|newsletter| {
+            Newsletter::new(chimera_root.join("newsletter"), newsletter, config.social.site_url.as_deref(), feed.clone())
+        });
+        let mut well_known = WellKnown::new(config.well_known);
+        if let Some(activitypub) = &activitypub {
+            well_known.add_actor(activitypub.account().as_str(), activitypub.actor_url(), activitypub.profile_url());
+        }
+        let offline = config.offline.map(|offline| {
+            Offline::new(offline, page_catalog.clone(), document_root.as_path(), vec![user_web_root.clone(), internal_web_root.clone()])
+        });
+
+        let authors = Authors::new(config.authors);
+        let asset_integrity = AssetIntegrity::new(&[user_web_root.as_path(), internal_web_root.as_path()], config.integrity);
+        let expiry = Expiry::new(&config.expiry);
+        let messages = Messages::new(config.i18n.as_ref(), chimera_root.as_path(), config.site_lang.as_str());
+        startup.phase("site-services");
+
+        let cfg = HtmlGeneratorCfg {
+            user_template_root,
+            internal_template_root,
+            site_title: config.site_title.as_str(),
+            site_lang: config.site_lang.as_str(),
+            highlight_style: config.highlight_style.as_str(),
+            math_renderer: config.math_renderer.as_str(),
+            messages: messages.clone(),
+            index_file: config.index_file.as_str(),
+            menu: config.menu,
+            file_manager: &file_manager,
+            image_size_cache,
+            glossary,
+            data_files,
+            #[cfg(feature = "scripting")]
+            scripts,
+            page_views: page_views.clone(),
+            comments: config.comments,
+            comment_store: comment_store.clone(),
+            webmention_store: webmention_store.clone(),
+            bookmark_store: bookmark_store.clone(),
+            reading_history: reading_history.is_some(),
+            page_catalog: page_catalog.clone(),
+            authors: authors.clone(),
+            expiry,
+            asset_integrity,
+            cached_fragments: config.cached_fragments,
+            markup: config.markup,
+            social: Social::new(&config.social),
+            offline: offline.is_some(),
+            dev_mode: config.dev_mode,
+            web_roots: vec![user_web_root.clone(), internal_web_root.clone()],
+            critical_css: config.inline_css_limit.and_then(|limit| {
+                critical_css::load(&[user_web_root.as_path(), internal_web_root.as_path()], limit)
+            }),
+        };
+        tracing::debug!("HtmlGenerator");
+        let html_generator = HtmlGenerator::new(cfg)?;
+        startup.phase("templates");
+
+        tracing::debug!("Full text index: {}", search_index_dir.to_string_lossy());
+        let full_text_index = FullTextIndex::new(search_index_dir.as_path(), expiry, WriterOptions {
+            heap: memory_budget.index_writer,
+            threads: config.search.indexing_threads,
+        })?;
+        startup.phase("search-index-open");
+        full_text_index.scan_directory(
+            document_root.clone(),
+            search_index_dir,
+            &file_manager,
+            access.clone(),
+            ScanOptions {
+                on_demand: config.index_on_demand,
+                image_captions: config.gallery.as_ref().is_some_and(|gallery| gallery.index_captions),
+            },
+        ).await?;
+        startup.phase("search-scan-queue");
+
+        Ok(AppState {
+            index_file: config.index_file,
+            generate_index: config.generate_index,
+            user_web_root,
+            internal_web_root,
+            full_text_index,
+            html_generator,
+            file_manager,
+            known_redirects: config.redirects,
+            result_cache,
+            admin: config.admin,
+            page_views,
+            top_pages,
+            comment_store,
+            trusted_proxies: TrustedProxies::new(config.trusted_proxies.as_slice()),
+            webmention_store,
+            bookmark_store,
+            reading_history,
+            backups,
+            trash,
+            load_shedder,
+            sessions: SessionManager::new(config.session.secret.as_deref(), config.session.secure_cookie),
+            access,
+            template_params: config.template_params,
+            uncached_folders: config.uncached_folders.iter().map(|folder| PathBuf::from(folder.trim_matches('/'))).collect(),
+            draft_token: config.draft_token.clone(),
+            serve_scheduled: config.serve_scheduled,
+            tracking_params: TrackingParams::new(config.tracking),
+            hotlink_guard: config.hotlinking.map(HotlinkGuard::new),
+            messages,
+            markup: config.markup,
+            dynamic_pages: config.dynamic_pages.into_iter().collect(),
+            page_catalog,
+            authors,
+            converters,
+            graphviz,
+            offline,
+            search_export,
+            feed,
+            qr_codes: QrCodes::default(),
+            well_known,
+            activitypub,
+            newsletter,
+            site_url: config.social.site_url,
+            startup: startup.finish(),
+        })
+    }
+}
+
+pub(crate) type AppStateType = Arc<AppState>;
+
+// The built-in routes, kept track of as they're added so that dynamic
+// pages can't be registered over one of them
+#[derive(Default)]
+struct Routes {
+    router: Router<AppStateType>,
+    paths: Vec<String>,
+}
+
+impl Routes {
+    fn route(mut self, path: &str, method_router: MethodRouter<AppStateType>) -> Self {
+        self.router = self.router.route(path, method_router);
+        self.paths.push(path.to_string());
+        self
+    }
+
+    fn merge(mut self, other: Routes) -> Self {
+        self.router = self.router.merge(other.router);
+        self.paths.extend(other.paths);
+        self
+    }
+
+    // For layers, which don't add routes
+    fn map(mut self, f: impl FnOnce(Router<AppStateType>) -> Router<AppStateType>) -> Self {
+        self.router = f(self.router);
+        self
+    }
+}
+
+// The fixed part of a route, eg: /tags/:tag => /tags. Empty for the
+// catch-all routes, which dynamic pages take precedence over
+fn route_prefix(route: &str) -> &str {
+    let end = route.find([':', '*']).unwrap_or(route.len());
+    route[..end].trim_end_matches('/')
+}
+
+// Dynamic pages can't shadow the built-in routes, or anything under them
+fn is_valid_dynamic_page(url: &str, routes: &[String]) -> bool {
+    url.starts_with('/')
+        && url.len() > 1
+        && !url.contains([':', '*', '?', '#'])
+        && !routes.iter().map(|route| route_prefix(route)).filter(|prefix| !prefix.is_empty()).any(|prefix| {
+            url == prefix || url.starts_with(format!("{prefix}/").as_str())
+        })
+}
+
+// Everything the server answers, along with the state behind it
+async fn build_router(toml_config: TomlConfig, chimera_root: PathBuf) -> Result<(Router, AppStateType), ChimeraError> {
+    let base_path = toml_config.base_path.as_deref().and_then(BasePath::new);
+    let state = Arc::new(AppState::new(chimera_root, toml_config).await?);
+
+    let mut app = Routes::default();
+    if state.admin.is_some() {
+        let admin_routes = Routes::default()
+            .route("/admin", get(admin::handle_admin))
+            .route("/admin/comments/:id/approve", post(admin::handle_approve_comment))
+            .route("/admin/comments/:id/delete", post(admin::handle_delete_comment))
+            .route("/admin/webmentions/:id/approve", post(admin::handle_approve_webmention))
+            .route("/admin/webmentions/:id/delete", post(admin::handle_delete_webmention))
+            .route("/admin/cache", get(admin::handle_cache))
+            .route("/admin/startup", get(admin::handle_startup))
+            .route("/admin/cache/flush", post(admin::handle_flush_cache))
+            .route("/admin/cache/evict", post(admin::handle_evict_cache))
+            .route("/admin/backup", post(admin::handle_backup))
+            .route("/admin/subscribers.csv", get(newsletter::handle_export))
+            .route(format!("{TRASH_URL}/*path").as_str(), get(trash::handle_trashed_page))
+            .map(|router| router.route_layer(middleware::from_fn_with_state(state.clone(), admin::mw_admin_auth)));
+        app = app.merge(admin_routes);
+    }
+    if state.comment_store.is_some() {
+        app = app.route("/comments", post(comments::handle_post_comment));
+    }
+    if state.webmention_store.is_some() {
+        app = app.route(WEBMENTION_URL, post(webmentions::handle_post_webmention));
+    }
+    if state.newsletter.is_some() {
+        app = app
+            .route(SUBSCRIBE_URL, get(newsletter::handle_subscribe_page).post(newsletter::handle_subscribe))
+            .route(format!("{SUBSCRIBE_URL}/confirm").as_str(), get(newsletter::handle_confirm_page).post(newsletter::handle_confirm))
+            .route(format!("{SUBSCRIBE_URL}/unsubscribe").as_str(), get(newsletter::handle_unsubscribe_page).post(newsletter::handle_unsubscribe));
+    }
+    if state.activitypub.is_some() {
+        app = app
+            .route(format!("{ACTIVITYPUB_DIR}/actor").as_str(), get(activitypub::handle_actor))
+            .route(format!("{ACTIVITYPUB_DIR}/outbox").as_str(), get(activitypub::handle_outbox))
+            .route(format!("{ACTIVITYPUB_DIR}/inbox").as_str(), post(activitypub::handle_inbox));
+    }
+    if state.bookmark_store.is_some() {
+        app = app.route(BOOKMARKS_URL, get(bookmarks::handle_bookmarks).post(bookmarks::handle_post_bookmark));
+    }
+    if state.reading_history.is_some() {
+        app = app.route(CLEAR_HISTORY_URL, post(reading_history::handle_clear_history));
+    }
+    if !state.authors.is_empty() {
+        app = app
+            .route(AUTHORS_DIR, get(authors::handle_authors))
+            .route(format!("{AUTHORS_DIR}/:name").as_str(), get(authors::handle_author));
+    }
+    if state.offline.is_some() {
+        app = app
+            .route(SERVICE_WORKER_URL, get(offline::handle_service_worker))
+            .route(OFFLINE_URL, get(offline::handle_offline_page));
+    }
+    app = app.route(format!("{SERIES_DIR}/:name").as_str(), get(series::handle_series))
+        .route(TAGS_DIR, get(tags::handle_tags))
+        .route(format!("{TAGS_DIR}/:tag").as_str(), get(tags::handle_tag))
+        .route(CALENDAR_DIR, get(calendar::handle_calendar_root))
+        .route(format!("{CALENDAR_DIR}/*path").as_str(), get(calendar::handle_calendar));
+    let app = app
+        .route("/search", get(handle_search))
+        .route(SEARCH_INDEX_URL, get(search_export::handle_search_index))
+        .route(FEED_URL, get(feed::handle_feed))
+        .route(QUICKOPEN_URL, get(quickopen::handle_quickopen))
+        .route(RANDOM_URL, get(random_page::handle_random))
+        .route(QR_URL, get(qr_code::handle_qr))
+        .route(format!("{WELL_KNOWN_DIR}/*name").as_str(), get(well_known::handle_well_known))
+        .route(format!("{HOME_DIR}/*path").as_str(), get(handle_home))
+        .route(format!("{HOME_DIR}/").as_str(), get(handle_home_folder))
+        .route("/*path", get(handle_root_path))
+        .route("/", get(handle_root));
+    let mut router = app.router;
+    for (url, template) in state.dynamic_pages.iter() {
+        match is_valid_dynamic_page(url.as_str(), app.paths.as_slice()) {
+            true => router = router.route(url.as_str(), get(handle_dynamic_page)),
+            false => tracing::warn!("Ignoring dynamic page {url} => {template}, the URL is reserved or invalid"),
+        }
+    }
+    let app: Router = router
+        .fallback_service(get(handle_fallback).with_state(state.clone()))
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.clone(), reading_history::mw_reading_history))
+        .layer(middleware::from_fn_with_state(state.clone(), session::mw_session));
+    let app = match base_path {
+        Some(base_path) => {
+            tracing::info!("Serving the site under {}", base_path.prefix());
+            let prefix = base_path.prefix().to_string();
+            Router::new()
+                // nest only matches the prefix itself, not with a trailing slash
+                .route(format!("{prefix}/").as_str(), get(handle_root))
+                .with_state(state.clone())
+                .nest(prefix.as_str(), app)
+                .layer(middleware::from_fn_with_state(base_path, base_path::mw_base_path))
+        },
+        None => app,
+    };
+    let app = app
+        .layer(tower_http::compression::CompressionLayer::new())
+        .layer(middleware::from_fn_with_state(state.clone(), mw_response_time))
+        .layer(middleware::from_fn_with_state(state.clone(), tracking_params::mw_strip_tracking))
+        .layer(middleware::from_fn_with_state(state.clone(), access::mw_trusted_user));
+    Ok((app, state))
+}
+
+// The whole site, to mount in another axum application. Set base_path in
+// the config to serve it somewhere other than the root. Handlers need the
+// client address, so serve with into_make_service_with_connect_info.
+// Documents are served by paths relative to the document root, so this
+// changes the process's current directory to chimera_root/home; anything
+// else in the process using relative paths will see them move
+pub async fn router(toml_config: TomlConfig) -> Result<Router, ChimeraError> {
+    let chimera_root = std::path::absolute(toml_config.chimera_root.as_str())?;
+    let (app, _state) = build_router(toml_config, chimera_root).await?;
+    Ok(app)
+}
+
+#[tokio::main]
+pub async fn run(toml_config: TomlConfig, chimera_root: PathBuf) -> Result<(), ChimeraError> {
+    tracing::info!("Starting up Chimera MD server \"{}\" on port {}", toml_config.site_title, toml_config.port);
+    let port = toml_config.port;
+    let reuse_port = toml_config.reuse_port;
+    let warm_up = WarmUp::default();
+    let early_server = match toml_config.serve_while_starting && !reuse_port {
+        true => {
+            let listener = listener::bind(port, reuse_port)?;
+            tracing::info!("Listening while the site starts up");
+            Some(tokio::spawn(serve(listener, warm_up.router())))
+        },
+        false => None,
+    };
+    let (app, state) = build_router(toml_config, chimera_root).await?;
+    warm_up.ready(app);
+
+    let server = match early_server {
+        Some(server) => server,
+        None => tokio::spawn(serve(listener::bind(port, reuse_port)?, warm_up.router())),
+    };
+    systemd::notify_ready();
+    systemd::spawn_watchdog(state.clone());
+    server.await.map_err(|e| ChimeraError::IOError(format!("Server task failed: {e}")))?;
+
+    if let Some(page_views) = &state.page_views {
+        page_views.save().await?;
+    }
+    if let Some(reading_history) = &state.reading_history {
+        reading_history.save().await?;
+    }
+    Ok(())
+}
+
+async fn serve(listener: tokio::net::TcpListener, app: Router) {
+    let connect_wrapper = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, connect_wrapper)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {
+            tracing::info!("Ctrl-c detected. Shutting down");
+        },
+        _ = terminate => {
+            tracing::info!("Signal detected. Shutting down");
+        },
+    }
+    systemd::notify_stopping();
+}
+
+#[debug_middleware]
+async fn mw_response_time(
+    State(app_state): State<AppStateType>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let start_time = std::time::Instant::now();
+    let path = match request.uri().path_and_query() {
+        Some(p_and_q) => { p_and_q.as_str().to_owned() },
+        None => { request.uri().path().to_string() }
+    };
+
+    let req_headers = request.headers();
+    let user_agent = req_headers.get("user-agent").cloned();
+    let referer = req_headers.get("referer").cloned();
+    let addr = app_state.trusted_proxies.client_addr(req_headers, addr);
+    // what's sent back to someone signed in shouldn't be kept for everyone
+    let cache_scope = match req_headers.contains_key(axum::http::header::AUTHORIZATION) {
+        true => "private",
+        false => "public",
+    };
+
+    let mut response = next.run(request).await;
+    let status = response.status();
+    let headers = response.headers_mut();
+    match path.ends_with(".md") {
+        true => {
+            let cached_status = match headers.remove(CACHED_HEADER) {
+                Some(status) => {
+                    match status.to_str() {
+                        Ok(str) => str.to_string(),
+                        Err(_) => "err".to_string(),
+                    }
+                },
+                None => "static".to_string(),
+            };
+            let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+            let time_str = format!("total; dur={}; desc=\"total ({})\"", elapsed, cached_status);
+            if let Ok(hval) = axum::http::HeaderValue::from_str(time_str.as_str()) {
+                headers.append(SERVER_TIMING, hval);
+            }
+            match status.is_success() || status.is_redirection() {
+                true => {
+                    if !headers.contains_key(axum::http::header::CACHE_CONTROL) {
+                        if let Ok(value) = axum::http::HeaderValue::from_str(format!("{cache_scope}, max-age=360").as_str()) {
+                            headers.insert(axum::http::header::CACHE_CONTROL, value);
+                        }
+                    }
+                    tracing::info!("{}: {path} in {elapsed} ms ({cached_status}), user_agent: {user_agent:?}, referer: {referer:?}, addr: {addr}", response.status().as_u16())
+                },
+                false => tracing::warn!("{}: {path} in {elapsed} ms ({cached_status}), user_agent: {user_agent:?}, referer: {referer:?}, addr: {addr}", response.status().as_u16())
+            }
+        },
+        false => {
+            let elapsed = start_time.elapsed().as_micros() as f64 / 1000.0;
+            match status.is_success()  || status.is_redirection() {
+                true => {
+                    if !headers.contains_key(axum::http::header::CACHE_CONTROL) {
+                        if let Ok(value) = axum::http::HeaderValue::from_str(format!("{cache_scope}, max-age=28800").as_str()) {
+                            headers.insert(axum::http::header::CACHE_CONTROL, value);
+                        }
+                    }
+                    tracing::debug!("{}: {path} in {elapsed} ms", response.status().as_u16())
+                },
+                false => tracing::warn!("{}: {path} in {elapsed} ms, user_agent: {user_agent:?}, addr: {addr}", response.status().as_u16())
+            }
+        },
+    }
+    response
+}
+
+#[derive(Deserialize)]
+struct SearchForm {
+    query: Option<String>,
+    // alias used by other search engines' URLs
+    q: Option<String>,
+}
+
+//#[debug_handler]
+async fn handle_search(
+    State(app_state): State<AppStateType>,
+    Query(search): Query<SearchForm>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let lang = app_state.messages.negotiate(&headers);
+    if let Some(query) = search.query.or(search.q) {
+        if !query.is_empty() {
+            tracing::debug!("Search for {}", query);
+            let user = app_state.access.user(&headers);
+            if let Ok((results, warnings)) = app_state.full_text_index.search(query.as_str(), user.as_deref()) {
+                if let Ok(html) = app_state.html_generator.gen_search(query.as_str(), results, warnings, lang.as_deref()) {
+                    return CsrfHtml(html).into_response();
+                }
+            }
+        }
+    }
+    if let Ok(html) = app_state.html_generator.gen_search_blank(lang.as_deref()) {
+        return CsrfHtml(html).into_response();
+    }    
+    handle_err(app_state).await.into_response()
+}
+
+async fn handle_dynamic_page(
+    State(app_state): State<AppStateType>,
+    uri: axum::http::Uri,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let url = uri.path();
+    let Some(template) = app_state.dynamic_pages.get(url) else {
+        return handle_404(app_state).await.into_response();
+    };
+    let request = RequestInfo::new(
+        url,
+        query.as_deref(),
+        app_state.template_params.as_slice(),
+        &headers,
+        app_state.access.user(&headers),
+        app_state.messages.negotiate(&headers),
+    );
+    // not a real file, but the cache only needs a unique key
+    let cache_key = PathBuf::from(url);
+    let variant = request.cache_variant(app_state.html_generator.template_request_usage(template.as_str()));
+    if let Some(html) = app_state.result_cache.get_variant(cache_key.as_path(), variant.as_str()).await {
+        return CsrfHtml(html).into_response();
+    }
+    let site = SiteData::from_catalog(&app_state.page_catalog);
+    match app_state.html_generator.gen_dynamic_page(template.as_str(), &site, &request) {
+        Ok(html) => {
+            app_state.result_cache.add_catalog_variant(cache_key.as_path(), variant.as_str(), html.as_str()).await;
+            CsrfHtml(html).into_response()
+        },
+        Err(e) => {
+            tracing::warn!("Error rendering dynamic page {url} with {template}: {e:?}");
+            handle_err(app_state).await.into_response()
+        },
+    }
+}
+
+async fn handle_root_path(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap
+) -> axum::response::Response {
+    if let Some(redirect) = app_state.known_redirects.get(&path) {
+        tracing::debug!("Known redirect: {path} => {redirect}");
+        return permanent_redirect(redirect, query.as_deref());
+    }
+    if let Some(redirect) = app_state.page_catalog.resolve_alias(path.as_str()) {
+        tracing::debug!("Alias redirect: {path} => {redirect}");
+        return permanent_redirect(redirect.as_str(), query.as_deref());
+    }
+    let mut new_path = app_state.user_web_root.join(path.as_str());
+    if !new_path.exists() {
+        new_path = app_state.internal_web_root.join(path.as_str());
+    }
+    tracing::debug!("Root request {path} => {}", new_path.display());
+    let mut req = Request::new(axum::body::Body::empty());
+    *req.headers_mut() = headers;
+    match ServeDir::new(new_path.as_path()).try_call(req).await {
+        Ok(resp) => {
+            resp.into_response()
+        },
+        Err(e) => {
+            tracing::warn!("Error serving file {}: {e}", new_path.display());
+            handle_404(app_state).await.into_response()
+        }
+    }
+}
+
+async fn handle_home_folder(
+    State(app_state): State<AppStateType>,
+    RawQuery(query): RawQuery,
+) -> axum::response::Response {
+    let redirect_path = format!("{HOME_DIR}/{}", app_state.index_file);
+    tracing::debug!("Redirecting /home/ => {redirect_path}");
+    permanent_redirect(redirect_path.as_str(), query.as_deref())
+}
+
+//#[debug_handler]
+async fn handle_home(
+    State(mut app_state): State<AppStateType>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap
+) -> axum::response::Response {
+    tracing::debug!("handle_home: {path}");
+    if let Some(redirect) = app_state.page_catalog.resolve_alias(format!("{HOME_DIR}/{path}").as_str()) {
+        tracing::debug!("Alias redirect: {path} => {redirect}");
+        return permanent_redirect(redirect.as_str(), query.as_deref());
+    }
+    let request = RequestInfo::new(
+        format!("{HOME_DIR}/{path}").as_str(),
+        query.as_deref(),
+        app_state.template_params.as_slice(),
+        &headers,
+        app_state.access.user(&headers),
+        app_state.messages.negotiate(&headers),
+    );
+    let path = PathBuf::from(path);
+    match get_response(&mut app_state, path.as_path(), &request, headers).await {
+        Ok(resp) => {
+            let status = resp.status();
+            // shed renders already carry their own page, and refused hotlinks
+            // aren't worth one
+            if status.is_success() || status.is_redirection() || status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::FORBIDDEN {
+                resp.into_response()
+            }
+            else if status == StatusCode::NOT_FOUND {
+                handle_404(app_state).await.into_response()
+            }
+            else {
+                handle_err(app_state).await.into_response()
+            }
+        },
+        Err(ChimeraError::IOError(e)) => {
+            tracing::warn!("IOError processing request for {}: {e:?}", path.display());
+            handle_404(app_state).await.into_response()
+        }
+        Err(e) => {
+            tracing::warn!("Error processing request for {}: {e:?}", path.display());
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
+
+async fn handle_root(
+    State(app_state): State<AppStateType>,
+    RawQuery(query): RawQuery,
+) -> axum::response::Response {
+    let redirect_path = format!("{HOME_DIR}/{}", app_state.index_file);
+    tracing::debug!("Redirecting / => {redirect_path}");
+    permanent_redirect(redirect_path.as_str(), query.as_deref())
+}
+
+// Redirects carry the query string along. Browsers keep the #fragment
+// themselves, unless the target names its own
+fn permanent_redirect(target: &str, query: Option<&str>) -> axum::response::Response {
+    Redirect::permanent(with_query(target, query).as_str()).into_response()
+}
+
+fn with_query(target: &str, query: Option<&str>) -> String {
+    let Some(query) = query.filter(|query| !query.is_empty()) else {
+        return target.to_string();
+    };
+    let (base, fragment) = match target.split_once('#') {
+        Some((base, fragment)) => (base, Some(fragment)),
+        None => (target, None),
+    };
+    let separator = match base.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    let mut url = format!("{base}{separator}{query}");
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    url
+}
+
+//#[debug_handler]
+async fn handle_fallback(
+    State(app_state): State<AppStateType>,
+    uri: axum::http::Uri,
+) -> axum::response::Response {
+    tracing::warn!("404: {uri}");
+    handle_404(app_state).await.into_response()
+}
+
+fn has_extension(file_name: &std::path::Path, match_ext: &str) -> bool {
+    if let Some(ext) = file_name.extension() {
+        return ext.eq_ignore_ascii_case(match_ext);
+    }
+    false
+}
+
+async fn serve_markdown_file(
+    app_state: &mut AppStateType,
+    path: &std::path::Path,
+    request: &RequestInfo,
+) -> Result<axum::response::Response, ChimeraError> {
+    tracing::debug!("Markdown request {}", path.display());
+    let mut headers = axum::http::header::HeaderMap::new();
+    let mut variant = request.cache_variant(app_state.html_generator.page_request_usage(path));
+    let mut no_store = app_state.uncached_folders.iter().any(|folder| path.starts_with(folder));
+    let html = match app_state.result_cache.get_variant(path, variant.as_str()).await {
+        Some(html) => {
+            if let Ok(hval) = axum::http::HeaderValue::from_str("cached") {
+                headers.append(CACHED_HEADER, hval);
+            }
+            html
+        },
+        None => {
+            // counted against the memory budget until the page is done
+            let _render = match &app_state.load_shedder {
+                Some(load_shedder) => {
+                    let size = tokio::fs::metadata(path).await.map_or(0, |metadata| metadata.len() as usize);
+                    match load_shedder.start_render(size) {
+                        Some(render) => Some(render),
+                        None => return handle_overloaded(app_state.clone()).await,
+                    }
+                },
+                None => None,
+            };
+            let mut perf_timer = PerfTimer::new();
+            let content = match app_state.converters.handles(path) {
+                true => app_state.converters.convert(path).await?,
+                false => Converted::Markdown(tokio::fs::read_to_string(path).await?),
+            };
+            perf_timer.sample("read-file", &mut headers);
+            let (body, scraper) = match content {
+                Converted::Markdown(md_content) => parse_markdown_with(md_content.as_str(), &app_state.markup),
+                Converted::Html(html) => {
+                    // the body is served as is, but its headings make the doclinks
+                    let (_body, scraper) = parse_markdown_with(html.as_str(), &app_state.markup);
+                    (html, scraper)
+                },
+            };
+            perf_timer.sample("parse-markdown", &mut headers);
+            if let Some(target) = scraper.metadata.get("redirect_to") {
+                // a stub left behind when the document moved
+                tracing::debug!("Front matter redirect: {} => {target}", path.display());
+                return Ok((
+                    StatusCode::MOVED_PERMANENTLY,
+                    [(axum::http::header::LOCATION, with_query(target, request.raw_query.as_deref()))],
+                ).into_response());
+            }
+            let scheduled = scraper.is_scheduled() && !app_state.serve_scheduled;
+            if scraper.is_draft() || scheduled {
+                let previewing = app_state.draft_token.as_ref().is_some_and(|token| {
+                    request.raw_param(DRAFT_PREVIEW_PARAM).is_some_and(|preview| preview == *token)
+                });
+                if !previewing {
+                    tracing::debug!("Hiding unpublished {}", path.display());
+                    return Ok(StatusCode::NOT_FOUND.into_response());
+                }
+                // never cached, or the next visitor would see it without the token
+                no_store = true;
+            }
+            // pages with dynamic template content can opt out with `cache: false`
+            no_store |= scraper.metadata.get("cache").is_some_and(|cache| cache == "false");
+            // the outdated banner depends on today's date, so don't cache it
+            let cacheable = !no_store && !expiry::is_dated(&scraper.metadata)
+                // nor the view count, which changes with every visit
+                && !app_state.html_generator.shows_view_count(scraper.get_template());
+            let from_catalog = app_state.html_generator.renders_from_catalog(&scraper);
+            let body = match &app_state.graphviz {
+                Some(graphviz) if scraper.has_graphs => graphviz.render(body, path).await,
+                _ => body,
+            };
+            let peers = match app_state.generate_index {
+                true => app_state.file_manager.find_peers(path),
+                false => None,
+            };
+            perf_timer.sample("find-peers", &mut headers);
+            let html = app_state.html_generator.gen_markdown(path, body, scraper, peers, request)?;
+            perf_timer.sample("generate-html", &mut headers);
+            // now the template is known
+            variant = request.cache_variant(app_state.html_generator.page_request_usage(path));
+            if cacheable {
+                match from_catalog {
+                    true => app_state.result_cache.add_catalog_variant(path, variant.as_str(), html.as_str()).await,
+                    false => app_state.result_cache.add_variant(path, variant.as_str(), html.as_str()).await,
+                }
+                perf_timer.sample("cache-results", &mut headers);
+            }
+            if let Ok(hval) = axum::http::HeaderValue::from_str("generated") {
+                headers.append(CACHED_HEADER, hval);
+            }
+            html
+        }
+    };
+    if let Some(page_views) = &app_state.page_views {
+        page_views.record(format!("{HOME_DIR}/{}", path.to_string_lossy()).as_str());
+    }
+    if app_state.messages.negotiates() {
+        headers.insert(axum::http::header::VARY, axum::http::HeaderValue::from_static("Accept-Language"));
+    }
+    if no_store {
+        headers.insert(axum::http::header::CACHE_CONTROL, axum::http::HeaderValue::from_static("no-store"));
+    }
+    else if !variant.is_empty() {
+        // shared caches don't know about the variant
+        if let Ok(hval) = axum::http::HeaderValue::from_str("private, no-cache") {
+            headers.insert(axum::http::header::CACHE_CONTROL, hval);
+        }
+    }
+    Ok((StatusCode::OK, headers, CsrfHtml(html)).into_response())
+}
+
+async fn serve_static_file(
+    path: &std::path::Path,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ChimeraError> {
+    tracing::debug!("Static request {}", path.display());
+    let mut req = Request::new(axum::body::Body::empty());
+    *req.headers_mut() = headers;
+    Ok(ServeDir::new(path).try_call(req).await?.into_response())
+}
+
+// Browsing to an audio file gets a page with a player. The player, range
+// requests and ?raw links get the file itself
+fn wants_player(request: &RequestInfo, headers: &HeaderMap) -> bool {
+    let raw = request.raw_query.as_deref().is_some_and(|query| {
+        query.split('&').any(|param| param == "raw" || param.starts_with("raw="))
+    });
+    let accepts_html = headers.get(axum::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    !raw && accepts_html && !headers.contains_key(axum::http::header::RANGE)
+}
+
+async fn serve_audio_page(
+    app_state: &mut AppStateType,
+    path: &std::path::Path,
+    request: &RequestInfo,
+) -> Result<axum::response::Response, ChimeraError> {
+    tracing::debug!("Audio request {}", path.display());
+    if !path.is_file() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let file = path.to_path_buf();
+    let metadata = tokio::task::spawn_blocking(move || audio_metadata::read(file.as_path()))
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let html = app_state.html_generator.gen_audio(path, &metadata, request.lang.as_deref())?;
+    if let Some(page_views) = &app_state.page_views {
+        page_views.record(format!("{HOME_DIR}/{}", path.to_string_lossy()).as_str());
+    }
+    Ok((StatusCode::OK, CsrfHtml(html)).into_response())
+}
+
+async fn serve_index(
+    app_state: &mut AppStateType,
+    path: &std::path::Path,
+    request: &RequestInfo,
+) -> Result<axum::response::Response, ChimeraError> {
+    let mut headers = axum::http::header::HeaderMap::new();
+    let html = match app_state.result_cache.get(path).await {
+        Some(html) => {
+            if let Ok(hval) = axum::http::HeaderValue::from_str("cached") {
+                headers.append(CACHED_HEADER, hval);
+            }
+            html
+        },
+        None => {
+            tracing::debug!("No file specified. Generating an index result at {}", path.display());
+            let peers = if let Ok(abs_path) = path.canonicalize() {
+                app_state.file_manager.find_peers_in_folder(abs_path.as_path(), None)
+            }
+            else {
+                app_state.file_manager.find_peers_in_folder(path, None)
+            };
+            if let Ok(hval) = axum::http::HeaderValue::from_str("generated") {
+                headers.append(CACHED_HEADER, hval);
+            }
+            app_state.html_generator.gen_index(path, peers, request.lang.as_deref()).await?
+        }
+    };
+    Ok((StatusCode::OK, headers, CsrfHtml(html)).into_response())
+}
+
+async fn get_response(
+    app_state: &mut AppStateType,
+    path: &std::path::Path,
+    request: &RequestInfo,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ChimeraError> {
+    tracing::debug!("Chimera request {}", path.display());
+    if app_state.access.allowed_users(path).is_some() {
+        let user = request.user.as_deref();
+        if !app_state.access.can_read(path, user) {
+            // Don't reveal that the document exists
+            tracing::info!("Access denied to {} for {user:?}", path.display());
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+        let mut response = get_permitted_response(app_state, path, request, headers).await?;
+        // no-store, if the page asked for it, is stricter still
+        response.headers_mut()
+            .entry(axum::http::header::CACHE_CONTROL)
+            .or_insert(axum::http::HeaderValue::from_static("private, no-cache"));
+        return Ok(response);
+    }
+    get_permitted_response(app_state, path, request, headers).await
+}
+
+async fn get_permitted_response(
+    app_state: &mut AppStateType,
+    path: &std::path::Path,
+    request: &RequestInfo,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ChimeraError> {
+    // with index_on_demand, the folder is catalogued and indexed on its first visit
+    let is_document = has_extension(path, "md") || app_state.converters.handles(path);
+    let folder = match is_document {
+        true => path.parent(),
+        false => Some(path).filter(|path| path.is_dir()),
+    };
+    if let Some(folder) = folder {
+        if let Some(abs_folder) = app_state.page_catalog.visit_folder(folder).await {
+            app_state.full_text_index.index_folder(abs_folder.as_path());
+        }
+    }
+    if is_document {
+        return serve_markdown_file(app_state, path, request).await;
+    }
+    else if path.is_dir() { 
+        // is this a folder?
+        let path_str = path.to_string_lossy();
+        if !path_str.ends_with('/') {
+            let path_with_slash = format!("{}/", path_str);
+            tracing::debug!("Missing /, redirecting to {path_with_slash}");
+            return Ok(permanent_redirect(path_with_slash.as_str(), request.raw_query.as_deref()));
+        }
+
+        let path_with_index = path.join(app_state.index_file.as_str());
+        if path_with_index.exists() {
+            tracing::debug!("No file specified, sending {}", path_with_index.display());
+            return serve_markdown_file(app_state, &path_with_index, request).await;
+        }
+        else if app_state.generate_index {
+            return serve_index(app_state, path, request).await;
+        }
+    }
+    if audio_metadata::is_audio(path) && wants_player(request, &headers) {
+        return serve_audio_page(app_state, path, request).await;
+    }
+    tracing::debug!("Not md or a dir {}. Falling back to static routing", path.display());
+    if let Some(hotlink_guard) = app_state.hotlink_guard.as_ref().filter(|guard| guard.protects(path)) {
+        if !hotlink_guard.allows(&headers) {
+            tracing::info!("Refused hotlink to {} from {:?}", path.display(), headers.get(axum::http::header::REFERER));
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+        // the answer depends on who's asking
+        let mut response = serve_static_file(path, headers).await?;
+        response.headers_mut().insert(axum::http::header::VARY, axum::http::HeaderValue::from_static("Referer"));
+        return Ok(response);
+    }
+    serve_static_file(path, headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dynamic_pages_skip_built_in_routes() {
+        let routes: Vec<String> = [HOME_DIR, "/home/*path", "/search", "/tags", "/tags/:tag", "/calendar/*path", "/random", "/*path", "/"]
+            .iter().map(|route| route.to_string()).collect();
+        assert!(is_valid_dynamic_page("/projects", routes.as_slice()));
+        assert!(is_valid_dynamic_page("/projects/current", routes.as_slice()));
+        assert!(!is_valid_dynamic_page("/", routes.as_slice()));
+        assert!(!is_valid_dynamic_page("/search", routes.as_slice()));
+        assert!(!is_valid_dynamic_page("/tags", routes.as_slice()));
+        assert!(!is_valid_dynamic_page("/tags/rust", routes.as_slice()));
+        assert!(!is_valid_dynamic_page("/calendar/journal", routes.as_slice()));
+        assert!(!is_valid_dynamic_page("/random", routes.as_slice()));
+        // only whole path segments are reserved
+        assert!(is_valid_dynamic_page("/tagsoup", routes.as_slice()));
+    }
+
+    #[test]
+    fn test_route_prefix() {
+        assert_eq!(route_prefix("/tags/:tag"), "/tags");
+        assert_eq!(route_prefix("/home/"), "/home");
+        assert_eq!(route_prefix("/admin/comments/:id/approve"), "/admin/comments");
+        assert_eq!(route_prefix("/*path"), "");
+        assert_eq!(route_prefix("/"), "");
+    }
+}
@@ -0,0 +1,41 @@
+use serde::Serialize;
+
+use crate::page_catalog::PageCatalog;
+
+// A document with links to anchors that none of its headings provide
+#[derive(Serialize)]
+pub struct BrokenAnchors {
+    pub url: String,
+    pub title: String,
+    pub anchors: Vec<String>,
+}
+
+pub fn check_anchors(catalog: &PageCatalog) -> Vec<BrokenAnchors> {
+    catalog.pages().into_iter()
+        .filter(|page| !page.broken_anchors.is_empty())
+        .map(|page| BrokenAnchors {
+            url: page.url,
+            title: page.title,
+            anchors: page.broken_anchors,
+        })
+        .collect()
+}
+
+// A document whose headings don't make a sound outline
+#[derive(Serialize)]
+pub struct OutlineProblems {
+    pub url: String,
+    pub title: String,
+    pub warnings: Vec<String>,
+}
+
+pub fn check_outlines(catalog: &PageCatalog) -> Vec<OutlineProblems> {
+    catalog.pages().into_iter()
+        .filter(|page| !page.outline_warnings.is_empty())
+        .map(|page| OutlineProblems {
+            url: page.url,
+            title: page.title,
+            warnings: page.outline_warnings,
+        })
+        .collect()
+}
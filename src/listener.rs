@@ -0,0 +1,30 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use socket2::{Domain, Socket, Type};
+
+use crate::chimera_error::ChimeraError;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+// Prefers a socket handed over by systemd socket activation (or systemfd),
+// so restarts don't refuse connections while the new process starts up.
+// Otherwise binds the port, optionally with SO_REUSEPORT so an upgraded
+// instance can start listening before the old one drains and exits
+pub fn bind(port: u16, reuse_port: bool) -> Result<tokio::net::TcpListener, ChimeraError> {
+    let mut listenfd = listenfd::ListenFd::from_env();
+    if let Some(listener) = listenfd.take_tcp_listener(0)? {
+        tracing::info!("Using inherited listener on {}", listener.local_addr()?);
+        listener.set_nonblocking(true)?;
+        return Ok(tokio::net::TcpListener::from_std(listener)?);
+    }
+
+    let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
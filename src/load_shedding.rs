@@ -0,0 +1,85 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+use crate::image_size_cache::ImageSizeCache;
+use crate::page_catalog::PageCatalog;
+use crate::result_cache::ResultCache;
+use crate::toml_config::MemoryConfig;
+
+// A render holds the markdown, the parsed body and the finished page at
+// once, each about the size of the document or more
+const RENDER_OVERHEAD: usize = 4;
+
+// Keeps the server inside its memory budget instead of letting the
+// container be killed. Usage is approximated as the result cache, the page
+// catalog, image sizes and the renders in progress; past max_mb,
+// background work is put off and uncached documents bigger than
+// large_document_kb are turned away with a 503
+#[derive(Clone)]
+pub struct LoadShedder {
+    result_cache: ResultCache,
+    page_catalog: PageCatalog,
+    image_size_cache: Option<ImageSizeCache>,
+    in_flight: Arc<AtomicUsize>,
+    max_bytes: usize,
+    large_document_bytes: usize,
+}
+
+// Counts a render towards the budget until it's dropped
+pub struct InFlightRender {
+    in_flight: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl Drop for InFlightRender {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(
+        config: MemoryConfig,
+        result_cache: ResultCache,
+        page_catalog: PageCatalog,
+        image_size_cache: Option<ImageSizeCache>,
+    ) -> Self {
+        LoadShedder {
+            result_cache,
+            page_catalog,
+            image_size_cache,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_bytes: config.max_mb.saturating_mul(1024 * 1024),
+            large_document_bytes: config.large_document_kb.saturating_mul(1024),
+        }
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.result_cache.size()
+            + self.page_catalog.approximate_size()
+            + self.image_size_cache.as_ref().map_or(0, ImageSizeCache::approximate_size)
+            + self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.used_bytes() > self.max_bytes
+    }
+
+    // None when the document is too big to render under the current
+    // pressure. Small documents are always let through
+    pub fn start_render(&self, document_bytes: usize) -> Option<InFlightRender> {
+        if document_bytes > self.large_document_bytes && self.under_pressure() {
+            tracing::warn!(
+                "Shedding a {document_bytes} byte render, using about {} of {} bytes",
+                self.used_bytes(),
+                self.max_bytes,
+            );
+            return None;
+        }
+        let bytes = document_bytes.saturating_mul(RENDER_OVERHEAD);
+        self.in_flight.fetch_add(bytes, Ordering::Relaxed);
+        Some(InFlightRender {
+            in_flight: self.in_flight.clone(),
+            bytes,
+        })
+    }
+}
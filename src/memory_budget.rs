@@ -0,0 +1,64 @@
+use crate::toml_config::{MemoryConfig, SearchConfig};
+
+// The tantivy writer's heap when neither [search] nor [memory] set it
+pub const DEFAULT_INDEX_WRITER_BYTES: usize = 50_000_000;
+// Tantivy refuses less than this per writer thread
+pub const MIN_INDEX_WRITER_BYTES: usize = 15_000_000;
+// Kept back for what can't be capped: the page catalog, image sizes and
+// renders in progress
+const MIN_HEADROOM_PERCENT: usize = 10;
+
+// How the [memory] ceiling is split between the subsystems that can be
+// sized up front. The result cache and the search index writer get their
+// configured percentages; the rest is headroom for the page catalog, the
+// image size cache and renders in progress, which are measured as they
+// grow, see LoadShedder. Without a ceiling, max_cache_size and a 50 MB
+// writer heap are used as before. A writer heap set in [search] is taken
+// as is, and comes out of the cache's share
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryBudget {
+    pub result_cache: usize,
+    pub index_writer: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(max_cache_size: usize, memory: Option<&MemoryConfig>, search: &SearchConfig) -> Self {
+        let writer_heap = search.writer_heap_mb.map(|mb| {
+            let bytes = mb.saturating_mul(1024 * 1024);
+            if bytes < MIN_INDEX_WRITER_BYTES {
+                tracing::warn!("[search] writer_heap_mb = {mb} is below tantivy's minimum of 15 MB, using that");
+            }
+            bytes.max(MIN_INDEX_WRITER_BYTES)
+        });
+        let Some(memory) = memory else {
+            return MemoryBudget {
+                result_cache: max_cache_size,
+                index_writer: writer_heap.unwrap_or(DEFAULT_INDEX_WRITER_BYTES),
+            };
+        };
+        let total = memory.max_mb.saturating_mul(1024 * 1024);
+        let (mut cache_percent, mut index_percent) = (memory.result_cache_percent, memory.search_index_percent);
+        let allotted = cache_percent + index_percent;
+        if allotted > 100 - MIN_HEADROOM_PERCENT {
+            tracing::warn!(
+                "[memory] gives away {allotted}% of max_mb, scaling it down to leave {MIN_HEADROOM_PERCENT}% headroom",
+            );
+            cache_percent = cache_percent * (100 - MIN_HEADROOM_PERCENT) / allotted;
+            index_percent = index_percent * (100 - MIN_HEADROOM_PERCENT) / allotted;
+        }
+        let index_writer = writer_heap.unwrap_or_else(|| (total / 100 * index_percent).max(MIN_INDEX_WRITER_BYTES));
+        // a writer bigger than its share comes out of the cache's
+        let headroom = total / 100 * MIN_HEADROOM_PERCENT;
+        let budget = MemoryBudget {
+            result_cache: (total / 100 * cache_percent).min(total.saturating_sub(index_writer + headroom)),
+            index_writer,
+        };
+        tracing::info!(
+            "Memory budget of {} MB: {} MB result cache, {} MB search index writer, the rest for pages in progress",
+            memory.max_mb,
+            budget.result_cache / (1024 * 1024),
+            budget.index_writer / (1024 * 1024),
+        );
+        budget
+    }
+}
@@ -0,0 +1,544 @@
+use std::{collections::HashMap, fmt::Write, net::SocketAddr, path::PathBuf, sync::{Arc, RwLock}, time::{Duration, Instant}};
+use axum::{extract::{ConnectInfo, Query, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Redirect, Response}, Form};
+use lettre::{message::{header::{ContentType, HeaderName, HeaderValue}, Mailbox}, transport::smtp::authentication::Credentials, Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::{handle_404, handle_err, ChimeraError};
+use crate::feed::{self, Feed};
+use crate::toml_config::{NewsletterConfig, SmtpSecurity};
+use crate::session::CsrfHtml;
+use crate::AppStateType;
+
+pub const SUBSCRIBE_URL: &str = "/subscribe";
+
+// Longest address RFC 5321 allows
+const MAX_EMAIL_LEN: usize = 254;
+// Between subscriptions from one address, which each send an email
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+// Between confirmation emails to one address, however many ask for them
+const CONFIRMATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Unconfirmed subscriptions are dropped after this long
+const CONFIRM_WITHIN: time::Duration = time::Duration::hours(48);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Subscriber {
+    email: String,
+    // In the confirm and unsubscribe links
+    token: String,
+    subscribed: String,
+    confirmed: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SubscriberFile {
+    // When the newest document already sent was published
+    last_sent: Option<String>,
+    subscribers: Vec<Subscriber>,
+}
+
+#[derive(Default, Serialize)]
+pub struct SubscriberCounts {
+    pub confirmed: usize,
+    pub unconfirmed: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SubscribeRejection {
+    Spam,
+    InvalidEmail,
+    TooSoon,
+    QueueFull,
+}
+
+struct NewsletterInternal {
+    path: PathBuf,
+    file: SubscriberFile,
+    last_post: HashMap<String, Instant>,
+    // Lowercased email => when it was last sent a confirmation
+    last_confirmation: HashMap<String, Instant>,
+}
+
+// Double opt-in subscriptions to the feed, by email. Subscribing sends a
+// link to confirm with, and only confirmed addresses are sent the feed's
+// new documents, checked for every check_interval_mins
+#[derive(Clone)]
+pub struct Newsletter {
+    lock: Arc<RwLock<NewsletterInternal>>,
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    // eg: https://notes.example.com, for the links in emails
+    site_url: String,
+    feed: Feed,
+    max_unconfirmed: usize,
+}
+
+impl Newsletter {
+    pub fn new(newsletter_dir: PathBuf, config: NewsletterConfig, site_url: Option<&str>, feed: Feed) -> Option<Self> {
+        let Some(site_url) = site_url else {
+            tracing::warn!("Ignoring [newsletter], it needs site_url in [social]");
+            return None;
+        };
+        let from = match config.from.parse::<Mailbox>() {
+            Ok(from) => from,
+            Err(e) => {
+                tracing::warn!("Ignoring [newsletter], from isn't an email address: {e}");
+                return None;
+            },
+        };
+        let builder = match config.smtp_security {
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(config.smtp_host.as_str()),
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(config.smtp_host.as_str()),
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(config.smtp_host.as_str())),
+        };
+        let mut builder = match builder {
+            Ok(builder) => builder,
+            Err(e) => {
+                tracing::warn!("Ignoring [newsletter], can't use {}: {e}", config.smtp_host);
+                return None;
+            },
+        };
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (config.smtp_username, config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        if let Err(e) = std::fs::create_dir_all(newsletter_dir.as_path()) {
+            tracing::warn!("Failed to create newsletter directory {}: {e}", newsletter_dir.display());
+        }
+        let path = newsletter_dir.join("subscribers.toml");
+        let file = match std::fs::read_to_string(path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", path.display());
+                        SubscriberFile::default()
+                    }
+                }
+            },
+            Err(_) => SubscriberFile::default(),
+        };
+        tracing::info!("Newsletter loaded with {} subscribers", file.subscribers.len());
+        let newsletter = Newsletter {
+            lock: Arc::new(RwLock::new(NewsletterInternal {
+                path,
+                file,
+                last_post: HashMap::new(),
+                last_confirmation: HashMap::new(),
+            })),
+            mailer: builder.build(),
+            from,
+            site_url: site_url.trim_end_matches('/').to_string(),
+            feed,
+            max_unconfirmed: config.max_unconfirmed,
+        };
+        tokio::spawn(scheduled_sends(newsletter.clone(), Duration::from_secs(config.check_interval_mins.max(1) * 60)));
+        Some(newsletter)
+    }
+
+    pub fn counts(&self) -> SubscriberCounts {
+        let Ok(lock) = self.lock.read() else {
+            return SubscriberCounts::default();
+        };
+        let confirmed = lock.file.subscribers.iter().filter(|s| s.confirmed).count();
+        SubscriberCounts {
+            confirmed,
+            unconfirmed: lock.file.subscribers.len() - confirmed,
+        }
+    }
+
+    // Confirmed subscribers, for the admin page to download
+    pub fn csv(&self) -> String {
+        let mut csv = String::from("email,subscribed\n");
+        if let Ok(lock) = self.lock.read() {
+            for subscriber in lock.file.subscribers.iter().filter(|s| s.confirmed) {
+                let _ = writeln!(csv, "{},{}", subscriber.email, subscriber.subscribed);
+            }
+        }
+        csv
+    }
+
+    // Returns the address and token to send a confirmation link to, or
+    // None when the address is already confirmed or was sent a link within
+    // CONFIRMATION_INTERVAL. The subscriber's IP is only kept in memory for
+    // rate limiting
+    pub fn subscribe(&self, addr: &str, email: &str, honeypot: &str) -> Result<Option<(String, String)>, SubscribeRejection> {
+        if !honeypot.is_empty() {
+            return Err(SubscribeRejection::Spam);
+        }
+        let email = email.trim();
+        // no display names or comments, and nothing a CSV would need to quote
+        let valid = email.len() <= MAX_EMAIL_LEN
+            && email.parse::<Address>().is_ok()
+            && !email.contains(|c: char| c.is_whitespace() || c.is_control() || "\",<>".contains(c))
+            // which spreadsheets would take for a formula
+            && !email.starts_with(['=', '+', '-', '@']);
+        if !valid {
+            return Err(SubscribeRejection::InvalidEmail);
+        }
+        let Ok(mut lock) = self.lock.write() else {
+            return Err(SubscribeRejection::QueueFull);
+        };
+        let now = Instant::now();
+        lock.last_post.retain(|_, when| now.duration_since(*when) < MIN_INTERVAL);
+        if lock.last_post.contains_key(addr) {
+            return Err(SubscribeRejection::TooSoon);
+        }
+        lock.last_post.insert(addr.to_string(), now);
+        let expired = OffsetDateTime::now_utc() - CONFIRM_WITHIN;
+        lock.file.subscribers.retain(|s| s.confirmed || parse_time(s.subscribed.as_str()).is_some_and(|time| time > expired));
+        lock.last_confirmation.retain(|_, when| now.duration_since(*when) < CONFIRMATION_INTERVAL);
+        let key = email.to_lowercase();
+        if lock.last_confirmation.contains_key(&key) {
+            return Ok(None);
+        }
+        if let Some(subscriber) = lock.file.subscribers.iter().find(|s| s.email.eq_ignore_ascii_case(email)) {
+            let resend = (!subscriber.confirmed).then(|| (subscriber.email.clone(), subscriber.token.clone()));
+            if resend.is_some() {
+                lock.last_confirmation.insert(key, now);
+            }
+            return Ok(resend);
+        }
+        if lock.file.subscribers.iter().filter(|s| !s.confirmed).count() >= self.max_unconfirmed {
+            return Err(SubscribeRejection::QueueFull);
+        }
+        lock.last_confirmation.insert(key, now);
+        let token = new_token();
+        lock.file.subscribers.push(Subscriber {
+            email: email.to_string(),
+            token: token.clone(),
+            subscribed: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            confirmed: false,
+        });
+        Ok(Some((email.to_string(), token)))
+    }
+
+    // Saves the subscription, then emails the link to confirm it
+    pub async fn send_confirmation(&self, email: &str, token: &str) -> Result<(), ChimeraError> {
+        self.save().await?;
+        let title = self.feed.title();
+        let body = format!(
+            "Someone, hopefully you, asked for {title}'s new posts to be sent to this address.\n\n\
+To confirm, follow this link:\n{}\n\n\
+If it wasn't you, ignore this email and you won't hear from us again.\n",
+            self.link("confirm", token),
+        );
+        self.send(email, format!("Confirm your subscription to {title}").as_str(), body, None).await
+    }
+
+    // Returns whether the token was found
+    pub async fn confirm(&self, token: &str) -> Result<bool, ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            match lock.file.subscribers.iter_mut().find(|s| s.token == token) {
+                Some(subscriber) => {
+                    subscriber.confirmed = true;
+                    true
+                },
+                None => false,
+            }
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(found)
+    }
+
+    pub async fn unsubscribe(&self, token: &str) -> Result<bool, ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            let before = lock.file.subscribers.len();
+            lock.file.subscribers.retain(|s| s.token != token);
+            lock.file.subscribers.len() != before
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(found)
+    }
+
+    // Emails the documents published since the last send. The first time,
+    // it only notes where the feed is, rather than sending all of it
+    async fn send_new_posts(&self) -> Result<(), ChimeraError> {
+        let pages = self.feed.newest();
+        let Some((newest, _page)) = pages.first() else {
+            return Ok(());
+        };
+        let (last_sent, subscribers) = {
+            let mut lock = self.lock.write()?;
+            let last_sent = lock.file.last_sent.as_deref().and_then(parse_time);
+            let sent_up_to = last_sent.map_or(*newest, |last_sent| last_sent.max(*newest));
+            lock.file.last_sent = Some(feed::rfc3339(sent_up_to));
+            let subscribers: Vec<Subscriber> = lock.file.subscribers.iter().filter(|s| s.confirmed).cloned().collect();
+            (last_sent, subscribers)
+        };
+        let Some(last_sent) = last_sent else {
+            return self.save().await;
+        };
+        let new_pages: Vec<_> = pages.iter().filter(|(published, _page)| *published > last_sent).collect();
+        if new_pages.is_empty() {
+            return Ok(());
+        }
+        self.save().await?;
+        let title = self.feed.title();
+        let subject = match new_pages.as_slice() {
+            [(_published, page)] => format!("{title}: {}", page.title),
+            _ => format!("{title}: {} new posts", new_pages.len()),
+        };
+        let mut posts = String::new();
+        for (_published, page) in &new_pages {
+            let _ = writeln!(posts, "{}\n{}{}", page.title, self.site_url, page.url);
+            if !page.excerpt.is_empty() {
+                let _ = writeln!(posts, "{}", page.excerpt);
+            }
+            posts.push('\n');
+        }
+        tracing::info!("Sending {} new posts to {} subscribers", new_pages.len(), subscribers.len());
+        for subscriber in subscribers {
+            let unsubscribe = self.link("unsubscribe", subscriber.token.as_str());
+            let body = format!("{posts}--\nTo stop getting these emails: {unsubscribe}\n");
+            if let Err(e) = self.send(subscriber.email.as_str(), subject.as_str(), body, Some(unsubscribe.as_str())).await {
+                tracing::warn!("Failed to send new posts to {}: {e:?}", subscriber.email);
+            }
+        }
+        Ok(())
+    }
+
+    fn link(&self, action: &str, token: &str) -> String {
+        format!("{}{SUBSCRIBE_URL}/{action}?token={token}", self.site_url)
+    }
+
+    async fn send(&self, to: &str, subject: &str, body: String, unsubscribe: Option<&str>) -> Result<(), ChimeraError> {
+        let to = to.parse::<Mailbox>().map_err(|e| ChimeraError::MailError(e.to_string()))?;
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        if let Some(unsubscribe) = unsubscribe {
+            let name = HeaderName::new_from_ascii_str("List-Unsubscribe");
+            message = message.raw_header(HeaderValue::new(name, format!("<{unsubscribe}>")));
+        }
+        let message = message.body(body).map_err(|e| ChimeraError::MailError(e.to_string()))?;
+        self.mailer.send(message).await.map_err(|e| ChimeraError::MailError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn save(&self) -> Result<(), ChimeraError> {
+        let (path, toml) = {
+            let lock = self.lock.read()?;
+            match toml::to_string(&lock.file) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting subscribers to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+async fn scheduled_sends(newsletter: Newsletter, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // the first tick is immediate; the page catalog may not be complete yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = newsletter.send_new_posts().await {
+            tracing::error!("Failed sending the newsletter: {e:?}");
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(time, &Rfc3339).ok()
+}
+
+fn new_token() -> String {
+    let mut bytes = [0_u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeQuery {
+    status: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeForm {
+    email: String,
+    // Left empty by people, filled in by naive bots
+    #[serde(default)]
+    website: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenForm {
+    token: String,
+}
+
+async fn subscribe_page(app_state: AppStateType, status: Option<&str>, action: Option<&str>, token: Option<&str>) -> Response {
+    if app_state.newsletter.is_none() {
+        return handle_404(app_state).await.into_response();
+    }
+    match app_state.html_generator.gen_subscribe(status, action, token) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+// eg: /subscribe?status=sent after subscribing
+pub async fn handle_subscribe_page(
+    State(app_state): State<AppStateType>,
+    Query(query): Query<SubscribeQuery>,
+) -> Response {
+    subscribe_page(app_state, query.status.as_deref(), None, None).await
+}
+
+pub async fn handle_subscribe(
+    State(app_state): State<AppStateType>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<SubscribeForm>,
+) -> Response {
+    let Some(newsletter) = app_state.newsletter.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    let addr = app_state.trusted_proxies.client_addr(&headers, addr);
+    let sent = format!("{SUBSCRIBE_URL}?status=sent");
+    match newsletter.subscribe(addr.as_str(), form.email.as_str(), form.website.as_str()) {
+        Ok(Some((email, token))) => {
+            let newsletter = newsletter.clone();
+            tokio::spawn(async move {
+                if let Err(e) = newsletter.send_confirmation(email.as_str(), token.as_str()).await {
+                    tracing::warn!("Failed to send a subscription confirmation: {e:?}");
+                }
+            });
+            Redirect::to(sent.as_str()).into_response()
+        },
+        // Don't tell anyone who's already subscribed, or a bot it was caught
+        Ok(None) | Err(SubscribeRejection::Spam) => Redirect::to(sent.as_str()).into_response(),
+        Err(SubscribeRejection::InvalidEmail) => {
+            Redirect::to(format!("{SUBSCRIBE_URL}?status=invalid").as_str()).into_response()
+        },
+        Err(SubscribeRejection::TooSoon) => {
+            (StatusCode::TOO_MANY_REQUESTS, "Please wait before subscribing again").into_response()
+        },
+        Err(SubscribeRejection::QueueFull) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "Please try again later").into_response()
+        },
+    }
+}
+
+// The links in emails show a button rather than acting straight away, so
+// mail scanners following them don't confirm or unsubscribe anyone
+pub async fn handle_confirm_page(
+    State(app_state): State<AppStateType>,
+    Query(query): Query<SubscribeQuery>,
+) -> Response {
+    subscribe_page(app_state, None, Some("confirm"), query.token.as_deref()).await
+}
+
+pub async fn handle_unsubscribe_page(
+    State(app_state): State<AppStateType>,
+    Query(query): Query<SubscribeQuery>,
+) -> Response {
+    subscribe_page(app_state, None, Some("unsubscribe"), query.token.as_deref()).await
+}
+
+pub async fn handle_confirm(
+    State(app_state): State<AppStateType>,
+    Form(form): Form<TokenForm>,
+) -> Response {
+    let Some(newsletter) = app_state.newsletter.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match newsletter.confirm(form.token.as_str()).await {
+        Ok(true) => Redirect::to(format!("{SUBSCRIBE_URL}?status=confirmed").as_str()).into_response(),
+        Ok(false) => Redirect::to(format!("{SUBSCRIBE_URL}?status=expired").as_str()).into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+pub async fn handle_unsubscribe(
+    State(app_state): State<AppStateType>,
+    Form(form): Form<TokenForm>,
+) -> Response {
+    let Some(newsletter) = app_state.newsletter.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    match newsletter.unsubscribe(form.token.as_str()).await {
+        // unknown tokens are most likely already unsubscribed
+        Ok(_) => Redirect::to(format!("{SUBSCRIBE_URL}?status=unsubscribed").as_str()).into_response(),
+        Err(_) => handle_err(app_state).await.into_response(),
+    }
+}
+
+// Confirmed subscribers, from the admin page
+pub async fn handle_export(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    let Some(newsletter) = app_state.newsletter.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"subscribers.csv\""),
+        ],
+        newsletter.csv(),
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessControl;
+    use crate::file_manager::FileManager;
+    use crate::page_catalog::PageCatalog;
+    use crate::toml_config::{AccessConfig, FeedConfig};
+
+    async fn newsletter(folder: &std::path::Path) -> Newsletter {
+        let document_root = folder.join("documents");
+        std::fs::create_dir_all(document_root.as_path()).unwrap();
+        let file_manager = FileManager::new(document_root.as_path(), "index.md").await.unwrap();
+        let access = AccessControl::new(AccessConfig::default());
+        let page_catalog = PageCatalog::new(document_root.as_path(), &file_manager, access, true).await;
+        let site_url = Some("https://notes.example.com");
+        let feed = Feed::new(&FeedConfig::default(), "Notes", site_url, page_catalog);
+        let config = NewsletterConfig {
+            from: "Notes <notes@example.com>".to_string(),
+            smtp_host: "localhost".to_string(),
+            smtp_port: None,
+            smtp_security: SmtpSecurity::None,
+            smtp_username: None,
+            smtp_password: None,
+            check_interval_mins: 60,
+            max_unconfirmed: 10,
+        };
+        Newsletter::new(folder.join("newsletter"), config, site_url, feed).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_no_second_confirmation_within_interval() {
+        let folder = std::env::temp_dir().join(format!("chimera-newsletter-{}", std::process::id()));
+        let newsletter = newsletter(folder.as_path()).await;
+        let first = newsletter.subscribe("192.0.2.1", "reader@example.com", "").unwrap();
+        assert!(matches!(first, Some((ref email, _)) if email == "reader@example.com"));
+        // from elsewhere, so MIN_INTERVAL doesn't turn it away first
+        assert_eq!(newsletter.subscribe("192.0.2.2", "reader@example.com", ""), Ok(None));
+        assert_eq!(newsletter.subscribe("192.0.2.3", "Reader@Example.com", ""), Ok(None));
+        assert_eq!(newsletter.subscribe("192.0.2.1", "other@example.com", ""), Err(SubscribeRejection::TooSoon));
+        assert_eq!(newsletter.counts().unconfirmed, 1);
+        let _ = std::fs::remove_dir_all(folder.as_path());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{path::{Path, PathBuf}, sync::{Arc, RwLock}};
+use axum::{extract::State, response::{IntoResponse, Response}};
+use sha2::{Digest, Sha256};
+
+use crate::bookmarks::BOOKMARKS_URL;
+use crate::chimera_error::handle_err;
+use crate::page_catalog::PageCatalog;
+use crate::search_export::SEARCH_INDEX_URL;
+use crate::toml_config::OfflineConfig;
+use crate::session::CsrfHtml;
+use crate::{AppStateType, HOME_DIR};
+
+pub const SERVICE_WORKER_URL: &str = "/sw.js";
+pub const OFFLINE_URL: &str = "/offline";
+
+// Precached along with the pages, so they're styled offline
+const CORE_ASSETS: [&str; 4] = ["/style/skeleton.css", "/style/chimera.css", "/style/site.css", "/offline.js"];
+
+// Pages for whoever's signed in, which the next person to use the browser
+// shouldn't be able to read offline
+const PRIVATE_PATHS: [&str; 3] = ["/admin", BOOKMARKS_URL, "/history"];
+
+// Everything after the generated constants. Pages visited while online are
+// kept (up to RECENT_PAGES) and served when the network isn't there, falling
+// back to the offline page. Responses marked no-store or private aren't
+// kept, nor are private paths or requests that sent credentials. The
+// precache is named for its content hashes, so a changed document gets a
+// new worker, which drops the old copies
+const WORKER_JS: &str = r#"
+const BASE = self.location.pathname.replace(/\/sw\.js$/, "");
+const PRECACHE_NAME = `chimera-precache-${VERSION}`;
+const PAGES_NAME = "chimera-pages";
+
+self.addEventListener("install", (event) => {
+  event.waitUntil(caches.open(PRECACHE_NAME).then((cache) => {
+    // one missing page shouldn't stop the rest being cached
+    return Promise.allSettled(PRECACHE.map((url) => cache.add(BASE + url)));
+  }).then(() => self.skipWaiting()));
+});
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(caches.keys().then((names) => {
+    return Promise.all(names
+      .filter((name) => name.startsWith("chimera-precache-") && name != PRECACHE_NAME)
+      .map((name) => caches.delete(name)));
+  }).then(() => self.clients.claim()));
+});
+
+function isKeepable(request, response) {
+  const path = new URL(request.url).pathname.slice(BASE.length);
+  if (PRIVATE_PATHS.some((prefix) => path == prefix || path.startsWith(prefix + "/"))) {
+    return false;
+  }
+  if (request.headers.has("authorization")) {
+    return false;
+  }
+  const cacheControl = (response.headers.get("cache-control") || "").toLowerCase();
+  return !/(^|[\s,])(no-store|private)($|[\s,=])/.test(cacheControl);
+}
+
+async function keepPage(request, response) {
+  const cache = await caches.open(PAGES_NAME);
+  await cache.put(request, response);
+  const keys = await cache.keys();
+  for (const key of keys.slice(0, Math.max(0, keys.length - RECENT_PAGES))) {
+    await cache.delete(key);
+  }
+}
+
+self.addEventListener("fetch", (event) => {
+  const request = event.request;
+  if (request.method != "GET" || new URL(request.url).origin != self.location.origin) {
+    return;
+  }
+  event.respondWith(fetch(request).then((response) => {
+    if (request.mode == "navigate" && response.ok && isKeepable(request, response)) {
+      event.waitUntil(keepPage(request, response.clone()));
+    }
+    return response;
+  }).catch(async () => {
+    const cached = await caches.match(request);
+    if (cached) {
+      return cached;
+    }
+    if (request.mode == "navigate") {
+      return caches.match(BASE + OFFLINE_URL);
+    }
+    return Response.error();
+  }));
+});
+"#;
+
+// Generates the service worker that keeps recently visited documents
+// readable offline. Its precache list comes from the page catalog, so the
+// worker is rebuilt whenever a document changes
+#[derive(Clone)]
+pub struct Offline {
+    // Catalog generation it was built at => worker source
+    worker: Arc<RwLock<Option<(u64, String)>>>,
+    page_catalog: PageCatalog,
+    document_root: PathBuf,
+    web_roots: Vec<PathBuf>,
+    precache_pages: usize,
+    recent_pages: usize,
+}
+
+impl Offline {
+    pub fn new(config: OfflineConfig, page_catalog: PageCatalog, document_root: &Path, web_roots: Vec<PathBuf>) -> Self {
+        Offline {
+            worker: Arc::new(RwLock::new(None)),
+            page_catalog,
+            document_root: document_root.to_path_buf(),
+            web_roots,
+            precache_pages: config.precache_pages,
+            recent_pages: config.recent_pages,
+        }
+    }
+
+    pub fn service_worker(&self) -> String {
+        let generation = self.page_catalog.generation();
+        if let Ok(lock) = self.worker.read() {
+            if let Some((built_at, worker)) = lock.as_ref() {
+                if *built_at == generation {
+                    return worker.clone();
+                }
+            }
+        }
+        let worker = self.build_worker();
+        if let Ok(mut lock) = self.worker.write() {
+            *lock = Some((generation, worker.clone()));
+        }
+        worker
+    }
+
+    // The offline and home pages, the most recently changed documents and
+    // the core assets, by URL and the file behind it
+    fn precache_list(&self) -> Vec<(String, Option<PathBuf>)> {
+        let mut pages = self.page_catalog.pages();
+        pages.sort_by_key(|page| std::cmp::Reverse(page.modtime));
+        let mut list = vec![
+            (OFFLINE_URL.to_string(), None),
+            (format!("{HOME_DIR}/"), None),
+            // for searching offline; it changes along with the documents
+            (SEARCH_INDEX_URL.to_string(), None),
+        ];
+        list.extend(pages.into_iter().take(self.precache_pages).map(|page| {
+            (page.url, Some(self.document_root.join(page.path.as_str())))
+        }));
+        list.extend(CORE_ASSETS.iter().map(|url| {
+            let relative = url.trim_start_matches('/');
+            let file = self.web_roots.iter().map(|root| root.join(relative)).find(|file| file.is_file());
+            (url.to_string(), file)
+        }));
+        list
+    }
+
+    fn build_worker(&self) -> String {
+        let list = self.precache_list();
+        let mut hasher = Sha256::new();
+        for (url, file) in &list {
+            hasher.update(url.as_bytes());
+            if let Some(contents) = file.as_ref().and_then(|file| std::fs::read(file).ok()) {
+                hasher.update(Sha256::digest(contents.as_slice()));
+            }
+        }
+        let version: String = hasher.finalize().iter().take(8).map(|byte| format!("{byte:02x}")).collect();
+        let urls: Vec<&str> = list.iter().map(|(url, _file)| url.as_str()).collect();
+        tracing::debug!("Built service worker {version} with {} precached URLs", urls.len());
+        format!(
+            "const VERSION = \"{version}\";\nconst OFFLINE_URL = \"{OFFLINE_URL}\";\nconst RECENT_PAGES = {};\nconst PRECACHE = {};\nconst PRIVATE_PATHS = {};\n{WORKER_JS}",
+            self.recent_pages,
+            serde_json::to_string(&urls).unwrap_or_else(|_| "[]".to_string()),
+            serde_json::to_string(&PRIVATE_PATHS).unwrap_or_else(|_| "[]".to_string()),
+        )
+    }
+}
+
+pub async fn handle_service_worker(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    let Some(offline) = &app_state.offline else {
+        return handle_err(app_state).await.into_response();
+    };
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "text/javascript"),
+            // browsers check for a new worker on navigation, but only past
+            // their HTTP cache
+            (axum::http::header::CACHE_CONTROL, "no-cache"),
+        ],
+        offline.service_worker(),
+    ).into_response()
+}
+
+pub async fn handle_offline_page(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    match app_state.html_generator.gen_offline() {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering the offline page: {e:?}");
+            handle_err(app_state).await.into_response()
+        },
+    }
+}
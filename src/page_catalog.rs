@@ -0,0 +1,571 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, ffi::OsStr, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock}, time::SystemTime};
+use lazy_static::lazy_static;
+use rand::seq::IteratorRandom;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::access::AccessControl;
+use crate::document_scraper::{parse_markdown, InternalLink};
+use crate::duplicates;
+use crate::file_manager::{find_markdown_files, markdown_files_in, FileManager};
+use crate::publishing::{self, Publisher};
+use crate::result_cache::ResultCache;
+use crate::tags;
+use crate::HOME_DIR;
+
+// Characters of a document's first paragraph kept as its excerpt
+const EXCERPT_LENGTH: usize = 200;
+
+lazy_static! {
+    static ref TAG_RE: Regex = Regex::new(r"<[^>]*>").unwrap();
+}
+
+#[derive(Clone, Serialize)]
+pub struct PageSummary {
+    pub url: String,
+    // Path relative to the document root
+    pub path: String,
+    pub title: String,
+    pub modified: String,
+    pub metadata: HashMap<String, String>,
+    pub tags: Vec<String>,
+    // Front matter `description:`, or the start of the first paragraph
+    pub excerpt: String,
+    #[serde(skip)]
+    pub headings: Vec<InternalLink>,
+    #[serde(skip)]
+    pub modtime: SystemTime,
+    // Old URLs that redirect here, from `aliases:` front matter
+    #[serde(skip)]
+    pub aliases: Vec<String>,
+    #[serde(skip)]
+    pub broken_anchors: Vec<String>,
+    // Problems with its headings, see DocumentScraper::outline_warnings
+    #[serde(skip)]
+    pub outline_warnings: Vec<String>,
+    // For finding near-duplicate documents, see duplicates.rs
+    #[serde(skip)]
+    pub signature: Option<Vec<u64>>,
+}
+
+#[derive(PartialEq)]
+struct MenuEntry {
+    menu: String,
+    weight: Option<String>,
+    title: String,
+    url: String,
+}
+
+struct PageCatalogInternal {
+    pages: BTreeMap<PathBuf, PageSummary>,
+    // Alias, without the leading slash => page url
+    aliases: HashMap<String, String>,
+}
+
+impl PageCatalogInternal {
+    fn rebuild_aliases(&mut self) {
+        self.aliases.clear();
+        for page in self.pages.values() {
+            for alias in &page.aliases {
+                if let Some(existing) = self.aliases.insert(alias.clone(), page.url.clone()) {
+                    tracing::warn!("Alias /{alias} is claimed by both {existing} and {}", page.url);
+                }
+            }
+        }
+    }
+}
+
+// Every document on the site, kept current by file change events.
+// Restricted documents are left out, since pages built from the catalog
+// are cached and shown to everyone. With index_on_demand, it only holds
+// the folders visited so far
+#[derive(Clone)]
+pub struct PageCatalog {
+    lock: Arc<RwLock<PageCatalogInternal>>,
+    // Bumped on every change, so anything built from the catalog can tell
+    // when it's stale
+    generation: Arc<AtomicU64>,
+    document_root: PathBuf,
+    access: AccessControl,
+    // Looks after documents left out until their publish date
+    publisher: Publisher,
+    // Absolute paths of the folders scanned so far, None when the whole
+    // tree is scanned up front
+    visited: Option<Arc<RwLock<HashSet<PathBuf>>>>,
+}
+
+impl PageCatalog {
+    pub async fn new(document_root: &Path, file_manager: &FileManager, access: AccessControl, on_demand: bool) -> Self {
+        let catalog = PageCatalog {
+            lock: Arc::new(RwLock::new(PageCatalogInternal {
+                pages: BTreeMap::new(),
+                aliases: HashMap::new(),
+            })),
+            generation: Arc::new(AtomicU64::new(0)),
+            document_root: document_root.to_path_buf(),
+            access,
+            publisher: file_manager.publisher(),
+            visited: on_demand.then(|| Arc::new(RwLock::new(HashSet::new()))),
+        };
+        match on_demand {
+            true => tracing::info!("Page catalog fills in as folders are visited"),
+            false => catalog.rescan(file_manager.get_markdown_files()).await,
+        }
+        catalog
+    }
+
+    // Adds the documents of a folder, relative to the document root, the
+    // first time it's seen. Returns its absolute path on that first visit;
+    // None after, or when the whole tree is already catalogued
+    pub async fn visit_folder(&self, folder: &Path) -> Option<PathBuf> {
+        let visited = self.visited.as_ref()?;
+        let folder = self.document_root.join(folder);
+        if !visited.write().ok()?.insert(folder.clone()) {
+            return None;
+        }
+        let mut pages = Vec::new();
+        for file in markdown_files_in(folder.as_path()) {
+            if let Some(page) = self.summarize(file.as_path()).await {
+                pages.push((file, page));
+            }
+        }
+        tracing::debug!("Page catalog visited {}, adding {} documents", folder.display(), pages.len());
+        if let Ok(mut lock) = self.lock.write() {
+            lock.pages.extend(pages);
+            lock.rebuild_aliases();
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Some(folder)
+    }
+
+    // Everything the catalog should hold: the whole tree, or the documents
+    // of the folders visited so far
+    fn markdown_files(&self) -> Vec<PathBuf> {
+        let Some(visited) = &self.visited else {
+            return find_markdown_files(self.document_root.as_path());
+        };
+        let Ok(lock) = visited.read() else {
+            return Vec::new();
+        };
+        lock.iter().flat_map(|folder| markdown_files_in(folder)).collect()
+    }
+
+    fn is_visited(&self, path: &Path) -> bool {
+        let Some(visited) = &self.visited else {
+            return true;
+        };
+        let Some(folder) = path.parent() else {
+            return false;
+        };
+        visited.read().is_ok_and(|lock| lock.contains(folder))
+    }
+
+    pub fn listen_for_changes(&self, file_manager: &FileManager, result_cache: ResultCache) {
+        let rx = file_manager.subscribe();
+        tokio::spawn(listen_for_changes(rx, self.clone(), result_cache));
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Bytes held by the catalog, counting its strings and a rough allowance
+    // for each struct around them
+    pub fn approximate_size(&self) -> usize {
+        let Ok(lock) = self.lock.read() else {
+            return 0;
+        };
+        let pages: usize = lock.pages.iter().map(|(path, page)| {
+            path.as_os_str().len()
+                + std::mem::size_of::<PageSummary>()
+                + page.url.len() + page.path.len() + page.title.len() + page.modified.len() + page.excerpt.len()
+                + page.metadata.iter().map(|(key, value)| key.len() + value.len() + 48).sum::<usize>()
+                + page.tags.iter().map(|tag| tag.len() + 24).sum::<usize>()
+                + page.headings.iter().map(|heading| heading.anchor.len() + heading.name.len() + 56).sum::<usize>()
+                + page.aliases.iter().chain(page.broken_anchors.iter()).chain(page.outline_warnings.iter()).map(|text| text.len() + 24).sum::<usize>()
+                + page.signature.as_ref().map_or(0, |signature| signature.len() * std::mem::size_of::<u64>())
+        }).sum();
+        let aliases: usize = lock.aliases.iter().map(|(alias, url)| alias.len() + url.len() + 48).sum();
+        pages + aliases
+    }
+
+    // Sorted by url
+    pub fn pages(&self) -> Vec<PageSummary> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        let mut pages: Vec<PageSummary> = lock.pages.values().cloned().collect();
+        pages.sort_by(|a, b| a.url.cmp(&b.url));
+        pages
+    }
+
+    // The best scoring pages, highest first, leaving out those scored None.
+    // Only the matches are cloned
+    pub fn best_matches(&self, limit: usize, score: impl Fn(&PageSummary) -> Option<i64>) -> Vec<(i64, PageSummary)> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        let mut matches: Vec<(i64, &PageSummary)> = lock.pages.values()
+            .filter_map(|page| score(page).map(|points| (points, page)))
+            .collect();
+        matches.sort_by(|(a_points, a), (b_points, b)| {
+            b_points.cmp(a_points).then_with(|| a.title.len().cmp(&b.title.len())).then_with(|| a.url.cmp(&b.url))
+        });
+        matches.into_iter().take(limit).map(|(points, page)| (points, page.clone())).collect()
+    }
+
+    // Any one of the pages that pass the filter, each as likely as the next
+    pub fn random_url(&self, filter: impl Fn(&PageSummary) -> bool) -> Option<String> {
+        let lock = self.lock.read().ok()?;
+        lock.pages.values()
+            .filter(|page| filter(page))
+            .choose(&mut rand::thread_rng())
+            .map(|page| page.url.clone())
+    }
+
+    pub fn page(&self, url: &str) -> Option<PageSummary> {
+        let lock = self.lock.read().ok()?;
+        lock.pages.values().find(|page| page.url == url).cloned()
+    }
+
+    // Where an old URL moved to, per the documents' aliases
+    pub fn resolve_alias(&self, path: &str) -> Option<String> {
+        let lock = self.lock.read().ok()?;
+        let path = path.trim_matches('/');
+        lock.aliases.get(path).cloned()
+    }
+
+    // Documents that opt into a menu with front matter like
+    // `menu: main` and `menu_weight: 3`. Lower weights come first
+    pub fn menu_items(&self, menu: &str) -> Vec<(String, String)> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        let mut items: Vec<(i64, &PageSummary)> = lock.pages.values()
+            .filter(|page| page.metadata.get("menu").is_some_and(|name| name == menu))
+            .map(|page| {
+                let weight = page.metadata.get("menu_weight").and_then(|weight| weight.parse().ok()).unwrap_or(0);
+                (weight, page)
+            })
+            .collect();
+        items.sort_by(|(a_weight, a), (b_weight, b)| a_weight.cmp(b_weight).then_with(|| a.title.cmp(&b.title)));
+        items.into_iter().map(|(_weight, page)| {
+            let title = page.metadata.get("menu_title").unwrap_or(&page.title);
+            (title.clone(), page.url.clone())
+        }).collect()
+    }
+
+    // Everything the menus are built from, to tell when they change
+    fn menu_entries(&self) -> Vec<MenuEntry> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.pages.values().filter_map(|page| {
+            let menu = page.metadata.get("menu")?;
+            Some(MenuEntry {
+                menu: menu.clone(),
+                weight: page.metadata.get("menu_weight").cloned(),
+                title: page.metadata.get("menu_title").unwrap_or(&page.title).clone(),
+                url: page.url.clone(),
+            })
+        }).collect()
+    }
+
+    async fn rescan(&self, files: Vec<PathBuf>) {
+        let mut pages = BTreeMap::new();
+        for file in files {
+            if let Some(page) = self.summarize(file.as_path()).await {
+                pages.insert(file, page);
+            }
+        }
+        tracing::info!("Page catalog holds {} documents", pages.len());
+        if let Ok(mut lock) = self.lock.write() {
+            lock.pages = pages;
+            lock.rebuild_aliases();
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    async fn update(&self, path: &Path) {
+        let page = self.summarize(path).await;
+        let Ok(mut lock) = self.lock.write() else {
+            return;
+        };
+        match page {
+            Some(page) => {
+                tracing::debug!("Page catalog update {}", path.display());
+                lock.pages.insert(path.to_path_buf(), page);
+            },
+            None => {
+                if lock.pages.remove(path).is_some() {
+                    tracing::debug!("Page catalog removed {}", path.display());
+                }
+            },
+        }
+        lock.rebuild_aliases();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    async fn summarize(&self, path: &Path) -> Option<PageSummary> {
+        let relative_path = path.strip_prefix(self.document_root.as_path()).ok()?;
+        if self.access.allowed_users(relative_path).is_some() {
+            return None;
+        }
+        let md = tokio::fs::read_to_string(path).await.ok()?;
+        let modtime = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        let (html, scraper) = parse_markdown(md.as_str());
+        if scraper.metadata.contains_key("redirect_to") || scraper.is_draft() {
+            return None;
+        }
+        if scraper.is_scheduled() {
+            if let Some(time) = publishing::publish_time(&scraper.metadata) {
+                self.publisher.schedule(path, time);
+            }
+            return None;
+        }
+        let title = scraper.page_title().cloned()
+            .unwrap_or_else(|| {
+                relative_path.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+            });
+        let tags = tags::document_tags(&scraper);
+        let aliases = match scraper.metadata_lists.get("aliases") {
+            Some(aliases) => aliases.clone(),
+            None => scraper.metadata.get("aliases").into_iter().cloned().collect(),
+        };
+        let aliases = aliases.iter()
+            .map(|alias| alias.trim().trim_matches('/').to_string())
+            .filter(|alias| !alias.is_empty())
+            .collect();
+        let broken_anchors = scraper.broken_anchors();
+        if !broken_anchors.is_empty() {
+            tracing::info!("{} links to missing anchors: {}", relative_path.display(), broken_anchors.join(", "));
+        }
+        let modified = time::OffsetDateTime::from(modtime)
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let relative = relative_path.iter()
+            .map(|part| part.to_string_lossy().into_owned())
+            .collect::<Vec<String>>();
+        let excerpt = scraper.summary(EXCERPT_LENGTH).unwrap_or_default();
+        Some(PageSummary {
+            url: page_url(relative.join("/").as_str()),
+            path: relative.join("/"),
+            title,
+            modified,
+            tags,
+            excerpt,
+            headings: scraper.internal_links,
+            metadata: scraper.metadata,
+            modtime,
+            aliases,
+            broken_anchors,
+            outline_warnings: scraper.outline_warnings,
+            signature: duplicates::signature(TAG_RE.replace_all(html.as_str(), " ").as_ref()),
+        })
+    }
+}
+
+// URL of a document, from its path relative to the document root
+pub fn page_url(relative_path: &str) -> String {
+    let url = relative_path.split('/')
+        .map(|part| urlencoding::encode(part).into_owned())
+        .collect::<Vec<String>>()
+        .join("/");
+    format!("{HOME_DIR}/{url}")
+}
+
+// Tera function for templates, eg: pages(folder="blog", sort="date", limit=10)
+// Sorting by date or modified puts the newest first. Other sort keys (title,
+// url, weight or any metadata field) are ascending. reverse=true flips the order
+pub fn pages_function(catalog: PageCatalog) -> impl tera::Function {
+    move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        let folder = args.get("folder").and_then(|v| v.as_str()).map(|folder| folder.trim_matches('/'));
+        let tag = args.get("tag").and_then(|v| v.as_str());
+        let sort = args.get("sort").and_then(|v| v.as_str()).unwrap_or("url");
+        let reverse = args.get("reverse").and_then(|v| v.as_bool()).unwrap_or(false);
+        let limit = args.get("limit").and_then(|v| v.as_u64()).map(|limit| limit as usize);
+
+        let mut pages: Vec<PageSummary> = catalog.pages().into_iter().filter(|page| {
+            let in_folder = folder.is_none_or(|folder| {
+                folder.is_empty() || page.path.starts_with(format!("{folder}/").as_str())
+            });
+            let has_tag = tag.is_none_or(|tag| page.tags.iter().any(|t| t == tag));
+            in_folder && has_tag
+        }).collect();
+        match sort {
+            "url" => {},
+            "title" => pages.sort_by_key(|page| page.title.to_lowercase()),
+            "modified" => pages.sort_by_key(|page| std::cmp::Reverse(page.modtime)),
+            // unweighted pages go last
+            "weight" => pages.sort_by_key(|page| {
+                page.metadata.get("weight").and_then(|weight| weight.parse::<i64>().ok()).unwrap_or(i64::MAX)
+            }),
+            // front matter date if there is one, otherwise the file time
+            "date" => pages.sort_by(|a, b| page_date(b).cmp(page_date(a))),
+            key => pages.sort_by(|a, b| a.metadata.get(key).cmp(&b.metadata.get(key))),
+        }
+        if reverse {
+            pages.reverse();
+        }
+        if let Some(limit) = limit {
+            pages.truncate(limit);
+        }
+        Ok(tera::to_value(pages)?)
+    }
+}
+
+fn page_date(page: &PageSummary) -> &str {
+    page.metadata.get("date").map_or(page.modified.as_str(), |date| date.as_str())
+}
+
+async fn listen_for_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    catalog: PageCatalog,
+    result_cache: ResultCache,
+) {
+    loop {
+        let path = match rx.recv().await {
+            Ok(path) => path,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::debug!("Page catalog missed {missed} change events, rescanning");
+                let menus = catalog.menu_entries();
+                catalog.rescan(catalog.markdown_files()).await;
+                evict_stale_pages(&catalog, &result_cache, menus);
+                continue;
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        if !path.starts_with(catalog.document_root.as_path()) {
+            continue;
+        }
+        let menus = catalog.menu_entries();
+        if path.extension() == Some(OsStr::new("md")) {
+            result_cache.keep_deleted(path.as_path());
+            if !catalog.is_visited(path.as_path()) {
+                continue;
+            }
+            catalog.update(path.as_path()).await;
+        }
+        else if path.is_dir() || path.extension().is_none() {
+            // a folder was added, moved or removed
+            catalog.rescan(catalog.markdown_files()).await;
+        }
+        else {
+            continue;
+        }
+        evict_stale_pages(&catalog, &result_cache, menus);
+    }
+}
+
+// Pages rendered from the catalog are now out of date. Every page shows
+// the menu, so if that changed, they all are
+fn evict_stale_pages(catalog: &PageCatalog, result_cache: &ResultCache, menus_before: Vec<MenuEntry>) {
+    if catalog.menu_entries() != menus_before {
+        result_cache.clear();
+        return;
+    }
+    let evicted = result_cache.evict_catalog_pages();
+    tracing::debug!("Evicted {evicted} pages rendered from the page catalog");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use tera::Function;
+    use crate::toml_config::AccessConfig;
+
+    const DOCUMENTS: [(&str, &str); 8] = [
+        ("index.md", "# Home\n"),
+        ("blog/a.md", "---\ndate: 2024-01-02\nweight: 2\ntags: [rust]\n---\n# Alpha\n"),
+        ("blog/b.md", "---\ndate: 2024-03-01\nweight: 1\ntags: [rust, web]\n---\n# Beta\n"),
+        ("blog/c.md", "# gamma\n"),
+        ("private/plans.md", "# Plans\n"),
+        ("draft.md", "---\ndraft: true\n---\n# Unfinished\n"),
+        ("later.md", "---\npublish_date: 2999-01-01\n---\n# Later\n"),
+        ("moved.md", "---\nredirect_to: /home/index.md\n---\n"),
+    ];
+
+    // The documents in a scratch folder, removed on drop
+    struct TestSite {
+        folder: PathBuf,
+        catalog: PageCatalog,
+    }
+
+    impl TestSite {
+        async fn new(name: &str) -> TestSite {
+            let folder = std::env::temp_dir().join(format!("chimera-catalog-{name}-{}", std::process::id()));
+            let document_root = folder.join("home");
+            for (path, md) in DOCUMENTS {
+                let path = document_root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, md).unwrap();
+            }
+            let file_manager = FileManager::new(document_root.as_path(), "index.md").await.unwrap();
+            let mut folders = IndexMap::new();
+            folders.insert("private".to_string(), vec!["alice".to_string()]);
+            let access = AccessControl::new(AccessConfig {
+                user_header: Some("Remote-User".to_string()),
+                folders,
+            });
+            let catalog = PageCatalog::new(document_root.as_path(), &file_manager, access, false).await;
+            TestSite {
+                folder,
+                catalog,
+            }
+        }
+    }
+
+    impl Drop for TestSite {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.folder.as_path());
+        }
+    }
+
+    fn call_pages(catalog: &PageCatalog, args: &[(&str, tera::Value)]) -> Vec<String> {
+        let args = args.iter().map(|(key, value)| (key.to_string(), value.clone())).collect();
+        let pages = pages_function(catalog.clone()).call(&args).unwrap();
+        pages.as_array().unwrap().iter().map(|page| page["title"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_pages_leave_out_hidden_documents() {
+        let site = TestSite::new("hidden").await;
+        let paths: Vec<String> = site.catalog.pages().into_iter().map(|page| page.path).collect();
+        // restricted, draft, scheduled and moved documents aren't listed
+        assert_eq!(paths, vec!["blog/a.md", "blog/b.md", "blog/c.md", "index.md"]);
+    }
+
+    #[tokio::test]
+    async fn test_pages_function() {
+        let site = TestSite::new("function").await;
+        let catalog = &site.catalog;
+        assert_eq!(call_pages(catalog, &[]), vec!["Alpha", "Beta", "gamma", "Home"]);
+        assert_eq!(call_pages(catalog, &[("folder", "/blog/".into())]), vec!["Alpha", "Beta", "gamma"]);
+        assert_eq!(call_pages(catalog, &[("tag", "web".into())]), vec!["Beta"]);
+        assert_eq!(call_pages(catalog, &[("folder", "blog".into()), ("sort", "weight".into())]), vec!["Beta", "Alpha", "gamma"]);
+        assert_eq!(call_pages(catalog, &[("tag", "rust".into()), ("sort", "date".into())]), vec!["Beta", "Alpha"]);
+        assert_eq!(call_pages(catalog, &[("sort", "title".into()), ("reverse", true.into()), ("limit", 2.into())]), vec!["Home", "gamma"]);
+    }
+
+    #[tokio::test]
+    async fn test_evict_stale_pages() {
+        let site = TestSite::new("evict").await;
+        let result_cache = ResultCache::new(10_000);
+        result_cache.add(Path::new("blog/a.md"), "plain").await;
+        result_cache.add_catalog_variant(Path::new("index.md"), "", "listing").await;
+        evict_stale_pages(&site.catalog, &result_cache, site.catalog.menu_entries());
+        assert_eq!(result_cache.get(Path::new("blog/a.md")).await.as_deref(), Some("plain"));
+        assert_eq!(result_cache.get(Path::new("index.md")).await, None);
+        // a document left the menu, which every page shows
+        let menus_before = vec![MenuEntry {
+            menu: "main".to_string(),
+            weight: None,
+            title: "Gone".to_string(),
+            url: "/home/gone.md".to_string(),
+        }];
+        evict_stale_pages(&site.catalog, &result_cache, menus_before);
+        assert_eq!(result_cache.get(Path::new("blog/a.md")).await, None);
+    }
+}
@@ -1,5 +1,6 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use axum::http::header::HeaderMap;
+use serde::Serialize;
 
 use crate::SERVER_TIMING;
 
@@ -27,3 +28,55 @@ impl PerfTimer {
         }
     }
 }
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StartupPhase {
+    pub name: &'static str,
+    pub ms: f64,
+}
+
+// How long each step of starting up took, logged once the server is built
+// and shown on the admin page
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StartupReport {
+    pub phases: Vec<StartupPhase>,
+    pub total_ms: f64,
+}
+
+pub struct StartupTimer {
+    prev_time: Instant,
+    report: StartupReport,
+}
+
+impl StartupTimer {
+    pub fn new() -> Self {
+        StartupTimer {
+            prev_time: Instant::now(),
+            report: StartupReport::default(),
+        }
+    }
+
+    // For a phase timed elsewhere, such as reading the config
+    pub fn add(&mut self, name: &'static str, elapsed: Duration) {
+        let ms = elapsed.as_micros() as f64 / 1000.0;
+        tracing::debug!("Startup: {name} took {ms} ms");
+        self.report.total_ms += ms;
+        self.report.phases.push(StartupPhase {
+            name,
+            ms,
+        });
+    }
+
+    // The time since the previous phase ended
+    pub fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.add(name, now.duration_since(self.prev_time));
+        self.prev_time = now;
+    }
+
+    pub fn finish(self) -> StartupReport {
+        let phases: Vec<String> = self.report.phases.iter().map(|phase| format!("{} {:.1} ms", phase.name, phase.ms)).collect();
+        tracing::info!("Started up in {:.1} ms: {}", self.report.total_ms, phases.join(", "));
+        self.report
+    }
+}
@@ -0,0 +1,184 @@
+use std::net::{IpAddr, SocketAddr};
+use axum::http::HeaderMap;
+
+// Reverse proxies whose forwarded headers are believed. A request from
+// anywhere else is taken to come from its socket address, since the
+// client could have set the headers to anything
+#[derive(Clone, Default)]
+pub struct TrustedProxies {
+    // Network address and prefix length, eg: 10.0.0.0/8
+    ranges: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    // Addresses, or ranges such as "10.0.0.0/8" and "fd00::/8"
+    pub fn new(proxies: &[String]) -> Self {
+        let ranges = proxies.iter().filter_map(|proxy| {
+            let range = parse_range(proxy.trim());
+            if range.is_none() {
+                tracing::warn!("Ignoring trusted proxy {proxy}, it isn't an address or a range");
+            }
+            range
+        }).collect();
+        TrustedProxies {
+            ranges,
+        }
+    }
+
+    pub fn trusts(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        self.ranges.iter().any(|(network, prefix)| in_range(addr, *network, *prefix))
+    }
+
+    // The client's address. Past trusted proxies, that's the nearest
+    // address in X-Forwarded-For that isn't one of them
+    pub fn client_addr(&self, headers: &HeaderMap, addr: SocketAddr) -> String {
+        let peer = addr.ip();
+        if !self.trusts(peer) {
+            return peer.to_string();
+        }
+        let forwarded: Vec<&str> = headers.get_all("X-Forwarded-For").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim())
+            .filter(|hop| !hop.is_empty())
+            .collect();
+        let mut client = peer.to_string();
+        for hop in forwarded.into_iter().rev() {
+            let Ok(hop_addr) = hop.parse::<IpAddr>() else {
+                // not an address, so not from a proxy that's trusted
+                break;
+            };
+            client = hop_addr.to_string();
+            if !self.trusts(hop_addr) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match range.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (range.parse::<IpAddr>().ok()?, None),
+    };
+    // a mapped range, eg: ::ffff:10.0.0.0/104, is the IPv4 range it maps to
+    let prefix = match (addr, canonical(addr), prefix) {
+        (IpAddr::V6(_), IpAddr::V4(_), Some(prefix)) => Some(prefix.checked_sub(96)?),
+        _ => prefix,
+    };
+    let addr = canonical(addr);
+    let max = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some((addr, prefix))
+}
+
+// IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn in_range(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(ranges: &[&str]) -> TrustedProxies {
+        let ranges: Vec<String> = ranges.iter().map(|range| range.to_string()).collect();
+        TrustedProxies::new(ranges.as_slice())
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_in_range() {
+        assert!(in_range(addr("10.1.2.3"), addr("10.0.0.0"), 8));
+        assert!(!in_range(addr("11.1.2.3"), addr("10.0.0.0"), 8));
+        assert!(in_range(addr("192.168.1.200"), addr("192.168.1.128"), 25));
+        assert!(!in_range(addr("192.168.1.100"), addr("192.168.1.128"), 25));
+        assert!(in_range(addr("203.0.113.9"), addr("0.0.0.0"), 0));
+        assert!(in_range(addr("fd12:3456::1"), addr("fd00::"), 8));
+        assert!(!in_range(addr("fe80::1"), addr("fd00::"), 8));
+        assert!(in_range(addr("::1"), addr("::1"), 128));
+        // families never match each other
+        assert!(!in_range(addr("10.1.2.3"), addr("::"), 0));
+    }
+
+    #[test]
+    fn test_trusts() {
+        let trusted = proxies(&["10.0.0.0/8", "::1", "fd00::/8"]);
+        assert!(trusted.trusts(addr("10.9.8.7")));
+        assert!(trusted.trusts(addr("::1")));
+        assert!(trusted.trusts(addr("fd00::abcd")));
+        // dual-stack listeners report IPv4 clients as mapped addresses
+        assert!(trusted.trusts(addr("::ffff:10.9.8.7")));
+        assert!(!trusted.trusts(addr("::ffff:192.0.2.1")));
+        assert!(!trusted.trusts(addr("127.0.0.1")));
+        let mapped = proxies(&["::ffff:192.0.2.0/120"]);
+        assert!(mapped.trusts(addr("192.0.2.44")));
+    }
+
+    #[test]
+    fn test_bad_ranges() {
+        assert_eq!(parse_range("10.0.0.0/33"), None);
+        assert_eq!(parse_range("fd00::/129"), None);
+        assert_eq!(parse_range("10.0.0.0/"), None);
+        assert_eq!(parse_range("10.0.0/8"), None);
+        assert_eq!(parse_range("localhost"), None);
+        assert_eq!(parse_range("::ffff:10.0.0.0/64"), None);
+        assert_eq!(parse_range("10.0.0.1"), Some((addr("10.0.0.1"), 32)));
+        assert_eq!(parse_range("::ffff:10.0.0.0/104"), Some((addr("10.0.0.0"), 8)));
+        // ignored rather than trusting everything
+        let trusted = proxies(&["not-an-address", "10.0.0.0/40"]);
+        assert!(!trusted.trusts(addr("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let proxy = SocketAddr::from(([10, 0, 0, 2], 443));
+        let stranger = SocketAddr::from(([198, 51, 100, 4], 443));
+        let mut headers = HeaderMap::new();
+        assert_eq!(trusted.client_addr(&headers, proxy), "10.0.0.2");
+
+        headers.insert("X-Forwarded-For", "203.0.113.5".parse().unwrap());
+        assert_eq!(trusted.client_addr(&headers, proxy), "203.0.113.5");
+        // anyone else could have set the header to anything
+        assert_eq!(trusted.client_addr(&headers, stranger), "198.51.100.4");
+
+        // right to left, stopping at the first hop that isn't a trusted proxy
+        headers.insert("X-Forwarded-For", "1.1.1.1, 203.0.113.5, 10.0.0.9".parse().unwrap());
+        assert_eq!(trusted.client_addr(&headers, proxy), "203.0.113.5");
+        headers.insert("X-Forwarded-For", "10.0.0.7, 10.0.0.9".parse().unwrap());
+        assert_eq!(trusted.client_addr(&headers, proxy), "10.0.0.7");
+        headers.insert("X-Forwarded-For", "203.0.113.5, garbage, 10.0.0.9".parse().unwrap());
+        assert_eq!(trusted.client_addr(&headers, proxy), "10.0.0.9");
+
+        // repeated headers are read as one list
+        headers.insert("X-Forwarded-For", "1.1.1.1".parse().unwrap());
+        headers.append("X-Forwarded-For", "203.0.113.5".parse().unwrap());
+        assert_eq!(trusted.client_addr(&headers, proxy), "203.0.113.5");
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::{broadcast, Notify};
+
+use crate::expiry::parse_date;
+
+// `publish_date:` front matter, as a date (midnight UTC) or an RFC 3339
+// time, eg: 2025-06-30T09:00:00+02:00
+pub fn publish_time(metadata: &HashMap<String, String>) -> Option<OffsetDateTime> {
+    let publish_date = metadata.get("publish_date")?.trim();
+    OffsetDateTime::parse(publish_date, &Rfc3339).ok()
+        .or_else(|| parse_date(publish_date).map(|date| date.midnight().assume_utc()))
+}
+
+// Not to be shown until its publish date
+pub fn is_scheduled(metadata: &HashMap<String, String>) -> bool {
+    publish_time(metadata).is_some_and(|time| time > OffsetDateTime::now_utc())
+}
+
+type Due = BTreeMap<OffsetDateTime, HashSet<PathBuf>>;
+
+// Announces scheduled documents as changed when their publish date comes,
+// so the caches, listings and search index that left them out take them in
+#[derive(Clone)]
+pub struct Publisher {
+    due: Arc<Mutex<Due>>,
+    wake: Arc<Notify>,
+}
+
+impl Publisher {
+    pub fn new(broadcast_tx: broadcast::Sender<PathBuf>) -> Self {
+        let publisher = Publisher {
+            due: Arc::new(Mutex::new(Due::new())),
+            wake: Arc::new(Notify::new()),
+        };
+        tokio::spawn(publish_when_due(publisher.clone(), broadcast_tx));
+        publisher
+    }
+
+    // Takes an absolute path, as file change events have them
+    pub fn schedule(&self, path: &Path, time: OffsetDateTime) {
+        let Ok(mut due) = self.due.lock() else {
+            return;
+        };
+        let earliest = due.first_key_value().is_none_or(|(first, _paths)| time < *first);
+        if due.entry(time).or_default().insert(path.to_path_buf()) {
+            tracing::debug!("{} is scheduled for {time}", path.display());
+        }
+        if earliest {
+            self.wake.notify_one();
+        }
+    }
+
+    // The documents whose time has come, and when the next one's due
+    fn take_due(&self) -> (Vec<PathBuf>, Option<OffsetDateTime>) {
+        let Ok(mut due) = self.due.lock() else {
+            return (Vec::new(), None);
+        };
+        let now = OffsetDateTime::now_utc();
+        let later = due.split_off(&now);
+        let published = std::mem::replace(&mut *due, later).into_values().flatten().collect();
+        (published, due.first_key_value().map(|(time, _paths)| *time))
+    }
+}
+
+async fn publish_when_due(publisher: Publisher, broadcast_tx: broadcast::Sender<PathBuf>) {
+    loop {
+        let (published, next) = publisher.take_due();
+        for path in published {
+            tracing::info!("Publishing {}", path.display());
+            let _ = broadcast_tx.send(path);
+        }
+        match next {
+            Some(next) => {
+                let wait = (next - OffsetDateTime::now_utc()).try_into().unwrap_or_default();
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {},
+                    _ = publisher.wake.notified() => {},
+                }
+            },
+            None => publisher.wake.notified().await,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+use axum::{extract::{Query, State}, http::{header, StatusCode}, response::{IntoResponse, Response}};
+use serde::Deserialize;
+
+use crate::AppStateType;
+
+pub const QR_URL: &str = "/qr";
+
+// Longest text a code is made for. Codes for more get too dense to print
+const MAX_QR_DATA: usize = 1024;
+
+// Codes kept, beyond which the cache starts over
+const MAX_CACHED_CODES: usize = 512;
+
+// Light modules around the code, as the standard asks for
+const QUIET_ZONE: usize = 4;
+
+// Rendered pixels per module, for the SVG's default size
+const MODULE_PIXELS: usize = 4;
+
+#[derive(Deserialize)]
+pub struct QrQuery {
+    data: Option<String>,
+}
+
+// A QR code symbol with medium (15%) error correction
+pub struct QrCode {
+    size: usize,
+    // Row by row, true for dark
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    // The smallest version that holds the data, or None if none can
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let code = qrcode::QrCode::with_error_correction_level(data, qrcode::EcLevel::M).ok()?;
+        Some(QrCode {
+            size: code.width(),
+            modules: code.to_colors().into_iter().map(|color| color == qrcode::Color::Dark).collect(),
+        })
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    pub fn to_svg(&self) -> String {
+        let width = self.size + QUIET_ZONE * 2;
+        let mut path = String::with_capacity(self.size * self.size * 4);
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.get(x, y) {
+                    path.push_str(format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE).as_str());
+                }
+            }
+        }
+        let pixels = width * MODULE_PIXELS;
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {width} {width}\" width=\"{pixels}\" height=\"{pixels}\" shape-rendering=\"crispEdges\">\
+<rect width=\"{width}\" height=\"{width}\" fill=\"#fff\"/><path d=\"{path}\" fill=\"#000\"/></svg>\n"
+        )
+    }
+}
+
+// Codes as SVG, kept by their text
+#[derive(Clone, Default)]
+pub struct QrCodes {
+    cache: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl QrCodes {
+    pub fn svg(&self, data: &str) -> Option<String> {
+        if let Ok(lock) = self.cache.read() {
+            if let Some(svg) = lock.get(data) {
+                return Some(svg.clone());
+            }
+        }
+        let svg = QrCode::encode(data.as_bytes())?.to_svg();
+        if let Ok(mut lock) = self.cache.write() {
+            if lock.len() >= MAX_CACHED_CODES {
+                lock.clear();
+            }
+            lock.insert(data.to_string(), svg.clone());
+        }
+        Some(svg)
+    }
+}
+
+// eg: /qr?data=https://notes.example.com/home/recipes/bread.md
+pub async fn handle_qr(
+    State(app_state): State<AppStateType>,
+    Query(query): Query<QrQuery>,
+) -> Response {
+    let Some(data) = query.data.filter(|data| !data.is_empty() && data.len() <= MAX_QR_DATA) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    match app_state.qr_codes.svg(data.as_str()) {
+        Some(svg) => (
+            [
+                (header::CONTENT_TYPE, "image/svg+xml"),
+                // the same text always makes the same code
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            ],
+            svg,
+        ).into_response(),
+        None => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions() {
+        assert_eq!(QrCode::encode(b"hi").map(|qr| qr.size), Some(21));
+        assert_eq!(QrCode::encode("x".repeat(300).as_bytes()).map(|qr| qr.size), Some(69));
+        assert!(QrCode::encode("x".repeat(3000).as_bytes()).is_none());
+    }
+
+    #[test]
+    fn test_svg() {
+        let qr = QrCode::encode(b"https://example.com/").unwrap();
+        // the finder's dark outer ring in the top left corner
+        assert!((0..7).all(|i| qr.get(i, 0) && qr.get(0, i)));
+        let svg = qr.to_svg();
+        let width = qr.size + QUIET_ZONE * 2;
+        assert!(svg.starts_with(format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {width} {width}\"").as_str()));
+        let dark = qr.modules.iter().filter(|dark| **dark).count();
+        assert_eq!(svg.matches("h1v1h-1z").count(), dark);
+        assert!(svg.contains(format!("M{QUIET_ZONE},{QUIET_ZONE}h1v1h-1z").as_str()));
+    }
+}
@@ -0,0 +1,85 @@
+use axum::{extract::{Query, State}, response::{IntoResponse, Json, Response}};
+use serde::{Deserialize, Serialize};
+
+use crate::AppStateType;
+
+pub const QUICKOPEN_URL: &str = "/api/quickopen";
+
+const DEFAULT_RESULTS: usize = 10;
+const MAX_RESULTS: usize = 50;
+
+#[derive(Deserialize)]
+pub struct QuickOpenQuery {
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct QuickOpenResult {
+    url: String,
+    title: String,
+    path: String,
+}
+
+// Points for matching the query's characters in order, anywhere in the
+// text: more when they're adjacent or start a word, less for the gaps
+// between them. None if they don't all appear
+fn fuzzy_score(query: &[char], text: &str) -> Option<i64> {
+    let mut points = 0;
+    let mut matched = 0;
+    let mut last_match: Option<usize> = None;
+    let mut previous: Option<char> = None;
+    for (i, c) in text.chars().enumerate() {
+        if matched == query.len() {
+            break;
+        }
+        if c.to_lowercase().eq(std::iter::once(query[matched])) {
+            points += 1;
+            let word_start = match previous {
+                None => true,
+                Some(previous) => !previous.is_alphanumeric() || (previous.is_lowercase() && c.is_uppercase()),
+            };
+            if word_start {
+                points += 6;
+            }
+            if let Some(last) = last_match {
+                match i - last {
+                    1 => points += 4,
+                    gap => points -= (gap as i64 - 1).min(3),
+                }
+            }
+            last_match = Some(i);
+            matched += 1;
+        }
+        previous = Some(c);
+    }
+    match matched == query.len() {
+        true => Some(points),
+        false => None,
+    }
+}
+
+// Fuzzy matches on document titles and paths, from the page catalog rather
+// than the full text index, for a Ctrl+K quick switcher
+pub async fn handle_quickopen(
+    State(app_state): State<AppStateType>,
+    Query(params): Query<QuickOpenQuery>,
+) -> Response {
+    let query: Vec<char> = params.q.unwrap_or_default().trim().to_lowercase().chars().collect();
+    if query.is_empty() {
+        return Json(Vec::<QuickOpenResult>::new()).into_response();
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_RESULTS).clamp(1, MAX_RESULTS);
+    let matches = app_state.page_catalog.best_matches(limit, |page| {
+        // titles are what people remember, so they count double
+        let title = fuzzy_score(query.as_slice(), page.title.as_str()).map(|points| points * 2);
+        let path = fuzzy_score(query.as_slice(), page.path.as_str());
+        title.max(path)
+    });
+    let results: Vec<QuickOpenResult> = matches.into_iter().map(|(_points, page)| QuickOpenResult {
+        url: page.url,
+        title: page.title,
+        path: page.path,
+    }).collect();
+    Json(results).into_response()
+}
@@ -0,0 +1,36 @@
+use axum::{extract::{Query, State}, http::header, response::{IntoResponse, Redirect, Response}};
+use serde::Deserialize;
+
+use crate::chimera_error::handle_404;
+use crate::AppStateType;
+
+pub const RANDOM_URL: &str = "/random";
+
+#[derive(Deserialize)]
+pub struct RandomQuery {
+    // Only pick documents under this folder, eg: ?folder=notes/recipes
+    folder: Option<String>,
+}
+
+// Sends the visitor to a document picked at random from the page catalog.
+// A fresh pick each time, so the redirect is temporary and never cached
+pub async fn handle_random(
+    State(app_state): State<AppStateType>,
+    Query(query): Query<RandomQuery>,
+) -> Response {
+    let folder = query.folder.as_deref().map(|folder| folder.trim_matches('/')).unwrap_or_default();
+    let prefix = format!("{folder}/");
+    let url = app_state.page_catalog.random_url(|page| {
+        folder.is_empty() || page.path.starts_with(prefix.as_str())
+    });
+    match url {
+        Some(url) => (
+            [(header::CACHE_CONTROL, "no-store")],
+            Redirect::temporary(url.as_str()),
+        ).into_response(),
+        None => {
+            tracing::debug!("No documents to pick at random in {folder:?}");
+            handle_404(app_state).await.into_response()
+        },
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf, sync::{Arc, RwLock}, time::Duration};
+use axum::{body::Body, extract::{Request, State}, http::{header, HeaderMap, HeaderValue, StatusCode}, middleware::Next, response::{IntoResponse, Redirect, Response}, Extension};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::ChimeraError;
+use crate::page_catalog::page_url;
+use crate::session::{has_csrf_placeholder, CsrfPlaceholder, KeepSession, Session};
+use crate::toml_config::ReadingHistoryConfig;
+use crate::{AppStateType, HOME_DIR};
+
+pub const CLEAR_HISTORY_URL: &str = "/history/clear";
+
+// Templates emit this marker via continue_reading(), and it's swapped for
+// the visitor's own list on the way out, so pages stay cacheable
+const CONTINUE_READING_PLACEHOLDER: &str = "<!--__CHIMERA_CONTINUE_READING__-->";
+
+// How often new visits are flushed to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Visit {
+    pub url: String,
+    pub title: String,
+    // RFC 3339
+    pub visited: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct HistoryFile {
+    // Visitor => their visits, most recent first
+    visitors: BTreeMap<String, Vec<Visit>>,
+}
+
+struct ReadingHistoryInternal {
+    path: PathBuf,
+    file: HistoryFile,
+    dirty: bool,
+}
+
+// The documents each visitor read last, for "continue where you left off".
+// Visitors are the proxy's user name when there is one, otherwise their
+// session. Kept for retention_days, not recorded for browsers that send
+// Do Not Track or Global Privacy Control, and visitors can clear their own
+#[derive(Clone)]
+pub struct ReadingHistory {
+    lock: Arc<RwLock<ReadingHistoryInternal>>,
+    max_pages: usize,
+    retention_days: u32,
+    respect_do_not_track: bool,
+}
+
+impl ReadingHistory {
+    pub fn new(history_dir: PathBuf, config: ReadingHistoryConfig) -> Self {
+        if let Err(e) = std::fs::create_dir_all(history_dir.as_path()) {
+            tracing::warn!("Failed to create reading history directory {}: {e}", history_dir.display());
+        }
+        let path = history_dir.join("history.toml");
+        let file = match std::fs::read_to_string(path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", path.display());
+                        HistoryFile::default()
+                    }
+                }
+            },
+            Err(_) => HistoryFile::default(),
+        };
+        let history = ReadingHistory {
+            lock: Arc::new(RwLock::new(ReadingHistoryInternal {
+                path,
+                file,
+                dirty: false,
+            })),
+            max_pages: config.max_pages,
+            retention_days: config.retention_days,
+            respect_do_not_track: config.respect_do_not_track,
+        };
+        history.prune();
+        tokio::spawn(periodic_save(history.clone()));
+        history
+    }
+
+    // None for visitors who asked not to be tracked, and for sessions the
+    // browser hasn't kept, such as crawlers'
+    fn visitor(&self, headers: &HeaderMap, user: Option<String>, session: &Session) -> Option<String> {
+        if self.respect_do_not_track && opted_out(headers) {
+            return None;
+        }
+        match user.is_none() && session.is_new() {
+            true => None,
+            false => Some(visitor_key(user, session)),
+        }
+    }
+
+    fn record(&self, visitor: &str, url: &str, title: &str) {
+        let Ok(mut lock) = self.lock.write() else {
+            return;
+        };
+        let visited = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let visits = lock.file.visitors.entry(visitor.to_string()).or_default();
+        visits.retain(|visit| visit.url != url);
+        visits.insert(0, Visit {
+            url: url.to_string(),
+            title: title.to_string(),
+            visited,
+        });
+        visits.truncate(self.max_pages);
+        lock.dirty = true;
+    }
+
+    fn visits(&self, visitor: &str) -> Vec<Visit> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.file.visitors.get(visitor).cloned().unwrap_or_default()
+    }
+
+    fn forget(&self, visitor: &str) {
+        let Ok(mut lock) = self.lock.write() else {
+            return;
+        };
+        if lock.file.visitors.remove(visitor).is_some() {
+            lock.dirty = true;
+        }
+    }
+
+    // Drops visits past the retention window, and visitors left with none
+    fn prune(&self) {
+        let Ok(mut lock) = self.lock.write() else {
+            return;
+        };
+        let cutoff = time::OffsetDateTime::now_utc() - time::Duration::days(self.retention_days as i64);
+        let before: usize = lock.file.visitors.values().map(Vec::len).sum();
+        for visits in lock.file.visitors.values_mut() {
+            visits.retain(|visit| {
+                time::OffsetDateTime::parse(visit.visited.as_str(), &time::format_description::well_known::Rfc3339)
+                    .is_ok_and(|visited| visited >= cutoff)
+            });
+        }
+        lock.file.visitors.retain(|_visitor, visits| !visits.is_empty());
+        let after: usize = lock.file.visitors.values().map(Vec::len).sum();
+        if after != before {
+            lock.dirty = true;
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), ChimeraError> {
+        self.prune();
+        let (path, toml) = {
+            let mut lock = self.lock.write()?;
+            if !lock.dirty {
+                return Ok(());
+            }
+            lock.dirty = false;
+            match toml::to_string(&lock.file) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting reading history to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        tracing::debug!("Saved {}", path.display());
+        Ok(())
+    }
+}
+
+fn visitor_key(user: Option<String>, session: &Session) -> String {
+    match user {
+        Some(user) => format!("user:{user}"),
+        None => format!("session:{}", session.id),
+    }
+}
+
+// Do Not Track, or its successor, Global Privacy Control
+fn opted_out(headers: &HeaderMap) -> bool {
+    ["dnt", "sec-gpc"].iter().any(|name| headers.get(*name).is_some_and(|value| value == "1"))
+}
+
+async fn periodic_save(history: ReadingHistory) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = history.save().await {
+            tracing::warn!("Failed to save reading history: {e:?}");
+        }
+    }
+}
+
+// Tera helper, eg: {{ continue_reading() }} in a sidebar. Adds nothing when
+// reading history is off
+pub fn continue_reading_function(enabled: bool) -> impl tera::Function {
+    move |_args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        match enabled {
+            true => Ok(tera::Value::String(CONTINUE_READING_PLACEHOLDER.to_string())),
+            false => Ok(tera::Value::String(String::new())),
+        }
+    }
+}
+
+// Records document visits, and fills in the visitor's list where a page
+// asked for it. Runs inside mw_session, which provides the session
+pub async fn mw_reading_history(
+    State(app_state): State<AppStateType>,
+    Extension(session): Extension<Session>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(history) = &app_state.reading_history else {
+        return next.run(request).await;
+    };
+    let visitor = history.visitor(request.headers(), app_state.access.user(request.headers()), &session);
+    let path = request.uri().path().to_string();
+    let lang = app_state.messages.negotiate(request.headers());
+    let response = next.run(request).await;
+    let is_html = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    if response.status() != StatusCode::OK || !is_html {
+        return response;
+    }
+    // only documents in the catalog, which leaves out restricted ones
+    let current = path.strip_prefix(format!("{HOME_DIR}/").as_str())
+        .and_then(|relative| urlencoding::decode(relative).ok())
+        .map(|relative| page_url(relative.as_ref()));
+    if let (Some(visitor), Some(page)) = (&visitor, current.as_deref().and_then(|url| app_state.page_catalog.page(url))) {
+        history.record(visitor.as_str(), page.url.as_str(), page.title.as_str());
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let Ok(html) = std::str::from_utf8(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    if !html.contains(CONTINUE_READING_PLACEHOLDER) {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    // visitors who opted out get an empty list
+    let visits: Vec<Visit> = visitor.map(|visitor| history.visits(visitor.as_str())).unwrap_or_default().into_iter()
+        .filter(|visit| current.as_ref().is_none_or(|current| *current != visit.url))
+        .collect();
+    let list = match app_state.html_generator.gen_continue_reading(visits, lang.as_deref()) {
+        Ok(list) => list,
+        Err(e) => {
+            tracing::warn!("Error rendering continue-reading.html: {e:?}");
+            String::new()
+        },
+    };
+    let html = html.replace(CONTINUE_READING_PLACEHOLDER, list.as_str());
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let no_store = parts.headers.get(header::CACHE_CONTROL).is_some_and(|value| value == "no-store");
+    if !no_store {
+        parts.headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, no-cache"));
+    }
+    // the visitor needs a session to come back to
+    parts.extensions.insert(KeepSession);
+    if has_csrf_placeholder(list.as_str()) {
+        parts.extensions.insert(CsrfPlaceholder);
+    }
+    Response::from_parts(parts, Body::from(html))
+}
+
+pub async fn handle_clear_history(
+    State(app_state): State<AppStateType>,
+    Extension(session): Extension<Session>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(history) = &app_state.reading_history {
+        history.forget(visitor_key(app_state.access.user(&headers), &session).as_str());
+        tracing::debug!("Cleared reading history for a visitor");
+    }
+    let back = headers.get(header::REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| axum::http::Uri::try_from(referer).ok())
+        .map(|referer| referer.path().to_string())
+        .filter(|path| path.starts_with('/') && !path.starts_with("//"))
+        .unwrap_or_else(|| "/".to_string());
+    Redirect::to(back.as_str()).into_response()
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use crate::bibliography::{Bibliography, CITATION_MARKER};
+use crate::chimera_error::ChimeraError;
+use crate::document_scraper::{parse_markdown_with, DocumentScraper, InternalLink};
+use crate::glossary::Glossary;
+use crate::html_generator::{finish_body, BodyOptions};
+use crate::image_size_cache::{ImageSizeCache, WidthAndHeight};
+use crate::toml_config::MarkupConfig;
+
+pub enum DocumentSource<'a> {
+    Path(&'a Path),
+    Markdown(&'a str),
+}
+
+// The parts of chimera.toml that change how a document body renders
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    pub markup: MarkupConfig,
+    // For documents that cite sources without naming a `bibliography:`
+    pub bibliography: Option<PathBuf>,
+    pub glossary_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderedDocument {
+    // The body, as the server puts it into the page template
+    pub html: String,
+    // The front matter title, or else the first heading
+    pub title: Option<String>,
+    // The table of contents, as the templates get it
+    pub doclinks: Vec<InternalLink>,
+    pub metadata: HashMap<String, String>,
+    pub metadata_lists: HashMap<String, Vec<String>>,
+}
+
+// Renders a markdown document the way the server does, minus the page
+// template and anything that depends on the rest of the site. A document's
+// own `bibliography:` is relative to its folder, or the working directory
+// for markdown passed in directly
+pub fn render_document(source: DocumentSource, options: &RenderOptions) -> Result<RenderedDocument, ChimeraError> {
+    let (md, folder) = match source {
+        DocumentSource::Path(path) => (
+            std::fs::read_to_string(path)?,
+            path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        ),
+        DocumentSource::Markdown(md) => (md.to_string(), PathBuf::new()),
+    };
+    let (body, scraper) = parse_markdown_with(md.as_str(), &options.markup);
+    let bibliography = match body.contains(CITATION_MARKER) {
+        true => match scraper.metadata.get("bibliography") {
+            Some(file) => Bibliography::load(folder.join(file).as_path()),
+            None => options.bibliography.as_deref().and_then(Bibliography::load),
+        },
+        false => None,
+    };
+    let glossary = options.glossary_file.clone().map(Glossary::new);
+    let (html, doclinks) = finish_body(body, &scraper, &BodyOptions {
+        markup: &options.markup,
+        image_size_cache: None,
+        document: Path::new(""),
+        glossary: glossary.as_ref(),
+        bibliography,
+        document_names: None,
+        rewrite_links: false,
+        table_page: 1,
+        query: "",
+    });
+    Ok(RenderedDocument {
+        html,
+        title: scraper.page_title().cloned(),
+        doclinks,
+        metadata: scraper.metadata,
+        metadata_lists: scraper.metadata_lists,
+    })
+}
+
+// Every pass the server makes over a document, without touching the file
+// system, for fuzzing and tests. All the optional markup is on, and every
+// image in the document is given a size, so each rewrite gets exercised. The
+// scraper's internal_links are the finished table of contents
+pub fn process_markdown(md: &str) -> (String, DocumentScraper) {
+    let markup = MarkupConfig {
+        heading_permalinks: true,
+        code_copy_buttons: true,
+        hide_repeated_title: true,
+        autolinks: true,
+        hard_breaks: false,
+        figures: true,
+        table_page_rows: Some(10),
+    };
+    let (body, mut scraper) = parse_markdown_with(md, &markup);
+    let image_sizes = scraper.images.iter()
+        .map(|src| (src.clone(), WidthAndHeight { width: 1, height: 1 }))
+        .collect();
+    let image_size_cache = ImageSizeCache::from_sizes(image_sizes);
+    let (html, doclinks) = finish_body(body, &scraper, &BodyOptions {
+        markup: &markup,
+        image_size_cache: Some(&image_size_cache),
+        document: Path::new(""),
+        glossary: None,
+        bibliography: None,
+        document_names: None,
+        rewrite_links: true,
+        table_page: 1,
+        query: "",
+    });
+    scraper.internal_links = doclinks;
+    (html, scraper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_heading_keeps_anchors_in_step() {
+        let (html, _scraper) = process_markdown("<h2 class=\"x\">Raw</h2>\n\n## Real One\n\n## Second\n");
+        assert!(html.contains("<h2 class=\"x\">Raw</h2>"));
+        assert!(html.contains("<h2 id=\"real-one\"><a class=\"permalink\" href=\"#real-one\""));
+        assert!(html.contains("<h2 id=\"second\"><a class=\"permalink\" href=\"#second\""));
+        // a recorded raw heading still takes its turn
+        let (html, _scraper) = process_markdown("<h2 id=\"raw\" class=\"x\">Raw</h2>\n\n## Styled {.wide}\n");
+        assert!(html.contains("<h2 id=\"raw\" class=\"x\">"));
+        assert!(html.contains("<h2 id=\"styled\" class=\"wide\">"));
+    }
+
+    #[test]
+    fn test_empty_heading_has_no_anchor() {
+        let (html, _scraper) = process_markdown("####\n\n## After\n");
+        assert!(html.contains("<h4></h4>"));
+        assert!(!html.contains("href=\"#\""));
+        assert!(html.contains("<h2 id=\"after\">"));
+    }
+
+    #[test]
+    fn test_inline_toc_skips_empty_headings() {
+        let (html, _scraper) = process_markdown("Intro\n\n[TOC]\n\n## One\n\n###\n\n## Two\n");
+        assert!(html.contains("<li><a href=\"#one\">One</a>"));
+        assert!(html.contains("<li><a href=\"#two\">Two</a>"));
+        assert!(!html.contains("<a href=\"#\">"));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use axum::http::{header, HeaderMap};
+use serde::Serialize;
+
+const THEME_PARAM: &str = "theme";
+const TABLE_PAGE_PARAM: &str = "table_page";
+const MAX_PARAM_LEN: usize = 200;
+const MAX_THEME_LEN: usize = 32;
+
+// Details of the current request, exposed to markdown templates as `request`
+#[derive(Serialize, Default)]
+pub struct RequestInfo {
+    pub path: String,
+    // Rebuilt from the allowed parameters only, never the raw query string
+    pub query: String,
+    pub params: BTreeMap<String, String>,
+    pub user: Option<String>,
+    pub theme: Option<String>,
+    // Which rows of long tables to show, past the first page
+    pub table_page: Option<usize>,
+    // Language of the server's own wording, when it isn't the site's
+    pub lang: Option<String>,
+    // Passed along when redirecting, never shown to templates
+    #[serde(skip)]
+    pub raw_query: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn new(
+        path: &str,
+        raw_query: Option<&str>,
+        allowed_params: &[String],
+        headers: &HeaderMap,
+        user: Option<String>,
+        lang: Option<String>,
+    ) -> Self {
+        let mut params = BTreeMap::new();
+        let mut theme = None;
+        let mut table_page = None;
+        if let Some(raw_query) = raw_query {
+            let pairs: Vec<(String, String)> = serde_urlencoded::from_str(raw_query).unwrap_or_default();
+            for (name, value) in pairs {
+                if name == THEME_PARAM {
+                    theme = sanitize_theme(value.as_str());
+                }
+                else if name == TABLE_PAGE_PARAM {
+                    table_page = value.parse::<usize>().ok().filter(|page| *page > 1);
+                }
+                else if allowed_params.contains(&name) && value.len() <= MAX_PARAM_LEN {
+                    params.insert(name, value);
+                }
+            }
+        }
+        if theme.is_none() {
+            theme = theme_cookie(headers);
+        }
+        let query = serde_urlencoded::to_string(&params).unwrap_or_default();
+        RequestInfo {
+            path: path.to_string(),
+            query,
+            params,
+            user,
+            theme,
+            table_page,
+            lang,
+            raw_query: raw_query.map(str::to_string),
+        }
+    }
+
+    // A parameter of the original query, whether or not templates may see it
+    pub fn raw_param(&self, name: &str) -> Option<String> {
+        let pairs: Vec<(String, String)> = serde_urlencoded::from_str(self.raw_query.as_deref()?).unwrap_or_default();
+        pairs.into_iter().find(|(param, _value)| param == name).map(|(_param, value)| value)
+    }
+
+    // Renders that depend on the request are cached separately for each
+    // combination of inputs. The user and theme only count when the render
+    // reads them. An empty variant shares the plain page cache
+    pub fn cache_variant(&self, usage: RequestUsage) -> String {
+        let mut variant = self.query.clone();
+        if let Some(user) = self.user.as_ref().filter(|_| usage.user) {
+            variant.push_str(format!("&user={}", urlencoding::encode(user)).as_str());
+        }
+        if let Some(theme) = self.theme.as_ref().filter(|_| usage.theme) {
+            variant.push_str(format!("&theme={theme}").as_str());
+        }
+        if let Some(table_page) = self.table_page {
+            variant.push_str(format!("&{TABLE_PAGE_PARAM}={table_page}").as_str());
+        }
+        if let Some(lang) = &self.lang {
+            variant.push_str(format!("&lang={lang}").as_str());
+        }
+        variant
+    }
+}
+
+// Which of the user and theme a render reads, to pick its cache variant
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RequestUsage {
+    pub user: bool,
+    pub theme: bool,
+}
+
+impl RequestUsage {
+    // For renders nothing is known about
+    pub const ALL: RequestUsage = RequestUsage {
+        user: true,
+        theme: true,
+    };
+
+    pub fn or(self, other: RequestUsage) -> RequestUsage {
+        RequestUsage {
+            user: self.user || other.user,
+            theme: self.theme || other.theme,
+        }
+    }
+
+    // What a template tag reads of `request`. Using it whole, eg: passing it
+    // to a filter, counts as reading everything. `user_vars` are other
+    // variables that are set per user
+    pub fn of_tag(tag: &str, user_vars: &[&str]) -> RequestUsage {
+        let mut usage = RequestUsage::default();
+        for name in tag.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')) {
+            let mut parts = name.split('.');
+            match (parts.next(), parts.next()) {
+                (Some("request"), Some("user")) => usage.user = true,
+                (Some("request"), Some("theme")) => usage.theme = true,
+                (Some("request"), Some(_)) => {},
+                (Some("request"), None) => return RequestUsage::ALL,
+                (Some(var), _) if user_vars.contains(&var) => usage.user = true,
+                _ => {},
+            }
+        }
+        usage
+    }
+}
+
+// The insides of a template's {{ }} and {% %} tags
+pub fn template_tags(source: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find('{') {
+        let close = match rest[start..].chars().nth(1) {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            _ => {
+                rest = &rest[start + 1..];
+                continue;
+            },
+        };
+        let inside = &rest[start + 2..];
+        let end = inside.find(close).unwrap_or(inside.len());
+        tags.push(&inside[..end]);
+        rest = &inside[(end + close.len()).min(inside.len())..];
+    }
+    tags
+}
+
+// Whether a cache variant (see cache_variant) was rendered for the user
+pub fn is_user_variant(variant: &str, user: &str) -> bool {
+    let user_part = format!("user={}", urlencoding::encode(user));
+    variant.split('&').any(|part| part == user_part)
+}
+
+// Link to another page of a long table, keeping the other parameters.
+// Escaped for use in an href
+pub fn table_page_url(query: &str, page: usize) -> String {
+    match query.is_empty() {
+        true => format!("?{TABLE_PAGE_PARAM}={page}"),
+        false => format!("?{}&amp;{TABLE_PAGE_PARAM}={page}", query.replace('&', "&amp;")),
+    }
+}
+
+// Themes end up in class names and cache keys, so keep them simple
+fn sanitize_theme(theme: &str) -> Option<String> {
+    let valid = !theme.is_empty()
+        && theme.len() <= MAX_THEME_LEN
+        && theme.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Some(theme.to_string()),
+        false => None,
+    }
+}
+
+fn theme_cookie(headers: &HeaderMap) -> Option<String> {
+    headers.get_all(header::COOKIE).iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == THEME_PARAM)
+        .and_then(|(_, value)| sanitize_theme(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(source: &str, user_vars: &[&str]) -> RequestUsage {
+        template_tags(source).into_iter()
+            .fold(RequestUsage::default(), |usage, tag| usage.or(RequestUsage::of_tag(tag, user_vars)))
+    }
+
+    #[test]
+    fn test_request_usage() {
+        let theme = r#"<body{% if request.theme %} class="theme-{{request.theme}}"{% endif %}>"#;
+        assert_eq!(usage(theme, &[]), RequestUsage { user: false, theme: true });
+        assert_eq!(usage("<p>Hi {{ request.user | default(value=\"you\") }}</p>", &[]), RequestUsage { user: true, theme: false });
+        assert_eq!(usage("{% for tag in request.params.tags %}{{ request.path }}{% endfor %}", &[]), RequestUsage::default());
+        assert_eq!(usage("{{ request | json_encode() }}", &[]), RequestUsage::ALL);
+        assert_eq!(usage("{% set r = request %}", &[]), RequestUsage::ALL);
+        assert_eq!(usage("{% if bookmarked is defined %}<form></form>{% endif %}", &["bookmarked"]), RequestUsage { user: true, theme: false });
+        assert_eq!(usage("{% if bookmarked is defined %}<form></form>{% endif %}", &[]), RequestUsage::default());
+        // only tags count, not the page's own words
+        assert_eq!(usage("<p>request.user and request</p>{# request #}", &[]), RequestUsage::default());
+    }
+
+    #[test]
+    fn test_cache_variant() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "theme=dark".parse().unwrap());
+        let allowed = ["tag".to_string()];
+        let request = RequestInfo::new("/home/a.md", Some("tag=rust&utm=x"), &allowed, &headers, Some("alice".to_string()), None);
+        assert_eq!(request.cache_variant(RequestUsage::default()), "tag=rust");
+        assert_eq!(request.cache_variant(RequestUsage { user: false, theme: true }), "tag=rust&theme=dark");
+        assert_eq!(request.cache_variant(RequestUsage::ALL), "tag=rust&user=alice&theme=dark");
+        assert!(is_user_variant(request.cache_variant(RequestUsage::ALL).as_str(), "alice"));
+        let plain = RequestInfo::new("/home/a.md", None, &allowed, &headers, Some("alice".to_string()), None);
+        assert_eq!(plain.cache_variant(RequestUsage { user: false, theme: true }), "&theme=dark");
+        assert_eq!(plain.cache_variant(RequestUsage::default()), "");
+    }
+}
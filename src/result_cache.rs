@@ -2,15 +2,20 @@ use std::ffi::OsStr;
 use std::fmt;
 use std::{path::PathBuf, sync::{Arc, RwLock}, time::SystemTime};
 use indexmap::IndexMap;
+use serde::Serialize;
 
 #[cfg(test)]
 use crate::chimera_error::ChimeraError;
 use crate::file_manager::FileManager;
+use crate::trash::Trash;
 
 struct CachedPage {
     when: SystemTime,
     modtime: SystemTime,
     html: String,
+    // Whether the render read the page catalog, so goes stale when any
+    // document changes, not just its own
+    from_catalog: bool,
 }
 
 struct WrappedCache {
@@ -19,6 +24,23 @@ struct WrappedCache {
     max_size: usize,
 }
 
+// A cached render, as listed on the admin pages
+#[derive(Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub size: usize,
+    pub age_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct CacheSummary {
+    pub entries: usize,
+    pub size: usize,
+    pub max_size: usize,
+    // Biggest entries first
+    pub largest: Vec<CacheEntry>,
+}
+
 enum CacheAction {
     Compact,
     Clean
@@ -28,6 +50,7 @@ enum CacheAction {
 pub struct ResultCache {
     lock: Arc<RwLock<WrappedCache>>,
     signal_tx: tokio::sync::mpsc::Sender<CacheAction>,
+    trash: Option<Trash>,
 }
 
 async fn get_modtime(path: &std::path::Path) -> SystemTime {
@@ -51,15 +74,38 @@ impl ResultCache {
         ResultCache {
             lock: wrapped_cache,
             signal_tx: tx,
+            trash: None,
         }
     }
 
+    // Deleted documents' renders go to the trash instead of being dropped
+    pub fn with_trash(mut self, trash: Option<Trash>) -> Self {
+        self.trash = trash;
+        self
+    }
+
     pub fn listen_for_changes(&self, file_manager: &FileManager) {
         let rx = file_manager.subscribe();
         tokio::spawn(listen_for_changes(rx, self.clone()));
     }
 
+    #[cfg(test)]
     pub async fn add(&self, path: &std::path::Path, html: &str) {
+        self.add_variant(path, "", html).await;
+    }
+
+    // Variants are separate renders of the same document, such as pages
+    // that depend on query parameters. They share the document's modtime
+    pub async fn add_variant(&self, path: &std::path::Path, variant: &str, html: &str) {
+        self.insert(path, variant, html, false).await;
+    }
+
+    // A render that lists other documents, see evict_catalog_pages
+    pub async fn add_catalog_variant(&self, path: &std::path::Path, variant: &str, html: &str) {
+        self.insert(path, variant, html, true).await;
+    }
+
+    async fn insert(&self, path: &std::path::Path, variant: &str, html: &str, from_catalog: bool) {
         let needs_compact =
         {
             let modtime = get_modtime(path).await;
@@ -71,9 +117,10 @@ impl ResultCache {
                 when: SystemTime::now(),
                 modtime,
                 html: html.to_string(),
+                from_catalog,
             };
             let size = page.html.len();
-            let prev = lock.cache.insert(path.to_path_buf(), page);
+            let prev = lock.cache.insert(cache_key(path, variant), page);
             if let Some(prev) = prev {
                 lock.current_size -= prev.html.len();
             }
@@ -88,13 +135,17 @@ impl ResultCache {
     }
 
     pub async fn get(&self, path: &std::path::Path) -> Option<String> {
+        self.get_variant(path, "").await
+    }
+
+    pub async fn get_variant(&self, path: &std::path::Path, variant: &str) -> Option<String> {
         let modtime = get_modtime(path).await;
         let mut needs_clean = false;
         {
             let Ok(lock) = self.lock.read() else {
                 return None;
             };
-            if let Some(res) = lock.cache.get(path) {
+            if let Some(res) = lock.cache.get(&cache_key(path, variant)) {
                 if res.modtime == modtime {
                     return Some(res.html.clone())
                 }
@@ -111,6 +162,11 @@ impl ResultCache {
         None
     }
 
+    // Bytes of html held, 0 if the lock is poisoned
+    pub fn size(&self) -> usize {
+        self.lock.read().map_or(0, |lock| lock.current_size)
+    }
+
     #[cfg(test)]
     pub fn get_size(&self) -> Result<usize, ChimeraError> {
         let lock = self.lock.read()?;
@@ -122,9 +178,118 @@ impl ResultCache {
             return;
         };
         lock.cache.clear();
+        lock.current_size = 0;
+    }
+
+    // Drops a page along with all its variants. Returns how many went
+    pub fn evict(&self, path: &std::path::Path) -> usize {
+        let Ok(mut lock) = self.lock.write() else {
+            return 0;
+        };
+        // variants are keyed as path?variant, see cache_key
+        let variant_prefix = format!("{}?", path.to_string_lossy());
+        let mut freed = 0;
+        let before = lock.cache.len();
+        lock.cache.retain(|key, page| {
+            let matches = key == path || key.to_string_lossy().starts_with(variant_prefix.as_str());
+            if matches {
+                freed += page.html.len();
+            }
+            !matches
+        });
+        lock.current_size -= freed;
+        before - lock.cache.len()
+    }
+
+    // Drops the renders that read the page catalog, for when it changes.
+    // Returns how many went
+    pub fn evict_catalog_pages(&self) -> usize {
+        let Ok(mut lock) = self.lock.write() else {
+            return 0;
+        };
+        let mut freed = 0;
+        let before = lock.cache.len();
+        lock.cache.retain(|_key, page| {
+            if page.from_catalog {
+                freed += page.html.len();
+            }
+            !page.from_catalog
+        });
+        lock.current_size -= freed;
+        before - lock.cache.len()
+    }
+
+    // The plain render of a page if there is one, otherwise any variant
+    pub fn last_render(&self, path: &std::path::Path) -> Option<String> {
+        let lock = self.lock.read().ok()?;
+        if let Some(page) = lock.cache.get(path) {
+            return Some(page.html.clone());
+        }
+        let variant_prefix = format!("{}?", path.to_string_lossy());
+        lock.cache.iter()
+            .find(|(key, _page)| key.to_string_lossy().starts_with(variant_prefix.as_str()))
+            .map(|(_key, page)| page.html.clone())
+    }
+
+    // Each listener that clears the cache for a document change calls this
+    // first, so whichever runs first saves a deleted document's render
+    pub fn keep_deleted(&self, path: &std::path::Path) {
+        if let Some(trash) = &self.trash {
+            trash.on_change(path, self);
+        }
+    }
+
+    // Drops the variants of every page that match, eg: those rendered for
+    // one user. Returns how many went
+    pub fn evict_variants(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let Ok(mut lock) = self.lock.write() else {
+            return 0;
+        };
+        let mut freed = 0;
+        let before = lock.cache.len();
+        lock.cache.retain(|key, page| {
+            let key = key.to_string_lossy();
+            let evict = key.split_once('?').is_some_and(|(_path, variant)| matches(variant));
+            if evict {
+                freed += page.html.len();
+            }
+            !evict
+        });
+        lock.current_size -= freed;
+        before - lock.cache.len()
+    }
+
+    pub fn summary(&self, limit: usize) -> Option<CacheSummary> {
+        let lock = self.lock.read().ok()?;
+        let now = SystemTime::now();
+        let mut largest: Vec<CacheEntry> = lock.cache.iter().map(|(key, page)| {
+            CacheEntry {
+                key: key.to_string_lossy().into_owned(),
+                size: page.html.len(),
+                age_secs: now.duration_since(page.when).map_or(0, |age| age.as_secs()),
+            }
+        }).collect();
+        largest.sort_by_key(|entry| std::cmp::Reverse(entry.size));
+        largest.truncate(limit);
+        Some(CacheSummary {
+            entries: lock.cache.len(),
+            size: lock.current_size,
+            max_size: lock.max_size,
+            largest,
+        })
     }
 }
 
+fn cache_key(path: &std::path::Path, variant: &str) -> PathBuf {
+    if variant.is_empty() {
+        return path.to_path_buf();
+    }
+    let mut key = path.as_os_str().to_os_string();
+    key.push("?");
+    key.push(variant);
+    PathBuf::from(key)
+}
+
 impl fmt::Debug for CachedPage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "({:?}-{:?})", self.when, &self.html[0..20])
@@ -162,6 +327,7 @@ async fn cache_compactor(
                     return;
                 };
                 lock.cache.clear();
+                lock.current_size = 0;
             },
         }
     }
@@ -174,10 +340,18 @@ async fn listen_for_changes(
     while let Ok(path) = rx.recv().await {
         tracing::debug!("RC change event {}", path.display());
         if let Some(ext) = path.extension() {
-            if ext == OsStr::new("md") || ext == OsStr::new("html") || ext == OsStr::new("toml") {
+            // bibliographies (.bib and .json) are cited from documents,
+            // templates load data files (.json, .toml and .yaml) and scripts
+            // (.lua) can change any page
+            if ["md", "html", "toml", "bib", "json", "yaml", "yml", "lua"].iter().any(|known| ext == OsStr::new(known)) {
+                cache.keep_deleted(path.as_path());
                 cache.clear();
             }
         }
+        else if path.file_name() == Some(OsStr::new(".order")) {
+            // folder listing order
+            cache.clear();
+        }
     }
 }
 
@@ -204,4 +378,62 @@ mod tests {
         tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
         assert_eq!(cache.get_size(), Ok(400));
     }
+
+    #[tokio::test]
+    async fn test_evict_and_summary() {
+        let cache = ResultCache::new(10_000);
+        cache.add(PathBuf::from("a.md").as_path(), "a".repeat(100).as_str()).await;
+        cache.add_variant(PathBuf::from("a.md").as_path(), "tag=rust", "a".repeat(300).as_str()).await;
+        cache.add(PathBuf::from("ab.md").as_path(), "b".repeat(200).as_str()).await;
+        let summary = cache.summary(2).unwrap();
+        assert_eq!(summary.entries, 3);
+        assert_eq!(summary.size, 600);
+        assert_eq!(summary.largest.len(), 2);
+        assert_eq!(summary.largest[0].key, "a.md?tag=rust");
+        assert_eq!(summary.largest[1].key, "ab.md");
+
+        assert_eq!(cache.evict(PathBuf::from("a.md").as_path()), 2);
+        assert_eq!(cache.get_size(), Ok(200));
+        assert_eq!(cache.get(PathBuf::from("ab.md").as_path()).await, Some("b".repeat(200)));
+
+        cache.clear();
+        assert_eq!(cache.get_size(), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_evict_variants() {
+        let cache = ResultCache::new(10_000);
+        cache.add(PathBuf::from("a.md").as_path(), "a".repeat(100).as_str()).await;
+        cache.add_variant(PathBuf::from("a.md").as_path(), "&user=alice", "a".repeat(200).as_str()).await;
+        cache.add_variant(PathBuf::from("b.md").as_path(), "tag=rust&user=alice&theme=dark", "b".repeat(300).as_str()).await;
+        cache.add_variant(PathBuf::from("b.md").as_path(), "&user=bob", "b".repeat(400).as_str()).await;
+
+        assert_eq!(cache.evict_variants(|variant| variant.split('&').any(|part| part == "user=alice")), 2);
+        assert_eq!(cache.get_size(), Ok(500));
+        assert_eq!(cache.get(PathBuf::from("a.md").as_path()).await, Some("a".repeat(100)));
+        assert_eq!(cache.get_variant(PathBuf::from("b.md").as_path(), "&user=bob").await, Some("b".repeat(400)));
+    }
+
+    #[tokio::test]
+    async fn test_evict_catalog_pages() {
+        let cache = ResultCache::new(10_000);
+        cache.add(PathBuf::from("a.md").as_path(), "a".repeat(100).as_str()).await;
+        cache.add_catalog_variant(PathBuf::from("blog/index.md").as_path(), "", "b".repeat(200).as_str()).await;
+        cache.add_catalog_variant(PathBuf::from("/tags/rust").as_path(), "&user=alice", "c".repeat(300).as_str()).await;
+
+        assert_eq!(cache.evict_catalog_pages(), 2);
+        assert_eq!(cache.get_size(), Ok(100));
+        assert_eq!(cache.get(PathBuf::from("a.md").as_path()).await, Some("a".repeat(100)));
+        assert_eq!(cache.get(PathBuf::from("blog/index.md").as_path()).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_last_render() {
+        let cache = ResultCache::new(10_000);
+        cache.add_variant(PathBuf::from("a.md").as_path(), "&user=alice", "alice".repeat(10).as_str()).await;
+        assert_eq!(cache.last_render(PathBuf::from("a.md").as_path()), Some("alice".repeat(10)));
+        cache.add(PathBuf::from("a.md").as_path(), "plain").await;
+        assert_eq!(cache.last_render(PathBuf::from("a.md").as_path()), Some("plain".to_string()));
+        assert_eq!(cache.last_render(PathBuf::from("a").as_path()), None);
+    }
 }
@@ -0,0 +1,192 @@
+use std::{path::{Path, PathBuf}, sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex}};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, LuaSerdeExt, StdLib};
+
+use crate::file_manager::FileManager;
+use crate::toml_config::ScriptsConfig;
+
+// How often (in Lua instructions) a running script is checked on
+const INSTRUCTIONS_PER_CHECK: u32 = 1000;
+
+struct Sandbox {
+    lua: Lua,
+    // Checks left before the current call is stopped
+    budget: Arc<AtomicU32>,
+}
+
+struct Script {
+    path: PathBuf,
+    sandbox: Mutex<Option<Sandbox>>,
+}
+
+// Lua scripts that adjust the pages in a folder, for changes that don't
+// warrant a rebuild. A script defines either or both of:
+//   function context(vars, request) ... return vars end
+//   function html(html, request) ... return html end
+// Scripts get the table, string, math and utf8 libraries, but nothing that
+// reaches the file system, and are stopped when they run too long or use
+// too much memory. A failing script leaves the page as it was
+#[derive(Clone)]
+pub struct Scripts {
+    // Folder => script, deepest folders first
+    folders: Arc<Vec<(PathBuf, Arc<Script>)>>,
+    max_memory: usize,
+    max_checks: u32,
+}
+
+impl Scripts {
+    pub fn new(config: ScriptsConfig, chimera_root: &Path) -> Option<Self> {
+        if config.folders.is_empty() {
+            return None;
+        }
+        let mut scripts = Scripts {
+            folders: Arc::new(Vec::new()),
+            max_memory: config.max_memory,
+            max_checks: config.max_instructions.div_ceil(INSTRUCTIONS_PER_CHECK),
+        };
+        let mut folders: Vec<(PathBuf, Arc<Script>)> = config.folders.into_iter().map(|(folder, file)| {
+            let script = Script {
+                path: chimera_root.join(file.as_str()),
+                sandbox: Mutex::new(None),
+            };
+            (PathBuf::from(folder.trim_matches('/')), Arc::new(script))
+        }).collect();
+        folders.sort_by_key(|(folder, _script)| std::cmp::Reverse(folder.components().count()));
+        for (_folder, script) in &folders {
+            scripts.load(script);
+        }
+        scripts.folders = Arc::new(folders);
+        Some(scripts)
+    }
+
+    pub fn script_files(&self) -> Vec<PathBuf> {
+        self.folders.iter().map(|(_folder, script)| script.path.clone()).collect()
+    }
+
+    pub fn listen_for_changes(&self, file_manager: &FileManager) {
+        let rx = file_manager.subscribe();
+        tokio::spawn(listen_for_changes(rx, self.clone()));
+    }
+
+    fn load(&self, script: &Script) {
+        let sandbox = match std::fs::read_to_string(script.path.as_path()) {
+            Ok(code) => match self.sandbox(code.as_str(), script.path.as_path()) {
+                Ok(sandbox) => {
+                    tracing::info!("Loaded script {}", script.path.display());
+                    Some(sandbox)
+                },
+                Err(e) => {
+                    tracing::error!("Error loading script {}: {e}", script.path.display());
+                    None
+                },
+            },
+            Err(e) => {
+                tracing::warn!("Failed to read script {}: {e}", script.path.display());
+                None
+            },
+        };
+        if let Ok(mut lock) = script.sandbox.lock() {
+            *lock = sandbox;
+        }
+    }
+
+    fn sandbox(&self, code: &str, path: &Path) -> mlua::Result<Sandbox> {
+        let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8, LuaOptions::default())?;
+        lua.set_memory_limit(self.max_memory)?;
+        let globals = lua.globals();
+        // the base library can still read files, or load bytecode
+        for name in ["dofile", "loadfile", "load", "require"] {
+            globals.set(name, mlua::Value::Nil)?;
+        }
+        let script_name = path.display().to_string();
+        globals.set("print", lua.create_function(move |_lua, message: String| {
+            tracing::info!("{script_name}: {message}");
+            Ok(())
+        })?)?;
+        let budget = Arc::new(AtomicU32::new(self.max_checks));
+        let hook_budget = budget.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK), move |_lua, _debug| {
+            match hook_budget.load(Ordering::Relaxed) {
+                0 => Err(mlua::Error::runtime("script ran too long")),
+                left => {
+                    hook_budget.store(left - 1, Ordering::Relaxed);
+                    Ok(())
+                },
+            }
+        });
+        lua.load(code).set_name(path.display().to_string()).exec()?;
+        drop(globals);
+        Ok(Sandbox {
+            lua,
+            budget,
+        })
+    }
+
+    fn script_for(&self, path: &Path) -> Option<&Script> {
+        self.folders.iter()
+            .find(|(folder, _script)| path.starts_with(folder))
+            .map(|(_folder, script)| script.as_ref())
+    }
+
+    pub fn applies_to(&self, path: &Path) -> bool {
+        self.script_for(path).is_some()
+    }
+
+    // Calls the script's `name` function, if it has one, with the value and
+    // the request, returning what it hands back
+    fn call<T: serde::Serialize + serde::de::DeserializeOwned>(
+        &self,
+        path: &Path,
+        name: &str,
+        value: T,
+        request: &impl serde::Serialize,
+    ) -> T {
+        let Some(script) = self.script_for(path) else {
+            return value;
+        };
+        let Ok(lock) = script.sandbox.lock() else {
+            return value;
+        };
+        let Some(sandbox) = lock.as_ref() else {
+            return value;
+        };
+        let lua = &sandbox.lua;
+        let Ok(Some(function)) = lua.globals().get::<_, Option<Function>>(name) else {
+            return value;
+        };
+        sandbox.budget.store(self.max_checks, Ordering::Relaxed);
+        let result = lua.to_value(&value)
+            .and_then(|lua_value| Ok((lua_value, lua.to_value(request)?)))
+            .and_then(|args| function.call::<_, mlua::Value>(args))
+            .and_then(|returned| lua.from_value::<T>(returned));
+        match result {
+            Ok(returned) => returned,
+            Err(e) => {
+                tracing::warn!("Script {} failed in {name}() for {}: {e}", script.path.display(), path.display());
+                value
+            },
+        }
+    }
+
+    pub fn transform_context(&self, path: &Path, vars: tera::Context, request: &impl serde::Serialize) -> tera::Context {
+        let json = self.call(path, "context", vars.clone().into_json(), request);
+        tera::Context::from_value(json).unwrap_or(vars)
+    }
+
+    pub fn transform_html(&self, path: &Path, html: String, request: &impl serde::Serialize) -> String {
+        self.call(path, "html", html, request)
+    }
+}
+
+async fn listen_for_changes(
+    mut rx: tokio::sync::broadcast::Receiver<PathBuf>,
+    scripts: Scripts,
+) {
+    while let Ok(path) = rx.recv().await {
+        for (_folder, script) in scripts.folders.iter() {
+            if script.path == path {
+                tracing::info!("Script change event {}", path.display());
+                scripts.load(script);
+            }
+        }
+    }
+}
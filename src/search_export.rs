@@ -0,0 +1,89 @@
+use std::sync::{Arc, RwLock};
+use axum::{extract::State, response::{IntoResponse, Response}};
+use serde::Serialize;
+
+use crate::chimera_error::handle_err;
+use crate::document_scraper::InternalLink;
+use crate::page_catalog::PageCatalog;
+use crate::AppStateType;
+
+pub const SEARCH_INDEX_URL: &str = "/search-index.json";
+
+#[derive(Serialize)]
+struct SearchEntry<'a> {
+    url: &'a str,
+    title: &'a str,
+    headings: &'a [InternalLink],
+    excerpt: &'a str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+}
+
+#[derive(Serialize)]
+struct SearchIndex<'a> {
+    pages: Vec<SearchEntry<'a>>,
+}
+
+// Titles, headings and excerpts of every document as JSON, for searching
+// with a client-side library (lunr, MiniSearch) where the server's full text
+// index isn't available, eg: offline. Rebuilt when a document changes
+#[derive(Clone)]
+pub struct SearchExport {
+    // Catalog generation it was built at => JSON
+    index: Arc<RwLock<Option<(u64, String)>>>,
+    page_catalog: PageCatalog,
+}
+
+impl SearchExport {
+    pub fn new(page_catalog: PageCatalog) -> Self {
+        SearchExport {
+            index: Arc::new(RwLock::new(None)),
+            page_catalog,
+        }
+    }
+
+    pub fn json(&self) -> Result<String, serde_json::Error> {
+        let generation = self.page_catalog.generation();
+        if let Ok(lock) = self.index.read() {
+            if let Some((built_at, json)) = lock.as_ref() {
+                if *built_at == generation {
+                    return Ok(json.clone());
+                }
+            }
+        }
+        let pages = self.page_catalog.pages();
+        let index = SearchIndex {
+            pages: pages.iter().map(|page| SearchEntry {
+                url: page.url.as_str(),
+                title: page.title.as_str(),
+                headings: page.headings.as_slice(),
+                excerpt: page.excerpt.as_str(),
+                tags: page.tags.as_slice(),
+            }).collect(),
+        };
+        let json = serde_json::to_string(&index)?;
+        tracing::debug!("Built search index export of {} pages, {} bytes", pages.len(), json.len());
+        if let Ok(mut lock) = self.index.write() {
+            *lock = Some((generation, json.clone()));
+        }
+        Ok(json)
+    }
+}
+
+pub async fn handle_search_index(
+    State(app_state): State<AppStateType>,
+) -> Response {
+    match app_state.search_export.json() {
+        Ok(json) => (
+            [
+                (axum::http::header::CONTENT_TYPE, "application/json"),
+                (axum::http::header::CACHE_CONTROL, "no-cache"),
+            ],
+            json,
+        ).into_response(),
+        Err(e) => {
+            tracing::warn!("Error exporting the search index: {e}");
+            handle_err(app_state).await.into_response()
+        },
+    }
+}
@@ -0,0 +1,100 @@
+use axum::{extract::State, response::{IntoResponse, Response}};
+use serde::Serialize;
+use slugify::slugify;
+
+use crate::chimera_error::{handle_404, handle_err};
+use crate::page_catalog::{PageCatalog, PageSummary};
+use crate::session::CsrfHtml;
+use crate::AppStateType;
+
+pub const SERIES_DIR: &str = "/series";
+
+#[derive(Serialize)]
+pub struct SeriesPart {
+    pub part: Option<i64>,
+    pub title: String,
+    pub url: String,
+    pub current: bool,
+}
+
+// A set of documents sharing a `series:` front matter value, ordered by `part:`
+#[derive(Serialize)]
+pub struct SeriesInfo {
+    pub name: String,
+    pub url: String,
+    pub parts: Vec<SeriesPart>,
+    // 1-based position of the current document, when there is one
+    pub position: Option<usize>,
+    pub total: usize,
+    pub prev: Option<usize>,
+    pub next: Option<usize>,
+}
+
+pub fn series_url(name: &str) -> String {
+    format!("{SERIES_DIR}/{}", slugify!(name))
+}
+
+fn part_number(page: &PageSummary) -> Option<i64> {
+    page.metadata.get("part").and_then(|part| part.parse().ok())
+}
+
+impl SeriesInfo {
+    // current_url marks which part is being viewed
+    pub fn build(catalog: &PageCatalog, name: &str, current_url: Option<&str>) -> Option<SeriesInfo> {
+        let mut pages: Vec<PageSummary> = catalog.pages().into_iter()
+            .filter(|page| page.metadata.get("series").is_some_and(|series| series == name))
+            .collect();
+        if pages.is_empty() {
+            return None;
+        }
+        // unnumbered parts go last
+        pages.sort_by(|a, b| {
+            part_number(a).unwrap_or(i64::MAX).cmp(&part_number(b).unwrap_or(i64::MAX))
+                .then_with(|| a.title.cmp(&b.title))
+        });
+        let parts: Vec<SeriesPart> = pages.into_iter().map(|page| {
+            SeriesPart {
+                part: part_number(&page),
+                current: current_url.is_some_and(|url| urls_match(url, page.url.as_str())),
+                title: page.title,
+                url: page.url,
+            }
+        }).collect();
+        let index = parts.iter().position(|part| part.current);
+        let total = parts.len();
+        Some(SeriesInfo {
+            name: name.to_string(),
+            url: series_url(name),
+            position: index.map(|index| index + 1),
+            total,
+            prev: index.and_then(|index| index.checked_sub(1)),
+            next: index.map(|index| index + 1).filter(|next| *next < total),
+            parts,
+        })
+    }
+}
+
+fn urls_match(a: &str, b: &str) -> bool {
+    let a = urlencoding::decode(a).map_or(a.to_string(), |a| a.into_owned());
+    let b = urlencoding::decode(b).map_or(b.to_string(), |b| b.into_owned());
+    a == b
+}
+
+pub async fn handle_series(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(slug): axum::extract::Path<String>,
+) -> Response {
+    let name = app_state.page_catalog.pages().into_iter()
+        .filter_map(|page| page.metadata.get("series").cloned())
+        .find(|name| slugify!(name.as_str()) == slug);
+    let Some(series) = name.and_then(|name| SeriesInfo::build(&app_state.page_catalog, name.as_str(), None)) else {
+        return handle_404(app_state).await.into_response();
+    };
+    match app_state.html_generator.gen_series(&series) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering series page for {slug}: {e:?}");
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use axum::{body::Body, extract::State, http::{header, HeaderMap, HeaderValue, Method, StatusCode}, middleware::Next, response::{Html, IntoResponse, Response}};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::activitypub::ACTIVITYPUB_DIR;
+use crate::webmentions::WEBMENTION_URL;
+use crate::AppStateType;
+
+pub const CSRF_FIELD: &str = "csrf_token";
+const CSRF_HEADER: &str = "x-csrf-token";
+
+// Templates emit this marker via csrf_field(), and it is swapped for the
+// caller's real token on the way out. That keeps rendered pages cacheable
+const CSRF_PLACEHOLDER: &str = "__CHIMERA_CSRF_TOKEN__";
+
+const SESSION_COOKIE: &str = "chimera_session";
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+// Largest page that gets its CSRF placeholder filled in
+const MAX_PAGE_SIZE: usize = 16 * 1024 * 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+type Base64 = base64::engine::GeneralPurpose;
+const BASE64: Base64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    is_new: bool,
+}
+
+impl Session {
+    // Made for this request, the browser hasn't sent it back yet
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+}
+
+// Set on a response by anything that relies on the session outliving it,
+// so new visitors get a session cookie without a form on the page
+#[derive(Clone)]
+pub struct KeepSession;
+
+pub struct SessionManager {
+    key: Vec<u8>,
+    secure_cookie: bool,
+}
+
+impl SessionManager {
+    pub fn new(secret: Option<&str>, secure_cookie: bool) -> Self {
+        let key = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                tracing::info!("No session secret configured, generating a random one");
+                let mut key = vec![0_u8; 32];
+                rand::thread_rng().fill_bytes(key.as_mut_slice());
+                key
+            }
+        };
+        SessionManager {
+            key,
+            secure_cookie,
+        }
+    }
+
+    fn mac(&self, data: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.key.as_slice())
+            .expect("HMAC can take a key of any size");
+        mac.update(data.as_bytes());
+        mac
+    }
+
+    fn verify(&self, data: &str, signature: &str) -> bool {
+        let Ok(signature) = BASE64.decode(signature) else {
+            return false;
+        };
+        self.mac(data).verify_slice(signature.as_slice()).is_ok()
+    }
+
+    fn sign(&self, data: &str) -> String {
+        BASE64.encode(self.mac(data).finalize().into_bytes())
+    }
+
+    fn read_cookie(&self, headers: &HeaderMap) -> Option<Session> {
+        for cookies in headers.get_all(header::COOKIE) {
+            let Ok(cookies) = cookies.to_str() else {
+                continue;
+            };
+            for cookie in cookies.split(';') {
+                let Some((name, value)) = cookie.trim().split_once('=') else {
+                    continue;
+                };
+                if name != SESSION_COOKIE {
+                    continue;
+                }
+                let Some((id, signature)) = value.split_once('.') else {
+                    continue;
+                };
+                if self.verify(id, signature) {
+                    return Some(Session {
+                        id: id.to_string(),
+                        is_new: false,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    fn new_session(&self) -> Session {
+        let mut id = [0_u8; 18];
+        rand::thread_rng().fill_bytes(&mut id);
+        Session {
+            id: BASE64.encode(id),
+            is_new: true,
+        }
+    }
+
+    fn cookie(&self, session: &Session) -> String {
+        let secure = match self.secure_cookie {
+            true => "; Secure",
+            false => "",
+        };
+        format!(
+            "{SESSION_COOKIE}={}.{}; Path=/; HttpOnly; SameSite=Lax{secure}",
+            session.id,
+            self.sign(session.id.as_str())
+        )
+    }
+
+    pub fn csrf_token(&self, session: &Session) -> String {
+        self.sign(format!("csrf:{}", session.id).as_str())
+    }
+
+    fn check_csrf(&self, session: &Session, token: &str) -> bool {
+        !session.is_new && self.verify(format!("csrf:{}", session.id).as_str(), token)
+    }
+}
+
+// Tera helper for forms: {{ csrf_field() }}
+pub fn csrf_field(_args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    Ok(tera::Value::String(format!(
+        "<input type=\"hidden\" name=\"{CSRF_FIELD}\" value=\"{CSRF_PLACEHOLDER}\">"
+    )))
+}
+
+// Set on responses with CSRF_PLACEHOLDER in them, the only ones mw_session
+// reads back to swap in the token
+#[derive(Clone)]
+pub struct CsrfPlaceholder;
+
+pub fn has_csrf_placeholder(html: &str) -> bool {
+    html.contains(CSRF_PLACEHOLDER)
+}
+
+// Html for rendered templates, which marks the response when a form on it
+// needs a token
+pub struct CsrfHtml(pub String);
+
+impl IntoResponse for CsrfHtml {
+    fn into_response(self) -> Response {
+        let has_placeholder = has_csrf_placeholder(self.0.as_str());
+        let mut response = Html(self.0).into_response();
+        if has_placeholder {
+            response.extensions_mut().insert(CsrfPlaceholder);
+        }
+        response
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD || method == Method::OPTIONS
+}
+
+// Posted to by other sites, which can't have a token
+fn is_cross_site(path: &str) -> bool {
+    path == WEBMENTION_URL || path == format!("{ACTIVITYPUB_DIR}/inbox")
+}
+
+// The request, with its body read back in, if it's safe or carries a valid
+// CSRF token in the header or a form field
+async fn check_request(sessions: &SessionManager, session: &Session, request: axum::extract::Request) -> Result<axum::extract::Request, Response> {
+    if is_safe_method(request.method()) || is_cross_site(request.uri().path()) {
+        return Ok(request);
+    }
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_BODY_SIZE).await else {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    };
+    let token = match parts.headers.get(CSRF_HEADER) {
+        Some(token) => token.to_str().ok().map(|token| token.to_string()),
+        None => {
+            let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(&bytes).unwrap_or_default();
+            fields.into_iter().find(|(name, _)| name == CSRF_FIELD).map(|(_, value)| value)
+        }
+    };
+    let valid = token.is_some_and(|token| sessions.check_csrf(session, token.as_str()));
+    if !valid {
+        tracing::warn!("CSRF check failed for {} {}", parts.method, parts.uri);
+        return Err((StatusCode::FORBIDDEN, "Invalid or missing CSRF token").into_response());
+    }
+    Ok(axum::extract::Request::from_parts(parts, Body::from(bytes)))
+}
+
+// Attaches a session to every request and requires a valid CSRF token on
+// anything that changes state
+pub async fn mw_session(
+    State(app_state): State<AppStateType>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let sessions = &app_state.sessions;
+    let session = sessions.read_cookie(request.headers()).unwrap_or_else(|| sessions.new_session());
+
+    let mut request = match check_request(sessions, &session, request).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    request.extensions_mut().insert(session.clone());
+
+    let response = next.run(request).await;
+    let has_placeholder = response.extensions().get::<CsrfPlaceholder>().is_some();
+    let keep_session = response.extensions().get::<KeepSession>().is_some();
+    if !has_placeholder && !keep_session {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    if has_placeholder {
+        let Ok(bytes) = axum::body::to_bytes(body, MAX_PAGE_SIZE).await else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let Ok(html) = std::str::from_utf8(&bytes) else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        body = Body::from(html.replace(CSRF_PLACEHOLDER, sessions.csrf_token(&session).as_str()));
+        parts.headers.remove(header::CONTENT_LENGTH);
+    }
+    let no_store = parts.headers.get(header::CACHE_CONTROL).is_some_and(|value| value == "no-store");
+    if !no_store {
+        parts.headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, no-cache"));
+    }
+    if session.is_new {
+        if let Ok(cookie) = HeaderValue::from_str(sessions.cookie(&session).as_str()) {
+            parts.headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, path: &str, headers: &[(&str, &str)], body: &str) -> axum::extract::Request {
+        let mut builder = axum::extract::Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    fn returning(sessions: &SessionManager) -> Session {
+        let session = sessions.new_session();
+        let cookie = format!("{SESSION_COOKIE}={}.{}", session.id, sessions.sign(session.id.as_str()));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(cookie.as_str()).unwrap());
+        sessions.read_cookie(&headers).unwrap()
+    }
+
+    async fn status(sessions: &SessionManager, session: &Session, request: axum::extract::Request) -> StatusCode {
+        match check_request(sessions, session, request).await {
+            Ok(_) => StatusCode::OK,
+            Err(response) => response.status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_csrf_token_in_header_or_form() {
+        let sessions = SessionManager::new(Some("secret"), false);
+        let session = returning(&sessions);
+        let token = sessions.csrf_token(&session);
+        let form = format!("page=%2Fhome%2Fa.md&{CSRF_FIELD}={token}");
+
+        let checked = check_request(&sessions, &session, request(Method::POST, "/comments", &[], form.as_str())).await.unwrap();
+        let body = axum::body::to_bytes(checked.into_body(), MAX_BODY_SIZE).await.unwrap();
+        assert_eq!(body, form.as_bytes(), "the handler still gets the form");
+        let header = request(Method::POST, "/bookmarks", &[(CSRF_HEADER, token.as_str())], "");
+        assert_eq!(status(&sessions, &session, header).await, StatusCode::OK);
+
+        let missing = request(Method::POST, "/comments", &[], "page=%2Fhome%2Fa.md");
+        assert_eq!(status(&sessions, &session, missing).await, StatusCode::FORBIDDEN);
+        let other_session = returning(&sessions);
+        let stolen = request(Method::POST, "/comments", &[(CSRF_HEADER, token.as_str())], "");
+        assert_eq!(status(&sessions, &other_session, stolen).await, StatusCode::FORBIDDEN);
+        // a bad header isn't rescued by a good form field
+        let bad_header = request(Method::POST, "/comments", &[(CSRF_HEADER, "nope")], form.as_str());
+        assert_eq!(status(&sessions, &session, bad_header).await, StatusCode::FORBIDDEN);
+        let delete = request(Method::DELETE, "/bookmarks", &[], "");
+        assert_eq!(status(&sessions, &session, delete).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_new_session_has_no_valid_token() {
+        let sessions = SessionManager::new(Some("secret"), false);
+        let session = sessions.new_session();
+        let token = sessions.csrf_token(&session);
+        let post = request(Method::POST, "/comments", &[(CSRF_HEADER, token.as_str())], "");
+        assert_eq!(status(&sessions, &session, post).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_exempt_requests() {
+        let sessions = SessionManager::new(Some("secret"), false);
+        let session = sessions.new_session();
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert_eq!(status(&sessions, &session, request(method, "/comments", &[], "")).await, StatusCode::OK);
+        }
+        let webmention = request(Method::POST, WEBMENTION_URL, &[], "source=a&target=b");
+        assert_eq!(status(&sessions, &session, webmention).await, StatusCode::OK);
+        let inbox = request(Method::POST, format!("{ACTIVITYPUB_DIR}/inbox").as_str(), &[], "{}");
+        assert_eq!(status(&sessions, &session, inbox).await, StatusCode::OK);
+        // only those exact paths
+        let outbox = request(Method::POST, format!("{ACTIVITYPUB_DIR}/outbox").as_str(), &[], "{}");
+        assert_eq!(status(&sessions, &session, outbox).await, StatusCode::FORBIDDEN);
+        let nested = request(Method::POST, format!("{WEBMENTION_URL}/x").as_str(), &[], "");
+        assert_eq!(status(&sessions, &session, nested).await, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_only_pages_with_forms_are_marked() {
+        let form = csrf_field(&HashMap::new()).unwrap();
+        let with_form = CsrfHtml(format!("<form>{}</form>", form.as_str().unwrap())).into_response();
+        assert!(with_form.extensions().get::<CsrfPlaceholder>().is_some());
+        let without = CsrfHtml("<p>Hello</p>".to_string()).into_response();
+        assert!(without.extensions().get::<CsrfPlaceholder>().is_none());
+    }
+}
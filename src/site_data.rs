@@ -0,0 +1,37 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+use crate::page_catalog::{PageCatalog, PageSummary};
+
+const RECENT_PAGES: usize = 20;
+
+// Everything dynamic pages know about the site
+#[derive(Default, Serialize)]
+pub struct SiteData {
+    pub pages: Vec<PageSummary>,
+    pub tags: BTreeMap<String, Vec<PageSummary>>,
+    pub recent: Vec<PageSummary>,
+}
+
+impl SiteData {
+    pub fn from_catalog(catalog: &PageCatalog) -> SiteData {
+        let pages = catalog.pages();
+
+        let mut tags: BTreeMap<String, Vec<PageSummary>> = BTreeMap::new();
+        for page in &pages {
+            for tag in &page.tags {
+                tags.entry(tag.clone()).or_default().push(page.clone());
+            }
+        }
+
+        let mut recent = pages.clone();
+        recent.sort_by_key(|page| std::cmp::Reverse(page.modtime));
+        recent.truncate(RECENT_PAGES);
+
+        SiteData {
+            pages,
+            tags,
+            recent,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use serde::Serialize;
+
+use crate::document_scraper::DocumentScraper;
+use crate::toml_config::SocialConfig;
+
+// Open Graph and Twitter card details of a document, for the previews
+// shown where it's shared. Templates get them as `social`
+#[derive(Serialize)]
+pub struct SocialCard {
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub url: Option<String>,
+    // og:type, an article unless the front matter says otherwise
+    #[serde(rename = "type")]
+    pub og_type: String,
+    // twitter:card, with a large image when there's one to show
+    pub card: &'static str,
+    pub twitter_site: Option<String>,
+}
+
+// The site-wide parts of the cards, see [social]
+#[derive(Clone)]
+pub struct Social {
+    site_url: Option<String>,
+    twitter_site: Option<String>,
+    image: Option<String>,
+}
+
+impl Social {
+    pub fn new(config: &SocialConfig) -> Self {
+        Social {
+            site_url: config.site_url.as_ref().map(|url| url.trim_end_matches('/').to_string()),
+            twitter_site: config.twitter_site.clone(),
+            image: config.image.clone(),
+        }
+    }
+
+    // Front matter `description:`, `image:`, `url:` and `type:` win over
+    // what's found in the document: its first paragraph and first image
+    pub fn card(&self, scraper: &DocumentScraper, title: &str, page_url: &str) -> SocialCard {
+        let image = scraper.metadata.get("image")
+            .or(scraper.images.first())
+            .map(|image| self.absolute(image.as_str(), page_url))
+            .or_else(|| self.image.as_ref().map(|image| self.absolute(image.as_str(), "/")));
+        let url = scraper.metadata.get("url").cloned()
+            .or_else(|| self.site_url.as_ref().map(|site_url| format!("{site_url}{page_url}")));
+        SocialCard {
+            title: title.to_string(),
+            description: scraper.description(),
+            card: match image.is_some() {
+                true => "summary_large_image",
+                false => "summary",
+            },
+            image,
+            url,
+            og_type: scraper.metadata.get("type").map_or("article", String::as_str).to_string(),
+            twitter_site: self.twitter_site.clone(),
+        }
+    }
+
+    // Previews need absolute URLs. Relative ones are from the page's folder,
+    // and both get the site_url, when there is one
+    fn absolute(&self, url: &str, page_url: &str) -> String {
+        if url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//") {
+            return url.to_string();
+        }
+        let path = match url.starts_with('/') {
+            true => url.to_string(),
+            false => {
+                let folder = page_url.rsplit_once('/').map_or("", |(folder, _file)| folder);
+                format!("{folder}/{url}")
+            },
+        };
+        match &self.site_url {
+            Some(site_url) => format!("{site_url}{path}"),
+            None => path,
+        }
+    }
+}
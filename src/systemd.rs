@@ -0,0 +1,50 @@
+use std::{path::Path, time::Duration};
+
+use crate::AppStateType;
+
+// Status updates for Type=notify systemd units. Outside of systemd these
+// quietly do nothing
+
+#[cfg(unix)]
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        tracing::warn!("Failed to notify systemd of startup: {e}");
+    }
+}
+
+#[cfg(unix)]
+pub fn notify_stopping() {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]) {
+        tracing::warn!("Failed to notify systemd of shutdown: {e}");
+    }
+}
+
+// With WatchdogSec= set, systemd restarts the server if these heartbeats stop
+#[cfg(unix)]
+pub fn spawn_watchdog(state: AppStateType) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = Duration::from_micros(usec / 2);
+    tracing::info!("Sending systemd watchdog heartbeats every {interval:?}");
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            // a wedged cache lock stops the heartbeat too
+            let _ = state.result_cache.get_variant(Path::new(""), "").await;
+            if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                tracing::warn!("Failed to send systemd watchdog heartbeat: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn notify_ready() {}
+
+#[cfg(not(unix))]
+pub fn notify_stopping() {}
+
+#[cfg(not(unix))]
+pub fn spawn_watchdog(_state: AppStateType) {}
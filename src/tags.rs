@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use axum::{extract::State, http::HeaderMap, response::{IntoResponse, Response}};
+use serde::Serialize;
+use slugify::slugify;
+
+use crate::chimera_error::{handle_404, handle_err};
+use crate::document_scraper::DocumentScraper;
+use crate::page_catalog::{PageCatalog, PageSummary};
+use crate::session::CsrfHtml;
+use crate::AppStateType;
+
+pub const TAGS_DIR: &str = "/tags";
+
+#[derive(Clone, Serialize)]
+pub struct TagLink {
+    pub name: String,
+    pub url: String,
+}
+
+// A tag and the documents that carry it, by title
+#[derive(Serialize)]
+pub struct TagInfo {
+    pub name: String,
+    pub url: String,
+    pub pages: Vec<PageSummary>,
+}
+
+// Every tag in use, for the /tags page
+#[derive(Serialize)]
+pub struct TagSummary {
+    pub name: String,
+    pub url: String,
+    pub count: usize,
+}
+
+pub fn tag_url(tag: &str) -> String {
+    format!("{TAGS_DIR}/{}", slugify!(tag))
+}
+
+pub fn tag_link(tag: &str) -> TagLink {
+    TagLink {
+        name: tag.to_string(),
+        url: tag_url(tag),
+    }
+}
+
+// A `tags:` list in front matter, or a comma separated string
+pub fn document_tags(scraper: &DocumentScraper) -> Vec<String> {
+    match scraper.metadata_lists.get("tags") {
+        Some(tags) => tags.clone(),
+        None => scraper.metadata.get("tags").map_or(Vec::new(), |tags| {
+            tags.split(',').map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty()).collect()
+        }),
+    }
+}
+
+// Tags that differ only in case or punctuation, like "Rust" and "rust",
+// share a slug and so a page. It's named as first seen
+fn tags_by_slug(catalog: &PageCatalog) -> BTreeMap<String, TagInfo> {
+    let mut tags: BTreeMap<String, TagInfo> = BTreeMap::new();
+    for page in catalog.pages() {
+        for tag in &page.tags {
+            let slug = slugify!(tag.as_str());
+            if slug.is_empty() {
+                continue;
+            }
+            let info = tags.entry(slug).or_insert_with(|| TagInfo {
+                name: tag.clone(),
+                url: tag_url(tag),
+                pages: Vec::new(),
+            });
+            if !info.pages.iter().any(|tagged| tagged.url == page.url) {
+                info.pages.push(page.clone());
+            }
+        }
+    }
+    for info in tags.values_mut() {
+        info.pages.sort_by_key(|page| page.title.to_lowercase());
+    }
+    tags
+}
+
+pub async fn handle_tags(
+    State(app_state): State<AppStateType>,
+    headers: HeaderMap,
+) -> Response {
+    let tags: Vec<TagSummary> = tags_by_slug(&app_state.page_catalog).into_values()
+        .map(|info| TagSummary {
+            name: info.name,
+            url: info.url,
+            count: info.pages.len(),
+        })
+        .collect();
+    let lang = app_state.messages.negotiate(&headers);
+    match app_state.html_generator.gen_tags(&tags, lang.as_deref()) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering the tags page: {e:?}");
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
+
+pub async fn handle_tag(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(slug): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(tag) = tags_by_slug(&app_state.page_catalog).remove(&slug) else {
+        return handle_404(app_state).await.into_response();
+    };
+    let lang = app_state.messages.negotiate(&headers);
+    match app_state.html_generator.gen_tag(&tag, lang.as_deref()) {
+        Ok(html) => CsrfHtml(html).into_response(),
+        Err(e) => {
+            tracing::warn!("Error rendering tag page for {slug}: {e:?}");
+            handle_err(app_state).await.into_response()
+        }
+    }
+}
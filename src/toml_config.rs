@@ -1,8 +1,14 @@
 use std::collections::HashMap;
 use indexmap::IndexMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use crate::chimera_error::ChimeraError;
 
+const INCLUDE_KEY: &str = "include";
+const MAX_INCLUDE_DEPTH: usize = 8;
+const FILE_SUFFIX: &str = "_file";
+// Only these can be read from a *_file, so settings like index_file keep their meaning
+const SECRET_SUFFIXES: [&str; 4] = ["password", "secret", "token", "key"];
+
 #[derive(Deserialize, Debug)]
 enum LogLevel {
     Trace,
@@ -26,53 +32,721 @@ pub struct TomlConfig {
     #[serde(default = "default_highlight_style")]
     pub highlight_style: String,
 
+    // How $math$ is typeset: "katex", or "none" to leave it to the site's
+    // own templates
+    #[serde(default = "default_math_renderer")]
+    pub math_renderer: String,
+
     #[serde(default = "default_site_lang")]
     pub site_lang: String,
 
     pub image_size_file: Option<String>,
 
+    // Terms to link or explain where documents first use them
+    pub glossary_file: Option<String>,
+
     #[serde(default)]
     pub generate_index: bool,
 
+    // Catalog, index and list a folder's documents the first time it's
+    // visited instead of scanning the whole tree at startup
+    #[serde(default)]
+    pub index_on_demand: bool,
+
+    // Shows authoring problems on the pages themselves, such as documents
+    // whose headings don't make a usable outline
+    #[serde(default)]
+    pub dev_mode: bool,
+
+    // Draft documents, `draft: true`, and scheduled ones 404 unless asked for
+    // with ?preview=<token>. Without a token they can't be seen at all
+    pub draft_token: Option<String>,
+
+    // Documents with a `publish_date:` still to come are left out of search
+    // and listings until then. This serves them to anyone with the link,
+    // rather than a 404
+    #[serde(default)]
+    pub serve_scheduled: bool,
+
     #[serde(default = "default_log_level")]
     log_level: LogLevel,
 
     #[serde(default = "default_max_cache_size")]
     pub max_cache_size: usize,
 
+    // Inline the core stylesheets into each page when they fit in this many
+    // bytes, deferring the rest
+    pub inline_css_limit: Option<usize>,
+
     #[serde(default = "default_port")]
     pub port: u16,
 
+    // Reverse proxies (addresses or ranges) whose X-Forwarded-For is
+    // believed. Anyone else could set it to whatever they like
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    // Serves the site under this path instead of the root, eg: "/docs"
+    pub base_path: Option<String>,
+
+    // Lets a new instance bind the port while the old one is still draining
+    #[serde(default)]
+    pub reuse_port: bool,
+
+    // Answer with a "starting up" 503 while the site loads, rather than
+    // refusing connections. Off with reuse_port, where the old instance
+    // is still serving
+    #[serde(default = "default_true")]
+    pub serve_while_starting: bool,
+
     #[serde(default)]
     pub redirects: HashMap<String, String>,
 
     #[serde(default)]
     pub menu: IndexMap<String, String>,
+
+    // External script or style URL => its integrity hash, eg: "sha384-..."
+    #[serde(default)]
+    pub integrity: HashMap<String, String>,
+
+    // Author key (as used in front matter) => profile
+    #[serde(default)]
+    pub authors: IndexMap<String, AuthorConfig>,
+
+    // URL => template rendered with site data, without a markdown document
+    #[serde(default)]
+    pub dynamic_pages: IndexMap<String, String>,
+
+    // File extension => external command that turns those documents into
+    // markdown or html
+    #[serde(default)]
+    pub converters: HashMap<String, ConverterConfig>,
+
+    // Draws ```dot blocks with Graphviz
+    pub graphviz: Option<GraphvizConfig>,
+
+    // Query parameters passed through to markdown templates as request.params
+    #[serde(default)]
+    pub template_params: Vec<String>,
+
+    // Partial templates rendered once with site data (as `site`) and reused
+    // until a document changes, eg: ["tag-cloud.html"]
+    #[serde(default)]
+    pub cached_fragments: Vec<String>,
+
+    // Folders (relative to the document root) whose pages are never cached
+    #[serde(default)]
+    pub uncached_folders: Vec<String>,
+
+    pub admin: Option<AdminConfig>,
+
+    pub analytics: Option<AnalyticsConfig>,
+
+    pub comments: Option<CommentsConfig>,
+
+    // Pages users can star, for sites behind an authenticating proxy
+    pub bookmarks: Option<BookmarksConfig>,
+
+    // The last documents each visitor read, for "continue reading"
+    pub reading_history: Option<ReadingHistoryConfig>,
+
+    // Service worker that keeps recent documents readable offline
+    pub offline: Option<OfflineConfig>,
+
+    // Photos listed in generated folder indexes, with their EXIF details
+    pub gallery: Option<GalleryConfig>,
+
+    // Translations of the server's own wording, see i18n.rs
+    pub i18n: Option<I18nConfig>,
+
+    // Refuses media under /home to pages on other sites
+    pub hotlinking: Option<HotlinkingConfig>,
+
+    // Dated archives of the documents, made on a schedule or from /admin
+    pub backup: Option<BackupConfig>,
+
+    // Keeps deleted documents' last render for a while, on the admin page
+    pub trash: Option<TrashConfig>,
+
+    // A memory ceiling split between the caches, past which large renders
+    // and background work are shed
+    pub memory: Option<MemoryConfig>,
+
+    #[serde(default)]
+    pub session: SessionConfig,
+
+    #[serde(default)]
+    pub access: AccessConfig,
+
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+
+    #[serde(default)]
+    pub tracking: TrackingConfig,
+
+    #[serde(default)]
+    pub search: SearchConfig,
+
+    #[serde(default)]
+    pub markup: MarkupConfig,
+
+    // Link previews for pages shared on social media
+    #[serde(default)]
+    pub social: SocialConfig,
+
+    // The Atom feed of the newest documents, at /feed.xml
+    #[serde(default)]
+    pub feed: FeedConfig,
+
+    // Mentions of the site's documents that other sites send, at /webmention
+    pub webmentions: Option<WebmentionsConfig>,
+
+    // An actor the feed's documents can be read from on the fediverse
+    pub activitypub: Option<ActivityPubConfig>,
+
+    // Emails the feed's new documents to confirmed subscribers
+    pub newsletter: Option<NewsletterConfig>,
+
+    // Responses under /.well-known/, without files in www
+    #[serde(default)]
+    pub well_known: WellKnownConfig,
+
+    // Lua scripts, for builds with the `scripting` feature
+    #[serde(default)]
+    pub scripts: ScriptsConfig,
+
+    // Unrecognized keys, most likely typos, to report once logging is up
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+
+    // How long reading the config took, for the startup report
+    #[serde(skip)]
+    pub load_time: std::time::Duration,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminConfig {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnalyticsConfig {
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+
+    #[serde(default = "default_top_pages")]
+    pub top_pages: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BookmarksConfig {
+    #[serde(default = "default_max_bookmarks")]
+    pub max_per_user: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReadingHistoryConfig {
+    #[serde(default = "default_history_pages")]
+    pub max_pages: usize,
+
+    // Visits older than this are forgotten
+    #[serde(default = "default_history_days")]
+    pub retention_days: u32,
+
+    // Don't record visits from browsers sending DNT or Sec-GPC
+    #[serde(default = "default_true")]
+    pub respect_do_not_track: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OfflineConfig {
+    // Most recently changed documents the worker caches when it's installed
+    #[serde(default = "default_precache_pages")]
+    pub precache_pages: usize,
+
+    // Visited pages kept for reading offline
+    #[serde(default = "default_recent_pages")]
+    pub recent_pages: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GalleryConfig {
+    // Makes photos with an EXIF caption findable through search
+    #[serde(default)]
+    pub index_captions: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct I18nConfig {
+    // Where the <lang>.toml catalogs are, relative to the chimera root
+    #[serde(default = "default_messages_dir")]
+    pub messages_dir: String,
+
+    // Pick the language by the browser's Accept-Language, rather than
+    // always using site_lang
+    #[serde(default = "default_true")]
+    pub negotiate: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HotlinkingConfig {
+    // Files that can only be embedded by the site's own pages
+    #[serde(default = "default_hotlink_extensions")]
+    pub extensions: Vec<String>,
+
+    // Other sites that may embed them. A leading `*.` matches subdomains
+    #[serde(default)]
+    pub allowed_sites: Vec<String>,
+
+    // Requests without a Referer, from privacy settings or direct loads
+    #[serde(default = "default_true")]
+    pub allow_no_referer: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BackupConfig {
+    // Where the archives go, relative to the chimera root
+    #[serde(default = "default_backup_dir")]
+    pub dir: String,
+
+    // Hours between backups. 0 only backs up when asked to from /admin
+    #[serde(default = "default_backup_interval")]
+    pub interval_hours: u64,
+
+    // Archives kept; older ones are deleted
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+
+    // Also archive the full text index, which is otherwise rebuilt on startup
+    #[serde(default)]
+    pub include_search: bool,
+
+    // Program and arguments run on each new archive, with {archive} replaced
+    // by its path, eg: ["aws", "s3", "cp", "{archive}", "s3://bucket/"]
+    #[serde(default)]
+    pub upload_command: Vec<String>,
+
+    #[serde(default = "default_upload_timeout")]
+    pub upload_timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TrashConfig {
+    // Hours a deleted document stays in the trash before it's purged
+    #[serde(default = "default_trash_hours")]
+    pub keep_hours: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemoryConfig {
+    // The ceiling shared by the result cache, the search index writer, the
+    // page catalog, image sizes and renders in progress. Approximate usage
+    // past it counts as memory pressure
+    #[serde(default = "default_memory_max")]
+    pub max_mb: usize,
+
+    // Shares of max_mb, replacing max_cache_size and the writer's 50 MB
+    #[serde(default = "default_result_cache_percent")]
+    pub result_cache_percent: usize,
+
+    #[serde(default = "default_search_index_percent")]
+    pub search_index_percent: usize,
+
+    // Under pressure, uncached documents bigger than this get a 503
+    #[serde(default = "default_large_document")]
+    pub large_document_kb: usize,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SessionConfig {
+    // Key used to sign session cookies. A random key is generated at startup
+    // if none is configured, which logs everyone out on restart
+    pub secret: Option<String>,
+
+    #[serde(default)]
+    pub secure_cookie: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct MarkupConfig {
+    // Adds a link to itself to each heading, eg: <a class="permalink" href="#intro">
+    #[serde(default)]
+    pub heading_permalinks: bool,
+
+    // Adds data-lang and a copy button to code blocks
+    #[serde(default)]
+    pub code_copy_buttons: bool,
+
+    // Drops a document's opening heading when it repeats the front matter
+    // title, for templates that show the title themselves
+    #[serde(default)]
+    pub hide_repeated_title: bool,
+
+    // Turns bare URLs like https://example.com into links, as GitHub does
+    #[serde(default)]
+    pub autolinks: bool,
+
+    // Line breaks within a paragraph are kept, as GitHub does in comments
+    #[serde(default)]
+    pub hard_breaks: bool,
+
+    // An image alone in its paragraph, with a title, becomes a <figure>
+    // with the title as its caption: ![alt](src "caption")
+    #[serde(default)]
+    pub figures: bool,
+
+    // Tables longer than this many rows are split into pages, eg: 500
+    pub table_page_rows: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthorConfig {
+    pub name: String,
+    pub avatar: Option<String>,
+    pub bio: Option<String>,
+    pub link: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccessConfig {
+    // Header carrying the user name set by an authenticating reverse proxy,
+    // eg: "Remote-User". It's only believed from trusted_proxies, and nobody
+    // is signed in without it
+    pub user_header: Option<String>,
+
+    // Folder (relative to the document root) => users allowed to read it
+    #[serde(default)]
+    pub folders: IndexMap<String, Vec<String>>,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            user_header: None,
+            folders: IndexMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExpiryConfig {
+    // Documents with a `reviewed:` date are outdated this long afterwards
+    #[serde(default = "default_review_days")]
+    pub review_days: u32,
+
+    // Search score multiplier for outdated documents, eg: 0.5
+    pub search_penalty: Option<f32>,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            review_days: default_review_days(),
+            search_penalty: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GraphvizConfig {
+    // Reads the graph on stdin and writes SVG to stdout
+    #[serde(default = "default_graphviz_command")]
+    pub command: Vec<String>,
+
+    #[serde(default = "default_converter_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConverterOutput {
+    Markdown,
+    Html,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConverterConfig {
+    // Program and arguments, eg: ["pandoc", "--from=org", "--to=html"]. It
+    // reads the document on stdin and writes the result to stdout
+    pub command: Vec<String>,
+
+    // What the command writes: "markdown", or "html" for the page body
+    #[serde(default = "default_converter_output")]
+    pub output: ConverterOutput,
+
+    #[serde(default = "default_converter_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScriptsConfig {
+    // Folder (relative to the document root) => Lua script (relative to the
+    // chimera root). The deepest folder holding a page picks its script
+    #[serde(default)]
+    pub folders: IndexMap<String, String>,
+
+    // Bytes each script can allocate
+    #[serde(default = "default_script_memory")]
+    pub max_memory: usize,
+
+    // Lua instructions a script can run per call
+    #[serde(default = "default_script_instructions")]
+    pub max_instructions: u32,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        ScriptsConfig {
+            folders: IndexMap::new(),
+            max_memory: default_script_memory(),
+            max_instructions: default_script_instructions(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchConfig {
+    // Heap shared by the index writer's threads. Defaults to 50, or
+    // search_index_percent of [memory] max_mb
+    pub writer_heap_mb: Option<usize>,
+
+    // Threads adding documents to the index. 0 picks one per CPU, up to 8,
+    // as far as the heap allows
+    #[serde(default)]
+    pub indexing_threads: usize,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SocialConfig {
+    // Where the site is served, eg: https://notes.example.com, since
+    // previews need absolute URLs
+    pub site_url: Option<String>,
+
+    // The site's account, eg: @example
+    pub twitter_site: Option<String>,
+
+    // Preview image for documents without an image of their own
+    pub image: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct WellKnownConfig {
+    // Contents of /.well-known/security.txt, see securitytxt.org
+    pub security_txt: Option<String>,
+
+    // Where /.well-known/change-password sends password managers
+    pub change_password: Option<String>,
+
+    // Accounts /.well-known/webfinger answers for, and their profile pages,
+    // eg: "acct:alice@example.com" = "https://mastodon.social/@alice"
+    #[serde(default)]
+    pub webfinger: HashMap<String, String>,
+
+    // Any other file by name, eg: "apple-app-site-association" = "{...}"
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WebmentionsConfig {
+    // Program and arguments that print the sending page, with {url}
+    // replaced by its address, to check that it links to the document.
+    // {host}, {port} and {address} are what the URL was checked to resolve
+    // to, for connecting to. It shouldn't follow redirects
+    #[serde(default = "default_fetch_command")]
+    pub fetch_command: Vec<String>,
+
+    #[serde(default = "default_fetch_timeout")]
+    pub fetch_timeout_secs: u64,
+
+    #[serde(default = "default_max_pending_webmentions")]
+    pub max_pending: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ActivityPubConfig {
+    // The account is @username@host, with the host from [social] site_url
+    pub username: String,
+
+    // The feed's title unless set
+    pub name: Option<String>,
+
+    pub summary: Option<String>,
+
+    // Avatar image URL
+    pub icon: Option<String>,
+
+    // A PEM public key, published on the actor for servers that insist on
+    // one. Nothing is signed with it, since activities aren't delivered
+    pub public_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Upgrades a plain connection, usually on port 587
+    Starttls,
+    // TLS from the start, usually on port 465
+    Tls,
+    // Only for a relay on the same machine
+    None,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewsletterConfig {
+    // The sender, eg: "Notes <notes@example.com>"
+    pub from: String,
+
+    pub smtp_host: String,
+
+    // The usual port for smtp_security unless set
+    pub smtp_port: Option<u16>,
+
+    #[serde(default = "default_smtp_security")]
+    pub smtp_security: SmtpSecurity,
+
+    pub smtp_username: Option<String>,
+
+    pub smtp_password: Option<String>,
+
+    // How often the feed is checked for new documents to send
+    #[serde(default = "default_newsletter_interval")]
+    pub check_interval_mins: u64,
+
+    // Subscriptions waiting for their confirmation link to be followed
+    #[serde(default = "default_max_unconfirmed")]
+    pub max_unconfirmed: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FeedConfig {
+    // The site_title unless set
+    pub title: Option<String>,
+
+    // Most documents listed
+    #[serde(default = "default_feed_limit")]
+    pub limit: usize,
+
+    // Only list documents in this folder, eg: "blog"
+    pub folder: Option<String>,
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        FeedConfig {
+            title: None,
+            limit: default_feed_limit(),
+            folder: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TrackingConfig {
+    // Query parameters ignored for caching, redirects and logging. A
+    // trailing `*` matches by prefix
+    #[serde(default = "default_strip_params")]
+    pub strip_params: Vec<String>,
+
+    // Send browsers a 301 to the URL without them
+    #[serde(default)]
+    pub redirect: bool,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        TrackingConfig {
+            strip_params: default_strip_params(),
+            redirect: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CommentsConfig {
+    // "builtin" uses the moderated comment store, anything else is passed
+    // through for the templates to embed (giscus, utterances, disqus, ...)
+    pub provider: String,
+
+    pub site_id: Option<String>,
+
+    pub repo: Option<String>,
+
+    #[serde(default)]
+    pub options: IndexMap<String, String>,
+
+    #[serde(default = "default_comment_interval")]
+    pub min_interval_secs: u64,
+
+    #[serde(default = "default_max_pending_comments")]
+    pub max_pending: usize,
 }
 
 fn default_chimera_root() -> String { "/data".to_string() }
 fn default_site_title() -> String { "Chimera-md".to_string() }
 fn default_index_file() -> String { "index.md".to_string() }
 fn default_highlight_style() -> String { "an-old-hope".to_string() }
+fn default_math_renderer() -> String { "katex".to_string() }
 fn default_site_lang() -> String { "en".to_string() }
 fn default_log_level() -> LogLevel { LogLevel::Info }
 fn default_max_cache_size() -> usize { 50 * 1024 * 1024 }
+fn default_comment_interval() -> u64 { 60 }
+fn default_max_pending_comments() -> usize { 200 }
+fn default_max_pending_webmentions() -> usize { 200 }
+fn default_smtp_security() -> SmtpSecurity { SmtpSecurity::Starttls }
+fn default_newsletter_interval() -> u64 { 60 }
+fn default_max_unconfirmed() -> usize { 500 }
 fn default_port() -> u16 { 8080 }
+fn default_converter_output() -> ConverterOutput { ConverterOutput::Html }
+fn default_converter_timeout() -> u64 { 10 }
+fn default_fetch_command() -> Vec<String> {
+    ["curl", "--silent", "--fail", "--proto", "=http,https", "--max-time", "10", "--max-filesize", "1000000", "--resolve", "{host}:{port}:{address}", "{url}"]
+        .into_iter().map(String::from).collect()
+}
+fn default_fetch_timeout() -> u64 { 15 }
+fn default_graphviz_command() -> Vec<String> { vec!["dot".to_string(), "-Tsvg".to_string()] }
+fn default_script_memory() -> usize { 16 * 1024 * 1024 }
+fn default_script_instructions() -> u32 { 10_000_000 }
+fn default_max_bookmarks() -> usize { 500 }
+fn default_true() -> bool { true }
+fn default_history_pages() -> usize { 10 }
+fn default_history_days() -> u32 { 30 }
+fn default_precache_pages() -> usize { 20 }
+fn default_recent_pages() -> usize { 50 }
+fn default_feed_limit() -> usize { 20 }
+fn default_hotlink_extensions() -> Vec<String> {
+    ["jpg", "jpeg", "png", "gif", "webp", "avif", "mp4", "webm", "mov", "mp3", "flac", "ogg"]
+        .iter().map(|ext| ext.to_string()).collect()
+}
+fn default_messages_dir() -> String { "messages".to_string() }
+fn default_backup_dir() -> String { "backups".to_string() }
+fn default_backup_interval() -> u64 { 24 }
+fn default_backup_keep() -> usize { 7 }
+fn default_upload_timeout() -> u64 { 600 }
+fn default_trash_hours() -> u32 { 72 }
+fn default_memory_max() -> usize { 512 }
+fn default_large_document() -> usize { 256 }
+fn default_result_cache_percent() -> usize { 50 }
+fn default_search_index_percent() -> usize { 25 }
+fn default_retention_days() -> u32 { 90 }
+fn default_top_pages() -> usize { 25 }
+fn default_review_days() -> u32 { 365 }
+fn default_strip_params() -> Vec<String> { vec!["utm_*".to_string(), "fbclid".to_string(), "gclid".to_string()] }
 
 impl TomlConfig {
     pub fn read_config(config_file: &str) -> Result<TomlConfig, ChimeraError> {
-        let config_file_data = match std::fs::read_to_string(config_file) {
-            Ok(config_file_data) => config_file_data,
-            Err(e) => {
-                if let Ok(cwd) = std::env::current_dir() {
-                    tracing::debug!("CWD: {}", cwd.display());
-                }
-                tracing::error!("Failed reading {config_file}");
-                return Err(ChimeraError::from(e));
-            },
-        };
-        tracing::debug!("Toml config file: {config_file_data}");
-        let config_data: TomlConfig = toml::from_str(config_file_data.as_str())?;
+        let start = std::time::Instant::now();
+        let config_path = std::path::Path::new(config_file);
+        let mut config_table = read_layered(config_path, 0)?;
+        interpolate_env(&mut config_table)?;
+        load_secret_files(&mut config_table, config_path.parent().unwrap_or(std::path::Path::new("")))?;
+        let mut unknown_keys = Vec::new();
+        let mut config_data: TomlConfig = serde_ignored::deserialize(toml::Value::Table(config_table), |path| {
+            unknown_keys.push(path_segments(&path));
+        })?;
+        config_data.warnings = unknown_keys.iter().map(|key| unknown_key_warning(key)).collect();
+        config_data.load_time = start.elapsed();
         Ok(config_data)
     }
 
@@ -86,3 +760,309 @@ impl TomlConfig {
         }
     }
 }
+
+// Files named in `include` are read after the file listing them, relative
+// to it, and later files win. Tables are merged key by key, anything else
+// is replaced outright
+fn read_layered(config_file: &std::path::Path, depth: usize) -> Result<toml::Table, ChimeraError> {
+    let config_file_data = match std::fs::read_to_string(config_file) {
+        Ok(config_file_data) => config_file_data,
+        Err(e) => {
+            if let Ok(cwd) = std::env::current_dir() {
+                tracing::debug!("CWD: {}", cwd.display());
+            }
+            tracing::error!("Failed reading {}", config_file.display());
+            return Err(ChimeraError::from(e));
+        },
+    };
+    tracing::debug!("Toml config file {}: {config_file_data}", config_file.display());
+    let mut config_table: toml::Table = toml::from_str(config_file_data.as_str())?;
+    let Some(includes) = config_table.remove(INCLUDE_KEY) else {
+        return Ok(config_table);
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(ChimeraError::TomlError(format!("Config includes nested too deeply at {}", config_file.display())));
+    }
+    let toml::Value::Array(includes) = includes else {
+        return Err(ChimeraError::TomlError(format!("{INCLUDE_KEY} in {} must be a list of file names", config_file.display())));
+    };
+    let base_dir = config_file.parent().unwrap_or(std::path::Path::new(""));
+    for include in includes {
+        let Some(include) = include.as_str() else {
+            return Err(ChimeraError::TomlError(format!("{INCLUDE_KEY} in {} must be a list of file names", config_file.display())));
+        };
+        tracing::debug!("Including config file {include}");
+        let layer = read_layered(base_dir.join(include).as_path(), depth + 1)?;
+        merge_tables(&mut config_table, layer);
+    }
+    Ok(config_table)
+}
+
+fn merge_tables(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(key.as_str()), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => merge_tables(existing, value),
+            (_, value) => {
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+// Replaces ${NAME} in string values with environment variables. $${ is a literal ${
+fn interpolate_env(table: &mut toml::Table) -> Result<(), ChimeraError> {
+    for (_key, value) in table.iter_mut() {
+        interpolate_value(value)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(value: &mut toml::Value) -> Result<(), ChimeraError> {
+    match value {
+        toml::Value::String(text) if text.contains("${") => {
+            *text = interpolate_string(text.as_str())?;
+        },
+        toml::Value::Array(values) => {
+            for value in values {
+                interpolate_value(value)?;
+            }
+        },
+        toml::Value::Table(table) => interpolate_env(table)?,
+        _ => {},
+    }
+    Ok(())
+}
+
+fn interpolate_string(text: &str) -> Result<String, ChimeraError> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(ChimeraError::TomlError(format!("Unterminated ${{ in config value \"{text}\"")));
+        };
+        let name = &rest[start + 2..start + end];
+        match std::env::var(name) {
+            Ok(value) => result.push_str(value.as_str()),
+            Err(_) => {
+                return Err(ChimeraError::TomlError(format!("Environment variable {name} used in the config is not set")));
+            },
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// `password_file = "/run/secrets/admin"` sets password to the file's contents
+fn load_secret_files(table: &mut toml::Table, base_dir: &std::path::Path) -> Result<(), ChimeraError> {
+    let secret_keys: Vec<String> = table.keys()
+        .filter(|key| {
+            key.strip_suffix(FILE_SUFFIX).is_some_and(|base| {
+                SECRET_SUFFIXES.iter().any(|suffix| base.ends_with(suffix))
+            })
+        })
+        .cloned()
+        .collect();
+    for key in secret_keys {
+        let Some(toml::Value::String(file)) = table.remove(key.as_str()) else {
+            return Err(ChimeraError::TomlError(format!("{key} must be a file name")));
+        };
+        let base_key = key.trim_end_matches(FILE_SUFFIX).to_string();
+        if table.contains_key(base_key.as_str()) {
+            return Err(ChimeraError::TomlError(format!("Both {base_key} and {key} are set in the config")));
+        }
+        let path = base_dir.join(file.as_str());
+        let secret = match std::fs::read_to_string(path.as_path()) {
+            Ok(secret) => secret,
+            Err(e) => {
+                tracing::error!("Failed reading {key} from {}", path.display());
+                return Err(ChimeraError::from(e));
+            },
+        };
+        let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+        table.insert(base_key, toml::Value::String(secret));
+    }
+    for (_key, value) in table.iter_mut() {
+        if let toml::Value::Table(table) = value {
+            load_secret_files(table, base_dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn path_segments(path: &serde_ignored::Path) -> Vec<String> {
+    match path {
+        serde_ignored::Path::Root => Vec::new(),
+        serde_ignored::Path::Seq { parent, index } => {
+            let mut segments = path_segments(parent);
+            segments.push(index.to_string());
+            segments
+        },
+        serde_ignored::Path::Map { parent, key } => {
+            let mut segments = path_segments(parent);
+            segments.push(key.clone());
+            segments
+        },
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => path_segments(parent),
+    }
+}
+
+fn unknown_key_warning(key: &[String]) -> String {
+    let Some((name, section)) = key.split_last() else {
+        return "Unknown config key".to_string();
+    };
+    let section: Vec<&str> = section.iter().map(String::as_str).collect();
+    let known = match section.as_slice() {
+        [] => struct_fields::<TomlConfig>(),
+        ["admin"] => struct_fields::<AdminConfig>(),
+        ["analytics"] => struct_fields::<AnalyticsConfig>(),
+        ["comments"] => struct_fields::<CommentsConfig>(),
+        ["bookmarks"] => struct_fields::<BookmarksConfig>(),
+        ["offline"] => struct_fields::<OfflineConfig>(),
+        ["gallery"] => struct_fields::<GalleryConfig>(),
+        ["hotlinking"] => struct_fields::<HotlinkingConfig>(),
+        ["i18n"] => struct_fields::<I18nConfig>(),
+        ["backup"] => struct_fields::<BackupConfig>(),
+        ["trash"] => struct_fields::<TrashConfig>(),
+        ["memory"] => struct_fields::<MemoryConfig>(),
+        ["reading_history"] => struct_fields::<ReadingHistoryConfig>(),
+        ["session"] => struct_fields::<SessionConfig>(),
+        ["access"] => struct_fields::<AccessConfig>(),
+        ["expiry"] => struct_fields::<ExpiryConfig>(),
+        ["tracking"] => struct_fields::<TrackingConfig>(),
+        ["search"] => struct_fields::<SearchConfig>(),
+        ["markup"] => struct_fields::<MarkupConfig>(),
+        ["social"] => struct_fields::<SocialConfig>(),
+        ["feed"] => struct_fields::<FeedConfig>(),
+        ["well_known"] => struct_fields::<WellKnownConfig>(),
+        ["webmentions"] => struct_fields::<WebmentionsConfig>(),
+        ["activitypub"] => struct_fields::<ActivityPubConfig>(),
+        ["newsletter"] => struct_fields::<NewsletterConfig>(),
+        ["scripts"] => struct_fields::<ScriptsConfig>(),
+        ["authors", _] => struct_fields::<AuthorConfig>(),
+        ["converters", _] => struct_fields::<ConverterConfig>(),
+        ["graphviz"] => struct_fields::<GraphvizConfig>(),
+        _ => &[],
+    };
+    let max_distance = (name.len() / 3).max(1);
+    let suggestion = known.iter()
+        .map(|field| (strsim::damerau_levenshtein(name, field), field))
+        .filter(|(distance, _field)| *distance <= max_distance)
+        .min_by_key(|(distance, _field)| *distance);
+    let key = key.join(".");
+    match suggestion {
+        Some((_distance, field)) => format!("Unknown config key {key}, did you mean {field}?"),
+        None => format!("Unknown config key {key}"),
+    }
+}
+
+// Field names of a config section, found by asking serde's derived
+// Deserialize impl which fields it would accept
+fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct FieldRecorder<'a> {
+        fields: &'a mut &'static [&'static str],
+    }
+
+    impl<'de> serde::Deserializer<'de> for FieldRecorder<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.fields = fields;
+            Err(serde::de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldRecorder { fields: &mut fields });
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A folder of config files, removed when dropped
+    struct ConfigFiles(std::path::PathBuf);
+
+    impl ConfigFiles {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let folder = std::env::temp_dir().join(format!("chimera-config-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(folder.as_path()).unwrap();
+            for (file, contents) in files {
+                std::fs::write(folder.join(file), contents).unwrap();
+            }
+            ConfigFiles(folder)
+        }
+
+        fn read(&self) -> Result<TomlConfig, ChimeraError> {
+            TomlConfig::read_config(self.0.join("chimera.toml").to_string_lossy().as_ref())
+        }
+    }
+
+    impl Drop for ConfigFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.as_path());
+        }
+    }
+
+    #[test]
+    fn test_include_overrides_base() {
+        let files = ConfigFiles::new("include", &[
+            ("chimera.toml", "include = [\"local.toml\"]\nsite_title = \"Base\"\nport = 8080\n[admin]\nusername = \"admin\"\npassword = \"base\"\n"),
+            ("local.toml", "site_title = \"Local\"\n[admin]\npassword = \"local\"\n"),
+        ]);
+        let config = files.read().unwrap();
+        assert_eq!(config.site_title, "Local");
+        assert_eq!(config.port, 8080);
+        // tables merge key by key
+        let admin = config.admin.unwrap();
+        assert_eq!(admin.username, "admin");
+        assert_eq!(admin.password, "local");
+    }
+
+    #[test]
+    fn test_missing_secret_file() {
+        let files = ConfigFiles::new("secret", &[
+            ("chimera.toml", "[admin]\nusername = \"admin\"\npassword_file = \"missing-password\"\n"),
+        ]);
+        assert!(files.read().is_err());
+        std::fs::write(files.0.join("missing-password"), "hunter2\n").unwrap();
+        assert_eq!(files.read().unwrap().admin.unwrap().password, "hunter2");
+    }
+
+    #[test]
+    fn test_unknown_key_reported() {
+        let files = ConfigFiles::new("unknown", &[
+            ("chimera.toml", "site_titel = \"Notes\"\n[admin]\nusername = \"admin\"\npassword = \"x\"\ncolour = \"red\"\n"),
+        ]);
+        let config = files.read().unwrap();
+        assert_eq!(config.warnings, [
+            "Unknown config key site_titel, did you mean site_title?",
+            "Unknown config key admin.colour",
+        ]);
+    }
+}
@@ -0,0 +1,147 @@
+use axum::{extract::{Request, State}, http::{header, Method, StatusCode, Uri}, middleware::Next, response::{IntoResponse, Response}};
+
+use crate::toml_config::TrackingConfig;
+use crate::AppStateType;
+
+// Query parameters added by analytics and ad platforms. They don't change
+// the page, so they're dropped before caching, redirects and logging
+#[derive(Clone)]
+pub struct TrackingParams {
+    // Exact names, or prefixes ending in `*`, eg: utm_*
+    patterns: Vec<String>,
+    redirect: bool,
+}
+
+impl TrackingParams {
+    pub fn new(config: TrackingConfig) -> Self {
+        TrackingParams {
+            patterns: config.strip_params,
+            redirect: config.redirect,
+        }
+    }
+
+    fn is_tracking(&self, name: &str) -> bool {
+        let name = urlencoding::decode(name).map_or(name.to_string(), |name| name.into_owned());
+        self.patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == *pattern,
+        })
+    }
+
+    // The query without tracking parameters, or None if there were none to
+    // remove. The rest are kept as sent, in order
+    pub fn strip(&self, query: &str) -> Option<String> {
+        let mut removed = false;
+        let kept: Vec<&str> = query.split('&')
+            .filter(|pair| {
+                let name = pair.split_once('=').map_or(*pair, |(name, _value)| name);
+                let tracking = self.is_tracking(name);
+                removed |= tracking;
+                !tracking
+            })
+            .collect();
+        match removed {
+            true => Some(kept.join("&")),
+            false => None,
+        }
+    }
+}
+
+fn clean_uri(uri: &Uri, query: &str) -> Option<Uri> {
+    let path_and_query = match query.is_empty() {
+        true => uri.path().to_string(),
+        false => format!("{}?{query}", uri.path()),
+    };
+    Uri::builder().path_and_query(path_and_query).build().ok()
+}
+
+// Drops tracking parameters from the request's URI, or answers with a
+// redirect to the URI without them
+fn clean_request(tracking: &TrackingParams, request: &mut Request) -> Option<Response> {
+    let uri = request.uri().query()
+        .and_then(|query| tracking.strip(query))
+        .and_then(|query| clean_uri(request.uri(), query.as_str()))?;
+    let method = request.method();
+    if tracking.redirect && (method == Method::GET || method == Method::HEAD) {
+        tracing::debug!("Redirecting {} => {uri}", request.uri());
+        return Some((
+            StatusCode::MOVED_PERMANENTLY,
+            [(header::LOCATION, uri.to_string())],
+        ).into_response());
+    }
+    *request.uri_mut() = uri;
+    None
+}
+
+pub async fn mw_strip_tracking(
+    State(app_state): State<AppStateType>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(redirect) = clean_request(&app_state.tracking_params, &mut request) {
+        return redirect;
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracking(redirect: bool) -> TrackingParams {
+        TrackingParams::new(TrackingConfig {
+            redirect,
+            ..TrackingConfig::default()
+        })
+    }
+
+    fn request(method: Method, uri: &str) -> Request {
+        Request::builder().method(method).uri(uri).body(axum::body::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_strip() {
+        let tracking = tracking(false);
+        assert_eq!(tracking.strip("utm_source=news&b=2&utm_medium=email&a=1").as_deref(), Some("b=2&a=1"));
+        assert_eq!(tracking.strip("z=1&fbclid=abc&y=2&gclid=def&x=3").as_deref(), Some("z=1&y=2&x=3"));
+        assert_eq!(tracking.strip("utm_source&q=rust").as_deref(), Some("q=rust"));
+        assert_eq!(tracking.strip("utm%5Fsource=news&q=rust").as_deref(), Some("q=rust"));
+        assert_eq!(tracking.strip("utm_campaign=spring").as_deref(), Some(""));
+        // only the configured names, matched exactly unless they end in *
+        assert_eq!(tracking.strip("b=2&a=1&utmost=1&fbclid_x=1"), None);
+        assert_eq!(tracking.strip("tag=a&tag=b"), None);
+    }
+
+    #[test]
+    fn test_redirect_only_when_stripped() {
+        let tracking = tracking(true);
+        let mut clean = request(Method::GET, "/home/a.md?b=2&a=1");
+        assert!(clean_request(&tracking, &mut clean).is_none());
+        assert_eq!(clean.uri(), "/home/a.md?b=2&a=1");
+
+        let mut tracked = request(Method::GET, "/home/a.md?b=2&utm_source=news&a=1");
+        let redirect = clean_request(&tracking, &mut tracked).unwrap();
+        assert_eq!(redirect.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(redirect.headers()[header::LOCATION], "/home/a.md?b=2&a=1");
+
+        let mut only_tracking = request(Method::HEAD, "/home/a.md?utm_source=news");
+        let redirect = clean_request(&tracking, &mut only_tracking).unwrap();
+        assert_eq!(redirect.headers()[header::LOCATION], "/home/a.md");
+
+        // forms can't follow a redirect, so they're cleaned in place
+        let mut post = request(Method::POST, "/comments?utm_source=news&x=1");
+        assert!(clean_request(&tracking, &mut post).is_none());
+        assert_eq!(post.uri(), "/comments?x=1");
+    }
+
+    #[test]
+    fn test_cleaned_in_place_without_redirect() {
+        let tracking = tracking(false);
+        let mut tracked = request(Method::GET, "/home/a.md?utm_source=news&b=2&a=1");
+        assert!(clean_request(&tracking, &mut tracked).is_none());
+        assert_eq!(tracked.uri(), "/home/a.md?b=2&a=1");
+        let mut only_tracking = request(Method::GET, "/home/a.md?gclid=1");
+        assert!(clean_request(&tracking, &mut only_tracking).is_none());
+        assert_eq!(only_tracking.uri(), "/home/a.md");
+    }
+}
@@ -0,0 +1,223 @@
+use std::{collections::BTreeMap, ffi::OsStr, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::Duration};
+use axum::{extract::State, response::{IntoResponse, Response}};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::{handle_404, ChimeraError};
+use crate::result_cache::ResultCache;
+use crate::toml_config::TrashConfig;
+use crate::session::CsrfHtml;
+use crate::AppStateType;
+
+pub const TRASH_URL: &str = "/admin/trash";
+
+// How often expired pages are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct TrashedPage {
+    // RFC 3339
+    deleted: String,
+    html: String,
+}
+
+// A deleted document, as listed on the admin page
+#[derive(Debug, Serialize)]
+pub struct TrashEntry {
+    pub path: String,
+    pub url: String,
+    pub deleted: String,
+    pub size: usize,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct TrashFile {
+    // Document path, relative to the document root => its last render
+    pages: BTreeMap<String, TrashedPage>,
+}
+
+struct TrashInternal {
+    path: PathBuf,
+    file: TrashFile,
+}
+
+// The last rendered HTML of deleted documents, taken from the result cache
+// as they're deleted and kept for keep_hours, so an accidental deletion can
+// be recovered from the admin pages. A document that comes back leaves the
+// trash. Pages not rendered since they last changed have nothing to keep
+#[derive(Clone)]
+pub struct Trash {
+    lock: Arc<RwLock<TrashInternal>>,
+    document_root: PathBuf,
+    keep_hours: u32,
+}
+
+impl Trash {
+    pub fn new(trash_dir: PathBuf, document_root: &Path, config: TrashConfig) -> Self {
+        if let Err(e) = std::fs::create_dir_all(trash_dir.as_path()) {
+            tracing::warn!("Failed to create trash directory {}: {e}", trash_dir.display());
+        }
+        let path = trash_dir.join("trash.toml");
+        let file = match std::fs::read_to_string(path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", path.display());
+                        TrashFile::default()
+                    }
+                }
+            },
+            Err(_) => TrashFile::default(),
+        };
+        let trash = Trash {
+            lock: Arc::new(RwLock::new(TrashInternal {
+                path,
+                file,
+            })),
+            document_root: document_root.to_path_buf(),
+            keep_hours: config.keep_hours,
+        };
+        tokio::spawn(periodic_purge(trash.clone()));
+        trash
+    }
+
+    // Called by the result cache with each changed file, before it's cleared
+    pub(crate) fn on_change(&self, path: &Path, result_cache: &ResultCache) {
+        if path.extension() != Some(OsStr::new("md")) {
+            return;
+        }
+        let Ok(relative) = path.strip_prefix(self.document_root.as_path()) else {
+            return;
+        };
+        let key = relative.to_string_lossy().replace('\\', "/");
+        if path.exists() {
+            // restored, or it was never deleted
+            if self.take(key.as_str()) {
+                tracing::info!("{key} is back, removed it from the trash");
+            }
+            return;
+        }
+        match result_cache.last_render(relative) {
+            Some(html) => {
+                tracing::info!("Moved the last render of deleted {key} to the trash");
+                self.put(key.as_str(), html);
+            },
+            None => tracing::info!("Deleted {key} wasn't in the result cache, there's nothing to keep"),
+        }
+    }
+
+    fn put(&self, key: &str, html: String) {
+        {
+            let Ok(mut lock) = self.lock.write() else {
+                return;
+            };
+            let deleted = time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default();
+            lock.file.pages.insert(key.to_string(), TrashedPage {
+                deleted,
+                html,
+            });
+        }
+        self.spawn_save();
+    }
+
+    fn take(&self, key: &str) -> bool {
+        let taken = match self.lock.write() {
+            Ok(mut lock) => lock.file.pages.remove(key).is_some(),
+            Err(_) => false,
+        };
+        if taken {
+            self.spawn_save();
+        }
+        taken
+    }
+
+    pub fn entries(&self) -> Vec<TrashEntry> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        let mut entries: Vec<TrashEntry> = lock.file.pages.iter().map(|(path, page)| TrashEntry {
+            path: path.clone(),
+            url: format!("{TRASH_URL}/{}", urlencoding::encode(path).replace("%2F", "/")),
+            deleted: page.deleted.clone(),
+            size: page.html.len(),
+        }).collect();
+        entries.sort_by(|a, b| b.deleted.cmp(&a.deleted));
+        entries
+    }
+
+    fn html(&self, key: &str) -> Option<String> {
+        let lock = self.lock.read().ok()?;
+        lock.file.pages.get(key).map(|page| page.html.clone())
+    }
+
+    // Drops pages that have been in the trash longer than keep_hours
+    fn purge(&self) -> bool {
+        let Ok(mut lock) = self.lock.write() else {
+            return false;
+        };
+        let cutoff = time::OffsetDateTime::now_utc() - time::Duration::hours(self.keep_hours as i64);
+        let before = lock.file.pages.len();
+        lock.file.pages.retain(|path, page| {
+            let keep = time::OffsetDateTime::parse(page.deleted.as_str(), &time::format_description::well_known::Rfc3339)
+                .is_ok_and(|deleted| deleted >= cutoff);
+            if !keep {
+                tracing::info!("Purged {path} from the trash");
+            }
+            keep
+        });
+        lock.file.pages.len() != before
+    }
+
+    fn spawn_save(&self) {
+        let trash = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = trash.save().await {
+                tracing::warn!("Failed to save the trash: {e:?}");
+            }
+        });
+    }
+
+    async fn save(&self) -> Result<(), ChimeraError> {
+        let (path, toml) = {
+            let lock = self.lock.read()?;
+            match toml::to_string(&lock.file) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting the trash to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+async fn periodic_purge(trash: Trash) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if trash.purge() {
+            if let Err(e) = trash.save().await {
+                tracing::warn!("Failed to save the trash: {e:?}");
+            }
+        }
+    }
+}
+
+// The deleted document's last render, eg: /admin/trash/notes/todo.md
+pub async fn handle_trashed_page(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+) -> Response {
+    match app_state.trash.as_ref().and_then(|trash| trash.html(path.as_str())) {
+        Some(html) => (
+            [(axum::http::header::CACHE_CONTROL, "no-store")],
+            CsrfHtml(html),
+        ).into_response(),
+        None => handle_404(app_state).await.into_response(),
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::{Arc, RwLock};
+use axum::{extract::Request, http::{header, StatusCode}, response::{Html, IntoResponse, Response}, Router};
+use tower::ServiceExt;
+
+// Seconds browsers and proxies are asked to wait before trying again
+const RETRY_AFTER: &str = "5";
+
+// Templates aren't compiled yet, so this can't come from one
+const WARMING_UP_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <meta http-equiv="refresh" content="5">
+  <title>Starting up</title>
+</head>
+<body>
+  <h1>Starting up</h1>
+  <p>The server is getting ready. This page will reload in a few seconds.</p>
+</body>
+</html>
+"#;
+
+// Lets the server listen before the site is ready. Until ready is called,
+// every request gets a 503 "starting up" page; from then on they go to the
+// real router
+#[derive(Clone, Default)]
+pub struct WarmUp {
+    app: Arc<RwLock<Option<Router>>>,
+}
+
+impl WarmUp {
+    pub fn router(&self) -> Router {
+        let app = self.app.clone();
+        Router::new().fallback(move |request: Request| forward(app.clone(), request))
+    }
+
+    pub fn ready(&self, app: Router) {
+        match self.app.write() {
+            Ok(mut lock) => *lock = Some(app),
+            Err(e) => tracing::error!("Failed to swap in the site, it will stay unavailable: {e}"),
+        }
+    }
+}
+
+async fn forward(app: Arc<RwLock<Option<Router>>>, request: Request) -> Response {
+    // cloned, so the lock isn't held while the request is served
+    let app = app.read().ok().and_then(|lock| lock.clone());
+    let Some(app) = app else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER), (header::CACHE_CONTROL, "no-store")],
+            Html(WARMING_UP_HTML),
+        ).into_response();
+    };
+    match app.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
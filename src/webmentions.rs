@@ -0,0 +1,481 @@
+use std::{collections::HashSet, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, process::Stdio, sync::{Arc, RwLock}, time::Duration};
+use axum::{extract::State, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, Form};
+use serde::{Deserialize, Serialize};
+
+use crate::access::AccessControl;
+use crate::atomic_write::write_atomic;
+use crate::chimera_error::{handle_404, ChimeraError};
+use crate::comments::page_exists;
+use crate::feed;
+use crate::toml_config::WebmentionsConfig;
+use crate::{AppStateType, HOME_DIR};
+
+pub const WEBMENTION_URL: &str = "/webmention";
+
+const MAX_URL_LEN: usize = 2048;
+const MAX_TITLE_LEN: usize = 200;
+// Sources being fetched at once, past which senders are asked to retry
+const MAX_VERIFYING: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webmention {
+    pub id: u64,
+    pub source: String,
+    pub page: String,
+    // The source's <title>, when it has one
+    pub title: String,
+    pub received: String,
+    pub approved: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct WebmentionFile {
+    next_id: u64,
+    webmentions: Vec<Webmention>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WebmentionRejection {
+    InvalidUrl,
+    SameUrl,
+    InvalidTarget,
+    Busy,
+    QueueFull,
+}
+
+struct WebmentionStoreInternal {
+    path: PathBuf,
+    file: WebmentionFile,
+    // (source, page) being fetched
+    verifying: HashSet<(String, String)>,
+}
+
+// Webmentions (w3.org/TR/webmention) are accepted straight away and checked
+// afterwards, by fetching the source and looking for a link to the target.
+// Ones that check out go into a moderation queue like comments do
+#[derive(Clone)]
+pub struct WebmentionStore {
+    lock: Arc<RwLock<WebmentionStoreInternal>>,
+    // eg: ["curl", ..., "--resolve", "{host}:{port}:{address}", "{url}"]
+    fetch_command: Arc<Vec<String>>,
+    fetch_timeout: Duration,
+    max_pending: usize,
+}
+
+impl WebmentionStore {
+    pub fn new(webmentions_dir: PathBuf, config: WebmentionsConfig) -> Option<Self> {
+        if config.fetch_command.is_empty() {
+            tracing::warn!("Ignoring [webmentions], it has no fetch_command");
+            return None;
+        }
+        if let Err(e) = std::fs::create_dir_all(webmentions_dir.as_path()) {
+            tracing::warn!("Failed to create webmentions directory {}: {e}", webmentions_dir.display());
+        }
+        let path = webmentions_dir.join("webmentions.toml");
+        let file = match std::fs::read_to_string(path.as_path()) {
+            Ok(data) => {
+                match toml::from_str(data.as_str()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::error!("Error parsing {}: {e}", path.display());
+                        WebmentionFile::default()
+                    }
+                }
+            },
+            Err(_) => WebmentionFile::default(),
+        };
+        tracing::info!("Webmention store loaded with {} webmentions", file.webmentions.len());
+        Some(WebmentionStore {
+            lock: Arc::new(RwLock::new(WebmentionStoreInternal {
+                path,
+                file,
+                verifying: HashSet::new(),
+            })),
+            fetch_command: Arc::new(config.fetch_command),
+            fetch_timeout: Duration::from_secs(config.fetch_timeout_secs),
+            max_pending: config.max_pending,
+        })
+    }
+
+    pub fn approved_for(&self, page: &str) -> Vec<Webmention> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.file.webmentions.iter().filter(|w| w.approved && w.page == page).cloned().collect()
+    }
+
+    pub fn pending(&self) -> Vec<Webmention> {
+        let Ok(lock) = self.lock.read() else {
+            return Vec::new();
+        };
+        lock.file.webmentions.iter().filter(|w| !w.approved).cloned().collect()
+    }
+
+    // Checks what can be checked without fetching the source, and returns
+    // the page mentioned. `site_url` is where this site is served. Restricted
+    // pages are refused like missing ones, so senders can't tell them apart
+    pub fn accept(&self, source: &str, target: &str, site_url: &str, access: &AccessControl) -> Result<String, WebmentionRejection> {
+        if !is_http_url(source) || !is_http_url(target) {
+            return Err(WebmentionRejection::InvalidUrl);
+        }
+        let page = target.split(['#', '?']).next().unwrap_or_default();
+        if source.split('#').next() == Some(page) {
+            return Err(WebmentionRejection::SameUrl);
+        }
+        // kept as pages know their own URL, unencoded
+        let page = match page.strip_prefix(site_url).filter(|page| page_exists(page)) {
+            Some(page) => urlencoding::decode(page).map_err(|_| WebmentionRejection::InvalidTarget)?.into_owned(),
+            None => return Err(WebmentionRejection::InvalidTarget),
+        };
+        let relative = page.strip_prefix(format!("{HOME_DIR}/").as_str()).unwrap_or(page.as_str());
+        if access.allowed_users(Path::new(relative)).is_some() {
+            return Err(WebmentionRejection::InvalidTarget);
+        }
+        let Ok(mut lock) = self.lock.write() else {
+            return Err(WebmentionRejection::Busy);
+        };
+        if lock.verifying.len() >= MAX_VERIFYING || lock.verifying.contains(&(source.to_string(), page.clone())) {
+            return Err(WebmentionRejection::Busy);
+        }
+        if lock.file.webmentions.iter().filter(|w| !w.approved).count() >= self.max_pending {
+            return Err(WebmentionRejection::QueueFull);
+        }
+        lock.verifying.insert((source.to_string(), page.clone()));
+        Ok(page)
+    }
+
+    // Fetches the source and records the mention, or forgets an earlier one
+    // if the source no longer links to the target. Returns whether an
+    // approved mention changed, since those are baked into rendered pages
+    pub async fn verify(&self, source: &str, target: &str, page: &str) -> bool {
+        let fetched = self.fetch(source).await;
+        let changed = match fetched {
+            Ok(html) if html.contains(target) => {
+                self.record(source, page, source_title(html.as_str()).as_str())
+            },
+            Ok(_) => {
+                tracing::info!("Discarded webmention from {source}, it doesn't link to {target}");
+                self.forget(source, page)
+            },
+            Err(e) => {
+                tracing::info!("Discarded webmention from {source}: {e:?}");
+                false
+            },
+        };
+        if let Ok(mut lock) = self.lock.write() {
+            lock.verifying.remove(&(source.to_string(), page.to_string()));
+        }
+        if let Err(e) = self.save().await {
+            tracing::warn!("Failed to save webmentions: {e:?}");
+        }
+        changed
+    }
+
+    // Only from public addresses, so a sender can't have the server fetch
+    // something on its own network. The command is given the address that
+    // was checked, to connect to rather than looking the host up again
+    async fn fetch(&self, url: &str) -> Result<String, ChimeraError> {
+        let (host, address) = resolve_public(url).await?;
+        let ip = match address.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        };
+        let command: Vec<String> = self.fetch_command.iter().map(|arg| {
+            arg.replace("{host}", host.as_str())
+                .replace("{port}", address.port().to_string().as_str())
+                .replace("{address}", ip.as_str())
+                .replace("{url}", url)
+        }).collect();
+        let program = command[0].as_str();
+        let child = tokio::process::Command::new(program)
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| ChimeraError::WebmentionError(format!("Failed to start {program}: {e}")))?;
+        let output = match tokio::time::timeout(self.fetch_timeout, child.wait_with_output()).await {
+            Ok(output) => output?,
+            Err(_) => {
+                return Err(ChimeraError::WebmentionError(format!("{program} took over {}s", self.fetch_timeout.as_secs())));
+            },
+        };
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(output.stderr.as_slice());
+            return Err(ChimeraError::WebmentionError(format!("{program} failed ({}): {}", output.status, stderr.trim())));
+        }
+        Ok(String::from_utf8_lossy(output.stdout.as_slice()).into_owned())
+    }
+
+    // A source sending again updates its mention, which keeps its approval
+    fn record(&self, source: &str, page: &str, title: &str) -> bool {
+        let Ok(mut lock) = self.lock.write() else {
+            return false;
+        };
+        if let Some(mention) = lock.file.webmentions.iter_mut().find(|w| w.source == source && w.page == page) {
+            let changed = mention.approved && mention.title != title;
+            mention.title = title.to_string();
+            return changed;
+        }
+        let id = lock.file.next_id;
+        lock.file.next_id += 1;
+        let received = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        tracing::info!("New webmention {id} from {source} queued for moderation on {page}");
+        lock.file.webmentions.push(Webmention {
+            id,
+            source: source.to_string(),
+            page: page.to_string(),
+            title: title.to_string(),
+            received,
+            approved: false,
+        });
+        false
+    }
+
+    fn forget(&self, source: &str, page: &str) -> bool {
+        let Ok(mut lock) = self.lock.write() else {
+            return false;
+        };
+        let approved = lock.file.webmentions.iter().any(|w| w.approved && w.source == source && w.page == page);
+        lock.file.webmentions.retain(|w| w.source != source || w.page != page);
+        approved
+    }
+
+    pub async fn approve(&self, id: u64) -> Result<bool, ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            match lock.file.webmentions.iter_mut().find(|w| w.id == id) {
+                Some(mention) => {
+                    mention.approved = true;
+                    true
+                },
+                None => false,
+            }
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(found)
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<bool, ChimeraError> {
+        let found = {
+            let mut lock = self.lock.write()?;
+            let before = lock.file.webmentions.len();
+            lock.file.webmentions.retain(|w| w.id != id);
+            lock.file.webmentions.len() != before
+        };
+        if found {
+            self.save().await?;
+        }
+        Ok(found)
+    }
+
+    async fn save(&self) -> Result<(), ChimeraError> {
+        let (path, toml) = {
+            let lock = self.lock.read()?;
+            match toml::to_string(&lock.file) {
+                Ok(toml) => (lock.path.clone(), toml),
+                Err(e) => {
+                    tracing::warn!("Failure converting webmentions to toml: {e}");
+                    return Ok(());
+                }
+            }
+        };
+        write_atomic(path.as_path(), toml.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+// Only http(s), which also keeps the URL from passing for an option to
+// the fetch command
+fn is_http_url(url: &str) -> bool {
+    (url.starts_with("https://") || url.starts_with("http://"))
+        && url.len() <= MAX_URL_LEN
+        && !url.contains(|c: char| c.is_whitespace() || c.is_control())
+}
+
+// The URL's host, and an address it resolves to. Fails if any of its
+// addresses is loopback, private, link-local or otherwise not public
+async fn resolve_public(url: &str) -> Result<(String, SocketAddr), ChimeraError> {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if authority.contains('@') {
+        return Err(ChimeraError::WebmentionError(format!("{url} has credentials in it")));
+    }
+    let (host, port) = match authority.rsplit_once(':').filter(|(_host, port)| !port.contains(']')) {
+        Some((host, port)) => {
+            let port = port.parse::<u16>().map_err(|_| ChimeraError::WebmentionError(format!("{url} has an invalid port")))?;
+            (host, port)
+        },
+        None => (authority, if scheme == "https" { 443 } else { 80 }),
+    };
+    // IPv6 hosts are in brackets in URLs, but looked up without them
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    if name.is_empty() {
+        return Err(ChimeraError::WebmentionError(format!("{url} has no host")));
+    }
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name, port)).await
+        .map_err(|e| ChimeraError::WebmentionError(format!("Failed to look up {host}: {e}")))?
+        .collect();
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(ChimeraError::WebmentionError(format!("{host} is at {}, which isn't public", address.ip())));
+    }
+    match addresses.first() {
+        Some(address) => Ok((host.to_string(), *address)),
+        None => Err(ChimeraError::WebmentionError(format!("{host} has no addresses"))),
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+                // shared address space (carrier-grade NAT), and 0.0.0.0/8
+                || (a == 100 && (64..128).contains(&b)) || a == 0)
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+fn source_title(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let Some(start) = lower.find("<title") else {
+        return String::new();
+    };
+    let Some(open_end) = lower[start..].find('>').map(|end| start + end + 1) else {
+        return String::new();
+    };
+    let Some(close) = lower[open_end..].find("</title>").map(|end| open_end + end) else {
+        return String::new();
+    };
+    let title = html[open_end..close].split_whitespace().collect::<Vec<_>>().join(" ");
+    let title = title.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&");
+    title.chars().take(MAX_TITLE_LEN).collect()
+}
+
+#[derive(Deserialize)]
+pub struct WebmentionForm {
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    target: String,
+}
+
+pub async fn handle_post_webmention(
+    State(app_state): State<AppStateType>,
+    headers: HeaderMap,
+    Form(form): Form<WebmentionForm>,
+) -> Response {
+    let Some(store) = app_state.webmention_store.as_ref() else {
+        return handle_404(app_state).await.into_response();
+    };
+    let site_url = feed::site_url(app_state.site_url.as_deref(), &headers);
+    match store.accept(form.source.as_str(), form.target.as_str(), site_url.as_str(), &app_state.access) {
+        Ok(page) => {
+            let store = store.clone();
+            let app_state = app_state.clone();
+            tokio::spawn(async move {
+                if store.verify(form.source.as_str(), form.target.as_str(), page.as_str()).await {
+                    app_state.result_cache.clear();
+                }
+            });
+            (StatusCode::ACCEPTED, "Webmention accepted for checking").into_response()
+        },
+        Err(WebmentionRejection::Busy) => {
+            (StatusCode::TOO_MANY_REQUESTS, "Please try again later").into_response()
+        },
+        Err(e) => {
+            tracing::info!("Rejected webmention from {}: {e:?}", form.source);
+            (StatusCode::BAD_REQUEST, "Webmention rejected").into_response()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use crate::toml_config::AccessConfig;
+
+    fn store(name: &str) -> (WebmentionStore, PathBuf) {
+        let folder = std::env::temp_dir().join(format!("chimera-webmentions-{name}-{}", std::process::id()));
+        let config = WebmentionsConfig {
+            fetch_command: vec!["true".to_string()],
+            fetch_timeout_secs: 1,
+            max_pending: 10,
+        };
+        (WebmentionStore::new(folder.clone(), config).unwrap(), folder)
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1", "::1",                         // loopback
+            "169.254.169.254", "fe80::1",               // link-local
+            "10.1.2.3", "172.16.0.1", "172.31.255.255", "192.168.1.1", // RFC1918
+            "fc00::1", "fd12:3456::1",                  // unique-local
+            "::ffff:127.0.0.1", "::ffff:10.0.0.1", "::ffff:192.168.1.1", "::ffff:169.254.169.254",
+            "0.0.0.0", "::", "100.64.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} isn't public");
+        }
+        for ip in ["93.184.216.34", "172.32.0.1", "2606:2800:220:1::1", "::ffff:93.184.216.34"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_public() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://[::1]:8080/page",
+            "https://169.254.169.254/latest/meta-data",
+            "http://[fe80::1]/",
+            "http://10.0.0.1/",
+            "http://192.168.0.10:80/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:10.0.0.1]/",
+            "http://user@93.184.216.34/",
+            "http://93.184.216.34:http/",
+        ] {
+            assert!(resolve_public(url).await.is_err(), "{url} should be refused");
+        }
+        let (host, address) = resolve_public("https://93.184.216.34/post").await.unwrap();
+        assert_eq!(host, "93.184.216.34");
+        assert_eq!(address, "93.184.216.34:443".parse().unwrap());
+        let (host, address) = resolve_public("http://[2606:2800:220:1::1]:8080/").await.unwrap();
+        assert_eq!(host, "[2606:2800:220:1::1]");
+        assert_eq!(address, "[2606:2800:220:1::1]:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn test_restricted_target_refused_like_missing() {
+        // readme.md is in the working directory tests run from
+        let (store, folder) = store("restricted");
+        let site = "https://example.com";
+        let source = "https://elsewhere.example/post";
+        let restricted = AccessControl::new(AccessConfig {
+            user_header: Some("Remote-User".to_string()),
+            folders: IndexMap::from([("readme.md".to_string(), vec!["alice".to_string()])]),
+        });
+        let open = AccessControl::new(AccessConfig::default());
+        let missing = store.accept(source, format!("{site}{HOME_DIR}/missing.md").as_str(), site, &open);
+        let refused = store.accept(source, format!("{site}{HOME_DIR}/readme.md").as_str(), site, &restricted);
+        assert_eq!(missing, Err(WebmentionRejection::InvalidTarget));
+        assert_eq!(refused, missing);
+        let accepted = store.accept(source, format!("{site}{HOME_DIR}/readme.md").as_str(), site, &open);
+        assert_eq!(accepted, Ok(format!("{HOME_DIR}/readme.md")));
+        let _ = std::fs::remove_dir_all(folder.as_path());
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use axum::{extract::{RawQuery, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Redirect, Response}};
+use serde::Serialize;
+
+use crate::toml_config::WellKnownConfig;
+use crate::AppStateType;
+
+pub const WELL_KNOWN_DIR: &str = "/.well-known";
+
+#[derive(Serialize)]
+struct WebfingerLink<'a> {
+    rel: &'a str,
+    #[serde(rename = "type")]
+    link_type: &'a str,
+    href: &'a str,
+}
+
+// RFC 7033's JSON Resource Descriptor
+#[derive(Serialize)]
+struct Webfinger<'a> {
+    subject: &'a str,
+    aliases: [&'a str; 1],
+    links: Vec<WebfingerLink<'a>>,
+}
+
+#[derive(Clone)]
+struct WebfingerAccount {
+    profile: String,
+    // The ActivityPub actor, which for accounts elsewhere is the profile
+    actor: String,
+}
+
+// The /.well-known/ responses set in the config. Anything else there is
+// looked for in the web roots, like any other file
+#[derive(Clone, Default)]
+pub struct WellKnown {
+    security_txt: Option<String>,
+    change_password: Option<String>,
+    // Lowercase resource, eg: acct:alice@example.com => profile URL
+    webfinger: HashMap<String, WebfingerAccount>,
+    files: HashMap<String, String>,
+}
+
+impl WellKnown {
+    pub fn new(config: WellKnownConfig) -> Self {
+        WellKnown {
+            security_txt: config.security_txt,
+            change_password: config.change_password,
+            webfinger: config.webfinger.into_iter()
+                .map(|(resource, profile)| (resource.to_lowercase(), WebfingerAccount {
+                    actor: profile.clone(),
+                    profile,
+                }))
+                .collect(),
+            files: config.files,
+        }
+    }
+
+    // The site's own ActivityPub actor
+    pub fn add_actor(&mut self, resource: &str, actor: String, profile: String) {
+        self.webfinger.insert(resource.to_lowercase(), WebfingerAccount { profile, actor });
+    }
+
+    fn webfinger(&self, query: Option<&str>) -> Response {
+        let resource = serde_urlencoded::from_str::<Vec<(String, String)>>(query.unwrap_or_default())
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|(name, value)| (name == "resource").then_some(value));
+        let Some(resource) = resource else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        let Some(account) = self.webfinger.get(resource.to_lowercase().as_str()) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let jrd = Webfinger {
+            subject: resource.as_str(),
+            aliases: [account.actor.as_str()],
+            links: vec![
+                WebfingerLink {
+                    rel: "http://webfinger.net/rel/profile-page",
+                    link_type: "text/html",
+                    href: account.profile.as_str(),
+                },
+                WebfingerLink {
+                    rel: "self",
+                    link_type: "application/activity+json",
+                    href: account.actor.as_str(),
+                },
+            ],
+        };
+        match serde_json::to_string(&jrd) {
+            Ok(json) => (
+                [
+                    (header::CONTENT_TYPE, "application/jrd+json"),
+                    // webfinger is looked up from other sites' pages
+                    (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+                ],
+                json,
+            ).into_response(),
+            Err(e) => {
+                tracing::warn!("Error writing the webfinger response: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
+
+    fn respond(&self, name: &str, query: Option<&str>) -> Option<Response> {
+        match name {
+            "security.txt" if self.security_txt.is_some() => {
+                let text = self.security_txt.clone().unwrap_or_default();
+                Some(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response())
+            },
+            "change-password" if self.change_password.is_some() => {
+                self.change_password.as_deref().map(|url| Redirect::to(url).into_response())
+            },
+            "webfinger" if !self.webfinger.is_empty() => Some(self.webfinger(query)),
+            name => self.files.get(name).map(|contents| {
+                // eg: apple-app-site-association, which has no extension
+                let content_type = match contents.trim_start().starts_with(['{', '[']) {
+                    true => "application/json",
+                    false => "text/plain; charset=utf-8",
+                };
+                ([(header::CONTENT_TYPE, content_type)], contents.clone()).into_response()
+            }),
+        }
+    }
+}
+
+pub async fn handle_well_known(
+    State(app_state): State<AppStateType>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    match app_state.well_known.respond(name.as_str(), query.as_deref()) {
+        Some(response) => response,
+        None => {
+            let path = format!("{}/{name}", WELL_KNOWN_DIR.trim_start_matches('/'));
+            crate::handle_root_path(State(app_state), axum::extract::Path(path), RawQuery(query), headers).await
+        },
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::file_manager::find_markdown_files;
+
+// How wikilinks start in a rendered body, before they're resolved
+pub const WIKILINK_MARKER: &str = "<a data-wikilink=";
+
+lazy_static! {
+    // Left in the body by the markdown parser for each [[Page#heading|alias]]
+    static ref WIKILINK_MARKER_RE: Regex = Regex::new(r#"<a data-wikilink="([^"]*)" data-anchor="([^"]*)">([^<]*)</a>"#).unwrap();
+}
+
+// Relative to the document root, by lowercase file stem
+type Names = HashMap<String, Vec<PathBuf>>;
+
+// The documents under the root by file name, so [[Page Name]] can find
+// Page Name.md wherever it is. Built on first use and dropped whenever a
+// document comes or goes
+#[derive(Clone)]
+pub struct DocumentNames {
+    lock: Arc<RwLock<Option<Names>>>,
+    document_root: PathBuf,
+}
+
+impl DocumentNames {
+    pub fn new(document_root: &Path) -> Self {
+        DocumentNames {
+            lock: Arc::new(RwLock::new(None)),
+            document_root: document_root.to_path_buf(),
+        }
+    }
+
+    // The document a wikilink names, relative to the document root. Names
+    // match case-insensitively, and from the end of the path, so [[Page]]
+    // and [[notes/Page]] both find notes/Page.md. Ties go to the linking
+    // document's own folder, then the shortest path
+    pub fn find(&self, target: &str, document: &Path) -> Option<PathBuf> {
+        let target = target.trim().trim_start_matches('/').to_lowercase();
+        let target = target.strip_suffix(".md").unwrap_or(target.as_str());
+        let stem = target.rsplit('/').next()?;
+        if let Ok(lock) = self.lock.read() {
+            if let Some(names) = lock.as_ref() {
+                return pick(names.get(stem)?, target, document);
+            }
+        }
+        let names = self.read_names();
+        let found = names.get(stem).and_then(|candidates| pick(candidates, target, document));
+        if let Ok(mut lock) = self.lock.write() {
+            *lock = Some(names);
+        }
+        found
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut lock) = self.lock.write() {
+            *lock = None;
+        }
+    }
+
+    fn read_names(&self) -> Names {
+        let mut names = Names::new();
+        for path in find_markdown_files(self.document_root.as_path()) {
+            let Ok(relative) = path.strip_prefix(self.document_root.as_path()) else {
+                continue;
+            };
+            if let Some(stem) = relative.file_stem() {
+                names.entry(stem.to_string_lossy().to_lowercase()).or_default().push(relative.to_path_buf());
+            }
+        }
+        names
+    }
+}
+
+fn pick(candidates: &[PathBuf], target: &str, document: &Path) -> Option<PathBuf> {
+    let folder = document.parent().unwrap_or(Path::new(""));
+    candidates.iter()
+        .filter(|candidate| {
+            let name = candidate.with_extension("").to_string_lossy().to_lowercase().replace('\\', "/");
+            name == target || name.ends_with(format!("/{target}").as_str())
+        })
+        .min_by_key(|candidate| (
+            candidate.parent() != Some(folder),
+            candidate.components().count(),
+            candidate.as_os_str().len(),
+        ))
+        .cloned()
+}
+
+// Swaps the wikilink markers in a rendered body for links to the documents
+// they name. Ones that name no document are left as text, marked missing
+pub fn resolve(html: &str, document: &Path, names: Option<&DocumentNames>) -> String {
+    WIKILINK_MARKER_RE.replace_all(html, |captures: &regex::Captures| {
+        let (target, anchor, label) = (&captures[1], &captures[2], &captures[3]);
+        let fragment = match anchor.is_empty() {
+            true => String::new(),
+            false => format!("#{anchor}"),
+        };
+        if target.is_empty() {
+            return format!("<a class=\"wikilink\" href=\"{fragment}\">{label}</a>");
+        }
+        let target = target.replace("&quot;", "\"").replace("&amp;", "&");
+        match names.and_then(|names| names.find(target.as_str(), document)) {
+            Some(found) => {
+                let url: Vec<String> = found.iter()
+                    .map(|part| urlencoding::encode(part.to_string_lossy().as_ref()).into_owned())
+                    .collect();
+                format!("<a class=\"wikilink\" href=\"/home/{}{fragment}\">{label}</a>", url.join("/"))
+            },
+            None => {
+                tracing::debug!("No document for wikilink {target} in {}", document.display());
+                format!("<span class=\"wikilink missing\">{label}</span>")
+            },
+        }
+    }).into_owned()
+}
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use chimera_md::golden::{assert_golden, GoldenHarness};
+
+// Renders the fixtures with the example site's templates. After a change
+// that's meant to alter the pages, accept it with:
+// CHIMERA_UPDATE_GOLDEN=1 cargo test --test golden
+#[tokio::test]
+async fn test_golden_pages() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let harness = GoldenHarness::new(manifest.join("example").as_path(), manifest.join("tests/fixtures/golden").as_path())
+        .await
+        .expect("building the site");
+    let pages = harness.render_all().await.expect("rendering the fixtures");
+    assert_eq!(pages.len(), 3);
+    for (document, html) in pages {
+        let name = document.replace('/', "__").replace(".md", ".html");
+        assert_golden(manifest.join("tests/golden").as_path(), name.as_str(), html.as_str());
+    }
+}